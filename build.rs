@@ -7,7 +7,13 @@ fn main() {
     let dest = env::var("OUT_DIR").unwrap();
     let mut file = File::create(&Path::new(&dest).join("bindings.rs")).unwrap();
 
-    Registry::new(Api::Gles2, (3, 0), Profile::Core, Fallbacks::None, [])
-        .write_bindings(GlobalGenerator, &mut file)
-        .unwrap();
+    Registry::new(
+        Api::Gles2,
+        (3, 0),
+        Profile::Core,
+        Fallbacks::None,
+        ["GL_KHR_debug"],
+    )
+    .write_bindings(GlobalGenerator, &mut file)
+    .unwrap();
 }
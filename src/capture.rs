@@ -0,0 +1,191 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+
+use ld53_base_code::renderer::gl;
+
+/// The directory screenshots and recordings are saved into, relative to the
+/// working directory.
+const CAPTURES_DIRECTORY: &str = "captures";
+
+/// Saves frames as PNGs: single screenshots, and recordings of every frame.
+/// [Capture::capture_frame] should be called every frame after rendering,
+/// before the window is swapped, to save whatever has been requested.
+pub struct Capture {
+    screenshot_requested: bool,
+    recording: Option<Recording>,
+}
+
+struct Recording {
+    directory: PathBuf,
+    frame_duration: f32,
+    frames: u32,
+}
+
+impl Capture {
+    pub fn new() -> Capture {
+        Capture {
+            screenshot_requested: false,
+            recording: None,
+        }
+    }
+
+    /// Saves the next frame as captures/screenshot-<date>_<time>.png.
+    pub fn take_screenshot(&mut self) {
+        self.screenshot_requested = true;
+    }
+
+    /// Starts saving every frame into a new captures/recording-<date>_<time>
+    /// directory, as frame-00000.png, frame-00001.png, and so on. While
+    /// recording, [Capture::time_step] advances time by a fixed step per
+    /// frame, so the frames play back at the same speed as the game runs at
+    /// when played at `frames_per_second`, however long saving them takes.
+    /// Returns the directory the frames are saved in.
+    pub fn start_recording(&mut self, frames_per_second: u32) -> anyhow::Result<&Path> {
+        let directory = unique_path(&format!("recording-{}", timestamp()), "");
+        std::fs::create_dir_all(&directory)
+            .with_context(|| format!("failed to create {}", directory.display()))?;
+        let recording = self.recording.insert(Recording {
+            directory,
+            frame_duration: 1.0 / frames_per_second.max(1) as f32,
+            frames: 0,
+        });
+        Ok(&recording.directory)
+    }
+
+    /// Stops the recording, returning the amount of frames recorded, or None
+    /// if nothing was being recorded.
+    pub fn stop_recording(&mut self) -> Option<u32> {
+        self.recording.take().map(|recording| recording.frames)
+    }
+
+    /// Returns how much time to advance the game by for a frame which took
+    /// `elapsed` seconds of real time: `elapsed` itself, or the recording's
+    /// fixed frame duration while recording.
+    pub fn time_step(&self, elapsed: f32) -> f32 {
+        match &self.recording {
+            Some(recording) => recording.frame_duration,
+            None => elapsed,
+        }
+    }
+
+    /// Saves the `width` by `height` frame rendered into the default
+    /// framebuffer, if a screenshot has been requested or a recording is in
+    /// progress. Returns the screenshot's path, if one was saved. A failure
+    /// stops the recording, to avoid reporting the same error every frame.
+    pub fn capture_frame(&mut self, width: u32, height: u32) -> anyhow::Result<Option<PathBuf>> {
+        if !self.screenshot_requested && self.recording.is_none() {
+            return Ok(None);
+        }
+        let image = gl::read_default_framebuffer(width, height);
+        let mut screenshot = None;
+
+        if self.screenshot_requested {
+            self.screenshot_requested = false;
+            std::fs::create_dir_all(CAPTURES_DIRECTORY)
+                .with_context(|| format!("failed to create {CAPTURES_DIRECTORY}"))?;
+            let path = unique_path(&format!("screenshot-{}", timestamp()), ".png");
+            image
+                .save(&path)
+                .with_context(|| format!("failed to save {}", path.display()))?;
+            screenshot = Some(path);
+        }
+
+        if let Some(recording) = &mut self.recording {
+            let path = recording
+                .directory
+                .join(format!("frame-{:05}.png", recording.frames));
+            if let Err(err) = image.save(&path) {
+                self.recording = None;
+                return Err(err).with_context(|| format!("failed to save {}", path.display()));
+            }
+            recording.frames += 1;
+        }
+
+        Ok(screenshot)
+    }
+}
+
+/// Returns a path in the captures directory for `name` followed by
+/// `extension`, with a number appended to the name if the path is already
+/// taken, e.g. by another capture in the same second.
+fn unique_path(name: &str, extension: &str) -> PathBuf {
+    let directory = Path::new(CAPTURES_DIRECTORY);
+    let mut path = directory.join(format!("{name}{extension}"));
+    let mut n = 2;
+    while path.exists() {
+        path = directory.join(format!("{name}-{n}{extension}"));
+        n += 1;
+    }
+    path
+}
+
+/// Returns the current UTC date and time, see [format_timestamp].
+fn timestamp() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    format_timestamp(seconds)
+}
+
+/// Formats the UTC date and time `seconds` after the Unix epoch as
+/// "YYYY-MM-DD_hh-mm-ss", which sorts chronologically and is a valid file
+/// name on all platforms.
+fn format_timestamp(seconds: u64) -> String {
+    let (days, seconds_of_day) = (seconds / 86400, seconds % 86400);
+
+    // Converts the days since 1970-01-01 to a date in the proleptic
+    // Gregorian calendar, from Howard Hinnant's "chrono-Compatible Low-Level
+    // Date Algorithms" (days_from_civil's inverse, civil_from_days).
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{year:04}-{month:02}-{day:02}_{:02}-{:02}-{:02}",
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01_00-00-00");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29_00-00-00");
+        assert_eq!(format_timestamp(951_868_799), "2000-02-29_23-59-59");
+        assert_eq!(format_timestamp(1_735_689_599), "2024-12-31_23-59-59");
+        assert_eq!(format_timestamp(4_107_542_400), "2100-03-01_00-00-00");
+    }
+
+    #[test]
+    fn time_step_is_fixed_while_recording() {
+        let mut capture = Capture::new();
+        assert_eq!(capture.time_step(0.1), 0.1);
+        capture.recording = Some(Recording {
+            directory: PathBuf::new(),
+            frame_duration: 1.0 / 30.0,
+            frames: 0,
+        });
+        assert_eq!(capture.time_step(0.1), 1.0 / 30.0);
+        assert_eq!(capture.stop_recording(), Some(0));
+        assert_eq!(capture.time_step(0.1), 0.1);
+    }
+}
@@ -0,0 +1,4 @@
+//! The renderer, as a library shared by the game binary and the golden image
+//! tests in tests/golden_images.
+
+pub mod renderer;
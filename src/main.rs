@@ -1,5 +1,8 @@
 use anyhow::Context;
+use glam::Vec2;
 use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
 use sdl2::rect::Point;
 use sdl2::sys::{SDL_Event, SDL_EventType, SDL_KeyCode};
 use sdl2::video::{GLProfile, Window};
@@ -11,11 +14,18 @@ use std::panic;
 use std::ptr;
 use std::time::Instant;
 
+mod capture;
 #[cfg(target_family = "wasm")]
 mod emscripten_h;
-mod renderer;
 
-use renderer::Renderer;
+use capture::Capture;
+use ld53_base_code::renderer::{self, Camera, Renderer};
+
+/// The frame rate of the recordings started with F11.
+const RECORDING_FRAMES_PER_SECOND: u32 = 60;
+/// The lowest LOD bias the L key cycles through, see
+/// [Renderer::set_lod_bias].
+const MIN_LOD_BIAS: f32 = 0.25;
 
 fn main() {
     panic::set_hook(Box::new(|panic_info| {
@@ -35,10 +45,16 @@ fn _main() -> anyhow::Result<()> {
     let gl_attr = video.gl_attr();
     gl_attr.set_context_profile(GLProfile::GLES);
     gl_attr.set_context_version(3, 0);
-    gl_attr.set_multisample_buffers(1);
-    gl_attr.set_multisample_samples(4);
+    // Multisampling is done in the renderer's HDR framebuffer, the default
+    // framebuffer only receives the post-processed image.
     // Linear->SRGB conversion is done in shader, thanks to lacking WebGL support.
     gl_attr.set_framebuffer_srgb_compatible(false);
+    // Setting GL_DEBUG enables the KHR_debug message callback and object
+    // labels, see renderer::gl::enable_debug_layer.
+    let gl_debug = std::env::var_os("GL_DEBUG").is_some();
+    if gl_debug {
+        gl_attr.set_context_flags().debug().set();
+    }
     let window = video
         .window(env!("CARGO_PKG_NAME"), 948, 533)
         .resizable()
@@ -62,6 +78,9 @@ fn _main() -> anyhow::Result<()> {
         use renderer::gl;
 
         gl::load_with(|s| video.gl_get_proc_address(s) as *const core::ffi::c_void);
+        if gl_debug && !gl::enable_debug_layer() {
+            eprintln!("GL_DEBUG is set, but KHR_debug is not supported");
+        }
         video.gl_set_swap_interval(1).unwrap();
         let (w, h) = window.drawable_size();
         gl::call!(gl::Viewport(0, 0, w as i32, h as i32));
@@ -92,6 +111,25 @@ fn _main() -> anyhow::Result<()> {
                     // Here, we specifically "unignore"
                     if keycode == SDL_KeyCode::SDLK_SPACE as i32 {
                         ACCEPTED
+                    } else if keycode == SDL_KeyCode::SDLK_t as i32 {
+                        // Cycles through the tonemappers.
+                        ACCEPTED
+                    } else if keycode == SDL_KeyCode::SDLK_q as i32 {
+                        // Cycles through the shadow qualities.
+                        ACCEPTED
+                    } else if keycode == SDL_KeyCode::SDLK_l as i32 {
+                        // Cycles through the LOD biases.
+                        ACCEPTED
+                    } else if keycode == SDL_KeyCode::SDLK_i as i32 {
+                        // Prints the last frame's draw stats.
+                        ACCEPTED
+                    } else if !cfg!(target_family = "wasm")
+                        && (keycode == SDL_KeyCode::SDLK_F11 as i32
+                            || keycode == SDL_KeyCode::SDLK_F12 as i32)
+                    {
+                        // The capture hotkeys, left to the browser on the
+                        // web, where they're fullscreen and developer tools.
+                        ACCEPTED
                     } else {
                         DROPPED
                     }
@@ -114,7 +152,7 @@ fn _main() -> anyhow::Result<()> {
         unsafe { emscripten_h::emscripten_sleep(100) };
     }
 
-    unsafe { STATE = Some(State::new(window, event_pump)) };
+    unsafe { STATE = Some(State::new(window, event_pump)?) };
 
     #[cfg(target_family = "wasm")]
     {
@@ -134,20 +172,35 @@ struct State {
     event_pump: EventPump,
     mouse_position: Point,
     renderer: Renderer,
+    camera: Camera,
+    /// The name of the glTF camera to view the scene through instead of
+    /// `camera`, from the GLTF_CAMERA environment variable.
+    gltf_camera: Option<String>,
+    capture: Capture,
     time: f32,
     last_frame: Instant,
 }
 
 impl State {
-    pub fn new(window: Window, event_pump: EventPump) -> State {
-        State {
-            renderer: Renderer::new(),
+    pub fn new(window: Window, event_pump: EventPump) -> anyhow::Result<State> {
+        let renderer = Renderer::new().context("renderer initialization failed")?;
+        let gltf_camera = std::env::var("GLTF_CAMERA").ok();
+        if let Some(name) = &gltf_camera {
+            if renderer.gltf_camera(name, 0.0).is_none() {
+                eprintln!("GLTF_CAMERA is set, but there's no glTF camera named {name:?}");
+            }
+        }
+        Ok(State {
+            renderer,
+            camera: Camera::default(),
+            gltf_camera,
+            capture: Capture::new(),
             window,
             event_pump,
             mouse_position: Point::new(0, 0),
             time: 0.0,
             last_frame: Instant::now(),
-        }
+        })
     }
 }
 
@@ -156,12 +209,18 @@ extern "C" fn run_frame() {
         event_pump,
         mouse_position,
         renderer,
+        camera,
+        gltf_camera,
+        capture,
         window,
         time,
         last_frame,
         ..
     } = unsafe { &mut STATE }.as_mut().unwrap();
 
+    // The camera the last frame was rendered with, for picking.
+    let frame_camera = view_camera(renderer, camera, gltf_camera.as_deref(), *time);
+
     for event in event_pump.poll_iter() {
         match event {
             Event::Quit { .. } => std::process::exit(0),
@@ -173,20 +232,103 @@ extern "C" fn run_frame() {
                 _ => {}
             },
             Event::MouseMotion { x, y, .. } => *mouse_position = Point::new(x, y),
+            Event::MouseButtonDown {
+                mouse_btn: MouseButton::Left,
+                x,
+                y,
+                ..
+            } => {
+                let (w, h) = window.size();
+                let screen_position = Vec2::new(x as f32, y as f32);
+                let screen_size = Vec2::new(w as f32, h as f32);
+                let pick = renderer.pick(&frame_camera, screen_position, screen_size);
+                renderer.set_highlighted_object(pick.map(|pick| pick.instance_data.object_id));
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F12),
+                repeat: false,
+                ..
+            } => capture.take_screenshot(),
+            Event::KeyDown {
+                keycode: Some(Keycode::F11),
+                repeat: false,
+                ..
+            } => {
+                if let Some(frames) = capture.stop_recording() {
+                    println!("Recorded {frames} frames");
+                } else {
+                    match capture.start_recording(RECORDING_FRAMES_PER_SECOND) {
+                        Ok(directory) => println!("Recording into {}", directory.display()),
+                        Err(err) => eprintln!("{err:?}"),
+                    }
+                }
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::T),
+                repeat: false,
+                ..
+            } => {
+                let mut settings = renderer.post_process_settings();
+                settings.tonemapper = settings.tonemapper.next();
+                renderer.set_post_process_settings(settings);
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::Q),
+                repeat: false,
+                ..
+            } => renderer.set_shadow_quality(renderer.shadow_quality().next()),
+            Event::KeyDown {
+                keycode: Some(Keycode::L),
+                repeat: false,
+                ..
+            } => {
+                // Halves the bias, to compare the lower levels of detail
+                // without moving the camera.
+                let bias = match renderer.lod_bias() {
+                    bias if bias > MIN_LOD_BIAS => bias / 2.0,
+                    _ => 1.0,
+                };
+                renderer.set_lod_bias(bias);
+                println!("LOD bias: {bias}");
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::I),
+                repeat: false,
+                ..
+            } => println!("{:#?}", renderer.draw_stats()),
             Event::KeyDown { keycode, .. } => println!("Pressed {keycode:?}!"),
             _ => {}
         }
     }
 
     let now = Instant::now();
-    *time += (now - *last_frame).as_secs_f32();
+    *time += capture.time_step((now - *last_frame).as_secs_f32());
     *last_frame = now;
 
     let (w, h) = window.drawable_size();
-    renderer.render(w as f32 / h as f32, *time);
+    let frame_camera = view_camera(renderer, camera, gltf_camera.as_deref(), *time);
+    renderer.render(&frame_camera, w as f32 / h as f32, *time);
+    match capture.capture_frame(w, h) {
+        Ok(Some(path)) => println!("Saved {}", path.display()),
+        Ok(None) => {}
+        Err(err) => eprintln!("{err:?}"),
+    }
     window.gl_swap_window();
 }
 
+/// Returns the glTF camera named `gltf_camera` at `time` if there is one,
+/// `camera` otherwise.
+fn view_camera(
+    renderer: &Renderer,
+    camera: &Camera,
+    gltf_camera: Option<&str>,
+    time: f32,
+) -> Camera {
+    gltf_camera
+        .and_then(|name| renderer.gltf_camera(name, time))
+        .unwrap_or(*camera)
+}
+
 fn display_error<D: Display>(err: D) {
     #[cfg(target_family = "wasm")]
     emscripten_h::run_javascript(
@@ -0,0 +1,38 @@
+#version 300 es
+precision highp float;
+
+out vec4 FRAG_COLOR;
+
+in vec2 tex_coords;
+
+uniform sampler2D source_tex;
+// x: brightness threshold, y: soft knee, z: 1.0 if the threshold should be
+// applied (only for the first downsample), w: exposure
+uniform vec4 params;
+
+// A soft threshold, so that the bloom fades in instead of popping in when the
+// brightness crosses the threshold.
+vec3 apply_threshold(vec3 color) {
+  float brightness = max(color.r, max(color.g, color.b));
+  float knee = params.x * params.y + 0.00001;
+  float soft = clamp(brightness - params.x + knee, 0.0, 2.0 * knee);
+  soft = soft * soft / (4.0 * knee);
+  float contribution = max(soft, brightness - params.x);
+  return color * contribution / max(brightness, 0.00001);
+}
+
+void main() {
+  // The downsampling filter from Marius Bjørge's "Bandwidth-Efficient
+  // Rendering" (the dual filter), taking 5 bilinear samples.
+  vec2 half_texel = 0.5 / vec2(textureSize(source_tex, 0));
+  vec3 color = texture(source_tex, tex_coords).rgb * 4.0;
+  color += texture(source_tex, tex_coords - half_texel).rgb;
+  color += texture(source_tex, tex_coords + half_texel).rgb;
+  color += texture(source_tex, tex_coords + vec2(half_texel.x, -half_texel.y)).rgb;
+  color += texture(source_tex, tex_coords - vec2(half_texel.x, -half_texel.y)).rgb;
+  color /= 8.0;
+  if (params.z > 0.0) {
+    color = apply_threshold(color * params.w);
+  }
+  FRAG_COLOR = vec4(color, 1.0);
+}
@@ -0,0 +1,25 @@
+#version 300 es
+precision highp float;
+
+out vec4 FRAG_COLOR;
+
+in vec2 tex_coords;
+
+uniform sampler2D source_tex;
+
+void main() {
+  // The upsampling filter of the dual filter, see
+  // bloom_downsample_fragment.glsl. The result is added to the larger mip with
+  // additive blending.
+  vec2 half_texel = 0.5 / vec2(textureSize(source_tex, 0));
+  vec3 color = vec3(0.0);
+  color += texture(source_tex, tex_coords + vec2(-half_texel.x * 2.0, 0.0)).rgb;
+  color += texture(source_tex, tex_coords + vec2(half_texel.x * 2.0, 0.0)).rgb;
+  color += texture(source_tex, tex_coords + vec2(0.0, -half_texel.y * 2.0)).rgb;
+  color += texture(source_tex, tex_coords + vec2(0.0, half_texel.y * 2.0)).rgb;
+  color += texture(source_tex, tex_coords + vec2(-half_texel.x, half_texel.y)).rgb * 2.0;
+  color += texture(source_tex, tex_coords + vec2(half_texel.x, half_texel.y)).rgb * 2.0;
+  color += texture(source_tex, tex_coords + vec2(half_texel.x, -half_texel.y)).rgb * 2.0;
+  color += texture(source_tex, tex_coords + vec2(-half_texel.x, -half_texel.y)).rgb * 2.0;
+  FRAG_COLOR = vec4(color / 12.0, 1.0);
+}
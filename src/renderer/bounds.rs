@@ -0,0 +1,87 @@
+use glam::{Mat4, Vec3, Vec4};
+
+/// An axis-aligned bounding box.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) / 2.0
+    }
+
+    pub fn half_extents(&self) -> Vec3 {
+        (self.max - self.min) / 2.0
+    }
+
+    /// Returns the smallest box containing both boxes.
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    /// Returns the smallest box containing this box transformed by
+    /// `transform`.
+    pub fn transformed(&self, transform: Mat4) -> Aabb {
+        // Arvo's method, from "Transforming Axis-Aligned Bounding Boxes" in
+        // Graphics Gems.
+        let center = transform.transform_point3(self.center());
+        let half_extents = self.half_extents();
+        let half_extents = transform.x_axis.truncate().abs() * half_extents.x
+            + transform.y_axis.truncate().abs() * half_extents.y
+            + transform.z_axis.truncate().abs() * half_extents.z;
+        Aabb {
+            min: center - half_extents,
+            max: center + half_extents,
+        }
+    }
+}
+
+/// The planes of a view frustum, for culling objects outside of it.
+pub struct CullingFrustum {
+    /// The planes as (normal, distance), with the normals pointing inside.
+    planes: [Vec4; 6],
+}
+
+impl CullingFrustum {
+    /// Extracts the planes from a projection matrix with the OpenGL clip
+    /// space, as in Gribb and Hartmann's "Fast Extraction of Viewing Frustum
+    /// Planes from the World-View-Projection Matrix". Works with reversed
+    /// depth ranges too, as only the order of the near and far planes
+    /// changes.
+    pub fn new(clip_from_world: Mat4) -> CullingFrustum {
+        let rows = [
+            clip_from_world.row(0),
+            clip_from_world.row(1),
+            clip_from_world.row(2),
+        ];
+        let w = clip_from_world.row(3);
+        let planes = [
+            w + rows[0],
+            w - rows[0],
+            w + rows[1],
+            w - rows[1],
+            w + rows[2],
+            w - rows[2],
+        ];
+        CullingFrustum {
+            planes: planes.map(|plane| plane / plane.truncate().length().max(f32::EPSILON)),
+        }
+    }
+
+    /// Returns false if the box is completely outside the frustum. Boxes
+    /// near the frustum's corners can be falsely reported as intersecting.
+    pub fn intersects(&self, aabb: &Aabb) -> bool {
+        let center = aabb.center();
+        let half_extents = aabb.half_extents();
+        self.planes.iter().all(|plane| {
+            let normal = plane.truncate();
+            let radius = half_extents.dot(normal.abs());
+            normal.dot(center) + plane.w >= -radius
+        })
+    }
+}
@@ -2,49 +2,40 @@ use std::ffi::c_void;
 use std::ptr;
 
 use crate::renderer::gl;
+use crate::renderer::handles::Buffer;
 
 pub struct BumpAllocatedBuffer {
-    buffer: gl::types::GLuint,
+    buffer: Buffer,
     target: gl::types::GLenum,
     usage: gl::types::GLenum,
     offset: usize,
     size: usize,
     data_copy: Vec<u8>,
-    buffer_leaked: bool,
 }
 
 impl BumpAllocatedBuffer {
     pub fn new(target: gl::types::GLenum, usage: gl::types::GLenum) -> BumpAllocatedBuffer {
-        let mut buffer = 0;
-        gl::call!(gl::GenBuffers(1, &mut buffer));
         BumpAllocatedBuffer {
-            buffer,
+            buffer: Buffer::new(),
             target,
             usage,
             offset: 0,
             size: 0,
             data_copy: Vec::new(),
-            buffer_leaked: false,
         }
     }
 
-    /// Returns the internal buffer of the bump allocator. If `leak` is true,
-    /// the buffer is marked as "leaked" and not deleted when [Self] is dropped.
-    pub fn get_buffer(&mut self, leak: bool) -> gl::types::GLuint {
-        self.buffer_leaked |= leak;
-        self.buffer
-    }
-
     /// Writes the bytes into the backing buffer of this bump allocator, and
     /// returns the buffer object and offset into it, where the bytes were
-    /// written.
-    pub fn allocate_buffer(&mut self, bytes: &[u8]) -> (gl::types::GLuint, usize) {
+    /// written. The buffer outlives the allocator if the returned handle is
+    /// kept.
+    pub fn allocate_buffer(&mut self, bytes: &[u8]) -> (Buffer, usize) {
         if self.offset + bytes.len() >= self.size {
             let additional = bytes.len() + self.size;
             let original_size = self.size;
             self.size += additional;
             self.data_copy.reserve_exact(additional);
-            gl::call!(gl::BindBuffer(self.target, self.buffer));
+            gl::call!(gl::BindBuffer(self.target, self.buffer.id()));
             gl::call!(gl::BufferData(
                 self.target,
                 self.size as isize,
@@ -59,7 +50,7 @@ impl BumpAllocatedBuffer {
             ));
         }
         let upload_offset = self.offset;
-        gl::call!(gl::BindBuffer(self.target, self.buffer));
+        gl::call!(gl::BindBuffer(self.target, self.buffer.id()));
         gl::call!(gl::BufferSubData(
             self.target,
             upload_offset as isize,
@@ -68,19 +59,52 @@ impl BumpAllocatedBuffer {
         ));
         self.data_copy.extend_from_slice(bytes);
         self.offset += bytes.len();
-        (self.buffer, upload_offset)
+        (self.buffer.clone(), upload_offset)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BumpAllocatedBuffer;
+    use crate::renderer::fake_gl::{self, args_of};
+    use crate::renderer::gl;
 
-    pub fn clear(&mut self) {
-        self.offset = 0;
-        self.data_copy.clear();
+    #[test]
+    fn allocations_are_packed_and_kept_when_growing() {
+        fake_gl::install();
+        let mut allocator = BumpAllocatedBuffer::new(gl::ELEMENT_ARRAY_BUFFER, gl::STATIC_DRAW);
+        let (buffer, first_offset) = allocator.allocate_buffer(&[1, 2, 3]);
+        let (second_buffer, second_offset) = allocator.allocate_buffer(&[4, 5, 6, 7, 8]);
+        assert_eq!(buffer, second_buffer);
+        assert_eq!((0, 3), (first_offset, second_offset));
+
+        // Both allocations grew the buffer, and the second one had to copy
+        // the first one's bytes over.
+        let calls = fake_gl::take_calls();
+        let target = gl::ELEMENT_ARRAY_BUFFER as i64;
+        let usage = gl::STATIC_DRAW as i64;
+        let buffer_data = args_of(&calls, "BufferData");
+        assert_eq!(vec![&[target, 3, usage], &[target, 11, usage]], buffer_data);
+        let sub_data = args_of(&calls, "BufferSubData");
+        let expected_sub_data = [
+            [target, 0, 0],
+            [target, 0, 3],
+            [target, 0, 3],
+            [target, 3, 5],
+        ];
+        assert_eq!(expected_sub_data.iter().collect::<Vec<_>>(), sub_data);
+        let contents = fake_gl::buffer_contents(buffer.id());
+        assert_eq!(&[1, 2, 3, 4, 5, 6, 7, 8], &contents[..8]);
     }
-}
 
-impl Drop for BumpAllocatedBuffer {
-    fn drop(&mut self) {
-        if !self.buffer_leaked {
-            gl::call!(gl::DeleteBuffers(1, &self.buffer));
-        }
+    #[test]
+    fn dropping_deletes_the_buffer_once_unused() {
+        fake_gl::install();
+        let mut allocator = BumpAllocatedBuffer::new(gl::ARRAY_BUFFER, gl::DYNAMIC_DRAW);
+        let (buffer, _) = allocator.allocate_buffer(&[1; 4]);
+        drop(allocator);
+        assert!(fake_gl::is_live("buffer", buffer.id()));
+        drop(buffer);
+        assert_eq!(0, fake_gl::live_objects());
     }
 }
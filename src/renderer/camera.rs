@@ -0,0 +1,257 @@
+use crate::renderer::{FORWARD, UP};
+use glam::{Mat4, Quat, Vec2, Vec3, Vec4Swizzles};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Projection {
+    /// A perspective projection with the given vertical field of view, in
+    /// radians.
+    Perspective { fov_y: f32 },
+    /// An orthographic projection showing the given height of the world, the
+    /// width is based on the aspect ratio.
+    Orthographic { height: f32 },
+}
+
+/// A ray in world space, e.g. from the camera through the mouse cursor.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Ray {
+    pub origin: Vec3,
+    /// Normalized.
+    pub direction: Vec3,
+}
+
+impl Ray {
+    pub fn at(&self, distance: f32) -> Vec3 {
+        self.origin + self.direction * distance
+    }
+}
+
+/// The viewpoint the scene is rendered from.
+///
+/// Like glTF cameras, the camera looks towards its local -Z axis, with +Y up
+/// and +X right, and the view space used in the shaders follows the same
+/// convention.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Camera {
+    pub position: Vec3,
+    pub orientation: Quat,
+    pub projection: Projection,
+    /// The distance to the near clipping plane.
+    pub near: f32,
+    /// The distance to the far clipping plane.
+    pub far: f32,
+}
+
+impl Default for Camera {
+    /// A perspective camera at the origin, looking towards [FORWARD].
+    fn default() -> Self {
+        Camera::perspective(74f32.to_radians(), 0.3, 100.0).looking_to(FORWARD, UP)
+    }
+}
+
+impl Camera {
+    /// Creates a perspective camera at the origin, looking towards -Z.
+    pub fn perspective(fov_y: f32, near: f32, far: f32) -> Camera {
+        Camera {
+            position: Vec3::ZERO,
+            orientation: Quat::IDENTITY,
+            projection: Projection::Perspective { fov_y },
+            near,
+            far,
+        }
+    }
+
+    /// Creates an orthographic camera at the origin, looking towards -Z.
+    pub fn orthographic(height: f32, near: f32, far: f32) -> Camera {
+        Camera {
+            position: Vec3::ZERO,
+            orientation: Quat::IDENTITY,
+            projection: Projection::Orthographic { height },
+            near,
+            far,
+        }
+    }
+
+    pub fn at(mut self, position: Vec3) -> Camera {
+        self.position = position;
+        self
+    }
+
+    pub fn looking_to(mut self, direction: Vec3, up: Vec3) -> Camera {
+        self.look_to(direction, up);
+        self
+    }
+
+    pub fn looking_at(mut self, target: Vec3, up: Vec3) -> Camera {
+        self.look_at(target, up);
+        self
+    }
+
+    /// Rotates the camera to look towards `direction`, with the camera's up
+    /// vector as close to `up` as possible.
+    pub fn look_to(&mut self, direction: Vec3, up: Vec3) {
+        let view_from_world = Mat4::look_to_rh(Vec3::ZERO, direction, up);
+        self.orientation = Quat::from_mat4(&view_from_world).inverse().normalize();
+    }
+
+    /// Rotates the camera to look at `target`, with the camera's up vector as
+    /// close to `up` as possible. Does nothing if the camera is at `target`.
+    pub fn look_at(&mut self, target: Vec3, up: Vec3) {
+        let direction = target - self.position;
+        if direction.length_squared() > 0.0 {
+            self.look_to(direction, up);
+        }
+    }
+
+    pub fn forward(&self) -> Vec3 {
+        self.orientation * Vec3::NEG_Z
+    }
+
+    pub fn right(&self) -> Vec3 {
+        self.orientation * Vec3::X
+    }
+
+    pub fn up(&self) -> Vec3 {
+        self.orientation * Vec3::Y
+    }
+
+    pub fn world_from_view(&self) -> Mat4 {
+        Mat4::from_rotation_translation(self.orientation, self.position)
+    }
+
+    pub fn view_from_world(&self) -> Mat4 {
+        self.world_from_view().inverse()
+    }
+
+    /// Returns the projection matrix for the given aspect ratio (width /
+    /// height). The depth range is reversed, so the near plane is at 1.0 and
+    /// the far plane at 0.0 in window coordinates.
+    pub fn proj_from_view(&self, aspect_ratio: f32) -> Mat4 {
+        // The near and far planes are swapped for the reversed depth range.
+        match self.projection {
+            Projection::Perspective { fov_y } => {
+                Mat4::perspective_rh_gl(fov_y, aspect_ratio, self.far, self.near)
+            }
+            Projection::Orthographic { height } => {
+                let (half_width, half_height) = (height * aspect_ratio / 2.0, height / 2.0);
+                Mat4::orthographic_rh_gl(
+                    -half_width,
+                    half_width,
+                    -half_height,
+                    half_height,
+                    self.far,
+                    self.near,
+                )
+            }
+        }
+    }
+
+    /// Returns the ray from the camera through the given point on the screen.
+    /// The screen coordinates are in pixels, with the origin at the top left
+    /// corner, like mouse coordinates.
+    pub fn screen_to_world_ray(&self, screen_position: Vec2, screen_size: Vec2) -> Ray {
+        let ndc = screen_to_ndc(screen_position, screen_size);
+        let clip_from_world =
+            self.proj_from_view(screen_size.x / screen_size.y) * self.view_from_world();
+        let world_from_clip = clip_from_world.inverse();
+        // With the reversed depth range, 1.0 is the near plane.
+        let near = world_from_clip.project_point3(ndc.extend(1.0));
+        let far = world_from_clip.project_point3(ndc.extend(-1.0));
+        Ray {
+            origin: near,
+            direction: (far - near).normalize(),
+        }
+    }
+
+    /// Returns the screen position of the world space point, in pixels from
+    /// the top left corner, or None if the point is behind the camera. Points
+    /// outside of the screen can be outside of `0..screen_size`.
+    pub fn world_to_screen(&self, point: Vec3, screen_size: Vec2) -> Option<Vec2> {
+        let view_position = self.view_from_world().transform_point3(point);
+        if view_position.z >= 0.0 {
+            return None;
+        }
+        let proj_from_view = self.proj_from_view(screen_size.x / screen_size.y);
+        let clip_position = proj_from_view * view_position.extend(1.0);
+        let ndc = clip_position.xy() / clip_position.w;
+        Some(Vec2::new(
+            (ndc.x + 1.0) / 2.0 * screen_size.x,
+            (1.0 - ndc.y) / 2.0 * screen_size.y,
+        ))
+    }
+}
+
+fn screen_to_ndc(screen_position: Vec2, screen_size: Vec2) -> Vec2 {
+    Vec2::new(
+        screen_position.x / screen_size.x * 2.0 - 1.0,
+        1.0 - screen_position.y / screen_size.y * 2.0,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Camera;
+    use crate::renderer::{FORWARD, RIGHT, UP};
+    use glam::{Vec2, Vec3};
+
+    const SCREEN_SIZE: Vec2 = Vec2::new(800.0, 600.0);
+
+    /// A perspective and an orthographic camera, looking slightly downwards.
+    fn cameras() -> [Camera; 2] {
+        let position = Vec3::new(1.0, 2.0, -3.0);
+        let target = Vec3::new(0.0, 0.5, 4.0);
+        [
+            Camera::perspective(60f32.to_radians(), 0.5, 50.0),
+            Camera::orthographic(8.0, 0.5, 50.0),
+        ]
+        .map(|camera| camera.at(position).looking_at(target, UP))
+    }
+
+    #[test]
+    fn default_camera_looks_forward() {
+        let camera = Camera::default();
+        assert!(camera.forward().abs_diff_eq(FORWARD, 1e-6));
+        assert!(camera.right().abs_diff_eq(RIGHT, 1e-6));
+        assert!(camera.up().abs_diff_eq(UP, 1e-6));
+    }
+
+    #[test]
+    fn screen_to_world_ray_and_world_to_screen_invert_each_other() {
+        for camera in cameras() {
+            for screen_position in [
+                SCREEN_SIZE / 2.0,
+                Vec2::new(10.0, 20.0),
+                Vec2::new(790.0, 555.0),
+            ] {
+                let ray = camera.screen_to_world_ray(screen_position, SCREEN_SIZE);
+                let near_distance = (ray.origin - camera.position).dot(camera.forward());
+                assert!((near_distance - camera.near).abs() < 1e-4, "{camera:?}");
+                for distance in [0.0, 1.0, 20.0] {
+                    let point = ray.at(distance);
+                    let projected = camera.world_to_screen(point, SCREEN_SIZE).unwrap();
+                    assert!(
+                        projected.abs_diff_eq(screen_position, 0.05),
+                        "{camera:?}: {screen_position} came back as {projected}",
+                    );
+                }
+            }
+            let center_ray = camera.screen_to_world_ray(SCREEN_SIZE / 2.0, SCREEN_SIZE);
+            assert!(center_ray.direction.abs_diff_eq(camera.forward(), 1e-4));
+            let behind = camera.position - camera.forward();
+            assert_eq!(None, camera.world_to_screen(behind, SCREEN_SIZE));
+        }
+    }
+
+    #[test]
+    fn near_and_far_planes_map_to_reversed_depth() {
+        for camera in cameras() {
+            let clip_from_world =
+                camera.proj_from_view(SCREEN_SIZE.x / SCREEN_SIZE.y) * camera.view_from_world();
+            for (distance, expected_depth) in [(camera.near, 1.0), (camera.far, 0.0)] {
+                let point = camera.position + camera.forward() * distance;
+                let ndc_depth = clip_from_world.project_point3(point).z;
+                let depth = ndc_depth * 0.5 + 0.5;
+                assert!((depth - expected_depth).abs() < 1e-4, "{camera:?}: {depth}");
+            }
+        }
+    }
+}
@@ -1,5 +1,12 @@
-use crate::renderer::bumpalloc_buffer::BumpAllocatedBuffer;
+use crate::renderer::bounds::{Aabb, CullingFrustum};
 use crate::renderer::gl;
+use crate::renderer::gltf::{
+    UniformBlockSkin, MAX_JOINTS, MAX_MORPH_TARGETS, TEX_UNIT_MORPH_TARGETS, UNIFORM_BLOCK_SKIN,
+};
+use crate::renderer::handles::{Buffer, Sampler, Texture, VertexArray};
+use crate::renderer::lod::LodSelector;
+use crate::renderer::streaming_buffer::StreamingBuffer;
+use bytemuck::{Pod, Zeroable};
 use glam::{Mat4, Vec4};
 use std::collections::HashMap;
 use std::ffi::c_void;
@@ -9,17 +16,17 @@ use std::{mem, ptr};
 pub struct Uniforms {
     /// The OpenGL textures to bind at GL_TEXTURE0 + i where each element is
     /// of this array is `(i, texture_object, sampler_object)`.
-    pub textures: [Option<(u32, u32, u32)>; 5],
+    pub textures: [Option<(u32, Texture, Sampler)>; 5],
     /// The OpenGL uniform buffers `buffer` to bind at indices `i`, where each
     /// element of this array is `(i, buffer, offset, size)`.
-    pub ubos: [Option<(u32, u32, usize, usize)>; 2],
+    pub ubos: [Option<(u32, Buffer, usize, usize)>; 3],
 }
 
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct DrawCall {
-    pub vao: gl::types::GLuint,
+    pub vao: VertexArray,
     pub mode: gl::types::GLenum,
-    pub index_buffer: gl::types::GLuint,
+    pub index_buffer: Buffer,
     pub index_type: gl::types::GLuint,
     pub index_byte_offset: usize,
     pub index_count: gl::types::GLint,
@@ -29,122 +36,907 @@ pub struct DrawCall {
     /// this holds the location of the vertex color attribute, if it's disabled.
     pub disabled_all_ones_vertex_attribute: Option<gl::types::GLuint>,
     pub front_face: gl::types::GLenum,
+    /// A TEXTURE_2D_ARRAY containing the primitive's morph target deltas, or
+    /// an empty one if the primitive has no morph targets.
+    pub morph_targets_texture: Texture,
+    /// Whether the primitive's material is alpha blended, in which case its
+    /// instances are drawn after the opaque ones, sorted back to front.
+    pub blend: bool,
+    /// Whether the primitive's material is double sided, in which case back
+    /// faces are not culled.
+    pub double_sided: bool,
+    /// Within the opaque and blended passes, draws on lower layers are drawn
+    /// before the ones on higher layers, e.g. for decals. Only the lowest 4
+    /// bits are used.
+    pub layer: u8,
 }
 
+/// The per-instance data passed to the shader as instanced vertex attributes.
+#[derive(Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct Instance {
+    pub transform: Mat4,
+    pub morph_weights: [f32; MAX_MORPH_TARGETS],
+    pub data: InstanceData,
+}
+
+/// Application-defined per-instance values, for varying the look of
+/// instances without duplicating their materials. Available in the shaders as
+/// `instance_tint`, `instance_custom` and `instance_object_id`.
+#[derive(Clone, Copy, Zeroable, Pod, PartialEq, Debug)]
+#[repr(C)]
+pub struct InstanceData {
+    /// Multiplies the base color and alpha. Note that the alpha only has an
+    /// effect on blended and masked materials.
+    pub tint: Vec4,
+    /// Not used by the built-in shaders, free for custom effects.
+    pub custom: [f32; 3],
+    /// Identifies the instance, e.g. for picking. 0 means no object.
+    pub object_id: u32,
+}
+
+impl Default for InstanceData {
+    fn default() -> Self {
+        InstanceData {
+            tint: Vec4::ONE,
+            custom: [0.0; 3],
+            object_id: 0,
+        }
+    }
+}
+
+/// An instance added with [DrawCalls::add], referring to its material and
+/// draw call by their indices in [DrawCalls::materials] and
+/// [DrawCalls::draw_call_list].
+struct DrawEntry {
+    material: u32,
+    draw_call: u32,
+    instance: Instance,
+    /// The world space bounding box of the instance, None for instances
+    /// which should never be culled.
+    bounds: Option<Aabb>,
+    source: DrawSource,
+}
+
+/// Where an instance added to [DrawCalls] came from, for finding out what was
+/// picked with [DrawCalls::draw_picking].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DrawSource {
+    /// The [Gltf::id](crate::renderer::gltf::Gltf::id) of the model.
+    pub gltf_id: u64,
+    pub node_index: usize,
+    /// The index of the primitive within the node's mesh.
+    pub primitive_index: usize,
+}
+
+/// Counters for the last [DrawCalls::draw], for measuring how well the
+/// instances were batched and sorted.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct DrawStats {
+    /// The amount of glDrawElementsInstanced calls.
+    pub draw_calls: usize,
+    pub instances: usize,
+    /// The amount of instances outside of the view frustum.
+    pub culled_instances: usize,
+    pub material_binds: usize,
+    pub vao_binds: usize,
+    /// The amount of material, VAO, morph target, and culling state changes
+    /// skipped because the state was already set by the previous draw.
+    pub skipped_binds: usize,
+}
+
+/// The state set by the previous draw of the current pass, for skipping
+/// redundant state changes.
 #[derive(Default)]
-struct InstanceData {
-    transforms: Vec<Mat4>,
-    count: gl::types::GLsizei,
+struct BoundState {
+    material: Option<u32>,
+    vao: Option<gl::types::GLuint>,
+    morph_targets_texture: Option<gl::types::GLuint>,
+    front_face: Option<gl::types::GLenum>,
+    double_sided: Option<bool>,
 }
 
 /// Stores the required information for rendering a set of primitives with
-/// various materials, in a form that's optimized for minimum state changes
-/// during rendering.
+/// various materials, and draws them sorted by a key made of the pass, layer,
+/// render state, material, draw call and depth, so that the submission order
+/// is deterministic and state changes are minimized.
+///
+/// The added materials and draw calls hold on to their GL objects until the
+/// next [DrawCalls::clear], so the glTF they're from can be dropped before
+/// they're drawn.
 pub struct DrawCalls {
-    draws: HashMap<Uniforms, HashMap<DrawCall, InstanceData>>,
-    temp_buffer: BumpAllocatedBuffer,
+    entries: Vec<DrawEntry>,
+    /// The materials used this frame, in the order they were first added.
+    materials: Vec<Uniforms>,
+    material_indices: HashMap<Uniforms, u32>,
+    /// The draw calls used this frame, in the order they were first added.
+    draw_call_list: Vec<DrawCall>,
+    draw_call_indices: HashMap<DrawCall, u32>,
+    /// The sort keys and indices of the entries being drawn, reused between
+    /// draws to avoid allocations.
+    sorted_entries: Vec<(u64, u32)>,
+    temp_buffer: StreamingBuffer,
+    skin_buffer: StreamingBuffer,
+    uniform_buffer_offset_alignment: usize,
+    /// The instances of a batch which passed frustum culling, reused between
+    /// batches to avoid allocations.
+    visible_instances: Vec<Instance>,
+    /// The indices of the entries in `visible_instances`, only collected for
+    /// picking.
+    visible_entries: Vec<u32>,
+    /// The entry index of each pick index written by the last
+    /// [DrawCalls::draw_picking], offset by one since 0 means nothing.
+    pick_entries: Vec<u32>,
+    stats: DrawStats,
+    lod_selector: LodSelector,
+}
+
+impl Default for DrawCalls {
+    fn default() -> Self {
+        DrawCalls::new()
+    }
 }
 
 impl DrawCalls {
     pub fn new() -> DrawCalls {
+        let mut uniform_buffer_offset_alignment = 0;
+        gl::call!(gl::GetIntegerv(
+            gl::UNIFORM_BUFFER_OFFSET_ALIGNMENT,
+            &mut uniform_buffer_offset_alignment,
+        ));
         DrawCalls {
-            draws: HashMap::new(),
-            temp_buffer: BumpAllocatedBuffer::new(gl::ARRAY_BUFFER, gl::STREAM_DRAW),
+            entries: Vec::new(),
+            materials: Vec::new(),
+            material_indices: HashMap::new(),
+            draw_call_list: Vec::new(),
+            draw_call_indices: HashMap::new(),
+            sorted_entries: Vec::new(),
+            temp_buffer: StreamingBuffer::new(gl::ARRAY_BUFFER),
+            skin_buffer: StreamingBuffer::new(gl::UNIFORM_BUFFER),
+            uniform_buffer_offset_alignment: uniform_buffer_offset_alignment.max(1) as usize,
+            visible_instances: Vec::new(),
+            visible_entries: Vec::new(),
+            pick_entries: Vec::new(),
+            stats: DrawStats::default(),
+            lod_selector: LodSelector::new(),
         }
     }
 
-    pub fn add(&mut self, uniforms: &Uniforms, draw_call: &DrawCall, transform: Mat4) {
-        let draw = if let Some(draw) = self.draws.get_mut(uniforms) {
-            draw
-        } else {
-            self.draws.entry(uniforms.clone()).or_default()
+    /// Adds a skinned instance. The joint matrices are uploaded right away,
+    /// and since they're bound as a uniform block, skinned instances are not
+    /// instanced together with other instances of the same primitive. Skinned
+    /// instances are never culled, as their bounds depend on the pose.
+    pub fn add_skinned(
+        &mut self,
+        uniforms: &Uniforms,
+        draw_call: &DrawCall,
+        instance: Instance,
+        joint_matrices: &[Mat4],
+        source: DrawSource,
+    ) {
+        let joint_count = joint_matrices.len().min(MAX_JOINTS);
+        let mut skin = UniformBlockSkin::zeroed();
+        skin.joint_count = joint_count as u32;
+        skin.joint_matrices[..joint_count].copy_from_slice(&joint_matrices[..joint_count]);
+        let skin_data = [skin];
+        let skin_data = bytemuck::cast_slice(&skin_data);
+        let (ubo, ubo_offset) = self
+            .skin_buffer
+            .allocate_buffer_aligned(skin_data, self.uniform_buffer_offset_alignment);
+        let mut uniforms = uniforms.clone();
+        for ubo_binding in &mut uniforms.ubos {
+            if matches!(ubo_binding, Some((UNIFORM_BLOCK_SKIN, ..))) {
+                let ubo = ubo.clone();
+                *ubo_binding = Some((UNIFORM_BLOCK_SKIN, ubo, ubo_offset, skin_data.len()));
+            }
+        }
+        self.add(&uniforms, draw_call, instance, None, source);
+    }
+
+    /// Adds an instance of the draw call. If `bounds` (the primitive's
+    /// bounding box in model space) are given, the instance is only drawn if
+    /// it's inside the view frustum.
+    pub fn add(
+        &mut self,
+        uniforms: &Uniforms,
+        draw_call: &DrawCall,
+        instance: Instance,
+        bounds: Option<&Aabb>,
+        source: DrawSource,
+    ) {
+        let material = match self.material_indices.get(uniforms) {
+            Some(&index) => index,
+            None => {
+                let index = self.materials.len() as u32;
+                self.materials.push(uniforms.clone());
+                self.material_indices.insert(uniforms.clone(), index);
+                index
+            }
         };
-        let mut draw_call = if let Some(draw_call) = draw.get_mut(draw_call) {
-            draw_call
-        } else {
-            draw.entry(draw_call.clone()).or_default()
+        let draw_call = match self.draw_call_indices.get(draw_call) {
+            Some(&index) => index,
+            None => {
+                let index = self.draw_call_list.len() as u32;
+                self.draw_call_list.push(draw_call.clone());
+                self.draw_call_indices.insert(draw_call.clone(), index);
+                index
+            }
+        };
+        self.entries.push(DrawEntry {
+            material,
+            draw_call,
+            instance,
+            bounds: bounds.map(|bounds| bounds.transformed(instance.transform)),
+            source,
+        });
+    }
+
+    /// Sets the camera used for picking the levels of detail of the
+    /// instances added after this.
+    pub fn set_lod_view(&mut self, view_from_world: Mat4, proj_from_view: Mat4) {
+        self.lod_selector.set_view(view_from_world, proj_from_view);
+    }
+
+    /// Returns the level of detail to draw an instance of a LOD chain at, see
+    /// [LodSelector::select].
+    pub fn select_lod(
+        &mut self,
+        group: (u64, usize),
+        world_bounds: &Aabb,
+        min_screen_coverages: &[f32],
+    ) -> usize {
+        (self.lod_selector).select(group, world_bounds, min_screen_coverages)
+    }
+
+    pub fn lod_bias(&self) -> f32 {
+        self.lod_selector.bias
+    }
+
+    /// Sets the multiplier for the instances' screen coverages when picking
+    /// their levels of detail. Values below 1.0 make less detailed levels be
+    /// used sooner, e.g. to save time on slower devices.
+    pub fn set_lod_bias(&mut self, bias: f32) {
+        self.lod_selector.bias = bias;
+    }
+
+    pub fn stats(&self) -> DrawStats {
+        self.stats
+    }
+
+    /// Draws the opaque instances front to back, and then the blended ones
+    /// back to front, based on their distance from the camera at
+    /// `view_from_world`. Instances outside of the view frustum are skipped.
+    pub fn draw(
+        &mut self,
+        view_from_world: Mat4,
+        proj_from_view: Mat4,
+        model_transform_attrib_locations: [u32; 4],
+        morph_weights_attrib_locations: [u32; 2],
+        instance_data_attrib_locations: [u32; 2],
+    ) {
+        let clip_from_world = proj_from_view * view_from_world;
+        let get_depth = |instance: &Instance| {
+            let position = view_from_world * instance.transform.w_axis;
+            position.truncate().length()
         };
-        draw_call.count += 1;
-        draw_call.transforms.push(transform);
+        self.stats = self.draw_sorted(
+            clip_from_world,
+            get_depth,
+            true,
+            get_instance_attrib_locations(
+                model_transform_attrib_locations,
+                morph_weights_attrib_locations,
+                instance_data_attrib_locations,
+            ),
+            None,
+        );
+    }
+
+    /// Draws only the opaque (including alpha masked) instances, e.g. for
+    /// rendering shadow maps, which blended instances don't cast. Instances
+    /// outside of the frustum of `clip_from_world` are skipped.
+    pub fn draw_opaque(
+        &mut self,
+        clip_from_world: Mat4,
+        model_transform_attrib_locations: [u32; 4],
+        morph_weights_attrib_locations: [u32; 2],
+        instance_data_attrib_locations: [u32; 2],
+    ) {
+        // The clip space w is the distance from the camera for perspective
+        // projections, and constant for orthographic ones.
+        let get_depth = |instance: &Instance| (clip_from_world * instance.transform.w_axis).w;
+        self.draw_sorted(
+            clip_from_world,
+            get_depth,
+            false,
+            get_instance_attrib_locations(
+                model_transform_attrib_locations,
+                morph_weights_attrib_locations,
+                instance_data_attrib_locations,
+            ),
+            None,
+        );
+    }
+
+    /// Draws all instances with the picking shader, which writes the pick
+    /// index of each instance, based on the `pick_base` uniform at
+    /// `pick_base_location` and the instance's index in the draw call. The
+    /// pick indices can be resolved with [DrawCalls::get_pick_target] until
+    /// the next [DrawCalls::clear].
+    pub fn draw_picking(
+        &mut self,
+        clip_from_world: Mat4,
+        model_transform_attrib_locations: [u32; 4],
+        morph_weights_attrib_locations: [u32; 2],
+        instance_data_attrib_locations: [u32; 2],
+        pick_base_location: gl::types::GLint,
+    ) {
+        self.pick_entries.clear();
+        let get_depth = |instance: &Instance| (clip_from_world * instance.transform.w_axis).w;
+        self.draw_sorted(
+            clip_from_world,
+            get_depth,
+            true,
+            get_instance_attrib_locations(
+                model_transform_attrib_locations,
+                morph_weights_attrib_locations,
+                instance_data_attrib_locations,
+            ),
+            Some(pick_base_location),
+        );
+    }
+
+    /// Returns the source and the instance data of the instance which was
+    /// drawn with the given pick index, or None for 0 (nothing) and unknown
+    /// indices.
+    pub fn get_pick_target(&self, pick_index: u32) -> Option<(DrawSource, InstanceData)> {
+        let entry_index = *self
+            .pick_entries
+            .get((pick_index as usize).checked_sub(1)?)?;
+        let entry = self.entries.get(entry_index as usize)?;
+        Some((entry.source, entry.instance.data))
     }
 
-    pub fn draw(&mut self, model_transform_attrib_locations: [u32; 4]) {
-        for (uniforms, draw_calls) in &self.draws {
-            let empty_draw = draw_calls
-                .values()
-                .all(|instance| instance.transforms.is_empty());
-            if empty_draw {
+    /// Sorts and draws the entries, batching consecutive instances of the
+    /// same material and draw call into instanced draws.
+    fn draw_sorted<D: Fn(&Instance) -> f32>(
+        &mut self,
+        clip_from_world: Mat4,
+        get_depth: D,
+        include_blended: bool,
+        instance_attrib_locations: [u32; 8],
+        pick_base_location: Option<gl::types::GLint>,
+    ) -> DrawStats {
+        let frustum = CullingFrustum::new(clip_from_world);
+        let mut stats = DrawStats::default();
+
+        self.sorted_entries.clear();
+        for (i, entry) in self.entries.iter().enumerate() {
+            let draw_call = &self.draw_call_list[entry.draw_call as usize];
+            if draw_call.blend && !include_blended {
                 continue;
             }
+            let key = get_sort_key(entry, draw_call, get_depth(&entry.instance));
+            self.sorted_entries.push((key, i as u32));
+        }
+        // A stable sort, so entries with equal keys keep the order they were
+        // added in.
+        self.sorted_entries.sort_by_key(|&(key, _)| key);
 
-            for (binding, texture, sampler) in uniforms.textures.iter().flatten() {
-                gl::call!(gl::ActiveTexture(
-                    gl::TEXTURE0 + *binding as gl::types::GLenum
-                ));
-                gl::call!(gl::BindTexture(gl::TEXTURE_2D, *texture));
-                gl::call!(gl::BindSampler(*binding as u32, *sampler));
+        let mut bound_state = BoundState::default();
+        let mut blending = false;
+        let mut batch_start = 0;
+        while batch_start < self.sorted_entries.len() {
+            let first_entry = &self.entries[self.sorted_entries[batch_start].1 as usize];
+            let (material, draw_call) = (first_entry.material, first_entry.draw_call);
+            self.visible_instances.clear();
+            self.visible_entries.clear();
+            let mut batch_end = batch_start;
+            for &(_, entry_index) in &self.sorted_entries[batch_start..] {
+                let entry = &self.entries[entry_index as usize];
+                if entry.material != material || entry.draw_call != draw_call {
+                    break;
+                }
+                if is_visible(&frustum, &entry.bounds) {
+                    self.visible_instances.push(entry.instance);
+                    if pick_base_location.is_some() {
+                        self.visible_entries.push(entry_index);
+                    }
+                } else {
+                    stats.culled_instances += 1;
+                }
+                batch_end += 1;
+            }
+            batch_start = batch_end;
+            if self.visible_instances.is_empty() {
+                continue;
             }
 
-            for &(index, buffer, offset, size) in uniforms.ubos.iter().flatten() {
-                gl::call!(gl::BindBufferRange(
-                    gl::UNIFORM_BUFFER,
-                    index,
-                    buffer,
-                    offset as isize,
-                    size as isize,
-                ));
+            let draw_call = &self.draw_call_list[draw_call as usize];
+            if draw_call.blend && !blending {
+                gl::call!(gl::Enable(gl::BLEND));
+                gl::call!(gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA));
+                gl::call!(gl::DepthMask(gl::FALSE));
+                blending = true;
+            }
+            if bound_state.material != Some(material) {
+                bind_uniforms(&self.materials[material as usize]);
+                bound_state.material = Some(material);
+                stats.material_binds += 1;
+            } else {
+                stats.skipped_binds += 1;
             }
+            if let Some(location) = pick_base_location {
+                let pick_base = self.pick_entries.len() as u32 + 1;
+                gl::call!(gl::Uniform1ui(location, pick_base));
+                self.pick_entries.extend_from_slice(&self.visible_entries);
+            }
+            draw_instances(
+                &mut self.temp_buffer,
+                draw_call,
+                &self.visible_instances,
+                instance_attrib_locations,
+                &mut bound_state,
+                &mut stats,
+            );
+        }
+        if blending {
+            gl::call!(gl::DepthMask(gl::TRUE));
+            gl::call!(gl::Disable(gl::BLEND));
+        }
+        stats
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.pick_entries.clear();
+        self.materials.clear();
+        self.material_indices.clear();
+        self.draw_call_list.clear();
+        self.draw_call_indices.clear();
+        self.temp_buffer.next_frame();
+        self.skin_buffer.next_frame();
+        self.lod_selector.next_frame();
+    }
+}
+
+/// Returns the key the draws are sorted by. From the most significant bits to
+/// the least significant ones:
+///
+/// - Opaque instances: the pass (0), the layer, the render state (double
+///   sidedness), the material, the draw call, and the depth, front to back.
+/// - Blended instances: the pass (1), the layer, the depth, back to front,
+///   and then the render state, the material and the draw call.
+///
+/// The material and draw call indices are truncated to 16 bits each, which
+/// only affects how well the draws are batched, not the correctness.
+fn get_sort_key(entry: &DrawEntry, draw_call: &DrawCall, depth: f32) -> u64 {
+    // Positive floats sort the same way as their bits. The lowest 8 bits of
+    // the mantissa are dropped to fit the depth in 24 bits.
+    let depth = (depth.max(0.0).to_bits() >> 8) as u64 & 0xFF_FFFF;
+    let state = draw_call.double_sided as u64;
+    let material = entry.material as u64 & 0xFFFF;
+    let draw_call_index = entry.draw_call as u64 & 0xFFFF;
+    let layer = draw_call.layer as u64 & 0xF;
+    if draw_call.blend {
+        let depth = 0xFF_FFFF - depth;
+        1 << 63 | layer << 59 | depth << 35 | state << 34 | material << 18 | draw_call_index << 2
+    } else {
+        layer << 59 | state << 58 | material << 42 | draw_call_index << 26 | depth << 2
+    }
+}
 
-            for (draw_call, instance_data) in draw_calls {
-                gl::call!(gl::BindVertexArray(draw_call.vao));
-                // Setup the transform vertex attribute
-                let transforms = bytemuck::cast_slice(&instance_data.transforms);
-                let (transforms_buffer, transforms_offset) =
-                    self.temp_buffer.allocate_buffer(transforms);
-                gl::call!(gl::BindBuffer(gl::ARRAY_BUFFER, transforms_buffer));
-                for i in 0..4 {
-                    let attrib_location = model_transform_attrib_locations[i];
-                    let offset = transforms_offset + mem::size_of::<Vec4>() * i;
-                    gl::call!(gl::EnableVertexAttribArray(attrib_location));
-                    gl::call!(gl::VertexAttribPointer(
-                        attrib_location,
-                        4,
-                        gl::FLOAT,
-                        gl::FALSE,
-                        mem::size_of::<Mat4>() as i32,
-                        ptr::null::<c_void>().add(offset)
-                    ));
-                    gl::call!(gl::VertexAttribDivisor(attrib_location, 1));
+fn is_visible(frustum: &CullingFrustum, bounds: &Option<Aabb>) -> bool {
+    match bounds {
+        Some(bounds) => frustum.intersects(bounds),
+        None => true,
+    }
+}
+
+/// Returns the locations of each vec4 of [Instance], in order.
+fn get_instance_attrib_locations(
+    model_transform_attrib_locations: [u32; 4],
+    morph_weights_attrib_locations: [u32; 2],
+    instance_data_attrib_locations: [u32; 2],
+) -> [u32; 8] {
+    [
+        model_transform_attrib_locations[0],
+        model_transform_attrib_locations[1],
+        model_transform_attrib_locations[2],
+        model_transform_attrib_locations[3],
+        morph_weights_attrib_locations[0],
+        morph_weights_attrib_locations[1],
+        instance_data_attrib_locations[0],
+        instance_data_attrib_locations[1],
+    ]
+}
+
+fn bind_uniforms(uniforms: &Uniforms) {
+    for (binding, texture, sampler) in uniforms.textures.iter().flatten() {
+        gl::call!(gl::ActiveTexture(
+            gl::TEXTURE0 + *binding as gl::types::GLenum
+        ));
+        gl::call!(gl::BindTexture(gl::TEXTURE_2D, texture.id()));
+        gl::call!(gl::BindSampler(*binding, sampler.id()));
+    }
+
+    for (index, buffer, offset, size) in uniforms.ubos.iter().flatten() {
+        gl::call!(gl::BindBufferRange(
+            gl::UNIFORM_BUFFER,
+            *index,
+            buffer.id(),
+            *offset as isize,
+            *size as isize,
+        ));
+    }
+}
+
+fn draw_instances(
+    temp_buffer: &mut StreamingBuffer,
+    draw_call: &DrawCall,
+    instances: &[Instance],
+    instance_attrib_locations: [u32; 8],
+    bound_state: &mut BoundState,
+    stats: &mut DrawStats,
+) {
+    if bound_state.vao != Some(draw_call.vao.id()) {
+        gl::call!(gl::BindVertexArray(draw_call.vao.id()));
+        // The index buffer binding is a part of the VAO's state.
+        gl::call!(gl::BindBuffer(
+            gl::ELEMENT_ARRAY_BUFFER,
+            draw_call.index_buffer.id()
+        ));
+        bound_state.vao = Some(draw_call.vao.id());
+        stats.vao_binds += 1;
+    } else {
+        stats.skipped_binds += 1;
+    }
+    // Setup the transform and morph weight vertex attributes
+    let (instances_buffer, instances_offset) =
+        temp_buffer.allocate_buffer(bytemuck::cast_slice(instances));
+    gl::call!(gl::BindBuffer(gl::ARRAY_BUFFER, instances_buffer.id()));
+    let stride = mem::size_of::<Instance>() as i32;
+    for (i, attrib_location) in instance_attrib_locations.into_iter().enumerate() {
+        let offset = instances_offset + mem::size_of::<Vec4>() * i;
+        gl::call!(gl::EnableVertexAttribArray(attrib_location));
+        if i == instance_attrib_locations.len() - 1 {
+            // The custom floats and the object id are passed as integers, so
+            // the id doesn't lose precision. The shader converts the floats
+            // back with uintBitsToFloat.
+            gl::call!(gl::VertexAttribIPointer(
+                attrib_location,
+                4,
+                gl::UNSIGNED_INT,
+                stride,
+                ptr::null::<c_void>().add(offset)
+            ));
+        } else {
+            gl::call!(gl::VertexAttribPointer(
+                attrib_location,
+                4,
+                gl::FLOAT,
+                gl::FALSE,
+                stride,
+                ptr::null::<c_void>().add(offset)
+            ));
+        }
+        gl::call!(gl::VertexAttribDivisor(attrib_location, 1));
+    }
+    // Bind the morph targets
+    let morph_targets_texture = draw_call.morph_targets_texture.id();
+    if bound_state.morph_targets_texture != Some(morph_targets_texture) {
+        gl::call!(gl::ActiveTexture(gl::TEXTURE0 + TEX_UNIT_MORPH_TARGETS));
+        gl::call!(gl::BindTexture(gl::TEXTURE_2D_ARRAY, morph_targets_texture));
+        bound_state.morph_targets_texture = Some(morph_targets_texture);
+    } else {
+        stats.skipped_binds += 1;
+    }
+    // Set color vertex attribute default value
+    if let Some(location) = draw_call.disabled_all_ones_vertex_attribute {
+        gl::call!(gl::VertexAttrib4f(location, 1.0, 1.0, 1.0, 1.0));
+    }
+    // Set the front face and culling
+    if bound_state.front_face != Some(draw_call.front_face) {
+        gl::call!(gl::FrontFace(draw_call.front_face));
+        bound_state.front_face = Some(draw_call.front_face);
+    } else {
+        stats.skipped_binds += 1;
+    }
+    if bound_state.double_sided != Some(draw_call.double_sided) {
+        if draw_call.double_sided {
+            gl::call!(gl::Disable(gl::CULL_FACE));
+        } else {
+            gl::call!(gl::Enable(gl::CULL_FACE));
+        }
+        bound_state.double_sided = Some(draw_call.double_sided);
+    } else {
+        stats.skipped_binds += 1;
+    }
+    gl::call!(gl::DrawElementsInstanced(
+        draw_call.mode,
+        draw_call.index_count,
+        draw_call.index_type,
+        ptr::null::<c_void>().add(draw_call.index_byte_offset),
+        instances.len() as gl::types::GLsizei
+    ));
+    stats.draw_calls += 1;
+    stats.instances += instances.len();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DrawCall, DrawCalls, DrawSource, Instance, InstanceData, Uniforms};
+    use crate::renderer::bounds::Aabb;
+    use crate::renderer::fake_gl::{self, args_of, Call};
+    use crate::renderer::gl;
+    use crate::renderer::gltf::{
+        ATTR_LOC_INSTANCE_DATA, ATTR_LOC_MODEL_TRANSFORM_COLUMNS, ATTR_LOC_MORPH_WEIGHTS,
+    };
+    use crate::renderer::handles::{Buffer, Texture, VertexArray};
+    use glam::{Mat4, Vec3};
+    use std::mem;
+
+    const SOURCE: DrawSource = DrawSource {
+        gltf_id: 0,
+        node_index: 0,
+        primitive_index: 0,
+    };
+
+    fn create_draw_call(blend: bool) -> DrawCall {
+        DrawCall {
+            vao: VertexArray::new(),
+            mode: gl::TRIANGLES,
+            index_buffer: Buffer::new(),
+            index_type: gl::UNSIGNED_SHORT,
+            index_byte_offset: 6,
+            index_count: 3,
+            disabled_all_ones_vertex_attribute: None,
+            front_face: gl::CCW,
+            morph_targets_texture: Texture::new(),
+            blend,
+            double_sided: false,
+            layer: 0,
+        }
+    }
+
+    fn create_uniforms() -> Uniforms {
+        Uniforms {
+            textures: Default::default(),
+            ubos: Default::default(),
+        }
+    }
+
+    fn create_instance(z: f32, object_id: u32) -> Instance {
+        Instance {
+            transform: Mat4::from_translation(Vec3::new(0.0, 0.0, z)),
+            morph_weights: Default::default(),
+            data: InstanceData {
+                object_id,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Draws with a camera at the origin, looking towards -Z, and returns
+    /// the GL calls made.
+    fn draw(draw_calls: &mut DrawCalls) -> Vec<Call> {
+        fake_gl::take_calls();
+        draw_calls.draw(
+            Mat4::IDENTITY,
+            Mat4::perspective_rh_gl(1.0, 1.0, 100.0, 0.1),
+            ATTR_LOC_MODEL_TRANSFORM_COLUMNS,
+            ATTR_LOC_MORPH_WEIGHTS,
+            ATTR_LOC_INSTANCE_DATA,
+        );
+        fake_gl::take_calls()
+    }
+
+    /// Returns the object ids of the instances of each draw, read from the
+    /// instance buffer at the offset of the model transform attribute.
+    fn get_drawn_object_ids(calls: &[Call]) -> Vec<Vec<u32>> {
+        let mut instance_buffer = 0;
+        let mut instance_offset = 0;
+        let mut object_ids = Vec::new();
+        for call in calls {
+            match (call.name, call.args.as_slice()) {
+                ("BindBuffer", &[target, buffer]) if target == gl::ARRAY_BUFFER as i64 => {
+                    instance_buffer = buffer as u32;
                 }
-                // Set color vertex attribute default value
-                if let Some(location) = draw_call.disabled_all_ones_vertex_attribute {
-                    gl::call!(gl::VertexAttrib4f(location, 1.0, 1.0, 1.0, 1.0));
+                ("VertexAttribPointer", &[location, .., offset])
+                    if location == ATTR_LOC_MODEL_TRANSFORM_COLUMNS[0] as i64 =>
+                {
+                    instance_offset = offset as usize;
                 }
-                // Set the front face
-                gl::call!(gl::FrontFace(draw_call.front_face));
-                // Bind the index buffer
-                gl::call!(gl::BindBuffer(
-                    gl::ELEMENT_ARRAY_BUFFER,
-                    draw_call.index_buffer
-                ));
-                gl::call!(gl::DrawElementsInstanced(
-                    draw_call.mode,
-                    draw_call.index_count,
-                    draw_call.index_type,
-                    ptr::null::<c_void>().add(draw_call.index_byte_offset),
-                    instance_data.count
-                ));
+                ("DrawElementsInstanced", &[.., instance_count]) => {
+                    let contents = fake_gl::buffer_contents(instance_buffer);
+                    let size = mem::size_of::<Instance>();
+                    let ids = (0..instance_count as usize).map(|i| {
+                        let start = instance_offset + i * size;
+                        let bytes = &contents[start..start + size];
+                        bytemuck::pod_read_unaligned::<Instance>(bytes)
+                            .data
+                            .object_id
+                    });
+                    object_ids.push(ids.collect());
+                }
+                _ => {}
             }
         }
+        object_ids
     }
 
-    pub fn clear(&mut self) {
-        for draw_calls in self.draws.values_mut() {
-            for instance_data in draw_calls.values_mut() {
-                instance_data.transforms.clear();
-                instance_data.count = 0;
-            }
+    #[test]
+    fn instances_are_batched_front_to_back() {
+        fake_gl::install();
+        let mut draw_calls = DrawCalls::new();
+        let (uniforms, draw_call) = (create_uniforms(), create_draw_call(false));
+        for (z, object_id) in [(-3.0, 3), (-1.0, 1), (-2.0, 2)] {
+            let instance = create_instance(z, object_id);
+            draw_calls.add(&uniforms, &draw_call, instance, None, SOURCE);
+        }
+        let calls = draw(&mut draw_calls);
+
+        let draws = args_of(&calls, "DrawElementsInstanced");
+        let expected_draw = [gl::TRIANGLES as i64, 3, gl::UNSIGNED_SHORT as i64, 6, 3];
+        assert_eq!(vec![&expected_draw], draws);
+        assert_eq!(vec![vec![1, 2, 3]], get_drawn_object_ids(&calls));
+        let stats = draw_calls.stats();
+        assert_eq!((1, 3), (stats.draw_calls, stats.instances));
+    }
+
+    #[test]
+    fn blended_instances_are_drawn_last_back_to_front() {
+        fake_gl::install();
+        let mut draw_calls = DrawCalls::new();
+        let uniforms = create_uniforms();
+        let (opaque, blended) = (create_draw_call(false), create_draw_call(true));
+        draw_calls.add(&uniforms, &blended, create_instance(-1.0, 3), None, SOURCE);
+        draw_calls.add(&uniforms, &opaque, create_instance(-2.0, 2), None, SOURCE);
+        draw_calls.add(&uniforms, &blended, create_instance(-5.0, 4), None, SOURCE);
+        draw_calls.add(&uniforms, &opaque, create_instance(-1.0, 1), None, SOURCE);
+        let calls = draw(&mut draw_calls);
+
+        assert_eq!(vec![vec![1, 2], vec![4, 3]], get_drawn_object_ids(&calls));
+        let blend = gl::BLEND as i64;
+        let events = (calls.iter())
+            .filter_map(|call| match (call.name, call.args.as_slice()) {
+                ("DrawElementsInstanced", _) => Some("draw"),
+                ("Enable", &[cap]) if cap == blend => Some("enable blending"),
+                ("Disable", &[cap]) if cap == blend => Some("disable blending"),
+                ("DepthMask", &[0]) => Some("disable depth writes"),
+                ("DepthMask", &[1]) => Some("enable depth writes"),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let expected_events = [
+            "draw",
+            "enable blending",
+            "disable depth writes",
+            "draw",
+            "enable depth writes",
+            "disable blending",
+        ];
+        assert_eq!(&expected_events[..], &events);
+    }
+
+    #[test]
+    fn instances_outside_the_frustum_are_culled() {
+        fake_gl::install();
+        let mut draw_calls = DrawCalls::new();
+        let (uniforms, draw_call) = (create_uniforms(), create_draw_call(false));
+        let bounds = Aabb {
+            min: Vec3::splat(-0.5),
+            max: Vec3::splat(0.5),
+        };
+        for (z, object_id) in [(-5.0, 1), (5.0, 2), (-500.0, 3)] {
+            let instance = create_instance(z, object_id);
+            draw_calls.add(&uniforms, &draw_call, instance, Some(&bounds), SOURCE);
         }
-        self.temp_buffer.clear();
+        let calls = draw(&mut draw_calls);
+
+        assert_eq!(vec![vec![1]], get_drawn_object_ids(&calls));
+        assert_eq!(2, draw_calls.stats().culled_instances);
+    }
+
+    #[test]
+    fn redundant_binds_are_skipped() {
+        fake_gl::install();
+        let mut draw_calls = DrawCalls::new();
+        let uniforms = create_uniforms();
+        let (first, second) = (create_draw_call(false), create_draw_call(false));
+        draw_calls.add(&uniforms, &first, create_instance(-1.0, 1), None, SOURCE);
+        draw_calls.add(&uniforms, &second, create_instance(-2.0, 2), None, SOURCE);
+        let calls = draw(&mut draw_calls);
+
+        let vao_binds = args_of(&calls, "BindVertexArray");
+        let vaos = [first.vao.id() as i64, second.vao.id() as i64];
+        assert_eq!(vec![&vaos[..1], &vaos[1..]], vao_binds);
+        let stats = draw_calls.stats();
+        assert_eq!((1, 2), (stats.material_binds, stats.vao_binds));
+        // The morph targets textures differ, but the material, front face and
+        // culling state are the same.
+        assert_eq!(2, args_of(&calls, "BindTexture").len());
+        assert_eq!(1, args_of(&calls, "FrontFace").len());
+        assert_eq!(3, stats.skipped_binds);
+    }
+
+    #[test]
+    fn queued_objects_are_kept_alive_until_cleared() {
+        fake_gl::install();
+        let mut draw_calls = DrawCalls::new();
+        let (uniforms, draw_call) = (create_uniforms(), create_draw_call(false));
+        let vao = draw_call.vao.id();
+        draw_calls.add(
+            &uniforms,
+            &draw_call,
+            create_instance(-1.0, 1),
+            None,
+            SOURCE,
+        );
+        // E.g. the glTF the draw call is from being dropped.
+        drop(draw_call);
+        assert!(fake_gl::is_live("vertex array", vao));
+
+        let calls = draw(&mut draw_calls);
+        assert_eq!(1, args_of(&calls, "DrawElementsInstanced").len());
+        draw_calls.clear();
+        assert!(!fake_gl::is_live("vertex array", vao));
+    }
+
+    #[test]
+    fn pick_indices_map_back_to_the_drawn_instances() {
+        fake_gl::install();
+        let mut draw_calls = DrawCalls::new();
+        let uniforms = create_uniforms();
+        let (first, second) = (create_draw_call(false), create_draw_call(false));
+        let bounds = Aabb {
+            min: Vec3::splat(-0.5),
+            max: Vec3::splat(0.5),
+        };
+        let source = |node_index| DrawSource {
+            node_index,
+            ..SOURCE
+        };
+        draw_calls.add(&uniforms, &first, create_instance(-1.0, 1), None, source(1));
+        draw_calls.add(
+            &uniforms,
+            &second,
+            create_instance(-3.0, 3),
+            None,
+            source(2),
+        );
+        draw_calls.add(&uniforms, &first, create_instance(-2.0, 2), None, source(1));
+        // Culled instances don't get a pick index.
+        let culled = create_instance(5.0, 4);
+        draw_calls.add(&uniforms, &second, culled, Some(&bounds), source(2));
+
+        const PICK_BASE_LOCATION: i32 = 7;
+        fake_gl::take_calls();
+        draw_calls.draw_picking(
+            Mat4::perspective_rh_gl(1.0, 1.0, 100.0, 0.1),
+            ATTR_LOC_MODEL_TRANSFORM_COLUMNS,
+            ATTR_LOC_MORPH_WEIGHTS,
+            ATTR_LOC_INSTANCE_DATA,
+            PICK_BASE_LOCATION,
+        );
+        let calls = fake_gl::take_calls();
+
+        // Each draw's instances are numbered from the draw's pick base, which
+        // continues from the previous draw's.
+        let pick_bases = args_of(&calls, "Uniform1ui");
+        assert_eq!(vec![&[7, 1][..], &[7, 3][..]], pick_bases);
+        let object_ids = get_drawn_object_ids(&calls);
+        assert_eq!(vec![vec![1, 2], vec![3]], object_ids);
+        for (pick_index, object_id) in (1..).zip(object_ids.concat()) {
+            let (source, data) = draw_calls.get_pick_target(pick_index).unwrap();
+            assert_eq!(object_id, data.object_id);
+            let node_index = if object_id == 3 { 2 } else { 1 };
+            assert_eq!(node_index, source.node_index);
+        }
+        assert!(draw_calls.get_pick_target(0).is_none());
+        assert!(draw_calls.get_pick_target(4).is_none());
+
+        draw_calls.clear();
+        assert!(draw_calls.get_pick_target(1).is_none());
     }
 }
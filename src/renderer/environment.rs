@@ -0,0 +1,409 @@
+use crate::renderer::gl;
+use crate::renderer::gltf::{self, UniformBlockEnvironment};
+use crate::renderer::handles::{Buffer, Program, Texture, VertexArray};
+use glam::{Mat4, Vec2, Vec3, Vec4};
+use image::codecs::hdr::HdrDecoder;
+use image::imageops::FilterType;
+use image::Rgb32FImage;
+use std::f32::consts::PI;
+use std::ffi::c_void;
+
+/// The width of the environment map's first mip level. The height is half of
+/// this, as the map is equirectangular.
+const ENVIRONMENT_MAP_WIDTH: u32 = 256;
+/// The width of the smallest mip level of the specular mip chain. The last
+/// level is prefiltered for a roughness of 1.
+const SMALLEST_MIP_WIDTH: u32 = 4;
+/// The width of the mip level the spherical harmonics are calculated from.
+const SPHERICAL_HARMONICS_SOURCE_WIDTH: u32 = 64;
+/// Samples per texel when prefiltering the specular mip chain.
+const PREFILTER_SAMPLE_COUNT: u32 = 32;
+
+/// An environment map used for image based lighting of the glTF models, and
+/// for drawing a skybox.
+///
+/// The specular reflections are sampled from an equirectangular texture with
+/// a mip chain where each level is prefiltered for an increasing roughness,
+/// and the diffuse irradiance is stored as 9 spherical harmonics coefficients.
+pub struct Environment {
+    texture: Texture,
+    uniform_buffer: Buffer,
+    uniform_block: UniformBlockEnvironment,
+}
+
+impl Environment {
+    /// Loads an equirectangular environment map from an image file, e.g. a
+    /// PNG or a Radiance HDR file.
+    pub fn from_image(image_file: &[u8]) -> Result<Environment, image::ImageError> {
+        // The generic loader converts HDR images to 8-bit, so they are
+        // decoded separately to keep the full range.
+        let image = if image_file.starts_with(b"#?") {
+            let decoder = HdrDecoder::new(image_file)?;
+            let metadata = decoder.metadata();
+            let pixels = decoder.read_image_hdr()?;
+            let pixels = pixels.iter().flat_map(|pixel| pixel.0).collect();
+            let (width, height) = (metadata.width, metadata.height);
+            Rgb32FImage::from_raw(width, height, pixels).unwrap()
+        } else {
+            let image = image::load_from_memory(image_file)?;
+            let mut image = image.into_rgb32f();
+            // 8-bit images are assumed to be sRGB.
+            for channel in image.iter_mut() {
+                *channel = srgb_to_linear(*channel);
+            }
+            image
+        };
+        let (width, height) = (ENVIRONMENT_MAP_WIDTH, ENVIRONMENT_MAP_WIDTH / 2);
+        let image = image::imageops::resize(&image, width, height, FilterType::Triangle);
+        let pixels = image
+            .pixels()
+            .map(|pixel| Vec3::from_array(pixel.0))
+            .collect::<Vec<_>>();
+        let base = EquirectImage {
+            width: width as usize,
+            height: height as usize,
+            pixels,
+        };
+
+        let mut downsampled = vec![base];
+        while downsampled.last().unwrap().width > SMALLEST_MIP_WIDTH as usize {
+            let next = downsampled.last().unwrap().downsample();
+            downsampled.push(next);
+        }
+        let roughest_mip = downsampled.len() - 1;
+        let mips = (downsampled.iter().enumerate())
+            .map(|(mip, image)| {
+                let roughness = mip as f32 / roughest_mip as f32;
+                image.prefilter_ggx(roughness, &downsampled)
+            })
+            .collect::<Vec<_>>();
+        let sh_source = downsampled
+            .iter()
+            .find(|image| image.width <= SPHERICAL_HARMONICS_SOURCE_WIDTH as usize)
+            .unwrap();
+
+        let uniform_block = UniformBlockEnvironment {
+            irradiance_sh: sh_source.get_irradiance_sh(),
+            params: Vec4::new(roughest_mip as f32, 1.0, 0.0, 0.0),
+        };
+        Ok(Environment::new(&mips, uniform_block))
+    }
+
+    /// Creates an environment with the same radiance in every direction.
+    pub fn uniform(radiance: Vec3) -> Environment {
+        let image = EquirectImage {
+            width: 1,
+            height: 1,
+            pixels: vec![radiance],
+        };
+        let mut irradiance_sh = [Vec4::ZERO; 9];
+        // The integral of radiance * Y_00 over the sphere, convolved with the
+        // cosine lobe (times pi), see EquirectImage::get_irradiance_sh.
+        irradiance_sh[0] = Vec4::from((radiance * 4.0 * PI * SH_Y00 * PI, 0.0));
+        let uniform_block = UniformBlockEnvironment {
+            irradiance_sh,
+            params: Vec4::new(0.0, 1.0, 0.0, 0.0),
+        };
+        Environment::new(&[image], uniform_block)
+    }
+
+    fn new(mips: &[EquirectImage], uniform_block: UniformBlockEnvironment) -> Environment {
+        let texture = Texture::new();
+        gl::call!(gl::BindTexture(gl::TEXTURE_2D, texture.id()));
+        for (mip, image) in mips.iter().enumerate() {
+            gl::call!(gl::TexImage2D(
+                gl::TEXTURE_2D,
+                mip as i32,
+                gl::RGB16F as i32,
+                image.width as i32,
+                image.height as i32,
+                0,
+                gl::RGB,
+                gl::FLOAT,
+                image.pixels.as_ptr() as *const c_void,
+            ));
+        }
+        let target = gl::TEXTURE_2D;
+        let max_level = mips.len() as i32 - 1;
+        let min_filter = gl::LINEAR_MIPMAP_LINEAR as i32;
+        gl::call!(gl::TexParameteri(target, gl::TEXTURE_MAX_LEVEL, max_level));
+        gl::call!(gl::TexParameteri(
+            target,
+            gl::TEXTURE_MIN_FILTER,
+            min_filter
+        ));
+        let linear = gl::LINEAR as i32;
+        gl::call!(gl::TexParameteri(target, gl::TEXTURE_MAG_FILTER, linear));
+        let repeat = gl::REPEAT as i32;
+        gl::call!(gl::TexParameteri(target, gl::TEXTURE_WRAP_S, repeat));
+        let clamp = gl::CLAMP_TO_EDGE as i32;
+        gl::call!(gl::TexParameteri(target, gl::TEXTURE_WRAP_T, clamp));
+
+        let mut environment = Environment {
+            texture,
+            uniform_buffer: Buffer::new(),
+            uniform_block,
+        };
+        environment.upload_uniform_block();
+        environment
+    }
+
+    pub fn intensity(&self) -> f32 {
+        self.uniform_block.params.y
+    }
+
+    /// Sets the multiplier for the environment's radiance, both for lighting
+    /// and the skybox.
+    pub fn set_intensity(&mut self, intensity: f32) {
+        self.uniform_block.params.y = intensity;
+        self.upload_uniform_block();
+    }
+
+    fn upload_uniform_block(&mut self) {
+        let data = [self.uniform_block];
+        let data: &[u8] = bytemuck::cast_slice(&data);
+        gl::call!(gl::BindBuffer(gl::UNIFORM_BUFFER, self.uniform_buffer.id()));
+        gl::call!(gl::BufferData(
+            gl::UNIFORM_BUFFER,
+            data.len() as isize,
+            data.as_ptr() as *const c_void,
+            gl::STATIC_DRAW,
+        ));
+    }
+
+    /// Binds the environment map and the environment uniform block for the
+    /// glTF and skybox shaders.
+    pub fn bind(&self) {
+        gl::call!(gl::ActiveTexture(gl::TEXTURE0 + gltf::TEX_UNIT_ENVIRONMENT));
+        gl::call!(gl::BindTexture(gl::TEXTURE_2D, self.texture.id()));
+        gl::call!(gl::BindSampler(gltf::TEX_UNIT_ENVIRONMENT, 0));
+        gl::call!(gl::BindBufferBase(
+            gl::UNIFORM_BUFFER,
+            gltf::UNIFORM_BLOCK_ENVIRONMENT,
+            self.uniform_buffer.id(),
+        ));
+    }
+}
+
+/// Draws the environment map behind everything else.
+pub struct Skybox {
+    program: Program,
+    world_from_clip_location: gl::types::GLint,
+    vao: VertexArray,
+}
+
+impl Skybox {
+    pub fn new() -> Skybox {
+        let vertex_shader =
+            gl::create_shader(gl::VERTEX_SHADER, include_str!("skybox_vertex.glsl"));
+        let fragment_shader =
+            gl::create_shader(gl::FRAGMENT_SHADER, include_str!("skybox_fragment.glsl"));
+        let program = gl::create_program(&[vertex_shader, fragment_shader]);
+        let handle = Program::from_raw(program);
+        gl::call!(gl::DeleteShader(vertex_shader));
+        gl::call!(gl::DeleteShader(fragment_shader));
+        gl::call!(gl::UseProgram(program));
+        let world_from_clip_location =
+            gl::get_uniform_location(program, "world_from_clip").unwrap();
+        if let Some(location) = gl::get_uniform_location(program, "environment_tex") {
+            gl::call!(gl::Uniform1i(location, gltf::TEX_UNIT_ENVIRONMENT as i32));
+        }
+        if let Some(loc) = gl::get_uniform_block_index(program, "Environment") {
+            let binding = gltf::UNIFORM_BLOCK_ENVIRONMENT;
+            gl::call!(gl::UniformBlockBinding(program, loc, binding));
+        }
+        // The fullscreen triangle is generated from gl_VertexID, so the VAO
+        // doesn't have any attributes.
+        Skybox {
+            program: handle,
+            world_from_clip_location,
+            vao: VertexArray::new(),
+        }
+    }
+
+    /// Draws the currently bound environment (see [Environment::bind]) over
+    /// the whole viewport, without depth testing or writing.
+    pub fn draw(&self, view_from_world: Mat4, proj_from_view: Mat4) {
+        // Only the rotation of the view matters for the skybox.
+        let view_rotation = Mat4::from_mat3(glam::Mat3::from_mat4(view_from_world));
+        let world_from_clip = (proj_from_view * view_rotation).inverse();
+        gl::call!(gl::UseProgram(self.program.id()));
+        gl::call!(gl::UniformMatrix4fv(
+            self.world_from_clip_location,
+            1,
+            gl::FALSE,
+            world_from_clip.to_cols_array().as_ptr(),
+        ));
+        gl::call!(gl::Disable(gl::DEPTH_TEST));
+        gl::call!(gl::DepthMask(gl::FALSE));
+        gl::call!(gl::BindVertexArray(self.vao.id()));
+        gl::call!(gl::DrawArrays(gl::TRIANGLES, 0, 3));
+        gl::call!(gl::DepthMask(gl::TRUE));
+        gl::call!(gl::Enable(gl::DEPTH_TEST));
+    }
+}
+
+const SH_Y00: f32 = 0.282095;
+const SH_Y1: f32 = 0.488603;
+const SH_Y2: f32 = 1.092548;
+const SH_Y20: f32 = 0.315392;
+const SH_Y22: f32 = 0.546274;
+
+/// An equirectangular image, where +Y is up, and u = 0.5 is towards +X. The
+/// same mapping is used in the shaders.
+struct EquirectImage {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3>,
+}
+
+impl EquirectImage {
+    fn get_direction(&self, x: usize, y: usize) -> Vec3 {
+        let u = (x as f32 + 0.5) / self.width as f32;
+        let v = (y as f32 + 0.5) / self.height as f32;
+        let phi = (u - 0.5) * 2.0 * PI;
+        let theta = v * PI;
+        Vec3::new(
+            theta.sin() * phi.cos(),
+            theta.cos(),
+            theta.sin() * phi.sin(),
+        )
+    }
+
+    fn sample(&self, direction: Vec3) -> Vec3 {
+        let u = direction.z.atan2(direction.x) / (2.0 * PI) + 0.5;
+        let v = direction.y.clamp(-1.0, 1.0).acos() / PI;
+        let uv = Vec2::new(u, v) * Vec2::new(self.width as f32, self.height as f32);
+        let x = (uv.x as usize).min(self.width - 1);
+        let y = (uv.y as usize).min(self.height - 1);
+        self.pixels[x + y * self.width]
+    }
+
+    fn downsample(&self) -> EquirectImage {
+        let width = self.width / 2;
+        let height = self.height / 2;
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let i = x * 2 + y * 2 * self.width;
+                let sum = self.pixels[i]
+                    + self.pixels[i + 1]
+                    + self.pixels[i + self.width]
+                    + self.pixels[i + self.width + 1];
+                pixels.push(sum / 4.0);
+            }
+        }
+        EquirectImage {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    /// Returns an image of the same size as this one, where each pixel is
+    /// the radiance from `mips[0]` convolved with the GGX distribution, using
+    /// filtered importance sampling to keep the sample count low.
+    fn prefilter_ggx(&self, roughness: f32, mips: &[EquirectImage]) -> EquirectImage {
+        if roughness == 0.0 {
+            return EquirectImage {
+                width: self.width,
+                height: self.height,
+                pixels: mips[0].pixels.clone(),
+            };
+        }
+        let alpha = roughness * roughness;
+        let alpha_sq = alpha * alpha;
+        let source_texel_solid_angle = 4.0 * PI / (mips[0].width * mips[0].height) as f32;
+        let mut pixels = Vec::with_capacity(self.width * self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                // The usual assumption for prefiltered environment maps:
+                // the view and normal directions are the reflection direction.
+                let normal = self.get_direction(x, y);
+                let (tangent, bitangent) = normal.any_orthonormal_pair();
+                let mut sum = Vec3::ZERO;
+                let mut weight = 0.0;
+                for i in 0..PREFILTER_SAMPLE_COUNT {
+                    let (xi_1, xi_2) = hammersley(i, PREFILTER_SAMPLE_COUNT);
+                    let phi = 2.0 * PI * xi_1;
+                    let cos_theta = ((1.0 - xi_2) / (1.0 + (alpha_sq - 1.0) * xi_2)).sqrt();
+                    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                    let half = tangent * (sin_theta * phi.cos())
+                        + bitangent * (sin_theta * phi.sin())
+                        + normal * cos_theta;
+                    let light = 2.0 * normal.dot(half) * half - normal;
+                    let n_dot_l = normal.dot(light);
+                    if n_dot_l <= 0.0 {
+                        continue;
+                    }
+                    // With N = V, the pdf of the light direction is D / 4.
+                    let d = cos_theta * cos_theta * (alpha_sq - 1.0) + 1.0;
+                    let pdf = alpha_sq / (PI * d * d) / 4.0;
+                    let sample_solid_angle = 1.0 / (PREFILTER_SAMPLE_COUNT as f32 * pdf);
+                    let lod = 0.5 * (sample_solid_angle / source_texel_solid_angle).log2() + 1.0;
+                    let mip = (lod.max(0.0).round() as usize).min(mips.len() - 1);
+                    sum += mips[mip].sample(light) * n_dot_l;
+                    weight += n_dot_l;
+                }
+                pixels.push(sum / weight.max(f32::EPSILON));
+            }
+        }
+        EquirectImage {
+            width: self.width,
+            height: self.height,
+            pixels,
+        }
+    }
+
+    /// Returns the first 9 spherical harmonics coefficients of the irradiance
+    /// of this environment, already convolved with the cosine lobe, as in
+    /// Ramamoorthi and Hanrahan's "An Efficient Representation for Irradiance
+    /// Environment Maps".
+    fn get_irradiance_sh(&self) -> [Vec4; 9] {
+        let mut sh = [Vec3::ZERO; 9];
+        let texel_size = (2.0 * PI / self.width as f32) * (PI / self.height as f32);
+        for y in 0..self.height {
+            let theta = (y as f32 + 0.5) / self.height as f32 * PI;
+            let solid_angle = texel_size * theta.sin();
+            for x in 0..self.width {
+                let d = self.get_direction(x, y);
+                let radiance = self.pixels[x + y * self.width] * solid_angle;
+                let basis = [
+                    SH_Y00,
+                    SH_Y1 * d.y,
+                    SH_Y1 * d.z,
+                    SH_Y1 * d.x,
+                    SH_Y2 * d.x * d.y,
+                    SH_Y2 * d.y * d.z,
+                    SH_Y20 * (3.0 * d.z * d.z - 1.0),
+                    SH_Y2 * d.x * d.z,
+                    SH_Y22 * (d.x * d.x - d.y * d.y),
+                ];
+                for (coefficient, basis) in sh.iter_mut().zip(basis) {
+                    *coefficient += radiance * basis;
+                }
+            }
+        }
+        // The cosine lobe's convolution constants for each band.
+        let bands = [PI, 2.0 * PI / 3.0, PI / 4.0];
+        let band_of_coefficient = [0, 1, 1, 1, 2, 2, 2, 2, 2];
+        let mut irradiance_sh = [Vec4::ZERO; 9];
+        for i in 0..9 {
+            let band = bands[band_of_coefficient[i]];
+            irradiance_sh[i] = Vec4::from((sh[i] * band, 0.0));
+        }
+        irradiance_sh
+    }
+}
+
+fn hammersley(i: u32, n: u32) -> (f32, f32) {
+    (i as f32 / n as f32, i.reverse_bits() as f32 / 2f32.powi(32))
+}
+
+fn srgb_to_linear(x: f32) -> f32 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
@@ -0,0 +1,602 @@
+// A fake OpenGL implementation for testing the renderer without a GPU or a
+// context. [install] loads its functions in place of the driver's, so the
+// renderer's code runs unchanged, while the calls and the contents of the
+// buffers are recorded for the test to inspect. Each thread (i.e. each test)
+// has its own state. Functions which aren't faked are left unloaded, and
+// panic if called.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::c_void;
+use std::ptr;
+use std::sync::Mutex;
+
+use crate::renderer::gl;
+use crate::renderer::gl::types::{
+    GLbitfield, GLboolean, GLchar, GLenum, GLfloat, GLint, GLintptr, GLsizei, GLsizeiptr, GLsync,
+    GLubyte, GLuint, GLuint64, GLDEBUGPROCKHR,
+};
+
+/// A GL function call, with its arguments cast to integers. Pointers into
+/// buffers are recorded as offsets, and pointers to client memory are left
+/// out, e.g. the uploaded data is recorded in the buffer contents instead.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Call {
+    /// The name of the function without the "gl" prefix, e.g. "BufferData".
+    pub name: &'static str,
+    pub args: Vec<i64>,
+}
+
+struct State {
+    calls: Vec<Call>,
+    /// The error for the next glGetError.
+    error: GLenum,
+    next_id: GLuint,
+    /// The objects which have been generated but not deleted, by kind.
+    live_objects: HashSet<(&'static str, GLuint)>,
+    buffers: HashMap<GLuint, Vec<u8>>,
+    bound_buffers: HashMap<GLenum, GLuint>,
+    uniform_buffer_offset_alignment: GLint,
+    max_label_length: GLint,
+    /// The labels set with glObjectLabelKHR, by identifier and name.
+    labels: HashMap<(GLenum, GLuint), String>,
+    next_sync: usize,
+    /// The result of glClientWaitSync.
+    client_wait_status: GLenum,
+}
+
+impl State {
+    fn new() -> State {
+        State {
+            calls: Vec::new(),
+            error: gl::NO_ERROR,
+            next_id: 0,
+            live_objects: HashSet::new(),
+            buffers: HashMap::new(),
+            bound_buffers: HashMap::new(),
+            uniform_buffer_offset_alignment: 256,
+            max_label_length: 256,
+            labels: HashMap::new(),
+            next_sync: 0,
+            client_wait_status: gl::ALREADY_SIGNALED,
+        }
+    }
+
+    /// Returns the contents of the buffer bound to the target, and flags an
+    /// error if the range doesn't fit in it.
+    fn bound_range(&mut self, target: GLenum, offset: isize, size: isize) -> Option<&mut [u8]> {
+        let buffer = self.bound_buffers.get(&target).copied().unwrap_or(0);
+        let range = usize::try_from(offset)
+            .ok()
+            .zip(usize::try_from(size).ok())
+            .map(|(offset, size)| offset..offset + size);
+        let data = self.buffers.get_mut(&buffer);
+        match (data, range) {
+            (Some(data), Some(range)) if range.end <= data.len() => Some(&mut data[range]),
+            _ => {
+                self.error = gl::INVALID_VALUE;
+                None
+            }
+        }
+    }
+}
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::new());
+}
+
+/// Loads the fake GL functions, and resets the current thread's state. The
+/// functions are loaded on every call, so that the fakes are used even if
+/// something else in the process has loaded the driver's functions since.
+pub fn install() {
+    // The function pointers are plain statics, so the loads are serialized.
+    static LOAD: Mutex<()> = Mutex::new(());
+    let _guard = LOAD.lock().unwrap_or_else(|err| err.into_inner());
+    gl::load_with(get_proc_address);
+    STATE.with(|state| *state.borrow_mut() = State::new());
+}
+
+/// Returns the calls made so far, and clears the list.
+pub fn take_calls() -> Vec<Call> {
+    STATE.with(|state| std::mem::take(&mut state.borrow_mut().calls))
+}
+
+/// Returns the arguments of each call to the function named `name` in
+/// `calls`.
+pub fn args_of<'a>(calls: &'a [Call], name: &str) -> Vec<&'a [i64]> {
+    (calls.iter())
+        .filter(|call| call.name == name)
+        .map(|call| call.args.as_slice())
+        .collect()
+}
+
+/// Returns the current contents of the buffer object.
+pub fn buffer_contents(buffer: GLuint) -> Vec<u8> {
+    STATE.with(|state| state.borrow().buffers[&buffer].clone())
+}
+
+/// Returns the amount of objects which have been generated and not deleted.
+pub fn live_objects() -> usize {
+    STATE.with(|state| state.borrow().live_objects.len())
+}
+
+pub fn is_live(kind: &str, id: GLuint) -> bool {
+    STATE.with(|state| (state.borrow().live_objects).contains(&(kind, id)))
+}
+
+pub fn set_uniform_buffer_offset_alignment(alignment: GLint) {
+    STATE.with(|state| state.borrow_mut().uniform_buffer_offset_alignment = alignment);
+}
+
+pub fn set_max_label_length(length: GLint) {
+    STATE.with(|state| state.borrow_mut().max_label_length = length);
+}
+
+/// Returns the label of the object, as set with glObjectLabelKHR.
+pub fn object_label(identifier: GLenum, name: GLuint) -> Option<String> {
+    STATE.with(|state| state.borrow().labels.get(&(identifier, name)).cloned())
+}
+
+/// Sets what glClientWaitSync returns, e.g. TIMEOUT_EXPIRED to pretend the GPU
+/// is still busy. By default, fences have already been signaled.
+pub fn set_client_wait_status(status: GLenum) {
+    STATE.with(|state| state.borrow_mut().client_wait_status = status);
+}
+
+fn record(name: &'static str, args: &[i64]) {
+    let args = args.to_vec();
+    STATE.with(|state| state.borrow_mut().calls.push(Call { name, args }));
+}
+
+fn get_proc_address(name: &'static str) -> *const c_void {
+    match name {
+        "glActiveTexture" => active_texture as *const c_void,
+        "glBindBuffer" => bind_buffer as *const c_void,
+        "glBindBufferRange" => bind_buffer_range as *const c_void,
+        "glBindSampler" => bind_sampler as *const c_void,
+        "glBindTexture" => bind_texture as *const c_void,
+        "glBindVertexArray" => bind_vertex_array as *const c_void,
+        "glBlendFunc" => blend_func as *const c_void,
+        "glBufferData" => buffer_data as *const c_void,
+        "glBufferSubData" => buffer_sub_data as *const c_void,
+        "glClientWaitSync" => client_wait_sync as *const c_void,
+        "glDebugMessageCallbackKHR" => debug_message_callback as *const c_void,
+        "glDeleteBuffers" => delete_buffers as *const c_void,
+        "glDeleteSamplers" => delete_samplers as *const c_void,
+        "glDeleteSync" => delete_sync as *const c_void,
+        "glDeleteTextures" => delete_textures as *const c_void,
+        "glDeleteVertexArrays" => delete_vertex_arrays as *const c_void,
+        "glDepthMask" => depth_mask as *const c_void,
+        "glDisable" => disable as *const c_void,
+        "glDrawElementsInstanced" => draw_elements_instanced as *const c_void,
+        "glEnable" => enable as *const c_void,
+        "glEnableVertexAttribArray" => enable_vertex_attrib_array as *const c_void,
+        "glFenceSync" => fence_sync as *const c_void,
+        "glFrontFace" => front_face as *const c_void,
+        "glGenBuffers" => gen_buffers as *const c_void,
+        "glGenSamplers" => gen_samplers as *const c_void,
+        "glGenTextures" => gen_textures as *const c_void,
+        "glGenVertexArrays" => gen_vertex_arrays as *const c_void,
+        "glGetError" => get_error as *const c_void,
+        "glGetIntegerv" => get_integerv as *const c_void,
+        "glGetStringi" => get_stringi as *const c_void,
+        "glMapBufferRange" => map_buffer_range as *const c_void,
+        "glObjectLabelKHR" => object_label_khr as *const c_void,
+        "glSamplerParameteri" => sampler_parameteri as *const c_void,
+        "glTexImage2D" => tex_image_2d as *const c_void,
+        "glTexImage3D" => tex_image_3d as *const c_void,
+        "glTexParameteri" => tex_parameteri as *const c_void,
+        "glUniform1ui" => uniform_1ui as *const c_void,
+        "glUnmapBuffer" => unmap_buffer as *const c_void,
+        "glVertexAttrib4f" => vertex_attrib_4f as *const c_void,
+        "glVertexAttribDivisor" => vertex_attrib_divisor as *const c_void,
+        "glVertexAttribIPointer" => vertex_attrib_i_pointer as *const c_void,
+        "glVertexAttribPointer" => vertex_attrib_pointer as *const c_void,
+        _ => ptr::null(),
+    }
+}
+
+fn gen_objects(name: &'static str, kind: &'static str, n: GLsizei, ids: *mut GLuint) {
+    let ids = unsafe { std::slice::from_raw_parts_mut(ids, n as usize) };
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        for id in ids.iter_mut() {
+            state.next_id += 1;
+            *id = state.next_id;
+            state.live_objects.insert((kind, *id));
+            if kind == "buffer" {
+                state.buffers.insert(*id, Vec::new());
+            }
+        }
+    });
+    record(name, &ids.iter().map(|&id| id as i64).collect::<Vec<_>>());
+}
+
+fn delete_objects(name: &'static str, kind: &'static str, n: GLsizei, ids: *const GLuint) {
+    let ids = unsafe { std::slice::from_raw_parts(ids, n as usize) };
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        for id in ids {
+            state.live_objects.remove(&(kind, *id));
+            state.buffers.remove(id);
+        }
+    });
+    record(name, &ids.iter().map(|&id| id as i64).collect::<Vec<_>>());
+}
+
+extern "system" fn gen_buffers(n: GLsizei, buffers: *mut GLuint) {
+    gen_objects("GenBuffers", "buffer", n, buffers);
+}
+
+extern "system" fn gen_textures(n: GLsizei, textures: *mut GLuint) {
+    gen_objects("GenTextures", "texture", n, textures);
+}
+
+extern "system" fn gen_samplers(n: GLsizei, samplers: *mut GLuint) {
+    gen_objects("GenSamplers", "sampler", n, samplers);
+}
+
+extern "system" fn gen_vertex_arrays(n: GLsizei, arrays: *mut GLuint) {
+    gen_objects("GenVertexArrays", "vertex array", n, arrays);
+}
+
+extern "system" fn delete_buffers(n: GLsizei, buffers: *const GLuint) {
+    delete_objects("DeleteBuffers", "buffer", n, buffers);
+}
+
+extern "system" fn delete_textures(n: GLsizei, textures: *const GLuint) {
+    delete_objects("DeleteTextures", "texture", n, textures);
+}
+
+extern "system" fn delete_samplers(n: GLsizei, samplers: *const GLuint) {
+    delete_objects("DeleteSamplers", "sampler", n, samplers);
+}
+
+extern "system" fn delete_vertex_arrays(n: GLsizei, arrays: *const GLuint) {
+    delete_objects("DeleteVertexArrays", "vertex array", n, arrays);
+}
+
+extern "system" fn bind_buffer(target: GLenum, buffer: GLuint) {
+    STATE.with(|state| state.borrow_mut().bound_buffers.insert(target, buffer));
+    record("BindBuffer", &[target as i64, buffer as i64]);
+}
+
+extern "system" fn buffer_data(
+    target: GLenum,
+    size: GLsizeiptr,
+    data: *const c_void,
+    usage: GLenum,
+) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let buffer = state.bound_buffers.get(&target).copied().unwrap_or(0);
+        let contents = if data.is_null() {
+            vec![0; size as usize]
+        } else {
+            unsafe { std::slice::from_raw_parts(data as *const u8, size as usize) }.to_vec()
+        };
+        match state.buffers.get_mut(&buffer) {
+            Some(buffer) => *buffer = contents,
+            None => state.error = gl::INVALID_OPERATION,
+        }
+    });
+    record("BufferData", &[target as i64, size as i64, usage as i64]);
+}
+
+extern "system" fn buffer_sub_data(
+    target: GLenum,
+    offset: GLintptr,
+    size: GLsizeiptr,
+    data: *const c_void,
+) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if let Some(range) = state.bound_range(target, offset, size) {
+            let data = unsafe { std::slice::from_raw_parts(data as *const u8, size as usize) };
+            range.copy_from_slice(data);
+        }
+    });
+    record(
+        "BufferSubData",
+        &[target as i64, offset as i64, size as i64],
+    );
+}
+
+extern "system" fn map_buffer_range(
+    target: GLenum,
+    offset: GLintptr,
+    length: GLsizeiptr,
+    access: GLbitfield,
+) -> *mut c_void {
+    record(
+        "MapBufferRange",
+        &[target as i64, offset as i64, length as i64, access as i64],
+    );
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        match state.bound_range(target, offset, length) {
+            // The contents aren't resized before the buffer is unmapped, so
+            // the pointer stays valid.
+            Some(range) => range.as_mut_ptr() as *mut c_void,
+            None => ptr::null_mut(),
+        }
+    })
+}
+
+extern "system" fn unmap_buffer(target: GLenum) -> GLboolean {
+    record("UnmapBuffer", &[target as i64]);
+    gl::TRUE
+}
+
+extern "system" fn bind_buffer_range(
+    target: GLenum,
+    index: GLuint,
+    buffer: GLuint,
+    offset: GLintptr,
+    size: GLsizeiptr,
+) {
+    record(
+        "BindBufferRange",
+        &[
+            target as i64,
+            index as i64,
+            buffer as i64,
+            offset as i64,
+            size as i64,
+        ],
+    );
+}
+
+extern "system" fn bind_vertex_array(array: GLuint) {
+    record("BindVertexArray", &[array as i64]);
+}
+
+extern "system" fn enable_vertex_attrib_array(index: GLuint) {
+    record("EnableVertexAttribArray", &[index as i64]);
+}
+
+extern "system" fn vertex_attrib_pointer(
+    index: GLuint,
+    size: GLint,
+    type_: GLenum,
+    normalized: GLboolean,
+    stride: GLsizei,
+    pointer: *const c_void,
+) {
+    record(
+        "VertexAttribPointer",
+        &[
+            index as i64,
+            size as i64,
+            type_ as i64,
+            normalized as i64,
+            stride as i64,
+            pointer as i64,
+        ],
+    );
+}
+
+extern "system" fn vertex_attrib_i_pointer(
+    index: GLuint,
+    size: GLint,
+    type_: GLenum,
+    stride: GLsizei,
+    pointer: *const c_void,
+) {
+    record(
+        "VertexAttribIPointer",
+        &[
+            index as i64,
+            size as i64,
+            type_ as i64,
+            stride as i64,
+            pointer as i64,
+        ],
+    );
+}
+
+extern "system" fn vertex_attrib_divisor(index: GLuint, divisor: GLuint) {
+    record("VertexAttribDivisor", &[index as i64, divisor as i64]);
+}
+
+extern "system" fn vertex_attrib_4f(index: GLuint, _: GLfloat, _: GLfloat, _: GLfloat, _: GLfloat) {
+    record("VertexAttrib4f", &[index as i64]);
+}
+
+extern "system" fn active_texture(texture: GLenum) {
+    record("ActiveTexture", &[texture as i64]);
+}
+
+extern "system" fn bind_texture(target: GLenum, texture: GLuint) {
+    record("BindTexture", &[target as i64, texture as i64]);
+}
+
+extern "system" fn tex_parameteri(target: GLenum, pname: GLenum, param: GLint) {
+    record(
+        "TexParameteri",
+        &[target as i64, pname as i64, param as i64],
+    );
+}
+
+extern "system" fn tex_image_2d(
+    target: GLenum,
+    level: GLint,
+    internal_format: GLint,
+    width: GLsizei,
+    height: GLsizei,
+    _border: GLint,
+    format: GLenum,
+    type_: GLenum,
+    _pixels: *const c_void,
+) {
+    record(
+        "TexImage2D",
+        &[
+            target as i64,
+            level as i64,
+            internal_format as i64,
+            width as i64,
+            height as i64,
+            format as i64,
+            type_ as i64,
+        ],
+    );
+}
+
+extern "system" fn tex_image_3d(
+    target: GLenum,
+    level: GLint,
+    internal_format: GLint,
+    width: GLsizei,
+    height: GLsizei,
+    depth: GLsizei,
+    _border: GLint,
+    format: GLenum,
+    type_: GLenum,
+    _pixels: *const c_void,
+) {
+    record(
+        "TexImage3D",
+        &[
+            target as i64,
+            level as i64,
+            internal_format as i64,
+            width as i64,
+            height as i64,
+            depth as i64,
+            format as i64,
+            type_ as i64,
+        ],
+    );
+}
+
+extern "system" fn bind_sampler(unit: GLuint, sampler: GLuint) {
+    record("BindSampler", &[unit as i64, sampler as i64]);
+}
+
+extern "system" fn sampler_parameteri(sampler: GLuint, pname: GLenum, param: GLint) {
+    record(
+        "SamplerParameteri",
+        &[sampler as i64, pname as i64, param as i64],
+    );
+}
+
+extern "system" fn get_integerv(pname: GLenum, data: *mut GLint) {
+    let value = STATE.with(|state| match pname {
+        gl::UNIFORM_BUFFER_OFFSET_ALIGNMENT => state.borrow().uniform_buffer_offset_alignment,
+        gl::MAX_LABEL_LENGTH_KHR => state.borrow().max_label_length,
+        gl::NUM_EXTENSIONS => EXTENSIONS.len() as GLint,
+        _ => 0,
+    });
+    unsafe { *data = value };
+    record("GetIntegerv", &[pname as i64]);
+}
+
+/// The extensions the fake claims to support.
+const EXTENSIONS: [&[u8]; 1] = [b"GL_KHR_debug\0"];
+
+extern "system" fn get_stringi(name: GLenum, index: GLuint) -> *const GLubyte {
+    record("GetStringi", &[name as i64, index as i64]);
+    match (name, EXTENSIONS.get(index as usize)) {
+        (gl::EXTENSIONS, Some(extension)) => extension.as_ptr(),
+        _ => {
+            STATE.with(|state| state.borrow_mut().error = gl::INVALID_VALUE);
+            ptr::null()
+        }
+    }
+}
+
+extern "system" fn debug_message_callback(_: GLDEBUGPROCKHR, _: *const c_void) {
+    record("DebugMessageCallbackKHR", &[]);
+}
+
+extern "system" fn object_label_khr(
+    identifier: GLenum,
+    name: GLuint,
+    length: GLsizei,
+    label: *const GLchar,
+) {
+    record(
+        "ObjectLabelKHR",
+        &[identifier as i64, name as i64, length as i64],
+    );
+    let label = unsafe { std::slice::from_raw_parts(label as *const u8, length as usize) };
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if length >= state.max_label_length {
+            state.error = gl::INVALID_VALUE;
+        } else {
+            let label = String::from_utf8(label.to_vec()).unwrap();
+            state.labels.insert((identifier, name), label);
+        }
+    });
+}
+
+extern "system" fn get_error() -> GLenum {
+    // Not recorded, as gl::call! checks for errors after every call.
+    STATE.with(|state| std::mem::replace(&mut state.borrow_mut().error, gl::NO_ERROR))
+}
+
+extern "system" fn fence_sync(condition: GLenum, flags: GLbitfield) -> GLsync {
+    record("FenceSync", &[condition as i64, flags as i64]);
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.next_sync += 1;
+        state.next_sync as GLsync
+    })
+}
+
+extern "system" fn delete_sync(sync: GLsync) {
+    record("DeleteSync", &[sync as i64]);
+}
+
+extern "system" fn client_wait_sync(sync: GLsync, flags: GLbitfield, _: GLuint64) -> GLenum {
+    record("ClientWaitSync", &[sync as i64, flags as i64]);
+    STATE.with(|state| state.borrow().client_wait_status)
+}
+
+extern "system" fn enable(cap: GLenum) {
+    record("Enable", &[cap as i64]);
+}
+
+extern "system" fn disable(cap: GLenum) {
+    record("Disable", &[cap as i64]);
+}
+
+extern "system" fn blend_func(source_factor: GLenum, destination_factor: GLenum) {
+    record(
+        "BlendFunc",
+        &[source_factor as i64, destination_factor as i64],
+    );
+}
+
+extern "system" fn depth_mask(flag: GLboolean) {
+    record("DepthMask", &[flag as i64]);
+}
+
+extern "system" fn front_face(mode: GLenum) {
+    record("FrontFace", &[mode as i64]);
+}
+
+extern "system" fn draw_elements_instanced(
+    mode: GLenum,
+    count: GLsizei,
+    type_: GLenum,
+    indices: *const c_void,
+    instance_count: GLsizei,
+) {
+    record(
+        "DrawElementsInstanced",
+        &[
+            mode as i64,
+            count as i64,
+            type_ as i64,
+            indices as i64,
+            instance_count as i64,
+        ],
+    );
+}
+
+extern "system" fn uniform_1ui(location: GLint, value: GLuint) {
+    record("Uniform1ui", &[location as i64, value as i64]);
+}
@@ -0,0 +1,12 @@
+#version 300 es
+
+out vec2 tex_coords;
+
+void main() {
+  // A triangle covering the whole screen, with the corners at (-1, -1),
+  // (3, -1), and (-1, 3).
+  vec2 clip_pos = vec2(float((gl_VertexID & 1) << 2) - 1.0,
+                       float((gl_VertexID & 2) << 1) - 1.0);
+  tex_coords = clip_pos * 0.5 + 0.5;
+  gl_Position = vec4(clip_pos, 0.0, 1.0);
+}
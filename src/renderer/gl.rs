@@ -2,27 +2,18 @@ include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
 /// A wrapper for OpenGL calls, wrapping the call inside unsafe {} and possibly
 /// panicing based on glGetError in debug builds.
+#[macro_export]
 macro_rules! call {
     ($expr:expr) => {{
+        // Wrapping the call in an unsafe block is what the macro is for.
+        #[allow(clippy::macro_metavars_in_unsafe)]
         let result = unsafe { $expr };
         if cfg!(debug_assertions) {
-            use crate::renderer::gl::*;
-            let error = unsafe { GetError() };
-            if error != NO_ERROR {
-                let error_number_stringified;
-                let error_name = match error {
-                    INVALID_ENUM => "INVALID_ENUM",
-                    INVALID_VALUE => "INVALID_VALUE",
-                    INVALID_OPERATION => "INVALID_OPERATION",
-                    OUT_OF_MEMORY => "OUT_OF_MEMORY",
-                    INVALID_FRAMEBUFFER_OPERATION => "INVALID_FRAMEBUFFER_OPERATION",
-                    _ => {
-                        error_number_stringified = format!("{error}");
-                        &error_number_stringified
-                    }
-                };
+            let error = unsafe { $crate::renderer::gl::GetError() };
+            if error != $crate::renderer::gl::NO_ERROR {
                 panic!(
-                    "OpenGL error {error_name} at {}:{}:{}",
+                    "OpenGL error {} at {}:{}:{}",
+                    $crate::renderer::gl::get_error_name(error),
                     file!(),
                     line!(),
                     column!(),
@@ -32,9 +23,66 @@ macro_rules! call {
         result
     }};
 }
-pub(crate) use call;
+pub use crate::call;
 
+/// Like [call], but returns the result of the call as an `Ok`, or a [GlError]
+/// if glGetError reports one, in all builds. Errors flagged by earlier calls
+/// which weren't checked are reported here as well.
+#[macro_export]
+macro_rules! try_call {
+    ($expr:expr) => {{
+        // Wrapping the call in an unsafe block is what the macro is for.
+        #[allow(clippy::macro_metavars_in_unsafe)]
+        let result = unsafe { $expr };
+        match unsafe { $crate::renderer::gl::GetError() } {
+            $crate::renderer::gl::NO_ERROR => Ok(result),
+            code => Err($crate::renderer::gl::GlError {
+                code,
+                file: file!(),
+                line: line!(),
+            }),
+        }
+    }};
+}
+pub use crate::try_call;
+
+use std::error::Error;
 use std::ffi::CString;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The context's GL_MAX_LABEL_LENGTH, set by [enable_debug_layer] if
+/// KHR_debug is available. 0 while the debug layer isn't enabled.
+static LABEL_LENGTH_LIMIT: AtomicUsize = AtomicUsize::new(0);
+
+/// An error reported by glGetError, see [try_call].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct GlError {
+    pub code: types::GLenum,
+    /// The source file of the failed call.
+    pub file: &'static str,
+    pub line: u32,
+}
+
+impl Display for GlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = get_error_name(self.code);
+        write!(f, "OpenGL error {name} at {}:{}", self.file, self.line)
+    }
+}
+
+impl Error for GlError {}
+
+pub fn get_error_name(error: types::GLenum) -> String {
+    match error {
+        INVALID_ENUM => "INVALID_ENUM".to_string(),
+        INVALID_VALUE => "INVALID_VALUE".to_string(),
+        INVALID_OPERATION => "INVALID_OPERATION".to_string(),
+        OUT_OF_MEMORY => "OUT_OF_MEMORY".to_string(),
+        INVALID_FRAMEBUFFER_OPERATION => "INVALID_FRAMEBUFFER_OPERATION".to_string(),
+        _ => format!("{error}"),
+    }
+}
 
 #[track_caller]
 pub fn create_shader(type_: types::GLenum, shader_source: &str) -> u32 {
@@ -113,3 +161,160 @@ pub fn get_uniform_block_index(program: u32, name: &str) -> Option<u32> {
         Some(location)
     }
 }
+
+/// Returns true if the context supports the extension. The "GL_" prefix is
+/// optional, as some platforms (e.g. WebGL) leave it out.
+pub fn has_extension(name: &str) -> bool {
+    let name = name.trim_start_matches("GL_");
+    let mut count = 0;
+    call!(GetIntegerv(NUM_EXTENSIONS, &mut count));
+    (0..count as u32).any(|i| {
+        let extension = call!(GetStringi(EXTENSIONS, i));
+        if extension.is_null() {
+            return false;
+        }
+        let extension = unsafe { std::ffi::CStr::from_ptr(extension as *const i8) };
+        extension
+            .to_bytes()
+            .strip_prefix(b"GL_")
+            .unwrap_or(extension.to_bytes())
+            == name.as_bytes()
+    })
+}
+
+/// Reads the bottom left `width` by `height` pixels of the default
+/// framebuffer's back buffer, e.g. the frame before it's swapped to the
+/// screen. The rows are flipped so the image starts from the top, and the
+/// alpha channel is made opaque, since the window doesn't blend with what's
+/// behind it.
+pub fn read_default_framebuffer(width: u32, height: u32) -> image::RgbaImage {
+    let mut pixels = vec![0u8; width as usize * height as usize * 4];
+    call!(BindFramebuffer(READ_FRAMEBUFFER, 0));
+    call!(PixelStorei(PACK_ALIGNMENT, 1));
+    call!(ReadPixels(
+        0,
+        0,
+        width as i32,
+        height as i32,
+        RGBA,
+        UNSIGNED_BYTE,
+        pixels.as_mut_ptr() as *mut std::ffi::c_void,
+    ));
+    let mut image = image::RgbaImage::from_raw(width, height, pixels).unwrap();
+    image::imageops::flip_vertical_in_place(&mut image);
+    for pixel in image.pixels_mut() {
+        pixel[3] = 0xFF;
+    }
+    image
+}
+
+/// Installs a KHR_debug message callback which prints the driver's warnings
+/// and errors, and makes [label] name objects for graphics debuggers. Returns
+/// false if the context doesn't support KHR_debug, in which case nothing is
+/// enabled. For the most detailed messages, the context should be created
+/// with the debug flag.
+pub fn enable_debug_layer() -> bool {
+    if !has_extension("GL_KHR_debug") {
+        return false;
+    }
+    call!(Enable(DEBUG_OUTPUT_KHR));
+    // Makes the callback run during the call causing the message, so the
+    // backtrace of a breakpoint in the callback points at the culprit.
+    call!(Enable(DEBUG_OUTPUT_SYNCHRONOUS_KHR));
+    call!(DebugMessageCallbackKHR(
+        Some(debug_message_callback),
+        std::ptr::null()
+    ));
+    let mut max_label_length = 0;
+    call!(GetIntegerv(MAX_LABEL_LENGTH_KHR, &mut max_label_length));
+    LABEL_LENGTH_LIMIT.store(max_label_length.max(1) as usize, Ordering::Relaxed);
+    true
+}
+
+/// Names the object for debug messages and graphics debuggers like
+/// RenderDoc, if the debug layer is enabled. `identifier` is the kind of the
+/// object, e.g. [BUFFER_KHR] or [TEXTURE]. The object must have been bound at
+/// least once before it can be labeled. Labels longer than the context
+/// allows are cut short.
+pub fn label(identifier: types::GLenum, name: types::GLuint, label: &str) {
+    let max_label_length = LABEL_LENGTH_LIMIT.load(Ordering::Relaxed);
+    if max_label_length == 0 {
+        return;
+    }
+    // The maximum includes the null terminator.
+    let mut length = label.len().min(max_label_length - 1);
+    while !label.is_char_boundary(length) {
+        length -= 1;
+    }
+    let label = &label[..length];
+    call!(ObjectLabelKHR(
+        identifier,
+        name,
+        label.len() as i32,
+        label.as_ptr() as *const i8
+    ));
+}
+
+extern "system" fn debug_message_callback(
+    _source: types::GLenum,
+    type_: types::GLenum,
+    _id: types::GLuint,
+    severity: types::GLenum,
+    length: types::GLsizei,
+    message: *const types::GLchar,
+    _user_param: *mut std::ffi::c_void,
+) {
+    let severity = match severity {
+        DEBUG_SEVERITY_HIGH_KHR => "high",
+        DEBUG_SEVERITY_MEDIUM_KHR => "medium",
+        DEBUG_SEVERITY_LOW_KHR => "low",
+        // Notifications are very chatty on some drivers, e.g. about where
+        // buffers are placed in memory.
+        _ => return,
+    };
+    let kind = match type_ {
+        DEBUG_TYPE_ERROR_KHR => "error",
+        DEBUG_TYPE_DEPRECATED_BEHAVIOR_KHR => "deprecated behavior",
+        DEBUG_TYPE_UNDEFINED_BEHAVIOR_KHR => "undefined behavior",
+        DEBUG_TYPE_PORTABILITY_KHR => "portability issue",
+        DEBUG_TYPE_PERFORMANCE_KHR => "performance issue",
+        _ => "message",
+    };
+    let message = if length < 0 {
+        unsafe { std::ffi::CStr::from_ptr(message) }.to_bytes()
+    } else {
+        unsafe { std::slice::from_raw_parts(message as *const u8, length as usize) }
+    };
+    let message = String::from_utf8_lossy(message);
+    eprintln!("OpenGL {kind} ({severity} severity): {message}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{enable_debug_layer, label, TEXTURE};
+    use crate::renderer::fake_gl;
+
+    #[test]
+    fn long_labels_are_cut_short_at_a_char_boundary() {
+        fake_gl::install();
+        // The labels can't be longer than 7 bytes, since the maximum includes
+        // the null terminator. The debug layer stays enabled for the rest of
+        // the tests, which is fine, as the fake accepts their labels too.
+        fake_gl::set_max_label_length(8);
+        assert!(enable_debug_layer());
+        label(TEXTURE, 1, "short");
+        label(TEXTURE, 2, "exactly");
+        label(TEXTURE, 3, "labelxü and more");
+        label(TEXTURE, 4, "much too long");
+        assert_eq!(Some("short"), fake_gl::object_label(TEXTURE, 1).as_deref());
+        assert_eq!(
+            Some("exactly"),
+            fake_gl::object_label(TEXTURE, 2).as_deref()
+        );
+        assert_eq!(Some("labelx"), fake_gl::object_label(TEXTURE, 3).as_deref());
+        assert_eq!(
+            Some("much to"),
+            fake_gl::object_label(TEXTURE, 4).as_deref()
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use crate::renderer::gltf::Gltf;
+use crate::renderer::gltf::{Gltf, MAX_MORPH_TARGETS};
 use glam::{Mat4, Quat, Vec3};
 
 pub struct Animation {
@@ -20,6 +20,9 @@ pub enum Keyframes {
     Translation(Vec<Vec3>),
     Rotation(Vec<Quat>),
     Scale(Vec<Vec3>),
+    /// The morph target weights of each keyframe. Weights past the mesh's
+    /// amount of morph targets are zero.
+    Weights(Vec<[f32; MAX_MORPH_TARGETS]>),
 }
 
 #[derive(Clone, Copy)]
@@ -32,15 +35,17 @@ pub enum Interpolation {
 pub struct NodeTransform<'a> {
     pub name: &'a str,
     pub transform: Mat4,
+    pub morph_weights: [f32; MAX_MORPH_TARGETS],
 }
 
 impl Gltf {
-    pub fn get_node_transforms(&self) -> Vec<NodeTransform> {
+    pub fn get_node_transforms(&self) -> Vec<NodeTransform<'_>> {
         self.nodes
             .iter()
             .map(|node| NodeTransform {
                 name: &node.name,
                 transform: node.transform,
+                morph_weights: node.morph_weights,
             })
             .collect::<Vec<_>>()
     }
@@ -80,6 +85,14 @@ impl Animation {
                             time,
                         )
                     }
+                    Keyframes::Weights(keyframes) => {
+                        transforms[i].morph_weights = sample_weights_keyframes(
+                            &animation.timestamps,
+                            keyframes,
+                            animation.interpolation,
+                            time,
+                        )
+                    }
                 }
             }
             transforms[i].transform = Mat4::from_scale_rotation_translation(s, r, t);
@@ -165,3 +178,44 @@ fn sample_quat_keyframes(
         }
     }
 }
+
+fn sample_weights_keyframes(
+    timestamps: &[f32],
+    keyframes: &[[f32; MAX_MORPH_TARGETS]],
+    interpolation: Interpolation,
+    t: f32,
+) -> [f32; MAX_MORPH_TARGETS] {
+    let mut i = timestamps.len();
+    let t = t % timestamps[i - 1];
+    for (i_, timestamps) in timestamps.windows(2).enumerate() {
+        let start = timestamps[0];
+        let end = timestamps[1];
+        if start <= t && t < end {
+            i = i_;
+            break;
+        }
+    }
+    assert!(i < timestamps.len());
+    let t_k = timestamps[i];
+    let t_d = timestamps[i + 1] - t_k;
+    let t = (t - t_k) / t_d;
+    let mut weights = [0.0; MAX_MORPH_TARGETS];
+    for (j, weight) in weights.iter_mut().enumerate() {
+        let v = |k: usize| keyframes[k][j];
+        *weight = match interpolation {
+            Interpolation::Step => v(i),
+            Interpolation::Linear => (1.0 - t) * v(i) + t * v(i + 1),
+            Interpolation::CubicSpline => {
+                let a_k1 = v((i + 1) * 3);
+                let v_k = v(i * 3 + 1);
+                let v_k1 = v((i + 1) * 3 + 1);
+                let b_k = v(i * 3 + 2);
+                (2.0 * t.powi(3) - 3.0 * t.powi(2) + 1.0) * v_k
+                    + t_d * (t.powi(3) - 2.0 * t.powi(2) + t) * b_k
+                    + (-2.0 * t.powi(3) + 3.0 * t.powi(2)) * v_k1
+                    + t_d * (t.powi(3) - t.powi(2)) * a_k1
+            }
+        };
+    }
+    weights
+}
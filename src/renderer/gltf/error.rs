@@ -0,0 +1,73 @@
+use crate::renderer::gl::GlError;
+use std::error::Error;
+use std::fmt::Display;
+
+/// The ways loading a glTF can fail. Errors pointing at a specific part of the
+/// glTF JSON carry a `path` to it, e.g.
+/// `materials[3].pbrMetallicRoughness.baseColorTexture`.
+#[derive(Debug)]
+pub enum GltfError {
+    /// The glTF JSON could not be parsed at all.
+    Json(String),
+    /// The GLB container is malformed.
+    Glb(String),
+    /// A required property is missing.
+    Missing { path: String },
+    /// A property is not of the expected JSON type or shape.
+    Invalid {
+        path: String,
+        expected: &'static str,
+    },
+    /// An index refers to an element which does not exist.
+    OutOfBounds { path: String, index: usize },
+    /// A buffer or image uri could not be found in the resources passed to
+    /// the loader.
+    MissingResource { path: String, uri: String },
+    /// The glTF requires extensions which this loader does not implement.
+    UnsupportedExtensions(Vec<String>),
+    /// The glTF is valid, but uses a feature this loader does not implement.
+    Unsupported { path: String, reason: String },
+    /// An image could not be decoded.
+    Image {
+        path: String,
+        error: image::ImageError,
+    },
+    /// Uploading the data of the buffer or image failed, e.g. because the GPU
+    /// ran out of memory.
+    Gl { path: String, error: GlError },
+}
+
+impl Display for GltfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GltfError::Json(err) => write!(f, "invalid glTF json: {err}"),
+            GltfError::Glb(err) => write!(f, "invalid glb container: {err}"),
+            GltfError::Missing { path } => write!(f, "{path} is missing"),
+            GltfError::Invalid { path, expected } => {
+                write!(f, "{path} is invalid, expected {expected}")
+            }
+            GltfError::OutOfBounds { path, index } => {
+                write!(f, "{path} refers to {index}, which does not exist")
+            }
+            GltfError::MissingResource { path, uri } => {
+                write!(f, "{path} refers to \"{uri}\", which was not provided")
+            }
+            GltfError::UnsupportedExtensions(exts) => {
+                write!(f, "glTF requires unsupported extensions: {exts:?}")
+            }
+            GltfError::Unsupported { path, reason } => write!(f, "{path}: {reason}"),
+            GltfError::Image { path, error } => write!(f, "{path} could not be decoded: {error}"),
+            GltfError::Gl { path, error } => write!(f, "{path} could not be uploaded: {error}"),
+        }
+    }
+}
+
+impl Error for GltfError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GltfError::Image { error, .. } => Some(error),
+            GltfError::Gl { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
@@ -1,10 +1,15 @@
 #version 300 es
 precision highp float;
 precision highp int;
+precision highp sampler2DArrayShadow;
 
 #define PI 3.14159265
 #define MAX_LIGHTS 32
-#define VIEW_VECTOR vec3(0.0, 0.0, 1.0)
+#define MAX_SHADOW_MAPS 8
+#define MAX_SHADOW_CASCADES 4
+#define LIGHT_DIRECTIONAL 1
+#define LIGHT_POINT 2
+#define LIGHT_SPOT 3
 
 out vec4 FRAG_COLOR;
 
@@ -13,6 +18,9 @@ in vec3 vertex_color;
 in vec3 vertex_normal;
 in vec4 vertex_tangent;
 in vec2 tex_coords;
+flat in vec4 instance_tint;
+flat in vec3 instance_custom;
+flat in uint instance_object_id;
 
 uniform mat4 view_from_world;
 uniform sampler2D base_color_tex;
@@ -26,104 +34,247 @@ layout(std140) uniform Material {
   // strength
   vec4 material_params;
   vec4 emissive_factor;
+  // x: alpha cutoff, y: 0.0 opaque, 1.0 mask, 2.0 blend
+  vec4 alpha_params;
 };
 layout(std140) uniform Lights {
   // w: 0.0 as the null terminator, 1.0: directional, 2.0: point, 3.0: spot,
   // xyz: rgb
   vec4 light_color_and_kind[MAX_LIGHTS];
-  // x: intensity, y: angle scale, z: angle offset
+  // x: intensity, y: angle scale, z: angle offset, w: range, 0.0 if infinite
   vec4 light_intensity_params[MAX_LIGHTS];
   vec4 light_position[MAX_LIGHTS];
   vec4 light_direction[MAX_LIGHTS];
 };
+uniform sampler2DArrayShadow shadow_maps_tex;
+layout(std140) uniform Shadows {
+  // x: the layer of the light's first shadow map, or -1.0 if the light doesn't
+  // cast shadows, y: amount of shadow maps (cascades), z: depth bias
+  vec4 light_shadow_params[MAX_LIGHTS];
+  // The view-space depths where each cascade ends
+  vec4 cascade_depths;
+  // xyz: the camera's forward vector in view space, w: PCF kernel radius
+  vec4 view_forward_and_filter_radius;
+  mat4 shadow_from_view[MAX_SHADOW_MAPS];
+};
+uniform sampler2D environment_tex;
+layout(std140) uniform Environment {
+  // The diffuse irradiance as spherical harmonics, convolved with the cosine
+  // lobe
+  vec4 irradiance_sh[9];
+  // x: the last mip level, prefiltered for roughness 1.0, y: intensity
+  vec4 environment_params;
+};
+
+// The BRDF and light evaluation follow the glTF spec's appendix B and the
+// KHR_lights_punctual spec, like the Khronos glTF sample viewer.
+
+vec3 fresnel_schlick(vec3 f0, float v_dot_h) {
+  return f0 + (1.0 - f0) * pow(clamp(1.0 - v_dot_h, 0.0, 1.0), 5.0);
+}
+
+float visibility_ggx(float n_dot_l, float n_dot_v, float alpha_sq) {
+  float ggx_v = n_dot_l * sqrt(n_dot_v * n_dot_v * (1.0 - alpha_sq) + alpha_sq);
+  float ggx_l = n_dot_v * sqrt(n_dot_l * n_dot_l * (1.0 - alpha_sq) + alpha_sq);
+  float ggx = ggx_v + ggx_l;
+  return ggx > 0.0 ? 0.5 / ggx : 0.0;
+}
 
-vec3 aces_filmic(vec3 x) {
-  float a = 2.51;
-  float b = 0.03;
-  float c = 2.43;
-  float d = 0.59;
-  float e = 0.14;
-  return clamp(x * (a * x + b) / (x * (c * x + d) + e), vec3(0), vec3(1));
+float distribution_ggx(float n_dot_h, float alpha_sq) {
+  float f = n_dot_h * n_dot_h * (alpha_sq - 1.0) + 1.0;
+  return alpha_sq / (PI * f * f);
 }
 
-vec3 diffuse_brdf(vec3 color) { return color / PI; }
+// The environment map is equirectangular, with u = 0.5 towards +X and v = 0.0
+// towards +Y. The same mapping is used in environment.rs.
+vec2 get_environment_uv(vec3 dir) {
+  return vec2(atan(dir.z, dir.x) / (2.0 * PI) + 0.5,
+              acos(clamp(dir.y, -1.0, 1.0)) / PI);
+}
 
-// There's no basis or source for any of this, except the idea of dotting the
-// reflected direction with the view direction. I just want some speculars.
-float specular(vec3 reflected_dir, vec3 view_dir, float shininess) {
-  return pow(max(0.0, dot(reflected_dir, view_dir)), shininess * 30.0) *
-         pow(shininess, 2.0);
+vec3 get_environment_irradiance(vec3 n) {
+  return irradiance_sh[0].rgb * 0.282095 +
+         irradiance_sh[1].rgb * 0.488603 * n.y +
+         irradiance_sh[2].rgb * 0.488603 * n.z +
+         irradiance_sh[3].rgb * 0.488603 * n.x +
+         irradiance_sh[4].rgb * 1.092548 * n.x * n.y +
+         irradiance_sh[5].rgb * 1.092548 * n.y * n.z +
+         irradiance_sh[6].rgb * 0.315392 * (3.0 * n.z * n.z - 1.0) +
+         irradiance_sh[7].rgb * 1.092548 * n.x * n.z +
+         irradiance_sh[8].rgb * 0.546274 * (n.x * n.x - n.y * n.y);
 }
 
-void get_incoming_light(inout vec3 out_diffuse, inout vec3 out_specular,
-                        int light_index, int kind, vec3 normal,
-                        float shininess) {
-  // TODO: Calculate light contribution in a physically based way. Don't have
-  // the time to do this before LD53 though.
+// An analytical approximation of the split sum's BRDF integral, from Karis'
+// "Physically Based Shading on Mobile".
+vec2 env_brdf_approx(float roughness, float n_dot_v) {
+  vec4 c0 = vec4(-1.0, -0.0275, -0.572, 0.022);
+  vec4 c1 = vec4(1.0, 0.0425, 1.04, -0.04);
+  vec4 r = roughness * c0 + c1;
+  float a004 = min(r.x * r.x, exp2(-9.28 * n_dot_v)) * r.x + r.y;
+  return vec2(-1.04, 1.04) * a004 + r.zw;
+}
 
-  // TODO: Handle spot and directional lights
+// Returns the direction towards the light and the light's intensity at the
+// fragment, or zero intensity if the fragment is outside of the light's range
+// or cone.
+vec3 get_incoming_light(out vec3 light_dir, int light_index, int kind) {
+  vec4 params = light_intensity_params[light_index];
+  vec3 radiance = light_color_and_kind[light_index].rgb * params.x;
+  vec3 direction =
+      normalize((view_from_world * light_direction[light_index]).xyz);
+  if (kind == LIGHT_DIRECTIONAL) {
+    light_dir = -direction;
+    return radiance;
+  }
 
-  vec3 color = light_color_and_kind[light_index].rgb;
   vec3 to_light =
       (view_from_world * light_position[light_index]).xyz - view_pos;
-  vec3 light_dir = normalize(to_light);
-  float distance_squared = dot(to_light, to_light);
-
-  float light_power = light_intensity_params[light_index].x / 1500.0;
-  float k_diffuse = max(0.0, dot(normal, light_dir));
-  float k_specular = 0.0;
-  if (k_diffuse > 0.0) {
-    vec3 reflected = reflect(light_dir, normal);
-    k_specular = specular(reflected, VIEW_VECTOR, shininess);
+  float distance_squared = max(dot(to_light, to_light), 0.0001);
+  light_dir = to_light * inversesqrt(distance_squared);
+  float attenuation = 1.0 / distance_squared;
+  float range = params.w;
+  if (range > 0.0) {
+    float distance_over_range = sqrt(distance_squared) / range;
+    attenuation *=
+        clamp(1.0 - pow(distance_over_range, 4.0), 0.0, 1.0);
+  }
+  if (kind == LIGHT_SPOT) {
+    float cd = dot(direction, -light_dir);
+    float angular_attenuation = clamp(cd * params.y + params.z, 0.0, 1.0);
+    attenuation *= angular_attenuation * angular_attenuation;
+  }
+  return radiance * attenuation;
+}
+
+// Returns how much of the light reaches the fragment, between 0.0 (completely
+// in shadow) and 1.0 (not in shadow).
+float get_shadow(int light_index) {
+  vec4 params = light_shadow_params[light_index];
+  if (params.x < 0.0) {
+    return 1.0;
+  }
+  int layer = int(params.x);
+  int cascades = int(params.y);
+  if (cascades > 1) {
+    float depth = dot(view_pos, view_forward_and_filter_radius.xyz);
+    if (depth > cascade_depths[cascades - 1]) {
+      return 1.0;
+    }
+    for (int i = 0; i < cascades - 1; i++) {
+      if (depth > cascade_depths[i]) {
+        layer++;
+      }
+    }
   }
 
-  out_diffuse += k_diffuse * color * light_power / distance_squared;
-  out_specular += k_specular * color * light_power / distance_squared;
+  vec4 shadow_pos = shadow_from_view[layer] * vec4(view_pos, 1.0);
+  vec3 coords = shadow_pos.xyz / shadow_pos.w * 0.5 + 0.5;
+  if (any(lessThan(coords, vec3(0.0))) || any(greaterThan(coords, vec3(1.0)))) {
+    return 1.0;
+  }
+  float reference_depth = coords.z - params.z;
+  int radius = int(view_forward_and_filter_radius.w);
+  vec2 texel_size = 1.0 / vec2(textureSize(shadow_maps_tex, 0).xy);
+  float lit = 0.0;
+  for (int y = -radius; y <= radius; y++) {
+    for (int x = -radius; x <= radius; x++) {
+      vec2 uv = coords.xy + vec2(x, y) * texel_size;
+      lit += texture(shadow_maps_tex, vec4(uv, float(layer), reference_depth));
+    }
+  }
+  float samples = float((radius * 2 + 1) * (radius * 2 + 1));
+  return lit / samples;
 }
 
 void main() {
   vec4 texel_base_color = texture(base_color_tex, tex_coords);
+  // Roughness is in the green channel and metallic in the blue channel.
   vec2 texel_metallic_roughness =
-      texture(metallic_roughness_tex, tex_coords).rg;
+      texture(metallic_roughness_tex, tex_coords).bg;
   vec3 texel_normal = texture(normal_tex, tex_coords).rgb * 2.0 - 1.0;
   float texel_occlusion = texture(occlusion_tex, tex_coords).r;
   vec3 texel_emissive = texture(emissive_tex, tex_coords).rgb;
 
   vec3 pixel_base_color =
-      texel_base_color.rgb * vertex_color * base_color_factor.rgb;
+      texel_base_color.rgb * vertex_color * base_color_factor.rgb *
+      instance_tint.rgb;
+  float pixel_alpha =
+      texel_base_color.a * base_color_factor.a * instance_tint.a;
+  int alpha_mode = int(alpha_params.y);
+  if (alpha_mode == 1 && pixel_alpha < alpha_params.x) {
+    discard;
+  } else if (alpha_mode != 2) {
+    pixel_alpha = 1.0;
+  }
   float pixel_metallic = texel_metallic_roughness.x * material_params.x;
-  float pixel_roughness = texel_metallic_roughness.y * material_params.y;
+  // Clamped to avoid the GGX distribution being undefined at 0 roughness.
+  float pixel_roughness =
+      clamp(texel_metallic_roughness.y * material_params.y, 0.03, 1.0);
 
-  vec3 tangent_space_normal =
-      normalize(vec3(texel_normal.xy * material_params.z, texel_normal.z));
-  vec3 vertex_bitangent =
-      normalize(cross(vertex_normal, vertex_tangent.xyz) * vertex_tangent.w);
-  vec3 pixel_normal =
-      normalize(mat3(vertex_tangent.xyz, vertex_bitangent, vertex_normal) *
-                tangent_space_normal);
+  vec3 pixel_normal = normalize(vertex_normal);
+  // The tangent is zero for primitives without tangents, which aren't normal
+  // mapped.
+  if (dot(vertex_tangent.xyz, vertex_tangent.xyz) > 0.0) {
+    vec3 tangent_space_normal =
+        normalize(vec3(texel_normal.xy * material_params.z, texel_normal.z));
+    vec3 vertex_bitangent =
+        normalize(cross(vertex_normal, vertex_tangent.xyz) * vertex_tangent.w);
+    pixel_normal =
+        normalize(mat3(vertex_tangent.xyz, vertex_bitangent, vertex_normal) *
+                  tangent_space_normal);
+  }
+  // Only double sided materials' back faces get this far, the rest are culled.
+  if (!gl_FrontFacing) {
+    pixel_normal = -pixel_normal;
+  }
 
   float pixel_occlusion = 1.0 + material_params.w * (texel_occlusion - 1.0);
   vec3 light_emitted = texel_emissive.rgb * emissive_factor.rgb;
 
-  float ambient_brightness = 0.2 * pixel_occlusion;
-  // Just to give a little shape to everything
-  float ambient_fudge = -max(0.0, dot(-VIEW_VECTOR, pixel_normal)) * 0.1;
-  vec3 light_diffuse = vec3(ambient_brightness + ambient_fudge);
-  vec3 light_specular = vec3(0.0);
+  vec3 c_diff = mix(pixel_base_color, vec3(0.0), pixel_metallic);
+  vec3 f0 = mix(vec3(0.04), pixel_base_color, pixel_metallic);
+  float alpha = pixel_roughness * pixel_roughness;
+  float alpha_sq = alpha * alpha;
+  vec3 view_dir = normalize(-view_pos);
+  float n_dot_v = clamp(dot(pixel_normal, view_dir), 0.0, 1.0);
+
+  mat3 world_from_view = transpose(mat3(view_from_world));
+  vec3 world_normal = world_from_view * pixel_normal;
+  vec3 world_reflection = world_from_view * reflect(-view_dir, pixel_normal);
+  vec3 irradiance = get_environment_irradiance(world_normal);
+  float mip = pixel_roughness * environment_params.x;
+  vec3 prefiltered_radiance =
+      textureLod(environment_tex, get_environment_uv(world_reflection), mip)
+          .rgb;
+  vec2 env_brdf = env_brdf_approx(pixel_roughness, n_dot_v);
+  vec3 environment_light =
+      c_diff * irradiance / PI +
+      prefiltered_radiance * (f0 * env_brdf.x + env_brdf.y);
+  vec3 light_outgoing_to_camera =
+      light_emitted +
+      environment_light * environment_params.y * pixel_occlusion;
   for (int i = 0; i < MAX_LIGHTS; i++) {
     int kind = int(light_color_and_kind[i].w);
     if (kind == 0) {
       break;
     }
-    get_incoming_light(light_diffuse, light_specular, i, kind, pixel_normal,
-                       (1.0 - pixel_roughness));
+    vec3 light_dir;
+    vec3 incoming_light = get_incoming_light(light_dir, i, kind);
+    float n_dot_l = clamp(dot(pixel_normal, light_dir), 0.0, 1.0);
+    if (n_dot_l <= 0.0) {
+      continue;
+    }
+    incoming_light *= get_shadow(i);
+    vec3 half_dir = normalize(light_dir + view_dir);
+    float n_dot_h = clamp(dot(pixel_normal, half_dir), 0.0, 1.0);
+    float v_dot_h = clamp(dot(view_dir, half_dir), 0.0, 1.0);
+    vec3 fresnel = fresnel_schlick(f0, v_dot_h);
+    vec3 diffuse = (1.0 - fresnel) * c_diff / PI;
+    vec3 specular = fresnel * visibility_ggx(n_dot_l, n_dot_v, alpha_sq) *
+                    distribution_ggx(n_dot_h, alpha_sq);
+    light_outgoing_to_camera += incoming_light * n_dot_l * (diffuse + specular);
   }
 
-  vec3 light_outgoing_to_camera =
-      light_emitted + pixel_base_color * light_diffuse + light_specular;
-  vec3 output_linear_color = aces_filmic(light_outgoing_to_camera);
-
-  // The framebuffer is not SRGB, so we transform the linear color to
-  // close-enough-to-srgb.
-  FRAG_COLOR = vec4(pow(output_linear_color, vec3(1.0 / 2.2)), 1.0);
+  // Tonemapping and the sRGB encoding are done in post_process_fragment.glsl.
+  FRAG_COLOR = vec4(light_outgoing_to_camera, pixel_alpha);
 }
@@ -1,4 +1,8 @@
 #version 300 es
+#define MAX_JOINTS 128
+#define MAX_MORPH_TARGETS 8
+precision highp sampler2DArray;
+
 layout(location = 0) in vec3 POSITION;
 layout(location = 1) in vec3 NORMAL;
 layout(location = 2) in vec4 TANGENT;
@@ -6,28 +10,87 @@ layout(location = 3) in vec2 TEXCOORD_0;
 layout(location = 4) in vec2 TEXCOORD_1;
 layout(location = 5) in vec3 COLOR_0;
 layout(location = 6) in mat4 MODEL_TRANSFORM;
+layout(location = 10) in uvec4 JOINTS_0;
+layout(location = 11) in vec4 WEIGHTS_0;
+layout(location = 12) in vec4 MORPH_WEIGHTS_0_3;
+layout(location = 13) in vec4 MORPH_WEIGHTS_4_7;
+layout(location = 14) in vec4 INSTANCE_TINT;
+// xyz: the custom floats' bits, w: the object id
+layout(location = 15) in uvec4 INSTANCE_CUSTOM;
 
 out vec3 view_pos;
 out vec3 vertex_color;
 out vec3 vertex_normal;
 out vec4 vertex_tangent;
 out vec2 tex_coords;
+flat out vec4 instance_tint;
+flat out vec3 instance_custom;
+flat out uint instance_object_id;
+flat out uint instance_index;
 
 uniform mat4 proj_from_view;
 uniform mat4 view_from_world;
+layout(std140) uniform Skin {
+  // x: the amount of joints, 0 if the mesh is not skinned
+  uvec4 skin_params;
+  mat4 joint_matrices[MAX_JOINTS];
+};
+// Each morph target is three layers: POSITION, NORMAL and TANGENT deltas. The
+// deltas of each vertex are at texel (gl_VertexID % width, gl_VertexID / width).
+uniform sampler2DArray morph_targets_tex;
 
 void main() {
+  vec3 position = POSITION;
+  vec3 normal = NORMAL;
+  vec3 tangent = TANGENT.xyz;
+  ivec3 morph_targets_size = textureSize(morph_targets_tex, 0);
+  int morph_target_count = min(morph_targets_size.z / 3, MAX_MORPH_TARGETS);
+  if (morph_target_count > 0) {
+    ivec2 texel = ivec2(gl_VertexID % morph_targets_size.x,
+                        gl_VertexID / morph_targets_size.x);
+    float weights[MAX_MORPH_TARGETS] = float[MAX_MORPH_TARGETS](
+        MORPH_WEIGHTS_0_3.x, MORPH_WEIGHTS_0_3.y, MORPH_WEIGHTS_0_3.z,
+        MORPH_WEIGHTS_0_3.w, MORPH_WEIGHTS_4_7.x, MORPH_WEIGHTS_4_7.y,
+        MORPH_WEIGHTS_4_7.z, MORPH_WEIGHTS_4_7.w);
+    for (int i = 0; i < morph_target_count; i++) {
+      position +=
+          weights[i] * texelFetch(morph_targets_tex, ivec3(texel, i * 3), 0).xyz;
+      normal += weights[i] *
+                texelFetch(morph_targets_tex, ivec3(texel, i * 3 + 1), 0).xyz;
+      tangent += weights[i] *
+                 texelFetch(morph_targets_tex, ivec3(texel, i * 3 + 2), 0).xyz;
+    }
+  }
+
+  mat4 model_transform = MODEL_TRANSFORM;
+  if (skin_params.x > 0u) {
+    mat4 skin_transform = WEIGHTS_0.x * joint_matrices[JOINTS_0.x] +
+                          WEIGHTS_0.y * joint_matrices[JOINTS_0.y] +
+                          WEIGHTS_0.z * joint_matrices[JOINTS_0.z] +
+                          WEIGHTS_0.w * joint_matrices[JOINTS_0.w];
+    model_transform = MODEL_TRANSFORM * skin_transform;
+  }
+
   // TODO: Move the inverse transpose of the model transfrom to the cpu
   // TODO: Move the inverse of view_from_world to the cpu
-  mat4 view_from_model = view_from_world * MODEL_TRANSFORM;
+  mat4 view_from_model = view_from_world * model_transform;
   mat3 inverse_transpose_model_transfrom =
       transpose(inverse(mat3(view_from_model)));
-  vec4 view_pos_full = view_from_model * vec4(POSITION, 1.0);
+  vec4 view_pos_full = view_from_model * vec4(position, 1.0);
   view_pos = view_pos_full.xyz;
   vertex_color = COLOR_0;
-  vertex_normal = normalize(inverse_transpose_model_transfrom * NORMAL);
-  vertex_tangent =
-      vec4(normalize(mat3(view_from_model) * TANGENT.xyz), TANGENT.w);
+  vertex_normal = normalize(inverse_transpose_model_transfrom * normal);
+  // Primitives without tangents get the attribute's default of zero, which
+  // can't be normalized, and is passed on as is to skip normal mapping.
+  vec3 view_tangent = mat3(view_from_model) * tangent;
+  if (dot(view_tangent, view_tangent) > 0.0) {
+    view_tangent = normalize(view_tangent);
+  }
+  vertex_tangent = vec4(view_tangent, TANGENT.w);
   tex_coords = TEXCOORD_0;
+  instance_tint = INSTANCE_TINT;
+  instance_custom = uintBitsToFloat(INSTANCE_CUSTOM.xyz);
+  instance_object_id = INSTANCE_CUSTOM.w;
+  instance_index = uint(gl_InstanceID);
   gl_Position = proj_from_view * view_pos_full;
 }
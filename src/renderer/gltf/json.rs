@@ -0,0 +1,166 @@
+use crate::renderer::gl;
+use crate::renderer::gltf::GltfError;
+use glam::{Quat, Vec3};
+use std::collections::HashMap;
+use tinyjson::JsonValue;
+
+/// A glTF JSON value along with its path from the root of the document, so
+/// that errors can point at the exact property that was wrong.
+#[derive(Clone)]
+pub struct Json<'a> {
+    pub value: &'a JsonValue,
+    pub path: String,
+}
+
+impl<'a> Json<'a> {
+    pub fn root(value: &'a JsonValue) -> Json<'a> {
+        Json {
+            value,
+            path: String::new(),
+        }
+    }
+
+    fn object(&self) -> Result<&'a HashMap<String, JsonValue>, GltfError> {
+        self.value.get().ok_or_else(|| self.invalid("an object"))
+    }
+
+    fn child_path(&self, key: &str) -> String {
+        if self.path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{key}", self.path)
+        }
+    }
+
+    /// Returns the property `key` of this object, or an error if it's missing.
+    pub fn get(&self, key: &str) -> Result<Json<'a>, GltfError> {
+        self.get_opt(key)?.ok_or_else(|| GltfError::Missing {
+            path: self.child_path(key),
+        })
+    }
+
+    /// Returns the property `key` of this object, if it exists.
+    pub fn get_opt(&self, key: &str) -> Result<Option<Json<'a>>, GltfError> {
+        Ok(self.object()?.get(key).map(|value| Json {
+            value,
+            path: self.child_path(key),
+        }))
+    }
+
+    /// Returns the elements of the array property `key` of this object, or an
+    /// empty list if the property doesn't exist.
+    pub fn array_opt(&self, key: &str) -> Result<Vec<Json<'a>>, GltfError> {
+        match self.get_opt(key)? {
+            Some(array) => array.as_array(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Returns the key-value pairs of this object, sorted by key so that the
+    /// iteration order doesn't depend on the hashmap.
+    pub fn entries(&self) -> Result<Vec<(&'a str, Json<'a>)>, GltfError> {
+        let mut entries = self
+            .object()?
+            .iter()
+            .map(|(key, value)| {
+                let path = self.child_path(key);
+                (key.as_str(), Json { value, path })
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|(key, _)| *key);
+        Ok(entries)
+    }
+
+    pub fn as_array(&self) -> Result<Vec<Json<'a>>, GltfError> {
+        let array: &Vec<JsonValue> = self.value.get().ok_or_else(|| self.invalid("an array"))?;
+        Ok(array
+            .iter()
+            .enumerate()
+            .map(|(i, value)| Json {
+                value,
+                path: format!("{}[{i}]", self.path),
+            })
+            .collect())
+    }
+
+    pub fn as_usize(&self) -> Result<usize, GltfError> {
+        let number: &f64 = self.value.get().ok_or_else(|| self.invalid("a number"))?;
+        // usize::MAX rounds up to the next power of two as an f64, so it's
+        // the first number which doesn't fit.
+        if *number < 0.0 || number.fract() != 0.0 || *number >= usize::MAX as f64 {
+            return Err(self.invalid("a non-negative integer"));
+        }
+        Ok(*number as usize)
+    }
+
+    pub fn as_f32(&self) -> Result<f32, GltfError> {
+        let number: &f64 = self.value.get().ok_or_else(|| self.invalid("a number"))?;
+        Ok(*number as f32)
+    }
+
+    pub fn as_bool(&self) -> Result<bool, GltfError> {
+        let boolean: &bool = self.value.get().ok_or_else(|| self.invalid("a boolean"))?;
+        Ok(*boolean)
+    }
+
+    pub fn as_str(&self) -> Result<&'a str, GltfError> {
+        let string: &String = self.value.get().ok_or_else(|| self.invalid("a string"))?;
+        Ok(string)
+    }
+
+    pub fn as_floats<const N: usize>(&self) -> Result<[f32; N], GltfError> {
+        let values = self.as_array()?;
+        if values.len() != N {
+            return Err(self.invalid(match N {
+                3 => "an array of 3 numbers",
+                4 => "an array of 4 numbers",
+                16 => "an array of 16 numbers",
+                _ => "an array of numbers",
+            }));
+        }
+        let mut floats = [0.0; N];
+        for (float, value) in floats.iter_mut().zip(values) {
+            *float = value.as_f32()?;
+        }
+        Ok(floats)
+    }
+
+    pub fn as_vec3(&self) -> Result<Vec3, GltfError> {
+        Ok(Vec3::from_array(self.as_floats()?))
+    }
+
+    pub fn as_quat(&self) -> Result<Quat, GltfError> {
+        Ok(Quat::from_array(self.as_floats()?))
+    }
+
+    /// Treats this value as an index into `array`, and returns the element it
+    /// refers to.
+    pub fn lookup<'b>(&self, array: &'b [Json<'a>]) -> Result<&'b Json<'a>, GltfError> {
+        let index = self.as_usize()?;
+        array.get(index).ok_or_else(|| GltfError::OutOfBounds {
+            path: self.path.clone(),
+            index,
+        })
+    }
+
+    pub fn invalid(&self, expected: &'static str) -> GltfError {
+        GltfError::Invalid {
+            path: self.path.clone(),
+            expected,
+        }
+    }
+
+    pub fn gl_error(&self, error: gl::GlError) -> GltfError {
+        GltfError::Gl {
+            path: self.path.clone(),
+            error,
+        }
+    }
+
+    pub fn unsupported<S: Into<String>>(&self, reason: S) -> GltfError {
+        GltfError::Unsupported {
+            path: self.path.clone(),
+            reason: reason.into(),
+        }
+    }
+}
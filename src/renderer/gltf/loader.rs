@@ -1,7 +1,11 @@
+use crate::renderer::bounds::Aabb;
 use crate::renderer::bumpalloc_buffer::BumpAllocatedBuffer;
 use crate::renderer::draw_calls::{DrawCall, Uniforms};
-use crate::renderer::gltf::MAX_LIGHTS;
-use crate::renderer::{gl, gltf, FORWARD};
+use crate::renderer::gltf::json::Json;
+use crate::renderer::gltf::{GltfError, MAX_LIGHTS, MAX_MORPH_TARGETS};
+use crate::renderer::handles::{Buffer, Sampler, Texture, VertexArray};
+use crate::renderer::uniform_buffer::UniformBufferAllocator;
+use crate::renderer::{gl, gltf, Projection};
 use bytemuck::Zeroable;
 use glam::{Mat4, Quat, Vec3, Vec4};
 use image::imageops::FilterType;
@@ -10,335 +14,921 @@ use std::collections::HashMap;
 use std::f32::consts::FRAC_PI_4;
 use std::ffi::c_void;
 use std::ptr;
+use std::sync::atomic::Ordering;
 use tinyjson::JsonValue;
 
-// TODO: load_glb
-
-#[track_caller]
-pub fn load_gltf(gltf: &str, resources: &[(&str, &[u8])]) -> gltf::Gltf {
-    let gltf: JsonValue = gltf.parse().unwrap();
-    let gltf = gltf.get::<HashMap<_, _>>().unwrap();
-
-    if let Some(required_exts) = gltf.get("extensionsRequired") {
-        let required_exts = required_exts.get::<Vec<_>>().unwrap();
-        let unsupported_exts = required_exts
-            .iter()
-            .flat_map(
-                |ext_name: &JsonValue| match ext_name.get::<String>().unwrap().as_str() {
-                    "KHR_lights_punctual" => None,
-                    ext_name => Some(ext_name),
-                },
-            )
-            .collect::<Vec<_>>();
-        if !unsupported_exts.is_empty() {
-            panic!("gltf requires unsupported extensions: {unsupported_exts:?}");
+const GLB_MAGIC: u32 = 0x46546C67; // "glTF"
+const GLB_CHUNK_JSON: u32 = 0x4E4F534A; // "JSON"
+const GLB_CHUNK_BIN: u32 = 0x004E4942; // "BIN\0"
+
+/// The width of morph target textures. Vertices past the first row wrap
+/// around to the next ones, since the height is limited too.
+const MORPH_TARGETS_TEXTURE_WIDTH: usize = 1024;
+
+/// The screen coverage (see [gltf::Node::lod_screen_coverages]) below which
+/// the second level of detail is used, for levels without explicit
+/// thresholds. The threshold is halved for each following level.
+const DEFAULT_LOD_SCREEN_COVERAGE: f32 = 0.1;
+
+/// Loads a binary glTF (.glb) file. The BIN chunk is used as the buffer
+/// without an uri (the first one, per the spec), so images and other data
+/// embedded via bufferViews load without having to pass in any resources.
+///
+/// The `name` is only used for labeling the GL objects, see
+/// [gl::enable_debug_layer].
+pub fn load_glb(name: &str, glb: &[u8]) -> Result<gltf::Gltf, GltfError> {
+    let read_u32 = |offset: usize| {
+        let bytes = glb
+            .get(offset..offset + 4)
+            .ok_or_else(|| GltfError::Glb(format!("truncated at byte {offset}")))?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+
+    let magic = read_u32(0)?;
+    if magic != GLB_MAGIC {
+        return Err(GltfError::Glb("invalid magic number".to_string()));
+    }
+    let version = read_u32(4)?;
+    if version != 2 {
+        let err = format!("container version {version} is not supported");
+        return Err(GltfError::Glb(err));
+    }
+    let length = read_u32(8)? as usize;
+    if length > glb.len() {
+        let err = format!("header claims {length} bytes, but only {} exist", glb.len());
+        return Err(GltfError::Glb(err));
+    }
+
+    let mut json = None;
+    let mut bin = None;
+    let mut chunk_offset = 12;
+    while chunk_offset < length {
+        let chunk_length = read_u32(chunk_offset)? as usize;
+        let chunk_type = read_u32(chunk_offset + 4)?;
+        // The chunk length comes straight from the file, so the end can
+        // overflow on 32-bit targets.
+        let chunk_end = (chunk_offset.checked_add(8))
+            .and_then(|chunk_start| chunk_start.checked_add(chunk_length))
+            .filter(|&chunk_end| chunk_end <= length);
+        let Some(chunk_end) = chunk_end else {
+            let err = format!("chunk at byte {chunk_offset} does not fit in the file");
+            return Err(GltfError::Glb(err));
+        };
+        let chunk_data = &glb[chunk_offset + 8..chunk_end];
+        match chunk_type {
+            GLB_CHUNK_JSON if json.is_none() => json = Some(chunk_data),
+            GLB_CHUNK_BIN if bin.is_none() => bin = Some(chunk_data),
+            _ => {} // Unknown chunks must be ignored, per the spec.
+        }
+        chunk_offset = chunk_end;
+    }
+
+    let Some(json) = json else {
+        return Err(GltfError::Glb("no JSON chunk".to_string()));
+    };
+    // The JSON chunk is padded with trailing spaces, which the parser is fine
+    // with, so the chunk can be used as-is.
+    let json = std::str::from_utf8(json)
+        .map_err(|err| GltfError::Glb(format!("JSON chunk is not utf-8: {err}")))?;
+    match bin {
+        Some(bin) => load_gltf(name, json, &[("", bin)]),
+        None => load_gltf(name, json, &[]),
+    }
+}
+
+/// Loads a glTF, with its buffers and images provided in `resources` by their
+/// uris. The `name` is only used for labeling the GL objects, see
+/// [gl::enable_debug_layer].
+pub fn load_gltf(
+    name: &str,
+    gltf: &str,
+    resources: &[(&str, &[u8])],
+) -> Result<gltf::Gltf, GltfError> {
+    let gltf: JsonValue = gltf
+        .parse()
+        .map_err(|err| GltfError::Json(format!("{err}")))?;
+    if !gltf.is_object() {
+        return Err(GltfError::Json("the root is not an object".to_string()));
+    }
+    let gltf = Json::root(&gltf);
+    let label =
+        |identifier, object, what: &str| gl::label(identifier, object, &format!("{name}: {what}"));
+
+    let mut unsupported_exts = Vec::new();
+    for ext_name in gltf.array_opt("extensionsRequired")? {
+        match ext_name.as_str()? {
+            "KHR_lights_punctual" | "MSFT_lod" => {}
+            ext_name => unsupported_exts.push(ext_name.to_string()),
         }
     }
+    if !unsupported_exts.is_empty() {
+        return Err(GltfError::UnsupportedExtensions(unsupported_exts));
+    }
 
     // TODO: Measure how much of the buffers is unused after load (i.e. used by textures and index buffers)
-    let buffers_json = gltf["buffers"].get::<Vec<_>>().unwrap();
-    let mut gl_buffers = vec![0; buffers_json.len()];
+    let buffers_json = gltf.get("buffers")?.as_array()?;
+    let mut gl_buffers = Vec::with_capacity(buffers_json.len());
     let mut buffer_slices = Vec::with_capacity(buffers_json.len());
-    gl::call!(gl::GenBuffers(
-        gl_buffers.len() as i32,
-        gl_buffers.as_mut_ptr()
-    ));
-    for (i, buffer) in buffers_json.into_iter().enumerate() {
-        let gl_buffer = gl_buffers[i];
-        let buffer: &HashMap<_, _> = buffer.get().unwrap();
-        let buffer_resource_name = if i != 0 || buffer.contains_key("uri") {
-            buffer["uri"].get::<String>().unwrap()
-        } else {
-            "" // The BIN buffer of GLBs
+    for (i, buffer) in buffers_json.iter().enumerate() {
+        let gl_buffer = Buffer::try_new().map_err(|error| buffer.gl_error(error))?;
+        let buffer_resource_name = match buffer.get_opt("uri")? {
+            Some(uri) => uri.as_str()?,
+            None if i == 0 => "", // The BIN buffer of GLBs
+            None => return Err(buffer.unsupported("buffers without an uri must be first")),
         };
         let mut buffer_data = None;
         for (resource_name, data) in resources {
             if *resource_name == buffer_resource_name {
-                buffer_data = Some(data);
+                buffer_data = Some(*data);
             }
         }
         let Some(buffer_data) = buffer_data else {
-            panic!("could not find buffer with uri \"{buffer_resource_name}\"");
+            return Err(GltfError::MissingResource {
+                path: buffer.path.clone(),
+                uri: buffer_resource_name.to_string(),
+            });
+        };
+        let byte_length_json = buffer.get("byteLength")?;
+        let byte_length = byte_length_json.as_usize()?;
+        // The BIN chunk of a GLB is padded to 4 bytes, so it can be a bit
+        // longer than the buffer it contains.
+        let valid_length = if buffer_resource_name.is_empty() {
+            byte_length <= buffer_data.len() && buffer_data.len() - byte_length < 4
+        } else {
+            byte_length == buffer_data.len()
         };
-        let byte_length = take_usize(&buffer["byteLength"]);
-        assert_eq!(byte_length, buffer_data.len());
-        gl::call!(gl::BindBuffer(gl::ARRAY_BUFFER, gl_buffer));
-        gl::call!(gl::BufferData(
+        if !valid_length {
+            return Err(byte_length_json.invalid("the length of the buffer's data"));
+        }
+        let buffer_data = &buffer_data[..byte_length];
+        gl::call!(gl::BindBuffer(gl::ARRAY_BUFFER, gl_buffer.id()));
+        gl::try_call!(gl::BufferData(
             gl::ARRAY_BUFFER,
             byte_length as isize,
             buffer_data.as_ptr() as *const c_void,
             gl::STATIC_READ,
-        ));
-        buffer_slices.push(*buffer_data);
+        ))
+        .map_err(|error| buffer.gl_error(error))?;
+        label(
+            gl::BUFFER_KHR,
+            gl_buffer.id(),
+            &format!("{} \"{buffer_resource_name}\"", buffer.path),
+        );
+        gl_buffers.push(gl_buffer);
+        buffer_slices.push(buffer_data);
     }
     gl::call!(gl::BindBuffer(gl::ARRAY_BUFFER, 0));
-    let get_buffer_slice = |buffer: usize, offset: usize, length: usize| {
-        &buffer_slices[buffer][offset..offset + length]
+
+    let buffer_views_json = gltf.array_opt("bufferViews")?;
+    // Returns the buffer index, the offset into the buffer, and the slice of the
+    // buffer's data the bufferView refers to, with the given offset and length
+    // within the view.
+    let get_buffer_view_slice = |buffer_view_json: &Json, offset: usize, length: Option<usize>| {
+        let buffer_view = buffer_view_json.lookup(&buffer_views_json)?;
+        let buffer_json = buffer_view.get("buffer")?;
+        let buffer = buffer_json.as_usize()?;
+        let data = *buffer_slices.get(buffer).ok_or(GltfError::OutOfBounds {
+            path: buffer_json.path.clone(),
+            index: buffer,
+        })?;
+        let view_offset = match buffer_view.get_opt("byteOffset")? {
+            Some(offset) => offset.as_usize()?,
+            None => 0,
+        };
+        let view_length = buffer_view.get("byteLength")?.as_usize()?;
+        let view_end = view_offset.checked_add(view_length);
+        if view_end
+            .filter(|&view_end| view_end <= data.len())
+            .is_none()
+        {
+            return Err(buffer_view.invalid("a byte range within the buffer"));
+        }
+        let length = length.unwrap_or(view_length);
+        let end = offset.checked_add(length);
+        if end.filter(|&end| end <= view_length).is_none() {
+            return Err(buffer_view_json.invalid("a bufferView large enough for the data"));
+        }
+        // Both fit in the buffer's data, so these can't overflow anymore.
+        let start = view_offset + offset;
+        Ok((buffer, start, &data[start..start + length]))
     };
 
-    let scenes_json = gltf["scenes"].get::<Vec<_>>().unwrap();
-    let mut scenes = Vec::with_capacity(scenes_json.len());
-    for scene in scenes_json {
-        let node_indices = scene["nodes"].get::<Vec<_>>().unwrap();
-        let node_indices = node_indices.iter().map(take_usize).collect::<Vec<_>>();
+    let nodes_json = gltf.array_opt("nodes")?;
+    let scenes_json = gltf.array_opt("scenes")?;
+    // glTF spec section 3.5.1: glTFs without scenes are just libraries of
+    // nodes and meshes, there's nothing to draw.
+    if scenes_json.is_empty() {
+        return Err(gltf.unsupported("glTFs without scenes are not supported"));
+    }
+    let mut scenes = Vec::new();
+    for scene in &scenes_json {
+        let mut node_indices = Vec::new();
+        for node_index in scene.array_opt("nodes")? {
+            node_index.lookup(&nodes_json)?;
+            node_indices.push(node_index.as_usize()?);
+        }
         scenes.push(gltf::Scene { node_indices });
     }
-    let scene = take_usize(&gltf["scene"]);
+    let scene = match gltf.get_opt("scene")? {
+        Some(scene_json) => {
+            let scene = scene_json.as_usize()?;
+            if scene >= scenes.len() {
+                let path = scene_json.path;
+                return Err(GltfError::OutOfBounds { path, index: scene });
+            }
+            scene
+        }
+        None => 0,
+    };
+
+    let cameras_json = gltf.array_opt("cameras")?;
+    let mut cameras = Vec::with_capacity(cameras_json.len());
+    for camera in &cameras_json {
+        let name = match camera.get_opt("name")? {
+            Some(name) => name.as_str()?.to_string(),
+            None => String::new(),
+        };
+        let kind_json = camera.get("type")?;
+        let (projection, near, far) = match kind_json.as_str()? {
+            "perspective" => {
+                let perspective = camera.get("perspective")?;
+                let fov_y = perspective.get("yfov")?.as_f32()?;
+                let near = perspective.get("znear")?.as_f32()?;
+                let far = match perspective.get_opt("zfar")? {
+                    Some(far) => far.as_f32()?,
+                    None => gltf::INFINITE_CAMERA_FAR,
+                };
+                (Projection::Perspective { fov_y }, near, far)
+            }
+            "orthographic" => {
+                let orthographic = camera.get("orthographic")?;
+                let height = orthographic.get("ymag")?.as_f32()? * 2.0;
+                let near = orthographic.get("znear")?.as_f32()?;
+                let far = orthographic.get("zfar")?.as_f32()?;
+                (Projection::Orthographic { height }, near, far)
+            }
+            _ => return Err(kind_json.invalid("perspective or orthographic")),
+        };
+        cameras.push(gltf::Camera {
+            name,
+            projection,
+            near,
+            far,
+        });
+    }
 
-    let nodes_json = gltf["nodes"].get::<Vec<_>>().unwrap();
     let mut nodes = Vec::with_capacity(nodes_json.len());
-    for node in nodes_json {
-        let node: &HashMap<_, _> = node.get().unwrap();
-        let child_node_indices = if let Some(children) = node.get("children") {
-            let children = children.get::<Vec<_>>().unwrap();
-            children.iter().map(take_usize).collect::<Vec<_>>()
-        } else {
-            Vec::new()
+    let mut parent_node_indices = vec![None; nodes_json.len()];
+    for (node_index, node) in nodes_json.iter().enumerate() {
+        let mut child_node_indices = Vec::new();
+        for child in node.array_opt("children")? {
+            child.lookup(&nodes_json)?;
+            let child_index = child.as_usize()?;
+            if parent_node_indices[child_index]
+                .replace(node_index)
+                .is_some()
+            {
+                return Err(child.invalid("a node which isn't the child of another node"));
+            }
+            child_node_indices.push(child_index);
+        }
+        let mesh_index = match node.get_opt("mesh")? {
+            Some(mesh) => Some(mesh.as_usize()?),
+            None => None,
         };
-        let mesh_index = node.get("mesh").map(take_usize);
-        let transform = if let Some(matrix_values) = node.get("matrix") {
-            let matrix_values = matrix_values.get::<Vec<_>>().unwrap();
-            let mut matrix: [f32; 16] = [0.0; 16];
-            assert_eq!(16, matrix_values.len());
-            for (i, value) in matrix_values.into_iter().enumerate() {
-                matrix[i] = take_f32(&value);
+        let skin_index = match node.get_opt("skin")? {
+            Some(skin) => Some(skin.as_usize()?),
+            None => None,
+        };
+        let camera_index = match node.get_opt("camera")? {
+            Some(camera) => {
+                camera.lookup(&cameras_json)?;
+                Some(camera.as_usize()?)
             }
-            Mat4::from_cols_slice(&matrix)
+            None => None,
+        };
+        let transform = if let Some(matrix) = node.get_opt("matrix")? {
+            Mat4::from_cols_array(&matrix.as_floats()?)
         } else {
-            let translation = node.get("translation").map(take_vec3).unwrap_or(Vec3::ZERO);
-            let scale = node.get("scale").map(take_vec3).unwrap_or(Vec3::ONE);
-            let rotation = node
-                .get("rotation")
-                .map(take_quat)
-                .unwrap_or(Quat::IDENTITY);
+            let translation = match node.get_opt("translation")? {
+                Some(translation) => translation.as_vec3()?,
+                None => Vec3::ZERO,
+            };
+            let scale = match node.get_opt("scale")? {
+                Some(scale) => scale.as_vec3()?,
+                None => Vec3::ONE,
+            };
+            let rotation = match node.get_opt("rotation")? {
+                Some(rotation) => rotation.as_quat()?,
+                None => Quat::IDENTITY,
+            };
             Mat4::from_scale_rotation_translation(scale, rotation, translation)
         };
         nodes.push(gltf::Node {
-            name: node["name"].get::<String>().unwrap().clone(),
+            name: match node.get_opt("name")? {
+                Some(name) => name.as_str()?.to_string(),
+                None => String::new(),
+            },
             mesh_index,
+            skin_index,
+            camera_index,
             child_node_indices,
             transform,
             original_transform: transform,
+            morph_weights: [0.0; MAX_MORPH_TARGETS], // Set after loading meshes
+            lod_node_indices: Vec::new(),
+            lod_screen_coverages: Vec::new(),
         });
     }
 
-    let accessors_json = gltf["accessors"].get::<Vec<_>>().unwrap();
-    let buffer_views_json = gltf["bufferViews"].get::<Vec<_>>().unwrap();
-    let unpack_accessor = |accessor: usize| {
-        let accessor = accessors_json[accessor].get::<HashMap<_, _>>().unwrap();
-        let buffer_view = buffer_views_json[take_usize(&accessor["bufferView"])]
-            .get::<HashMap<_, _>>()
-            .unwrap();
+    // glTF spec section 3.5.2: the nodes form disjoint trees, and the scenes
+    // are made of their roots. With at most one parent per node, the nodes
+    // which can't be reached from the roots are in a cycle, which would make
+    // the transform calculations loop forever.
+    for scene in &scenes_json {
+        for node_index in scene.array_opt("nodes")? {
+            if parent_node_indices[node_index.as_usize()?].is_some() {
+                return Err(node_index.invalid("a root node"));
+            }
+        }
+    }
+    let mut reachable = vec![false; nodes.len()];
+    let mut node_queue = (0..nodes.len())
+        .filter(|&i| parent_node_indices[i].is_none())
+        .collect::<Vec<_>>();
+    while let Some(node_index) = node_queue.pop() {
+        reachable[node_index] = true;
+        node_queue.extend(&nodes[node_index].child_node_indices);
+    }
+    if let Some(node_index) = reachable.iter().position(|&reachable| !reachable) {
+        return Err(nodes_json[node_index].invalid("a node which isn't its own ancestor"));
+    }
 
-        let buffer = take_usize(&buffer_view["buffer"]);
-        let byte_offset = accessor.get("byteOffset").map(take_usize).unwrap_or(0)
-            + buffer_view.get("byteOffset").map(take_usize).unwrap_or(0);
-        let count = take_usize(&accessor["count"]) as gl::types::GLint;
-        assert!(
-            !buffer_view.contains_key("byteStride"),
-            "byteStride is not supported for attributes"
-        );
-        let size = match accessor["type"].get::<String>().unwrap().as_ref() {
+    // Levels of detail from the MSFT_lod extension:
+    let get_default_lod_screen_coverages = |lod_count: usize| {
+        (0..lod_count)
+            .map(|i| DEFAULT_LOD_SCREEN_COVERAGE * 0.5f32.powi(i as i32))
+            .collect::<Vec<f32>>()
+    };
+    for (node_index, node) in nodes_json.iter().enumerate() {
+        let Some(extensions) = node.get_opt("extensions")? else {
+            continue;
+        };
+        let Some(msft_lod) = extensions.get_opt("MSFT_lod")? else {
+            continue;
+        };
+        let mut lod_node_indices = Vec::new();
+        for id in msft_lod.array_opt("ids")? {
+            id.lookup(&nodes_json)?;
+            lod_node_indices.push(id.as_usize()?);
+        }
+        let screen_coverages = match node.get_opt("extras")? {
+            Some(extras) => extras.get_opt("MSFT_screencoverage")?,
+            None => None,
+        };
+        let lod_screen_coverages = match screen_coverages {
+            Some(screen_coverages) => {
+                let mut coverages = Vec::new();
+                for coverage in screen_coverages.as_array()? {
+                    coverages.push(coverage.as_f32()?);
+                }
+                // One threshold for each level, and one more for culling.
+                coverages.truncate(lod_node_indices.len() + 1);
+                coverages
+            }
+            None => get_default_lod_screen_coverages(lod_node_indices.len()),
+        };
+        nodes[node_index].lod_node_indices = lod_node_indices;
+        nodes[node_index].lod_screen_coverages = lod_screen_coverages;
+    }
+
+    // Levels of detail from sibling nodes named like "Rock_LOD0", "Rock_LOD1":
+    let mut parent_node_indices = vec![None; nodes.len()];
+    for (node_index, node) in nodes.iter().enumerate() {
+        for &child_index in &node.child_node_indices {
+            parent_node_indices[child_index] = Some(node_index);
+        }
+    }
+    // Grouped by parent and name, with (level, node index) pairs as values.
+    let mut lod_groups = HashMap::<_, Vec<_>>::new();
+    for (node_index, node) in nodes.iter().enumerate() {
+        let Some((name, level)) = node.name.rsplit_once("_LOD") else {
+            continue;
+        };
+        let Ok(level) = level.parse::<u32>() else {
+            continue;
+        };
+        let group = (parent_node_indices[node_index], name.to_string());
+        lod_groups
+            .entry(group)
+            .or_default()
+            .push((level, node_index));
+    }
+    for ((parent_index, _), mut levels) in lod_groups {
+        levels.sort();
+        let node_index = levels[0].1;
+        if levels.len() < 2 || !nodes[node_index].lod_node_indices.is_empty() {
+            continue;
+        }
+        let lod_node_indices = levels[1..].iter().map(|&(_, i)| i).collect::<Vec<_>>();
+        // The lower levels are only drawn in place of the first one.
+        let siblings = match parent_index {
+            Some(parent_index) => vec![&mut nodes[parent_index].child_node_indices],
+            None => (scenes.iter_mut())
+                .map(|scene| &mut scene.node_indices)
+                .collect(),
+        };
+        for siblings in siblings {
+            siblings.retain(|i| !lod_node_indices.contains(i));
+        }
+        let node = &mut nodes[node_index];
+        node.lod_screen_coverages = get_default_lod_screen_coverages(lod_node_indices.len());
+        node.lod_node_indices = lod_node_indices;
+    }
+
+    let accessors_json = gltf.array_opt("accessors")?;
+    let unpack_accessor = |accessor_json: &Json| {
+        let accessor = accessor_json.lookup(&accessors_json)?;
+        let Some(buffer_view) = accessor.get_opt("bufferView")? else {
+            return Err(accessor.unsupported("accessors without a bufferView are not supported"));
+        };
+        let buffer_view_object = buffer_view.lookup(&buffer_views_json)?;
+        if buffer_view_object.get_opt("byteStride")?.is_some() {
+            let reason = "byteStride is not supported for attributes";
+            return Err(buffer_view_object.unsupported(reason));
+        }
+
+        let byte_offset = match accessor.get_opt("byteOffset")? {
+            Some(offset) => offset.as_usize()?,
+            None => 0,
+        };
+        let count = accessor.get("count")?.as_usize()?;
+        let type_json = accessor.get("type")?;
+        let size = match type_json.as_str()? {
             "SCALAR" => 1,
             "VEC2" => 2,
             "VEC3" => 3,
             "VEC4" => 4,
-            type_ => panic!("unexpected vertex attribute accessor type \"{type_}\""),
+            "MAT4" => 16,
+            _ => return Err(type_json.invalid("SCALAR, VEC2, VEC3, VEC4 or MAT4")),
+        };
+        let type_json = accessor.get("componentType")?;
+        let type_ = type_json.as_usize()? as gl::types::GLuint;
+        let component_size = match type_ {
+            gl::BYTE | gl::UNSIGNED_BYTE => 1,
+            gl::SHORT | gl::UNSIGNED_SHORT => 2,
+            gl::UNSIGNED_INT | gl::FLOAT => 4,
+            _ => return Err(type_json.invalid("a glTF component type")),
         };
-        let type_ = take_usize(&accessor["componentType"]) as gl::types::GLuint;
-        let normalized = accessor
-            .get("normalized")
-            .map(|v| *v.get::<bool>().unwrap())
-            .unwrap_or(false);
+        let normalized = match accessor.get_opt("normalized")? {
+            Some(normalized) => normalized.as_bool()?,
+            None => false,
+        };
+
+        // Checks that the data is actually there, so the GL calls using these
+        // offsets don't read out of bounds.
+        let byte_length = (count.checked_mul(size))
+            .and_then(|length| length.checked_mul(component_size))
+            .ok_or_else(|| accessor.invalid("a count which fits in memory"))?;
+        let (buffer, byte_offset, data) =
+            get_buffer_view_slice(&buffer_view, byte_offset, Some(byte_length))?;
 
-        (buffer, byte_offset, count, size, type_, normalized)
+        Ok(Accessor {
+            buffer,
+            byte_offset,
+            count: count as gl::types::GLint,
+            size: size as gl::types::GLint,
+            type_,
+            normalized,
+            data,
+        })
     };
 
-    let meshes_json = gltf["meshes"].get::<Vec<_>>().unwrap();
-    let primitive_count = meshes_json
-        .iter()
-        .flat_map(|mesh| mesh["primitives"].get::<Vec<_>>())
-        .count();
-    let mut gl_vaos = vec![0; primitive_count];
-    gl::call!(gl::GenVertexArrays(
-        gl_vaos.len() as i32,
-        gl_vaos.as_mut_ptr()
-    ));
+    let skins_json = gltf.array_opt("skins")?;
+    let mut skins = Vec::with_capacity(skins_json.len());
+    for skin in &skins_json {
+        let joints_json = skin.get("joints")?;
+        let mut joints = Vec::new();
+        for joint in joints_json.as_array()? {
+            joint.lookup(&nodes_json)?;
+            joints.push(joint.as_usize()?);
+        }
+        if joints.len() > gltf::MAX_JOINTS {
+            let reason = format!("skins can have at most {} joints", gltf::MAX_JOINTS);
+            return Err(joints_json.unsupported(reason));
+        }
+        let inverse_bind_matrices = match skin.get_opt("inverseBindMatrices")? {
+            Some(accessor_json) => {
+                let accessor = unpack_accessor(&accessor_json)?;
+                if accessor.type_ != gl::FLOAT
+                    || accessor.size != 16
+                    || accessor.count as usize != joints.len()
+                {
+                    return Err(accessor_json.invalid("a MAT4 accessor with a matrix per joint"));
+                }
+                bytemuck::pod_collect_to_vec(accessor.data)
+            }
+            None => vec![Mat4::IDENTITY; joints.len()],
+        };
+        // The skeleton root isn't needed, since the joint matrices are
+        // calculated from the joints' transforms relative to the scene root.
+        if let Some(skeleton) = skin.get_opt("skeleton")? {
+            skeleton.lookup(&nodes_json)?;
+        }
+        skins.push(gltf::Skin {
+            joints,
+            inverse_bind_matrices,
+        });
+    }
+    for node in &nodes_json {
+        if let Some(skin) = node.get_opt("skin")? {
+            skin.lookup(&skins_json)?;
+        }
+    }
+
+    let meshes_json = gltf.array_opt("meshes")?;
+    let mut primitive_count = 0;
+    for mesh in &meshes_json {
+        primitive_count += mesh.get("primitives")?.as_array()?.len();
+    }
     let mut index_buffer_allocator =
         BumpAllocatedBuffer::new(gl::ELEMENT_ARRAY_BUFFER, gl::DYNAMIC_DRAW);
-    gl_buffers.push(index_buffer_allocator.get_buffer(true));
+    // Primitives without a material use the default material, which is
+    // represented by an empty material object at the end of the list.
+    let default_material_json = JsonValue::Object(HashMap::new());
+    let mut materials_json = gltf.array_opt("materials")?;
+    let default_material_index = materials_json.len();
+    materials_json.push(Json {
+        value: &default_material_json,
+        path: "(default material)".to_string(),
+    });
+    let get_double_sided = |material: &Json| match material.get_opt("doubleSided")? {
+        Some(double_sided) => double_sided.as_bool(),
+        None => Ok(false),
+    };
+    let get_alpha_mode = |material: &Json| {
+        let Some(alpha_mode) = material.get_opt("alphaMode")? else {
+            return Ok(gltf::AlphaMode::Opaque);
+        };
+        match alpha_mode.as_str()? {
+            "OPAQUE" => Ok(gltf::AlphaMode::Opaque),
+            "MASK" => Ok(gltf::AlphaMode::Mask {
+                cutoff: match material.get_opt("alphaCutoff")? {
+                    Some(cutoff) => cutoff.as_f32()?,
+                    None => 0.5,
+                },
+            }),
+            "BLEND" => Ok(gltf::AlphaMode::Blend),
+            _ => Err(alpha_mode.invalid("OPAQUE, MASK or BLEND")),
+        }
+    };
+    // Primitives without morph targets use this texture with a single layer,
+    // which the shader sees as zero morph targets.
+    let empty_morph_targets_texture = Texture::try_new().map_err(|error| gltf.gl_error(error))?;
+    create_morph_targets_texture(&empty_morph_targets_texture, 1, 1, 1, &[0.0; 3])
+        .map_err(|error| gltf.gl_error(error))?;
     let mut primitives = Vec::with_capacity(primitive_count);
     let mut meshes = Vec::with_capacity(meshes_json.len());
-    for mesh in meshes_json {
-        let primitives_json = mesh["primitives"].get::<Vec<_>>().unwrap();
+    let mut mesh_morph_target_counts = Vec::with_capacity(meshes_json.len());
+    for mesh in &meshes_json {
+        let primitives_json = mesh.get("primitives")?.as_array()?;
         let mut primitive_indices = Vec::with_capacity(primitives_json.len());
+        let mut morph_target_count = None;
         for primitive_json in primitives_json {
-            let primitive_json = primitive_json.get::<HashMap<_, _>>().unwrap();
-
             let primitive_index = primitives.len();
-            let material_index = take_usize(&primitive_json["material"]);
-            let mode = primitive_json.get("mode").map(take_usize).unwrap_or(4) as gl::types::GLuint;
-            let vao = gl_vaos[primitive_index];
+            let material_index = match primitive_json.get_opt("material")? {
+                Some(material) => {
+                    material.lookup(&materials_json)?;
+                    material.as_usize()?
+                }
+                None => default_material_index,
+            };
+            let material = &materials_json[material_index];
+            let blend = get_alpha_mode(material)? == gltf::AlphaMode::Blend;
+            let double_sided = get_double_sided(material)?;
+            let mode = match primitive_json.get_opt("mode")? {
+                Some(mode) => mode.as_usize()? as gl::types::GLuint,
+                None => gl::TRIANGLES,
+            };
+            let vao = VertexArray::try_new().map_err(|error| primitive_json.gl_error(error))?;
             let mut disabled_all_ones_vertex_attribute = Some(gltf::ATTR_LOC_COLOR_0);
-            let attribute_accessors = primitive_json["attributes"].get::<HashMap<_, _>>().unwrap();
-            gl::call!(gl::BindVertexArray(vao));
-            for (attr_name, accessor) in attribute_accessors {
-                let accessor = take_usize(accessor);
-                let location = match attr_name.as_ref() {
+            gl::call!(gl::BindVertexArray(vao.id()));
+            label(gl::VERTEX_ARRAY_KHR, vao.id(), &primitive_json.path);
+            for (attr_name, accessor) in primitive_json.get("attributes")?.entries()? {
+                let location = match attr_name {
                     "POSITION" => gltf::ATTR_LOC_POSITION,
                     "NORMAL" => gltf::ATTR_LOC_NORMAL,
                     "TANGENT" => gltf::ATTR_LOC_TANGENT,
                     "TEXCOORD_0" => gltf::ATTR_LOC_TEXCOORD_0,
                     "TEXCOORD_1" => gltf::ATTR_LOC_TEXCOORD_1,
                     "COLOR_0" => gltf::ATTR_LOC_COLOR_0,
-                    attr => panic!("unsupported attribute semantic \"{attr}\""),
+                    "JOINTS_0" => gltf::ATTR_LOC_JOINTS_0,
+                    "WEIGHTS_0" => gltf::ATTR_LOC_WEIGHTS_0,
+                    attr => {
+                        let reason = format!("unsupported attribute semantic \"{attr}\"");
+                        return Err(accessor.unsupported(reason));
+                    }
                 };
-                let (buffer, offset, _, size, type_, normalized) = unpack_accessor(accessor);
+                let accessor_json = accessor;
+                let accessor = unpack_accessor(&accessor_json)?;
                 gl::call!(gl::EnableVertexAttribArray(location));
-                gl::call!(gl::BindBuffer(gl::ARRAY_BUFFER, gl_buffers[buffer]));
-                gl::call!(gl::VertexAttribPointer(
-                    location,
-                    size,
-                    type_,
-                    if normalized { gl::TRUE } else { gl::FALSE },
-                    0,
-                    ptr::null::<c_void>().add(offset),
-                ));
+                let buffer = &gl_buffers[accessor.buffer];
+                gl::call!(gl::BindBuffer(gl::ARRAY_BUFFER, buffer.id()));
+                vao.attach(buffer);
+                if location == gltf::ATTR_LOC_JOINTS_0 {
+                    // Joint indices are read as integers in the shader.
+                    if !matches!(accessor.type_, gl::UNSIGNED_BYTE | gl::UNSIGNED_SHORT) {
+                        return Err(accessor_json.invalid("an unsigned byte or short accessor"));
+                    }
+                    gl::try_call!(gl::VertexAttribIPointer(
+                        location,
+                        accessor.size,
+                        accessor.type_,
+                        0,
+                        ptr::null::<c_void>().add(accessor.byte_offset),
+                    ))
+                    .map_err(|error| accessor_json.gl_error(error))?;
+                } else {
+                    gl::try_call!(gl::VertexAttribPointer(
+                        location,
+                        accessor.size,
+                        accessor.type_,
+                        if accessor.normalized {
+                            gl::TRUE
+                        } else {
+                            gl::FALSE
+                        },
+                        0,
+                        ptr::null::<c_void>().add(accessor.byte_offset),
+                    ))
+                    .map_err(|error| accessor_json.gl_error(error))?;
+                }
                 if location == gltf::ATTR_LOC_COLOR_0 {
                     disabled_all_ones_vertex_attribute = None;
                 }
             }
 
-            let indices_accessor = take_usize(&primitive_json["indices"]);
-            let (index_buffer, index_byte_offset, index_count, size, index_type, _) =
-                unpack_accessor(indices_accessor);
-            let index_type_byte_size = match index_type {
-                gl::UNSIGNED_BYTE => 1,
-                gl::UNSIGNED_SHORT => 2,
-                gl::UNSIGNED_INT => 4,
-                type_ => panic!("invalid index buffer type {type_}"),
+            let Some(indices_json) = primitive_json.get_opt("indices")? else {
+                return Err(primitive_json.unsupported("non-indexed geometry is not supported"));
             };
-            let index_byte_length = (index_count * size * index_type_byte_size) as usize;
-            let index_buffer = get_buffer_slice(index_buffer, index_byte_offset, index_byte_length);
+            let indices = unpack_accessor(&indices_json)?;
+            if !matches!(
+                indices.type_,
+                gl::UNSIGNED_BYTE | gl::UNSIGNED_SHORT | gl::UNSIGNED_INT
+            ) {
+                return Err(indices_json.invalid("an accessor of unsigned integers"));
+            }
             let (index_buffer, index_byte_offset) =
-                index_buffer_allocator.allocate_buffer(index_buffer);
+                index_buffer_allocator.allocate_buffer(indices.data);
+            vao.attach(&index_buffer);
+            label(gl::BUFFER_KHR, index_buffer.id(), "index buffer");
+
+            // The bounds of the primitive, from the POSITION accessor's min
+            // and max, and grown by each morph target's min and max, assuming
+            // the weights are between 0 and 1.
+            let get_bounds = |attributes: &Json| {
+                let Some(position) = attributes.get_opt("POSITION")? else {
+                    return Ok(None);
+                };
+                let position = position.lookup(&accessors_json)?;
+                match (position.get_opt("min")?, position.get_opt("max")?) {
+                    (Some(min), Some(max)) => Ok(Some(Aabb {
+                        min: min.as_vec3()?,
+                        max: max.as_vec3()?,
+                    })),
+                    _ => Ok(None),
+                }
+            };
+            let mut bounds = get_bounds(&primitive_json.get("attributes")?)?;
+
+            let targets_json = primitive_json.array_opt("targets")?;
+            for target in &targets_json {
+                match (&mut bounds, get_bounds(target)?) {
+                    (Some(bounds), Some(deltas)) => {
+                        bounds.min += deltas.min.min(Vec3::ZERO);
+                        bounds.max += deltas.max.max(Vec3::ZERO);
+                    }
+                    // Without the deltas' bounds, the primitive's bounds are
+                    // unknown as well.
+                    _ => bounds = None,
+                }
+            }
+            if targets_json.len() > MAX_MORPH_TARGETS {
+                let reason =
+                    format!("only a maximum of {MAX_MORPH_TARGETS} morph targets is supported");
+                return Err(primitive_json.unsupported(reason));
+            }
+            // glTF spec section 3.7.2.2: all primitives of a mesh must have
+            // the same amount of morph targets.
+            if *morph_target_count.get_or_insert(targets_json.len()) != targets_json.len() {
+                return Err(primitive_json.invalid("as many morph targets as the other primitives"));
+            }
+            let morph_targets_texture = if targets_json.is_empty() {
+                empty_morph_targets_texture.clone()
+            } else {
+                let attributes = primitive_json.get("attributes")?;
+                let vertex_count = unpack_accessor(&attributes.get("POSITION")?)?.count as usize;
+                if vertex_count == 0 {
+                    return Err(primitive_json.invalid("vertices for the morph targets"));
+                }
+                let width = vertex_count.min(MORPH_TARGETS_TEXTURE_WIDTH);
+                let height = vertex_count.div_ceil(width);
+                let layer_len = width * height * 3;
+                // Each target is stored as three layers: the POSITION, NORMAL
+                // and TANGENT deltas, zeroed if the target doesn't have them.
+                let mut deltas = vec![0.0f32; layer_len * 3 * targets_json.len()];
+                for (i, target) in targets_json.iter().enumerate() {
+                    for (attr_name, accessor_json) in target.entries()? {
+                        let layer = match attr_name {
+                            "POSITION" => 0,
+                            "NORMAL" => 1,
+                            "TANGENT" => 2,
+                            attr => {
+                                let reason =
+                                    format!("unsupported morph target attribute \"{attr}\"");
+                                return Err(accessor_json.unsupported(reason));
+                            }
+                        };
+                        let accessor = unpack_accessor(&accessor_json)?;
+                        if accessor.type_ != gl::FLOAT
+                            || accessor.size != 3
+                            || accessor.count as usize != vertex_count
+                        {
+                            return Err(accessor_json.invalid("a VEC3 float accessor per vertex"));
+                        }
+                        let start = layer_len * (i * 3 + layer);
+                        let layer_deltas = &mut deltas[start..start + vertex_count * 3];
+                        bytemuck::cast_slice_mut(layer_deltas).copy_from_slice(accessor.data);
+                    }
+                }
+                let texture = Texture::try_new().map_err(|error| primitive_json.gl_error(error))?;
+                let layers = targets_json.len() * 3;
+                create_morph_targets_texture(&texture, width, height, layers, &deltas)
+                    .map_err(|error| primitive_json.gl_error(error))?;
+                let what = format!("{}.targets", primitive_json.path);
+                label(gl::TEXTURE, texture.id(), &what);
+                texture
+            };
 
             primitives.push(gltf::Primitive {
                 material_index,
+                bounds,
                 draw_call: DrawCall {
                     mode,
                     vao,
-                    index_type,
+                    index_type: indices.type_,
                     index_buffer,
                     index_byte_offset,
-                    index_count,
+                    index_count: indices.count * indices.size,
                     disabled_all_ones_vertex_attribute,
                     front_face: gl::CCW,
+                    morph_targets_texture,
+                    blend,
+                    double_sided,
+                    layer: 0,
                 },
             });
             primitive_indices.push(primitive_index);
         }
-        meshes.push(gltf::Mesh { primitive_indices });
+        let mut primitive_bounds = primitive_indices.iter().map(|&i| primitives[i].bounds);
+        let first_bounds = primitive_bounds.next().flatten();
+        let bounds = primitive_bounds.fold(first_bounds, |bounds, primitive_bounds| {
+            Some(bounds?.union(&primitive_bounds?))
+        });
+        meshes.push(gltf::Mesh {
+            primitive_indices,
+            bounds,
+        });
+        mesh_morph_target_counts.push(morph_target_count.unwrap_or(0));
+    }
+    for (node_index, node) in nodes_json.iter().enumerate() {
+        let Some(mesh) = node.get_opt("mesh")? else {
+            continue;
+        };
+        let mesh = mesh.lookup(&meshes_json)?;
+        // The node's weights override the mesh's default weights.
+        let weights = match node.get_opt("weights")? {
+            Some(weights) => Some(weights),
+            None => mesh.get_opt("weights")?,
+        };
+        if let Some(weights_json) = weights {
+            let weights = weights_json.as_array()?;
+            if weights.len() > MAX_MORPH_TARGETS {
+                return Err(weights_json.invalid("a weight for each morph target"));
+            }
+            for (weight, weight_json) in nodes[node_index].morph_weights.iter_mut().zip(weights) {
+                *weight = weight_json.as_f32()?;
+            }
+        }
     }
 
-    let materials_json = gltf["materials"].get::<Vec<_>>().unwrap();
-    let textures_json = gltf["textures"].get::<Vec<_>>().unwrap();
-    let images_json = gltf["images"].get::<Vec<_>>().unwrap();
+    let textures_json = gltf.array_opt("textures")?;
+    let images_json = gltf.array_opt("images")?;
+    // Returns the index of the texture info's texture, and its source image.
+    let get_texture = |texture_info: &Json| {
+        let texture_index = texture_info.get("index")?;
+        let source = texture_index.lookup(&textures_json)?.get("source")?;
+        source.lookup(&images_json)?;
+        Ok((texture_index.as_usize()?, source.as_usize()?))
+    };
     let mut is_srgb = vec![None; images_json.len()];
-    for material in materials_json {
-        let material = material.get::<HashMap<_, _>>().unwrap();
-        let pbr_image = |name: &str| {
-            let pbr = material
-                .get("pbrMetallicRoughness")?
-                .get::<HashMap<_, _>>()
-                .unwrap();
-            let texture = take_usize(&pbr.get(name)?["index"]);
-            Some(take_usize(&textures_json[texture]["source"]))
-        };
-        let additional_image = |name: &str| {
-            let texture = take_usize(&material.get(name)?["index"]);
-            Some(take_usize(&textures_json[texture]["source"]))
-        };
-        let set_srgb_status = |is_srgb: &mut [Option<bool>], index: usize, expected: bool| {
-            assert!(
-                is_srgb[index] != Some(!expected),
-                "images[{}] is used both as srgb and not",
-                index,
-            );
+    // The names of the materials using each image, for labeling the textures.
+    let mut image_users = vec![Vec::new(); images_json.len()];
+    for material in &materials_json {
+        let material_name = match material.get_opt("name")? {
+            Some(name) => name.as_str()?,
+            None => &material.path,
+        };
+        let mut set_srgb_status = |texture_info: Option<Json>, expected: bool| {
+            let Some(texture_info) = texture_info else {
+                return Ok(());
+            };
+            let (_, index) = get_texture(&texture_info)?;
+            if is_srgb[index] == Some(!expected) {
+                let reason = format!("images[{index}] is used both as srgb and not");
+                return Err(texture_info.unsupported(reason));
+            }
             is_srgb[index] = Some(expected);
+            if !image_users[index].contains(&material_name) {
+                image_users[index].push(material_name);
+            }
+            Ok(())
         };
-        if let Some(image) = pbr_image("baseColorTexture") {
-            set_srgb_status(&mut is_srgb, image, true);
-        }
-        if let Some(image) = pbr_image("metallicRoughnessTexture") {
-            set_srgb_status(&mut is_srgb, image, false);
-        }
-        if let Some(image) = additional_image("normalTexture") {
-            set_srgb_status(&mut is_srgb, image, false);
-        }
-        if let Some(image) = additional_image("occlusionTexture") {
-            set_srgb_status(&mut is_srgb, image, false);
-        }
-        if let Some(image) = additional_image("emissiveTexture") {
-            set_srgb_status(&mut is_srgb, image, true);
+        if let Some(pbr) = material.get_opt("pbrMetallicRoughness")? {
+            set_srgb_status(pbr.get_opt("baseColorTexture")?, true)?;
+            set_srgb_status(pbr.get_opt("metallicRoughnessTexture")?, false)?;
         }
+        set_srgb_status(material.get_opt("normalTexture")?, false)?;
+        set_srgb_status(material.get_opt("occlusionTexture")?, false)?;
+        set_srgb_status(material.get_opt("emissiveTexture")?, true)?;
     }
 
-    let mut gl_textures = vec![0; images_json.len() + 3];
-    gl::call!(gl::GenTextures(
-        gl_textures.len() as i32,
-        gl_textures.as_mut_ptr()
-    ));
-    let white_tex = gl_textures[gl_textures.len() - 1];
-    let normal_tex = gl_textures[gl_textures.len() - 2];
-    let black_tex = gl_textures[gl_textures.len() - 3];
-    let make_pixel_tex = |tex: u32, color: [u8; 3]| {
+    let gl_textures = (images_json.iter())
+        .map(|image| Texture::try_new().map_err(|error| image.gl_error(error)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let make_pixel_tex = |color: [u8; 3], what: &str| {
+        let tex = Texture::try_new().map_err(|error| gltf.gl_error(error))?;
         let target = gl::TEXTURE_2D;
         let ifmt = gl::RGB as i32;
         let fmt = gl::RGB;
         let type_ = gl::UNSIGNED_BYTE;
         let pixels = color.as_ptr() as *const c_void;
-        gl::call!(gl::BindTexture(target, tex));
+        gl::call!(gl::BindTexture(target, tex.id()));
         gl::call!(gl::TexImage2D(target, 0, ifmt, 1, 1, 0, fmt, type_, pixels));
+        label(gl::TEXTURE, tex.id(), what);
+        Ok(tex)
     };
-    make_pixel_tex(white_tex, [0xFF, 0xFF, 0xFF]);
-    make_pixel_tex(normal_tex, [0x7F, 0x7F, 0xFF]);
-    make_pixel_tex(black_tex, [0, 0, 0]);
-    for (i, image) in images_json.into_iter().enumerate() {
+    let white_tex = make_pixel_tex([0xFF, 0xFF, 0xFF], "default white texture")?;
+    let normal_tex = make_pixel_tex([0x7F, 0x7F, 0xFF], "default normal texture")?;
+    let black_tex = make_pixel_tex([0, 0, 0], "default black texture")?;
+    for (i, image) in images_json.iter().enumerate() {
         let Some(is_srgb) = is_srgb[i] else {
             continue; // Not used by any material.
         };
 
-        let image = image.get::<HashMap<_, _>>().unwrap();
-        let image_data = if let Some(uri) = image.get("uri") {
-            let uri = uri.get::<String>().unwrap().as_str();
+        let image_data = if let Some(uri) = image.get_opt("uri")? {
+            let uri = uri.as_str()?;
             match resources
                 .iter()
                 .find(|(name, _)| *name == uri)
                 .map(|(_, data)| *data)
             {
                 Some(data) => data,
-                None => panic!("the uri of image {i} ({uri}) is not included in resources"),
+                None => {
+                    return Err(GltfError::MissingResource {
+                        path: image.path.clone(),
+                        uri: uri.to_string(),
+                    })
+                }
             }
         } else {
-            let buffer_view = image["bufferView"].get::<HashMap<_, _>>().unwrap();
-            let buffer = take_usize(&buffer_view["buffer"]);
-            let offset = buffer_view.get("byteOffset").map(take_usize).unwrap_or(0);
-            let length = take_usize(&buffer_view["byteLength"]);
-            assert!(
-                !buffer_view.contains_key("byteStride"),
-                "byteStride is not supported for image data"
-            );
-            get_buffer_slice(buffer, offset, length)
+            let buffer_view = image.get("bufferView")?;
+            if buffer_view
+                .lookup(&buffer_views_json)?
+                .get_opt("byteStride")?
+                .is_some()
+            {
+                let reason = "byteStride is not supported for image data";
+                return Err(buffer_view.unsupported(reason));
+            }
+            get_buffer_view_slice(&buffer_view, 0, None)?.2
         };
 
-        let mut parsed_image = image::load_from_memory(image_data).unwrap();
+        let mut parsed_image =
+            image::load_from_memory(image_data).map_err(|error| GltfError::Image {
+                path: image.path.clone(),
+                error,
+            })?;
         let (format, type_, bpp) = match parsed_image {
             DynamicImage::ImageRgb8(_) => (gl::RGB, gl::UNSIGNED_BYTE, 3),
             DynamicImage::ImageRgba8(_) => (gl::RGBA, gl::UNSIGNED_BYTE, 4),
             DynamicImage::ImageRgb16(_) => (gl::RGB, gl::UNSIGNED_SHORT, 6),
             DynamicImage::ImageRgba16(_) => (gl::RGBA, gl::UNSIGNED_SHORT, 8),
-            img => panic!("image {img:?} is of an unsupported format"),
+            img => {
+                let color = img.color();
+                let reason = format!("images with {color:?} pixels are not supported");
+                return Err(image.unsupported(reason));
+            }
         };
         let internal_format = match (is_srgb, format) {
             (true, gl::RGBA) => gl::SRGB8_ALPHA8,
@@ -346,7 +936,17 @@ pub fn load_gltf(gltf: &str, resources: &[(&str, &[u8])]) -> gltf::Gltf {
             (false, format) => format,
             _ => unreachable!(),
         };
-        gl::call!(gl::BindTexture(gl::TEXTURE_2D, gl_textures[i]));
+        gl::call!(gl::BindTexture(gl::TEXTURE_2D, gl_textures[i].id()));
+        let image_name = match (image.get_opt("name")?, image.get_opt("uri")?) {
+            (Some(name), _) | (None, Some(name)) => format!(" \"{}\"", name.as_str()?),
+            (None, None) => String::new(),
+        };
+        let users = image_users[i].join(", ");
+        label(
+            gl::TEXTURE,
+            gl_textures[i].id(),
+            &format!("{}{image_name} ({users})", image.path),
+        );
         let size = parsed_image.width().min(parsed_image.height());
         let mip_levels = (size as f32).log2().floor() as i32 + 1;
         for mip_level in 0..mip_levels {
@@ -356,7 +956,7 @@ pub fn load_gltf(gltf: &str, resources: &[(&str, &[u8])]) -> gltf::Gltf {
                 parsed_image.as_bytes(),
             );
             assert_eq!(width * height * bpp, data.len() as i32);
-            gl::call!(gl::TexImage2D(
+            gl::try_call!(gl::TexImage2D(
                 gl::TEXTURE_2D,
                 mip_level,
                 internal_format as i32,
@@ -366,7 +966,8 @@ pub fn load_gltf(gltf: &str, resources: &[(&str, &[u8])]) -> gltf::Gltf {
                 format,
                 type_,
                 data.as_ptr() as *const c_void,
-            ));
+            ))
+            .map_err(|error| image.gl_error(error))?;
             if mip_level < mip_levels - 1 {
                 parsed_image = parsed_image.resize_exact(
                     width as u32 / 2,
@@ -381,152 +982,192 @@ pub fn load_gltf(gltf: &str, resources: &[(&str, &[u8])]) -> gltf::Gltf {
         }
     }
 
-    let samplers_json_fallback = Vec::with_capacity(0);
-    let samplers_json = gltf
-        .get("samplers")
-        .map(|v| v.get::<Vec<_>>().unwrap())
-        .unwrap_or(&samplers_json_fallback);
-    let mut gl_samplers = vec![0; samplers_json.len() + 1];
-    gl::call!(gl::GenSamplers(
-        gl_samplers.len() as i32,
-        gl_samplers.as_mut_ptr()
-    ));
-    let default_sampler = gl_samplers[gl_samplers.len() - 1];
+    let samplers_json = gltf.array_opt("samplers")?;
+    let gl_samplers = (0..samplers_json.len() + 1)
+        .map(|_| Sampler::try_new())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| gltf.gl_error(error))?;
+    let default_sampler = &gl_samplers[gl_samplers.len() - 1];
     gl::call!(gl::SamplerParameteri(
-        default_sampler,
+        default_sampler.id(),
         gl::TEXTURE_MAG_FILTER,
         gl::LINEAR as i32,
     ));
     gl::call!(gl::SamplerParameteri(
-        default_sampler,
+        default_sampler.id(),
         gl::TEXTURE_MIN_FILTER,
         gl::LINEAR_MIPMAP_LINEAR as i32,
     ));
     gl::call!(gl::SamplerParameteri(
-        default_sampler,
+        default_sampler.id(),
         gl::TEXTURE_WRAP_S,
         gl::REPEAT as i32,
     ));
     gl::call!(gl::SamplerParameteri(
-        default_sampler,
+        default_sampler.id(),
         gl::TEXTURE_WRAP_T,
         gl::REPEAT as i32,
     ));
-    for (i, sampler) in samplers_json.into_iter().enumerate() {
-        let sampler = sampler.get::<HashMap<_, _>>().unwrap();
-        gl::call!(gl::SamplerParameteri(
-            gl_samplers[i],
-            gl::TEXTURE_MAG_FILTER,
-            sampler
-                .get("magFilter")
-                .map(take_usize)
-                .unwrap_or(gl::LINEAR as usize) as i32,
-        ));
-        gl::call!(gl::SamplerParameteri(
-            gl_samplers[i],
-            gl::TEXTURE_MIN_FILTER,
-            sampler
-                .get("minFilter")
-                .map(take_usize)
-                .unwrap_or(gl::LINEAR_MIPMAP_LINEAR as usize) as i32,
-        ));
-        gl::call!(gl::SamplerParameteri(
-            gl_samplers[i],
-            gl::TEXTURE_WRAP_S,
-            sampler.get("wrapS").map(take_usize).unwrap_or(10497) as i32,
-        ));
-        gl::call!(gl::SamplerParameteri(
-            gl_samplers[i],
-            gl::TEXTURE_WRAP_T,
-            sampler.get("wrapT").map(take_usize).unwrap_or(10497) as i32,
-        ));
-    }
-
-    let mut uniform_buffer_allocator =
-        BumpAllocatedBuffer::new(gl::UNIFORM_BUFFER, gl::DYNAMIC_DRAW);
-    gl_buffers.push(uniform_buffer_allocator.get_buffer(true));
+    for (i, sampler) in samplers_json.iter().enumerate() {
+        let get_param = |name: &str, default: gl::types::GLenum| {
+            Ok(match sampler.get_opt(name)? {
+                Some(param) => param.as_usize()? as i32,
+                None => default as i32,
+            })
+        };
+        let mag_filter = get_param("magFilter", gl::LINEAR)?;
+        let min_filter = get_param("minFilter", gl::LINEAR_MIPMAP_LINEAR)?;
+        let wrap_s = get_param("wrapS", gl::REPEAT)?;
+        let wrap_t = get_param("wrapT", gl::REPEAT)?;
+        let gl_sampler = gl_samplers[i].id();
+        for (param, value) in [
+            (gl::TEXTURE_MAG_FILTER, mag_filter),
+            (gl::TEXTURE_MIN_FILTER, min_filter),
+            (gl::TEXTURE_WRAP_S, wrap_s),
+            (gl::TEXTURE_WRAP_T, wrap_t),
+        ] {
+            gl::try_call!(gl::SamplerParameteri(gl_sampler, param, value))
+                .map_err(|error| sampler.gl_error(error))?;
+        }
+    }
+
+    for (i, sampler) in gl_samplers.iter().enumerate() {
+        let sampler = sampler.id();
+        match samplers_json.get(i) {
+            Some(sampler_json) => label(gl::SAMPLER_KHR, sampler, &sampler_json.path),
+            None => label(gl::SAMPLER_KHR, sampler, "default sampler"),
+        }
+    }
+
+    let mut uniform_buffer = UniformBufferAllocator::new();
 
     // KHR_lights_punctual extension:
-    let lights_json_fallback = Vec::with_capacity(0);
-    let lights_json = gltf
-        .get("extensions")
-        .and_then(|v| v.get::<HashMap<_, _>>().unwrap().get("KHR_lights_punctual"))
-        .and_then(|v| v.get::<HashMap<_, _>>().unwrap().get("lights"))
-        .map(|v| v.get::<Vec<_>>().unwrap())
-        .unwrap_or(&lights_json_fallback);
-    let mut lights = gltf::UniformBlockLights::zeroed();
-    let mut light_node_index = 0;
-    for (node_index, node) in nodes_json.into_iter().enumerate() {
-        if let Some(khr_lights_punctual) = node
-            .get::<HashMap<_, _>>()
-            .unwrap()
-            .get("extensions")
-            .map(|extensions| extensions.get::<HashMap<_, _>>().unwrap())
-            .map(|extensions| extensions.get("KHR_lights_punctual"))
-            .flatten()
-        {
-            if light_node_index >= MAX_LIGHTS {
-                panic!("this gltf renderer only supports a maximum of {MAX_LIGHTS} lights");
-            }
-
-            let light_index = take_usize(&khr_lights_punctual["light"]);
-            let light = lights_json[light_index].get::<HashMap<_, _>>().unwrap();
-            let color = light.get("color").map(take_vec3).unwrap_or(Vec3::ONE);
-            let intensity = light.get("intensity").map(take_f32).unwrap_or(1.0);
-            let kind = match light["type"].get::<String>().unwrap().as_str() {
-                "directional" => 1.0,
-                "point" => 2.0,
-                "spot" => 3.0,
-                kind => panic!("light has a non-standard type '{kind}'"),
-            };
-            let transform = nodes[node_index].transform;
-            let inner_angle = light.get("innerConeAngle").map(take_f32).unwrap_or(0.0);
-            let outer_angle = light
-                .get("outerConeAngle")
-                .map(take_f32)
-                .unwrap_or(FRAC_PI_4);
-            // https://github.com/KhronosGroup/glTF/blob/main/extensions/2.0/Khronos/KHR_lights_punctual/README.md#inner-and-outer-cone-angles
-            let light_angle_scale = 1.0 / 0.001f32.max(inner_angle.cos() - outer_angle.cos());
-            let light_angle_offset = -outer_angle.cos() * light_angle_scale;
-
-            let i = light_node_index;
-            lights.color_and_kind[i] = Vec4::from((color, kind));
-            lights.intensity_params[i] =
-                Vec4::new(intensity, light_angle_scale, light_angle_offset, 0.0);
-            lights.position[i] = transform * Vec4::new(0.0, 0.0, 0.0, 1.0);
-            lights.direction[i] = transform * Vec4::from((FORWARD, 0.0));
-            light_node_index += 1;
-        }
-    }
-    let lights_uniform_block = {
-        let lights_data = [lights];
-        let lights_data = bytemuck::cast_slice(&lights_data);
-        let (ubo, ubo_offset) = uniform_buffer_allocator.allocate_buffer(lights_data);
-        let ubo_size = lights_data.len();
-        (gltf::UNIFORM_BLOCK_LIGHTS, ubo, ubo_offset, ubo_size)
+    let lights_json = match gltf.get_opt("extensions")? {
+        Some(extensions) => match extensions.get_opt("KHR_lights_punctual")? {
+            Some(khr_lights_punctual) => khr_lights_punctual.array_opt("lights")?,
+            None => Vec::new(),
+        },
+        None => Vec::new(),
     };
+    let global_transforms = scenes[scene].get_global_transforms(&nodes, |i| nodes[i].transform);
+    let mut lights = Vec::new();
+    let mut lights_block = gltf::UniformBlockLights::zeroed();
+    let mut light_node_index = 0;
+    for (node_index, node) in nodes_json.iter().enumerate() {
+        let Some(extensions) = node.get_opt("extensions")? else {
+            continue;
+        };
+        let Some(khr_lights_punctual) = extensions.get_opt("KHR_lights_punctual")? else {
+            continue;
+        };
+        if light_node_index >= MAX_LIGHTS {
+            let reason = format!("only a maximum of {MAX_LIGHTS} lights is supported");
+            return Err(khr_lights_punctual.unsupported(reason));
+        }
+
+        let light = khr_lights_punctual.get("light")?.lookup(&lights_json)?;
+        let color = match light.get_opt("color")? {
+            Some(color) => color.as_vec3()?,
+            None => Vec3::ONE,
+        };
+        let intensity = match light.get_opt("intensity")? {
+            Some(intensity) => intensity.as_f32()?,
+            None => 1.0,
+        };
+        let kind_json = light.get("type")?;
+        let kind = match kind_json.as_str()? {
+            "directional" => gltf::LightKind::Directional,
+            "point" => gltf::LightKind::Point,
+            "spot" => gltf::LightKind::Spot,
+            _ => return Err(kind_json.invalid("directional, point or spot")),
+        };
+        let casts_shadows = match light.get_opt("extras")? {
+            Some(extras) => match extras.get_opt("castShadows")? {
+                Some(casts_shadows) => casts_shadows.as_bool()?,
+                None => true,
+            },
+            None => true,
+        };
+        let range = match light.get_opt("range")? {
+            Some(range) => range.as_f32()?,
+            None => 0.0,
+        };
+        let transform = global_transforms[node_index];
+        let (inner_angle, outer_angle) = match light.get_opt("spot")? {
+            Some(spot) => (
+                match spot.get_opt("innerConeAngle")? {
+                    Some(angle) => angle.as_f32()?,
+                    None => 0.0,
+                },
+                match spot.get_opt("outerConeAngle")? {
+                    Some(angle) => angle.as_f32()?,
+                    None => FRAC_PI_4,
+                },
+            ),
+            None => (0.0, FRAC_PI_4),
+        };
+        // https://github.com/KhronosGroup/glTF/blob/main/extensions/2.0/Khronos/KHR_lights_punctual/README.md#inner-and-outer-cone-angles
+        let light_angle_scale = 1.0 / 0.001f32.max(inner_angle.cos() - outer_angle.cos());
+        let light_angle_offset = -outer_angle.cos() * light_angle_scale;
+
+        let i = light_node_index;
+        let kind_index = match kind {
+            gltf::LightKind::Directional => 1.0,
+            gltf::LightKind::Point => 2.0,
+            gltf::LightKind::Spot => 3.0,
+        };
+        lights_block.color_and_kind[i] = Vec4::from((color, kind_index));
+        // The intensities are used in their KHR_lights_punctual units, candela
+        // for point and spot lights and lux for directional ones, like in the
+        // Khronos sample viewer. Scenes are brought into the displayable range
+        // with the exposure, see PostProcessSettings::exposure.
+        lights_block.intensity_params[i] =
+            Vec4::new(intensity, light_angle_scale, light_angle_offset, range);
+        lights_block.position[i] = transform * Vec4::new(0.0, 0.0, 0.0, 1.0);
+        // Lights point towards their local -Z axis.
+        lights_block.direction[i] = transform * Vec4::new(0.0, 0.0, -1.0, 0.0);
+        lights.push(gltf::Light {
+            kind,
+            position: lights_block.position[i].truncate(),
+            direction: lights_block.direction[i].truncate().normalize_or_zero(),
+            range: (range > 0.0).then_some(range),
+            outer_cone_angle: outer_angle,
+            casts_shadows,
+        });
+        light_node_index += 1;
+    }
+    let lights_uniform_block =
+        (uniform_buffer.allocate(&lights_block)).binding(gltf::UNIFORM_BLOCK_LIGHTS);
+    // Skinned meshes replace this with their joint matrices at draw time, see
+    // DrawCalls::add_skinned.
+    let unskinned_uniform_block = (uniform_buffer.allocate(&gltf::UniformBlockSkin::zeroed()))
+        .binding(gltf::UNIFORM_BLOCK_SKIN);
+    label(
+        gl::BUFFER_KHR,
+        uniform_buffer.buffer().id(),
+        "uniform blocks",
+    );
 
-    let materials_json = gltf["materials"].get::<Vec<_>>().unwrap();
     let mut materials = Vec::with_capacity(materials_json.len());
-    for material in materials_json {
-        let unpack_texture_info = |texture_info: &JsonValue| {
-            let texture_info = texture_info.get::<HashMap<_, _>>().unwrap();
+    for material in &materials_json {
+        let unpack_texture_info = |texture_info: &Json| {
             // TODO: Support TEXCOORD_1
-            assert!(matches!(
-                texture_info.get("texCoord").map(take_usize),
-                None | Some(0)
-            ));
-            let texture = &textures_json[take_usize(&texture_info["index"])];
-            let texture = texture.get::<HashMap<_, _>>().unwrap();
-            let sampler = texture
-                .get("sampler")
-                .map(take_usize)
-                .unwrap_or(gl_samplers.len() - 1);
-            let source = take_usize(&texture["source"]);
-            (gl_textures[source], gl_samplers[sampler])
-        };
-
-        let material = material.get::<HashMap<_, _>>().unwrap();
+            if let Some(tex_coord) = texture_info.get_opt("texCoord")? {
+                if tex_coord.as_usize()? != 0 {
+                    return Err(tex_coord.unsupported("only TEXCOORD_0 is supported"));
+                }
+            }
+            let (texture, source) = get_texture(texture_info)?;
+            let sampler = match textures_json[texture].get_opt("sampler")? {
+                Some(sampler) => {
+                    sampler.lookup(&samplers_json)?;
+                    sampler.as_usize()?
+                }
+                None => gl_samplers.len() - 1,
+            };
+            Ok((gl_textures[source].clone(), gl_samplers[sampler].clone()))
+        };
+
         let mut material_buffer = gltf::UniformBlockMaterial {
             base_color_factor: Vec4::splat(1.0),
             metallic_factor: 1.0,
@@ -534,131 +1175,183 @@ pub fn load_gltf(gltf: &str, resources: &[(&str, &[u8])]) -> gltf::Gltf {
             normal_scale: 1.0,
             occlusion_strength: 1.0,
             emissive_factor: Vec4::splat(0.0),
+            alpha_cutoff: 0.0,
+            alpha_mode: 0.0,
+            _padding: [0.0; 2],
         };
+        let alpha_mode = get_alpha_mode(material)?;
+        match alpha_mode {
+            gltf::AlphaMode::Opaque => {}
+            gltf::AlphaMode::Mask { cutoff } => {
+                material_buffer.alpha_cutoff = cutoff;
+                material_buffer.alpha_mode = 1.0;
+            }
+            gltf::AlphaMode::Blend => material_buffer.alpha_mode = 2.0,
+        }
 
-        let mut textures = [None; 5];
-        if let Some(pbr) = material.get("pbrMetallicRoughness") {
-            let pbr = pbr.get::<HashMap<_, _>>().unwrap();
-            if let Some(texture_info) = pbr.get("baseColorTexture") {
-                let (texture, sampler) = unpack_texture_info(texture_info);
-                textures[0] = Some((gltf::TEX_UNIT_BASE_COLOR, texture, sampler));
-            } else {
-                textures[0] = Some((gltf::TEX_UNIT_BASE_COLOR, white_tex, default_sampler));
+        let mut textures = [
+            (gltf::TEX_UNIT_BASE_COLOR, &white_tex),
+            (gltf::TEX_UNIT_METALLIC_ROUGHNESS, &white_tex),
+            (gltf::TEX_UNIT_NORMAL, &normal_tex),
+            (gltf::TEX_UNIT_OCCLUSION, &white_tex),
+            (gltf::TEX_UNIT_EMISSIVE, &black_tex),
+        ]
+        .map(|(unit, texture)| Some((unit, texture.clone(), default_sampler.clone())));
+        if let Some(pbr) = material.get_opt("pbrMetallicRoughness")? {
+            if let Some(texture_info) = pbr.get_opt("baseColorTexture")? {
+                let (texture, sampler) = unpack_texture_info(&texture_info)?;
+                textures[gltf::MaterialTexture::BaseColor.index()] =
+                    Some((gltf::TEX_UNIT_BASE_COLOR, texture, sampler));
             }
-            if let Some(texture_info) = pbr.get("metallicRoughnessTexture") {
-                let (texture, sampler) = unpack_texture_info(texture_info);
-                textures[1] = Some((gltf::TEX_UNIT_METALLIC_ROUGHNESS, texture, sampler));
-            } else {
-                textures[1] = Some((
-                    gltf::TEX_UNIT_METALLIC_ROUGHNESS,
-                    white_tex,
-                    default_sampler,
-                ));
-            }
-            if let Some(factor) = pbr.get("baseColorFactor") {
-                let factor = factor.get::<Vec<_>>().unwrap();
-                let x = take_f32(&factor[0]);
-                let y = take_f32(&factor[1]);
-                let z = take_f32(&factor[2]);
-                let w = take_f32(&factor[3]);
-                material_buffer.base_color_factor = Vec4::new(x, y, z, w);
-            }
-            if let Some(factor) = pbr.get("metallicFactor") {
-                material_buffer.metallic_factor = take_f32(&factor);
-            }
-            if let Some(factor) = pbr.get("roughnessFactor") {
-                material_buffer.roughness_factor = take_f32(&factor);
-            }
-        }
-        if let Some(texture_info) = material.get("normalTexture") {
-            let (texture, sampler) = unpack_texture_info(texture_info);
-            textures[2] = Some((gltf::TEX_UNIT_NORMAL, texture, sampler));
-            let texture_info = texture_info.get::<HashMap<_, _>>().unwrap();
-            if let Some(factor) = texture_info.get("scale") {
-                material_buffer.normal_scale = take_f32(&factor);
+            if let Some(texture_info) = pbr.get_opt("metallicRoughnessTexture")? {
+                let (texture, sampler) = unpack_texture_info(&texture_info)?;
+                textures[gltf::MaterialTexture::MetallicRoughness.index()] =
+                    Some((gltf::TEX_UNIT_METALLIC_ROUGHNESS, texture, sampler));
+            }
+            if let Some(factor) = pbr.get_opt("baseColorFactor")? {
+                material_buffer.base_color_factor = Vec4::from_array(factor.as_floats()?);
+            }
+            if let Some(factor) = pbr.get_opt("metallicFactor")? {
+                material_buffer.metallic_factor = factor.as_f32()?;
+            }
+            if let Some(factor) = pbr.get_opt("roughnessFactor")? {
+                material_buffer.roughness_factor = factor.as_f32()?;
             }
-        } else {
-            textures[2] = Some((gltf::TEX_UNIT_NORMAL, normal_tex, default_sampler));
         }
-        if let Some(texture_info) = material.get("occlusionTexture") {
-            let (texture, sampler) = unpack_texture_info(texture_info);
-            textures[3] = Some((gltf::TEX_UNIT_OCCLUSION, texture, sampler));
-            let texture_info = texture_info.get::<HashMap<_, _>>().unwrap();
-            if let Some(factor) = texture_info.get("strength") {
-                material_buffer.occlusion_strength = take_f32(&factor);
+        if let Some(texture_info) = material.get_opt("normalTexture")? {
+            let (texture, sampler) = unpack_texture_info(&texture_info)?;
+            textures[gltf::MaterialTexture::Normal.index()] =
+                Some((gltf::TEX_UNIT_NORMAL, texture, sampler));
+            if let Some(factor) = texture_info.get_opt("scale")? {
+                material_buffer.normal_scale = factor.as_f32()?;
             }
-        } else {
-            textures[3] = Some((gltf::TEX_UNIT_OCCLUSION, white_tex, default_sampler));
         }
-        if let Some(texture_info) = material.get("emissiveTexture") {
-            let (texture, sampler) = unpack_texture_info(texture_info);
-            textures[4] = Some((gltf::TEX_UNIT_EMISSIVE, texture, sampler));
-        } else {
-            textures[4] = Some((gltf::TEX_UNIT_EMISSIVE, black_tex, default_sampler));
+        if let Some(texture_info) = material.get_opt("occlusionTexture")? {
+            let (texture, sampler) = unpack_texture_info(&texture_info)?;
+            textures[gltf::MaterialTexture::Occlusion.index()] =
+                Some((gltf::TEX_UNIT_OCCLUSION, texture, sampler));
+            if let Some(factor) = texture_info.get_opt("strength")? {
+                material_buffer.occlusion_strength = factor.as_f32()?;
+            }
+        }
+        if let Some(texture_info) = material.get_opt("emissiveTexture")? {
+            let (texture, sampler) = unpack_texture_info(&texture_info)?;
+            textures[gltf::MaterialTexture::Emissive.index()] =
+                Some((gltf::TEX_UNIT_EMISSIVE, texture, sampler));
         }
-        if let Some(factor) = material.get("emissiveFactor") {
-            let factor = factor.get::<Vec<_>>().unwrap();
-            let x = take_f32(&factor[0]);
-            let y = take_f32(&factor[1]);
-            let z = take_f32(&factor[2]);
-            material_buffer.emissive_factor = Vec4::new(x, y, z, 1.0);
+        if let Some(factor) = material.get_opt("emissiveFactor")? {
+            material_buffer.emissive_factor = Vec4::from((factor.as_vec3()?, 1.0));
         }
 
-        let material_data = [material_buffer];
-        let material_data = bytemuck::cast_slice(&material_data);
-        let (ubo, ubo_offset) = uniform_buffer_allocator.allocate_buffer(material_data);
-        let ubo_size = material_data.len();
+        let block = uniform_buffer.allocate(&material_buffer);
         let ubos = [
-            Some((gltf::UNIFORM_BLOCK_MATERIAL, ubo, ubo_offset, ubo_size)),
-            Some(lights_uniform_block),
+            Some(block.binding(gltf::UNIFORM_BLOCK_MATERIAL)),
+            Some(lights_uniform_block.clone()),
+            Some(unskinned_uniform_block.clone()),
         ];
 
         materials.push(gltf::Material {
-            name: material["name"].get::<String>().unwrap().clone(),
+            name: match material.get_opt("name")? {
+                Some(name) => name.as_str()?.to_string(),
+                None => String::new(),
+            },
+            alpha_mode,
+            double_sided: get_double_sided(material)?,
             uniforms: Uniforms { textures, ubos },
+            block,
         });
     }
 
-    let animations_json_fallback = Vec::with_capacity(0);
-    let animations_json = gltf
-        .get("animations")
-        .map(|v| v.get::<Vec<_>>().unwrap())
-        .unwrap_or(&animations_json_fallback);
-    let mut animations = Vec::with_capacity(animations_json.len());
-    for animation in animations_json {
-        let name = animation["name"].get::<String>().unwrap().to_string();
+    let mut animations = Vec::new();
+    for animation in gltf.array_opt("animations")? {
+        let name = match animation.get_opt("name")? {
+            Some(name) => name.as_str()?.to_string(),
+            None => String::new(),
+        };
         let mut start = f32::INFINITY;
         let mut end = f32::NEG_INFINITY;
         let mut nodes_animations = vec![Vec::new(); nodes.len()];
-        let samplers = animation["samplers"].get::<Vec<_>>().unwrap();
-        for channel in animation["channels"].get::<Vec<_>>().unwrap() {
-            let get_accessor_slice = |accessor: usize, bpc: usize| {
-                let (buffer, offset, count, ..) = unpack_accessor(accessor);
-                let length = count as usize * bpc;
-                get_buffer_slice(buffer, offset, length)
+        let samplers = animation.get("samplers")?.as_array()?;
+        for channel in animation.get("channels")?.as_array()? {
+            let sampler = channel.get("sampler")?.lookup(&samplers)?;
+            let target = channel.get("target")?;
+            let Some(node_json) = target.get_opt("node")? else {
+                continue; // Per the spec, channels without a node are ignored.
+            };
+            node_json.lookup(&nodes_json)?;
+            let node = node_json.as_usize()?;
+            let path_json = target.get("path")?;
+            let path = path_json.as_str()?;
+
+            let input_json = sampler.get("input")?;
+            let input = unpack_accessor(&input_json)?;
+            if input.type_ != gl::FLOAT || input.size != 1 || input.count == 0 {
+                return Err(input_json.invalid("an accessor of float timestamps"));
+            }
+            // Weight keyframes have a scalar per morph target of the mesh.
+            let values_per_keyframe = if path == "weights" {
+                let morph_target_count = match nodes[node].mesh_index {
+                    Some(mesh) => mesh_morph_target_counts[mesh],
+                    None => 0,
+                };
+                if morph_target_count == 0 {
+                    return Err(node_json.invalid("a node with a morphed mesh"));
+                }
+                morph_target_count
+            } else {
+                1
+            };
+            let output_json = sampler.get("output")?;
+            let output = unpack_accessor(&output_json)?;
+            let output_size = match path {
+                "rotation" => 4,
+                "weights" => 1,
+                _ => 3,
             };
+            if output.type_ != gl::FLOAT || output.size != output_size {
+                return Err(output_json.unsupported("only float keyframes are supported"));
+            }
+
+            let interpolation_json = sampler.get_opt("interpolation")?;
+            let interpolation = match &interpolation_json {
+                Some(interpolation) => match interpolation.as_str()? {
+                    "STEP" => gltf::Interpolation::Step,
+                    "LINEAR" => gltf::Interpolation::Linear,
+                    "CUBICSPLINE" => gltf::Interpolation::CubicSpline,
+                    _ => return Err(interpolation.invalid("STEP, LINEAR or CUBICSPLINE")),
+                },
+                None => gltf::Interpolation::Linear,
+            };
+            let keyframes_per_timestamp = match interpolation {
+                gltf::Interpolation::CubicSpline => 3,
+                _ => 1,
+            };
+            if output.count != input.count * keyframes_per_timestamp * values_per_keyframe as i32 {
+                return Err(output_json.invalid("a keyframe for each timestamp"));
+            }
 
-            let sampler = &samplers[take_usize(&channel["sampler"])];
-            let node = take_usize(&channel["target"]["node"]);
-            let path = channel["target"]["path"].get::<String>().unwrap().as_str();
-            let input_accessor = take_usize(&sampler["input"]);
-            let input = get_accessor_slice(input_accessor, 4);
-            let output_bpc = if path == "rotation" { 16 } else { 12 };
-            let output = get_accessor_slice(take_usize(&sampler["output"]), output_bpc);
-            let timestamps = bytemuck::pod_collect_to_vec(input);
+            let timestamps: Vec<f32> = bytemuck::pod_collect_to_vec(input.data);
             start = start.min(timestamps[0]);
             end = end.max(timestamps[timestamps.len() - 1]);
             let keyframes = match path {
-                "translation" => gltf::Keyframes::Translation(bytemuck::pod_collect_to_vec(output)),
-                "rotation" => gltf::Keyframes::Rotation(bytemuck::pod_collect_to_vec(output)),
-                "scale" => gltf::Keyframes::Scale(bytemuck::pod_collect_to_vec(output)),
-                target => panic!("unsupported animation target '{target}'"),
-            };
-            let interpolation = match sampler["interpolation"].get::<String>().unwrap().as_str() {
-                "STEP" => gltf::Interpolation::Step,
-                "LINEAR" => gltf::Interpolation::Linear,
-                "CUBICSPLINE" => gltf::Interpolation::CubicSpline,
-                interp => panic!("invalid interpolation '{interp}'"),
+                "translation" => {
+                    gltf::Keyframes::Translation(bytemuck::pod_collect_to_vec(output.data))
+                }
+                "rotation" => gltf::Keyframes::Rotation(bytemuck::pod_collect_to_vec(output.data)),
+                "scale" => gltf::Keyframes::Scale(bytemuck::pod_collect_to_vec(output.data)),
+                "weights" => {
+                    let weights: Vec<f32> = bytemuck::pod_collect_to_vec(output.data);
+                    let keyframes = weights.chunks(values_per_keyframe).map(|weights| {
+                        let mut keyframe = [0.0; MAX_MORPH_TARGETS];
+                        keyframe[..weights.len()].copy_from_slice(weights);
+                        keyframe
+                    });
+                    gltf::Keyframes::Weights(keyframes.collect())
+                }
+                target => {
+                    let reason = format!("unsupported animation target '{target}'");
+                    return Err(path_json.unsupported(reason));
+                }
             };
             nodes_animations[node].push(gltf::NodeAnimation {
                 timestamps,
@@ -674,50 +1367,601 @@ pub fn load_gltf(gltf: &str, resources: &[(&str, &[u8])]) -> gltf::Gltf {
         });
     }
 
-    gltf::Gltf {
+    Ok(gltf::Gltf {
+        id: gltf::NEXT_GLTF_ID.fetch_add(1, Ordering::Relaxed),
         scene,
         animations,
+        lights,
+        cameras,
         scenes,
         nodes,
+        skins,
         meshes,
         materials,
         primitives,
-        gl_vaos,
-        gl_buffers,
-        gl_textures,
-        gl_samplers,
-    }
+        uniform_buffer,
+    })
 }
 
-/// Return usize if JsonValue is a number, otherwise panic.
-fn take_usize(json_value: &JsonValue) -> usize {
-    let i: &f64 = json_value.get().unwrap();
-    *i as usize
+/// Uploads the morph target deltas into `texture` as a 2D array texture of
+/// RGB32F texels, which are read with texelFetch in the vertex shader.
+fn create_morph_targets_texture(
+    texture: &Texture,
+    width: usize,
+    height: usize,
+    layers: usize,
+    deltas: &[f32],
+) -> Result<(), gl::GlError> {
+    assert_eq!(width * height * layers * 3, deltas.len());
+    let target = gl::TEXTURE_2D_ARRAY;
+    gl::call!(gl::BindTexture(target, texture.id()));
+    // Float textures aren't filterable, so the texture is only complete with
+    // nearest filtering and no mipmaps.
+    let nearest = gl::NEAREST as i32;
+    gl::call!(gl::TexParameteri(target, gl::TEXTURE_MIN_FILTER, nearest));
+    gl::call!(gl::TexParameteri(target, gl::TEXTURE_MAG_FILTER, nearest));
+    gl::try_call!(gl::TexImage3D(
+        target,
+        0,
+        gl::RGB32F as i32,
+        width as i32,
+        height as i32,
+        layers as i32,
+        0,
+        gl::RGB,
+        gl::FLOAT,
+        deltas.as_ptr() as *const c_void,
+    ))
 }
 
-/// Return f32 if JsonValue is a number, otherwise panic.
-fn take_f32(json_value: &JsonValue) -> f32 {
-    let f: &f64 = json_value.get().unwrap();
-    *f as f32
+/// The location of an accessor's data, both in the GL buffers and on the CPU.
+struct Accessor<'a> {
+    buffer: usize,
+    byte_offset: usize,
+    count: gl::types::GLint,
+    size: gl::types::GLint,
+    type_: gl::types::GLenum,
+    normalized: bool,
+    data: &'a [u8],
 }
 
-/// Return Vec3 if JsonValue is an array, otherwise panic.
-fn take_vec3(json_value: &JsonValue) -> Vec3 {
-    let values: &Vec<JsonValue> = json_value.get().unwrap();
-    assert_eq!(3, values.len());
-    let x = *values[0].get::<f64>().unwrap() as f32;
-    let y = *values[1].get::<f64>().unwrap() as f32;
-    let z = *values[2].get::<f64>().unwrap() as f32;
-    Vec3::new(x, y, z)
-}
+#[cfg(test)]
+mod tests {
+    use super::{load_glb, load_gltf};
+    use crate::renderer::draw_calls::{DrawCalls, InstanceData};
+    use crate::renderer::fake_gl::{self, args_of};
+    use crate::renderer::gl;
+    use crate::renderer::gltf::{self, GltfError};
+    use crate::renderer::Projection;
+    use glam::{Mat4, Quat, Vec3};
+    use std::f32::consts::FRAC_PI_2;
+
+    /// A glTF with one triangle, with the positions and the indices in
+    /// `triangle.bin`, see [get_triangle_bin].
+    const TRIANGLE_GLTF: &str = r#"{
+        "asset": { "version": "2.0" },
+        "scenes": [{ "nodes": [0] }],
+        "nodes": [{ "mesh": 0 }],
+        "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0 }, "indices": 1 }] }],
+        "accessors": [
+            {
+                "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                "min": [0, 0, 0], "max": [1, 1, 0]
+            },
+            { "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" }
+        ],
+        "bufferViews": [
+            { "buffer": 0, "byteOffset": 0, "byteLength": 36 },
+            { "buffer": 0, "byteOffset": 36, "byteLength": 6 }
+        ],
+        "buffers": [{ "uri": "triangle.bin", "byteLength": 42 }]
+    }"#;
+
+    fn get_triangle_bin() -> Vec<u8> {
+        let positions = [Vec3::ZERO, Vec3::X, Vec3::Y];
+        let indices: [u16; 3] = [0, 1, 2];
+        let mut bin = bytemuck::cast_slice::<Vec3, u8>(&positions).to_vec();
+        bin.extend_from_slice(bytemuck::cast_slice(&indices));
+        bin
+    }
+
+    fn load_triangle() -> gltf::Gltf {
+        let bin = get_triangle_bin();
+        load_gltf("triangle.gltf", TRIANGLE_GLTF, &[("triangle.bin", &bin)]).unwrap()
+    }
+
+    #[test]
+    fn buffers_and_vertex_attributes_are_uploaded() {
+        fake_gl::install();
+        let gltf = load_triangle();
+        let calls = fake_gl::take_calls();
+
+        let bin = get_triangle_bin();
+        let buffer_data = args_of(&calls, "BufferData");
+        let array_buffer = gl::ARRAY_BUFFER as i64;
+        assert!(buffer_data.contains(&&[array_buffer, 42, gl::STATIC_READ as i64][..]));
+
+        // The position attribute reads from the start of the glTF buffer.
+        let position_index = (calls.iter())
+            .position(|call| call.name == "VertexAttribPointer")
+            .unwrap();
+        let float = gl::FLOAT as i64;
+        let position_args = [gltf::ATTR_LOC_POSITION as i64, 3, float, 0, 0, 0];
+        assert_eq!(&position_args[..], calls[position_index].args);
+        let bound_buffer = (calls[..position_index].iter().rev())
+            .find(|call| call.name == "BindBuffer" && call.args[0] == array_buffer)
+            .map(|call| call.args[1] as u32)
+            .unwrap();
+        assert_eq!(bin, fake_gl::buffer_contents(bound_buffer));
+
+        // The indices are copied into the index buffer.
+        let draw_call = &gltf.primitives[0].draw_call;
+        assert_eq!(
+            (gl::UNSIGNED_SHORT, 3),
+            (draw_call.index_type, draw_call.index_count)
+        );
+        let index_buffer = fake_gl::buffer_contents(draw_call.index_buffer.id());
+        let offset = draw_call.index_byte_offset;
+        assert_eq!(&bin[36..], &index_buffer[offset..offset + 6]);
+    }
+
+    #[test]
+    fn loaded_primitives_are_drawn_with_aligned_uniform_blocks() {
+        fake_gl::install();
+        fake_gl::set_uniform_buffer_offset_alignment(64);
+        let gltf = load_triangle();
+        let mut draw_calls = DrawCalls::new();
+        let model_transform = Mat4::from_translation(Vec3::new(0.0, 0.0, -5.0));
+        gltf.draw(&mut draw_calls, model_transform, InstanceData::default());
+        fake_gl::take_calls();
+        draw_calls.draw(
+            Mat4::IDENTITY,
+            Mat4::perspective_rh_gl(1.0, 1.0, 100.0, 0.1),
+            gltf::ATTR_LOC_MODEL_TRANSFORM_COLUMNS,
+            gltf::ATTR_LOC_MORPH_WEIGHTS,
+            gltf::ATTR_LOC_INSTANCE_DATA,
+        );
+        let calls = fake_gl::take_calls();
+
+        let draw_call = &gltf.primitives[0].draw_call;
+        let draws = args_of(&calls, "DrawElementsInstanced");
+        let offset = draw_call.index_byte_offset as i64;
+        let expected_draw = [
+            gl::TRIANGLES as i64,
+            3,
+            gl::UNSIGNED_SHORT as i64,
+            offset,
+            1,
+        ];
+        assert_eq!(vec![&expected_draw], draws);
 
-/// Return Quat if JsonValue is an array, otherwise panic.
-fn take_quat(json_value: &JsonValue) -> Quat {
-    let values: &Vec<JsonValue> = json_value.get().unwrap();
-    assert_eq!(4, values.len());
-    let x = *values[0].get::<f64>().unwrap() as f32;
-    let y = *values[1].get::<f64>().unwrap() as f32;
-    let z = *values[2].get::<f64>().unwrap() as f32;
-    let w = *values[3].get::<f64>().unwrap() as f32;
-    Quat::from_xyzw(x, y, z, w)
+        // The material, lights and skin blocks.
+        let ubo_bindings = args_of(&calls, "BindBufferRange");
+        assert_eq!(3, ubo_bindings.len());
+        for binding in ubo_bindings {
+            let [_, _, _, offset, _] = binding else {
+                panic!("unexpected arguments {binding:?}");
+            };
+            assert_eq!(0, offset % 64);
+        }
+    }
+
+    #[test]
+    fn failed_loads_delete_their_objects() {
+        fake_gl::install();
+        let result = load_gltf("triangle.gltf", TRIANGLE_GLTF, &[]);
+        assert!(matches!(result, Err(GltfError::MissingResource { .. })));
+        assert_eq!(0, fake_gl::live_objects());
+    }
+
+    #[test]
+    fn morph_targets_without_vertices_are_invalid() {
+        fake_gl::install();
+        let json = TRIANGLE_GLTF
+            .replace(
+                r#""indices": 1"#,
+                r#""indices": 1, "targets": [{ "POSITION": 0 }]"#,
+            )
+            .replacen(r#""count": 3"#, r#""count": 0"#, 1);
+        let bin = get_triangle_bin();
+        let result = load_gltf("triangle.gltf", &json, &[("triangle.bin", &bin)]);
+        assert!(matches!(result, Err(GltfError::Invalid { .. })));
+        assert_eq!(0, fake_gl::live_objects());
+    }
+
+    #[test]
+    fn gltfs_without_scenes_are_unsupported() {
+        fake_gl::install();
+        let bin = get_triangle_bin();
+        let without_scenes = TRIANGLE_GLTF.replace(r#""scenes": [{ "nodes": [0] }],"#, "");
+        let empty_scenes = TRIANGLE_GLTF.replace(r#"[{ "nodes": [0] }]"#, "[]");
+        for json in [without_scenes, empty_scenes] {
+            let result = load_gltf("triangle.gltf", &json, &[("triangle.bin", &bin)]);
+            assert!(matches!(result, Err(GltfError::Unsupported { .. })));
+        }
+        assert_eq!(0, fake_gl::live_objects());
+    }
+
+    #[test]
+    fn cyclic_and_shared_nodes_are_invalid() {
+        fake_gl::install();
+        let bin = get_triangle_bin();
+        let nodes = r#""nodes": [{ "mesh": 0 }],"#;
+        let cycle = r#""nodes": [{ "mesh": 0 }, { "children": [2] }, { "children": [1] }],"#;
+        let self_parent = r#""nodes": [{ "mesh": 0 }, { "children": [1] }],"#;
+        let shared = r#""nodes": [{ "mesh": 0, "children": [2] }, { "children": [2] }, {}],"#;
+        let child_in_scene = r#""nodes": [{ "mesh": 0 }, { "children": [0] }],"#;
+        for replacement in [cycle, self_parent, shared, child_in_scene] {
+            let json = TRIANGLE_GLTF.replace(nodes, replacement);
+            assert_ne!(TRIANGLE_GLTF, json);
+            let result = load_gltf("triangle.gltf", &json, &[("triangle.bin", &bin)]);
+            assert!(matches!(result, Err(GltfError::Invalid { .. })));
+        }
+        assert_eq!(0, fake_gl::live_objects());
+    }
+
+    #[test]
+    fn huge_counts_and_offsets_are_invalid() {
+        fake_gl::install();
+        let bin = get_triangle_bin();
+        let huge_count = TRIANGLE_GLTF.replacen(r#""count": 3"#, r#""count": 1e300"#, 1);
+        let overflowing_count =
+            TRIANGLE_GLTF.replacen(r#""count": 3"#, r#""count": 4611686018427387904"#, 1);
+        let overflowing_view = TRIANGLE_GLTF.replace(
+            r#""byteOffset": 36, "byteLength": 6"#,
+            r#""byteOffset": 9223372036854775808, "byteLength": 9223372036854775808"#,
+        );
+        for json in [huge_count, overflowing_count, overflowing_view] {
+            assert_ne!(TRIANGLE_GLTF, json);
+            let result = load_gltf("triangle.gltf", &json, &[("triangle.bin", &bin)]);
+            assert!(matches!(result, Err(GltfError::Invalid { .. })));
+        }
+        assert_eq!(0, fake_gl::live_objects());
+    }
+
+    #[test]
+    fn glb_chunks_past_the_end_are_invalid() {
+        fake_gl::install();
+        let mut glb = Vec::new();
+        for word in [super::GLB_MAGIC, 2, 20, u32::MAX, super::GLB_CHUNK_JSON] {
+            glb.extend_from_slice(&word.to_le_bytes());
+        }
+        let result = load_glb("chunk.glb", &glb);
+        assert!(matches!(result, Err(GltfError::Glb(_))));
+    }
+
+    #[test]
+    fn dropped_gltfs_delete_their_objects() {
+        fake_gl::install();
+        let glb = include_bytes!("../../../resources/models/testing-static/InterpolationTest.glb");
+        let gltf = load_glb("InterpolationTest.glb", glb).unwrap();
+        assert_ne!(0, fake_gl::live_objects());
+        drop(gltf);
+        assert_eq!(0, fake_gl::live_objects());
+    }
+
+    /// The triangle of [TRIANGLE_GLTF], a perspective camera, and an
+    /// orthographic camera under a node which moves 10 units along -Z over 2
+    /// seconds, with the data in `cameras.bin`, see [get_cameras_bin].
+    const CAMERAS_GLTF: &str = r#"{
+        "asset": { "version": "2.0" },
+        "scenes": [{ "nodes": [0, 1, 2] }],
+        "nodes": [
+            { "mesh": 0 },
+            { "name": "PerspectiveNode", "camera": 0, "translation": [0, 1, 5] },
+            { "name": "Pivot", "children": [3], "translation": [10, 0, 0] },
+            {
+                "name": "OrthographicNode", "camera": 1,
+                "rotation": [0, 0.70710677, 0, 0.70710677]
+            }
+        ],
+        "cameras": [
+            {
+                "name": "Perspective", "type": "perspective",
+                "perspective": { "yfov": 1.0, "znear": 0.1 }
+            },
+            {
+                "name": "Orthographic", "type": "orthographic",
+                "orthographic": { "xmag": 4, "ymag": 3, "znear": 0.5, "zfar": 50 }
+            }
+        ],
+        "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0 }, "indices": 1 }] }],
+        "animations": [{
+            "samplers": [{ "input": 2, "output": 3 }],
+            "channels": [{ "sampler": 0, "target": { "node": 2, "path": "translation" } }]
+        }],
+        "accessors": [
+            {
+                "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                "min": [0, 0, 0], "max": [1, 1, 0]
+            },
+            { "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" },
+            {
+                "bufferView": 2, "componentType": 5126, "count": 2, "type": "SCALAR",
+                "min": [0], "max": [2]
+            },
+            { "bufferView": 3, "componentType": 5126, "count": 2, "type": "VEC3" }
+        ],
+        "bufferViews": [
+            { "buffer": 0, "byteOffset": 0, "byteLength": 36 },
+            { "buffer": 0, "byteOffset": 36, "byteLength": 6 },
+            { "buffer": 0, "byteOffset": 44, "byteLength": 8 },
+            { "buffer": 0, "byteOffset": 52, "byteLength": 24 }
+        ],
+        "buffers": [{ "uri": "cameras.bin", "byteLength": 76 }]
+    }"#;
+
+    fn get_cameras_bin() -> Vec<u8> {
+        let timestamps = [0.0f32, 2.0];
+        let translations = [Vec3::new(10.0, 0.0, 0.0), Vec3::new(10.0, 0.0, -10.0)];
+        let mut bin = get_triangle_bin();
+        bin.extend_from_slice(&[0; 2]);
+        bin.extend_from_slice(bytemuck::cast_slice(&timestamps));
+        bin.extend_from_slice(bytemuck::cast_slice(&translations));
+        bin
+    }
+
+    fn load_cameras() -> gltf::Gltf {
+        let bin = get_cameras_bin();
+        load_gltf("cameras.gltf", CAMERAS_GLTF, &[("cameras.bin", &bin)]).unwrap()
+    }
+
+    #[test]
+    fn perspective_and_orthographic_cameras_are_loaded() {
+        fake_gl::install();
+        let gltf = load_cameras();
+
+        let perspective = gltf.get_camera("PerspectiveNode", Mat4::IDENTITY).unwrap();
+        assert_eq!(
+            Projection::Perspective { fov_y: 1.0 },
+            perspective.projection
+        );
+        assert_eq!(
+            (0.1, gltf::INFINITE_CAMERA_FAR),
+            (perspective.near, perspective.far)
+        );
+        assert!(perspective
+            .position
+            .abs_diff_eq(Vec3::new(0.0, 1.0, 5.0), 1e-6));
+        assert!(perspective.orientation.abs_diff_eq(Quat::IDENTITY, 1e-6));
+        // Cameras can also be looked up by the name of the camera.
+        assert_eq!(
+            Some(perspective),
+            gltf.get_camera("Perspective", Mat4::IDENTITY)
+        );
+        let model_transform = Mat4::from_translation(Vec3::new(1.0, 0.0, 0.0));
+        let moved = gltf.get_camera("Perspective", model_transform).unwrap();
+        assert!(moved.position.abs_diff_eq(Vec3::new(1.0, 1.0, 5.0), 1e-6));
+
+        // The orthographic camera is positioned by its parent node, with the
+        // glTF's half height converted to the full height.
+        let orthographic = gltf.get_camera("Orthographic", Mat4::IDENTITY).unwrap();
+        assert_eq!(
+            Projection::Orthographic { height: 6.0 },
+            orthographic.projection
+        );
+        assert_eq!((0.5, 50.0), (orthographic.near, orthographic.far));
+        assert!(orthographic
+            .position
+            .abs_diff_eq(Vec3::new(10.0, 0.0, 0.0), 1e-6));
+        let turned_left = Quat::from_rotation_y(FRAC_PI_2);
+        assert!(orthographic.orientation.abs_diff_eq(turned_left, 1e-6));
+
+        assert!(gltf.get_camera("Pivot", Mat4::IDENTITY).is_none());
+        assert!(gltf.get_camera("Missing", Mat4::IDENTITY).is_none());
+    }
+
+    #[test]
+    fn cameras_follow_their_animated_parents() {
+        fake_gl::install();
+        let gltf = load_cameras();
+        let mut transforms = gltf.get_node_transforms();
+        gltf.animations[0].animate_transforms(&mut transforms, 1.0);
+
+        let camera =
+            (gltf.get_animated_camera("Orthographic", Mat4::IDENTITY, &transforms)).unwrap();
+        assert!(camera
+            .position
+            .abs_diff_eq(Vec3::new(10.0, 0.0, -5.0), 1e-5));
+        let turned_left = Quat::from_rotation_y(FRAC_PI_2);
+        assert!(camera.orientation.abs_diff_eq(turned_left, 1e-6));
+        // The unanimated camera stays where it was.
+        let perspective =
+            (gltf.get_animated_camera("Perspective", Mat4::IDENTITY, &transforms)).unwrap();
+        assert!(perspective
+            .position
+            .abs_diff_eq(Vec3::new(0.0, 1.0, 5.0), 1e-6));
+    }
+
+    #[test]
+    fn lod_chains_are_loaded_from_msft_lod_and_node_names() {
+        fake_gl::install();
+        let json = TRIANGLE_GLTF
+            .replace(r#""nodes": [0] }"#, r#""nodes": [0, 3, 4, 5] }"#)
+            .replace(
+                r#""nodes": [{ "mesh": 0 }]"#,
+                r#""nodes": [
+                    {
+                        "name": "Rock", "mesh": 0,
+                        "extensions": { "MSFT_lod": { "ids": [1, 2] } },
+                        "extras": { "MSFT_screencoverage": [0.5, 0.2, 0.1, 0.05] }
+                    },
+                    { "name": "Rock (less detail)", "mesh": 0 },
+                    { "name": "Rock (least detail)", "mesh": 0 },
+                    { "name": "Tree_LOD0", "mesh": 0 },
+                    { "name": "Tree_LOD2", "mesh": 0 },
+                    { "name": "Tree_LOD1", "mesh": 0 }
+                ]"#,
+            );
+        assert_ne!(TRIANGLE_GLTF, json);
+        let bin = get_triangle_bin();
+        let gltf = load_gltf("lods.gltf", &json, &[("triangle.bin", &bin)]).unwrap();
+
+        // The screen coverages are cut to one per level, plus one for culling.
+        let rock = &gltf.nodes[0];
+        assert_eq!(vec![1, 2], rock.lod_node_indices);
+        assert_eq!(vec![0.5, 0.2, 0.1], rock.lod_screen_coverages);
+        // The suffixed nodes are sorted by level, with default coverages,
+        // and the lower levels are only drawn through the first one.
+        let tree = &gltf.nodes[3];
+        assert_eq!(vec![5, 4], tree.lod_node_indices);
+        assert_eq!(vec![0.1, 0.05], tree.lod_screen_coverages);
+        assert_eq!(vec![0, 3], gltf.scenes[0].node_indices);
+    }
+
+    #[test]
+    fn lod_chains_draw_nothing_below_the_culling_coverage() {
+        fake_gl::install();
+        let json = TRIANGLE_GLTF.replace(
+            r#""nodes": [{ "mesh": 0 }]"#,
+            r#""nodes": [
+                {
+                    "mesh": 0,
+                    "extensions": { "MSFT_lod": { "ids": [1] } },
+                    "extras": { "MSFT_screencoverage": [0.5, 0.1] }
+                },
+                { "mesh": 0 }
+            ]"#,
+        );
+        assert_ne!(TRIANGLE_GLTF, json);
+        let bin = get_triangle_bin();
+        let gltf = load_gltf("lods.gltf", &json, &[("triangle.bin", &bin)]).unwrap();
+
+        // The triangle's bounding sphere has a radius of ~0.71, so with a 90
+        // degree field of view, it covers ~0.71 / distance of the screen.
+        let proj_from_view = Mat4::perspective_rh_gl(FRAC_PI_2, 1.0, 100.0, 0.1);
+        let mut draw_calls = DrawCalls::new();
+        for (distance, expected_draws) in [(2.0, 1), (20.0, 0)] {
+            draw_calls.clear();
+            draw_calls.set_lod_view(Mat4::IDENTITY, proj_from_view);
+            let model_transform = Mat4::from_translation(Vec3::new(-0.5, -0.5, -distance));
+            gltf.draw(&mut draw_calls, model_transform, InstanceData::default());
+            fake_gl::take_calls();
+            draw_calls.draw(
+                Mat4::IDENTITY,
+                proj_from_view,
+                gltf::ATTR_LOC_MODEL_TRANSFORM_COLUMNS,
+                gltf::ATTR_LOC_MORPH_WEIGHTS,
+                gltf::ATTR_LOC_INSTANCE_DATA,
+            );
+            let calls = fake_gl::take_calls();
+            let draws = args_of(&calls, "DrawElementsInstanced");
+            assert_eq!(expected_draws, draws.len(), "at distance {distance}");
+        }
+    }
+
+    /// Returns the triangle of [TRIANGLE_GLTF] skinned to two joints, with
+    /// the inverse bind matrices after the triangle in the buffer, see
+    /// [get_skin_bin].
+    fn get_skin_gltf(joints: &str) -> String {
+        let json = TRIANGLE_GLTF
+            .replace(
+                r#""nodes": [{ "mesh": 0 }]"#,
+                &format!(
+                    r#""nodes": [
+                        {{ "mesh": 0, "skin": 0, "children": [1] }},
+                        {{ "name": "Root", "translation": [0, 1, 0], "children": [2] }},
+                        {{ "name": "Tip", "translation": [0, 2, 0] }}
+                    ],
+                    "skins": [{{ "joints": {joints}, "inverseBindMatrices": 2 }}]"#
+                ),
+            )
+            .replace(
+                r#"{ "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" }"#,
+                r#"{ "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" },
+                { "bufferView": 2, "componentType": 5126, "count": 2, "type": "MAT4" }"#,
+            )
+            .replace(
+                r#"{ "buffer": 0, "byteOffset": 36, "byteLength": 6 }"#,
+                r#"{ "buffer": 0, "byteOffset": 36, "byteLength": 6 },
+                { "buffer": 0, "byteOffset": 44, "byteLength": 128 }"#,
+            )
+            .replace(r#""byteLength": 42 }]"#, r#""byteLength": 172 }]"#);
+        assert_ne!(TRIANGLE_GLTF, json);
+        json
+    }
+
+    /// The inverse bind matrices of [get_skin_gltf]'s joints, which undo
+    /// their original translations.
+    const INVERSE_BIND_MATRICES: [Mat4; 2] = [
+        Mat4::from_cols_array(&[
+            1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, -1.0, 0.0, 1.0,
+        ]),
+        Mat4::from_cols_array(&[
+            1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, -3.0, 0.0, 1.0,
+        ]),
+    ];
+
+    fn get_skin_bin() -> Vec<u8> {
+        let mut bin = get_triangle_bin();
+        bin.extend_from_slice(&[0; 2]);
+        bin.extend_from_slice(bytemuck::cast_slice(&INVERSE_BIND_MATRICES));
+        bin
+    }
+
+    #[test]
+    fn skinned_primitives_are_drawn_with_their_joint_matrices() {
+        fake_gl::install();
+        let json = get_skin_gltf("[1, 2]");
+        let bin = get_skin_bin();
+        let gltf = load_gltf("skin.gltf", &json, &[("triangle.bin", &bin)]).unwrap();
+
+        // In the bind pose, the joint matrices cancel out.
+        let mut transforms = gltf.get_node_transforms();
+        assert_eq!(
+            vec![Mat4::IDENTITY; 2],
+            gltf.get_joint_matrices(0, &transforms)
+        );
+        // Posed joints move their children with them.
+        let lift = Mat4::from_translation(Vec3::new(0.0, 0.0, 1.0));
+        transforms[1].transform = lift * transforms[1].transform;
+        assert_eq!(vec![lift; 2], gltf.get_joint_matrices(0, &transforms));
+
+        let mut draw_calls = DrawCalls::new();
+        let model_transform = Mat4::from_translation(Vec3::new(0.0, 0.0, -5.0));
+        gltf.draw_animated(
+            &mut draw_calls,
+            model_transform,
+            &transforms,
+            InstanceData::default(),
+        );
+        fake_gl::take_calls();
+        draw_calls.draw(
+            Mat4::IDENTITY,
+            Mat4::perspective_rh_gl(1.0, 1.0, 100.0, 0.1),
+            gltf::ATTR_LOC_MODEL_TRANSFORM_COLUMNS,
+            gltf::ATTR_LOC_MORPH_WEIGHTS,
+            gltf::ATTR_LOC_INSTANCE_DATA,
+        );
+        let calls = fake_gl::take_calls();
+        let skin_bindings = args_of(&calls, "BindBufferRange")
+            .into_iter()
+            .filter(|args| args[1] == gltf::UNIFORM_BLOCK_SKIN as i64)
+            .collect::<Vec<_>>();
+        let [[_, _, buffer, offset, size]] = skin_bindings[..] else {
+            panic!("expected one skin binding, got {skin_bindings:?}");
+        };
+        let contents = fake_gl::buffer_contents(*buffer as u32);
+        let (offset, size) = (*offset as usize, *size as usize);
+        let skin: gltf::UniformBlockSkin =
+            bytemuck::pod_read_unaligned(&contents[offset..offset + size]);
+        assert_eq!(2, skin.joint_count);
+        assert_eq!([lift; 2], skin.joint_matrices[..2]);
+    }
+
+    #[test]
+    fn skins_with_too_many_joints_are_unsupported() {
+        fake_gl::install();
+        let joints = vec!["1"; gltf::MAX_JOINTS + 1].join(", ");
+        let json = get_skin_gltf(&format!("[{joints}]"));
+        let bin = get_skin_bin();
+        let result = load_gltf("skin.gltf", &json, &[("triangle.bin", &bin)]);
+        assert!(matches!(result, Err(GltfError::Unsupported { .. })));
+        assert_eq!(0, fake_gl::live_objects());
+
+        // Mismatched inverse bind matrices are invalid.
+        let json = get_skin_gltf("[1]");
+        let result = load_gltf("skin.gltf", &json, &[("triangle.bin", &bin)]);
+        assert!(matches!(result, Err(GltfError::Invalid { .. })));
+    }
 }
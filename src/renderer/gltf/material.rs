@@ -0,0 +1,331 @@
+use crate::renderer::gltf::{Gltf, Material, UniformBlockMaterial, UNIFORM_BLOCK_MATERIAL};
+use crate::renderer::handles::{Sampler, Texture};
+use glam::{Vec3, Vec4};
+
+/// The factors of a glTF material, which multiply the values sampled from the
+/// respective textures.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MaterialFactors {
+    pub base_color: Vec4,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub emissive: Vec3,
+    pub normal_scale: f32,
+    pub occlusion_strength: f32,
+}
+
+/// The texture slots of a glTF material.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MaterialTexture {
+    BaseColor,
+    MetallicRoughness,
+    Normal,
+    Occlusion,
+    Emissive,
+}
+
+impl MaterialTexture {
+    /// The index of the texture in the material's uniforms, which is also its
+    /// texture unit.
+    pub(super) fn index(self) -> usize {
+        match self {
+            MaterialTexture::BaseColor => 0,
+            MaterialTexture::MetallicRoughness => 1,
+            MaterialTexture::Normal => 2,
+            MaterialTexture::Occlusion => 3,
+            MaterialTexture::Emissive => 4,
+        }
+    }
+}
+
+impl Gltf {
+    pub fn materials(&self) -> &[Material] {
+        &self.materials
+    }
+
+    /// Returns the index of the first material with the given name.
+    pub fn find_material(&self, name: &str) -> Option<usize> {
+        (self.materials.iter()).position(|material| material.name == name)
+    }
+
+    pub fn material_factors(&self, material_index: usize) -> MaterialFactors {
+        let block = self
+            .uniform_buffer
+            .get(&self.materials[material_index].block);
+        MaterialFactors {
+            base_color: block.base_color_factor,
+            metallic: block.metallic_factor,
+            roughness: block.roughness_factor,
+            emissive: block.emissive_factor.truncate(),
+            normal_scale: block.normal_scale,
+            occlusion_strength: block.occlusion_strength,
+        }
+    }
+
+    /// Changes the material's factors for every primitive using it. The
+    /// factors are updated in place, so draws already queued in a
+    /// [DrawCalls] that hasn't been drawn yet use the new factors too, like
+    /// with any `UniformBufferAllocator::update`.
+    ///
+    /// [DrawCalls]: crate::renderer::DrawCalls
+    pub fn set_material_factors(&mut self, material_index: usize, factors: &MaterialFactors) {
+        let block = &self.materials[material_index].block;
+        let block_data = UniformBlockMaterial {
+            base_color_factor: factors.base_color,
+            metallic_factor: factors.metallic,
+            roughness_factor: factors.roughness,
+            emissive_factor: Vec4::from((factors.emissive, 1.0)),
+            normal_scale: factors.normal_scale,
+            occlusion_strength: factors.occlusion_strength,
+            ..self.uniform_buffer.get(block)
+        };
+        self.uniform_buffer.update(block, &block_data);
+    }
+
+    /// Returns the texture and sampler objects bound to the slot. Every slot
+    /// has a texture: the loader fills the slots the glTF doesn't use with
+    /// default textures, which don't change the factors.
+    pub fn material_texture(
+        &self,
+        material_index: usize,
+        slot: MaterialTexture,
+    ) -> (Texture, Sampler) {
+        let textures = &self.materials[material_index].uniforms.textures;
+        let (_, texture, sampler) =
+            (textures[slot.index()].as_ref()).expect("materials have a texture in every slot");
+        (texture.clone(), sampler.clone())
+    }
+
+    /// Replaces the texture in the slot. The material shares the ownership
+    /// of the texture and sampler objects, so they stay alive while it uses
+    /// them. Unlike the factors, the textures are copied into the draw calls
+    /// when queued, so only draws queued after this use the new texture.
+    pub fn set_material_texture(
+        &mut self,
+        material_index: usize,
+        slot: MaterialTexture,
+        texture: Texture,
+        sampler: Sampler,
+    ) {
+        let textures = &mut self.materials[material_index].uniforms.textures;
+        textures[slot.index()] = Some((slot.index() as u32, texture, sampler));
+    }
+
+    /// Adds a copy of the material with its own factors and textures, and
+    /// returns its index. The copy isn't used by any primitive, but can be
+    /// drawn in place of the original with [Gltf::draw_with_materials], e.g.
+    /// to change the color of a single instance.
+    pub fn clone_material(&mut self, material_index: usize, name: &str) -> usize {
+        let original = &self.materials[material_index];
+        let block_data = self.uniform_buffer.get(&original.block);
+        let block = self.uniform_buffer.allocate(&block_data);
+        let mut uniforms = original.uniforms.clone();
+        for ubo in uniforms.ubos.iter_mut().flatten() {
+            if ubo.0 == UNIFORM_BLOCK_MATERIAL {
+                *ubo = block.binding(UNIFORM_BLOCK_MATERIAL);
+            }
+        }
+        self.materials.push(Material {
+            name: name.to_string(),
+            alpha_mode: original.alpha_mode,
+            double_sided: original.double_sided,
+            uniforms,
+            block,
+        });
+        self.materials.len() - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MaterialFactors, MaterialTexture};
+    use crate::renderer::draw_calls::{DrawCalls, InstanceData};
+    use crate::renderer::fake_gl::{self, args_of};
+    use crate::renderer::gl;
+    use crate::renderer::gltf::{
+        self, load_gltf, UniformBlockMaterial, UNIFORM_BLOCK_LIGHTS, UNIFORM_BLOCK_MATERIAL,
+    };
+    use crate::renderer::handles::{Sampler, Texture};
+    use glam::{Mat4, Vec3, Vec4};
+    use std::mem;
+
+    /// A glTF with a red and a blue material, and a triangle using the red
+    /// one, with the positions and the indices in `triangle.bin`, see
+    /// [get_triangle_bin].
+    const MATERIALS_GLTF: &str = r#"{
+        "asset": { "version": "2.0" },
+        "scenes": [{ "nodes": [0] }],
+        "nodes": [{ "mesh": 0 }],
+        "meshes": [{
+            "primitives": [{ "attributes": { "POSITION": 0 }, "indices": 1, "material": 0 }]
+        }],
+        "materials": [
+            {
+                "name": "Red",
+                "pbrMetallicRoughness": { "baseColorFactor": [1, 0, 0, 1], "metallicFactor": 0 }
+            },
+            { "name": "Blue", "pbrMetallicRoughness": { "baseColorFactor": [0, 0, 1, 1] } }
+        ],
+        "accessors": [
+            {
+                "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                "min": [0, 0, 0], "max": [1, 1, 0]
+            },
+            { "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" }
+        ],
+        "bufferViews": [
+            { "buffer": 0, "byteOffset": 0, "byteLength": 36 },
+            { "buffer": 0, "byteOffset": 36, "byteLength": 6 }
+        ],
+        "buffers": [{ "uri": "triangle.bin", "byteLength": 42 }]
+    }"#;
+
+    fn get_triangle_bin() -> Vec<u8> {
+        let positions = [Vec3::ZERO, Vec3::X, Vec3::Y];
+        let indices: [u16; 3] = [0, 1, 2];
+        let mut bin = bytemuck::cast_slice::<Vec3, u8>(&positions).to_vec();
+        bin.extend_from_slice(bytemuck::cast_slice(&indices));
+        bin
+    }
+
+    fn load_materials() -> gltf::Gltf {
+        let bin = get_triangle_bin();
+        load_gltf("materials.gltf", MATERIALS_GLTF, &[("triangle.bin", &bin)]).unwrap()
+    }
+
+    /// Returns the material's uniform buffer binding of the block as
+    /// `(binding, buffer, offset, size)`.
+    fn ubo(gltf: &gltf::Gltf, material_index: usize, binding: u32) -> (u32, u32, usize, usize) {
+        let ubos = &gltf.materials()[material_index].uniforms.ubos;
+        (ubos.iter().flatten())
+            .find(|ubo| ubo.0 == binding)
+            .map(|(binding, buffer, offset, size)| (*binding, buffer.id(), *offset, *size))
+            .unwrap()
+    }
+
+    /// Returns the material's block as uploaded to the fake GL buffer.
+    fn uploaded_block(gltf: &gltf::Gltf, material_index: usize) -> UniformBlockMaterial {
+        let (_, buffer, offset, size) = ubo(gltf, material_index, UNIFORM_BLOCK_MATERIAL);
+        let contents = fake_gl::buffer_contents(buffer);
+        bytemuck::pod_read_unaligned(&contents[offset..offset + size])
+    }
+
+    #[test]
+    fn materials_are_found_by_name() {
+        fake_gl::install();
+        let mut gltf = load_materials();
+        assert_eq!(Some(0), gltf.find_material("Red"));
+        assert_eq!(Some(1), gltf.find_material("Blue"));
+        assert_eq!(None, gltf.find_material("Green"));
+        let copy = gltf.clone_material(1, "Green");
+        assert_eq!(Some(copy), gltf.find_material("Green"));
+    }
+
+    #[test]
+    fn material_factors_are_updated_in_the_uniform_buffer() {
+        fake_gl::install();
+        let mut gltf = load_materials();
+        let factors = MaterialFactors {
+            base_color: Vec4::new(0.0, 1.0, 0.0, 0.5),
+            metallic: 0.25,
+            roughness: 0.75,
+            emissive: Vec3::new(1.0, 2.0, 3.0),
+            normal_scale: 0.5,
+            occlusion_strength: 0.125,
+        };
+        gltf.set_material_factors(0, &factors);
+        assert_eq!(factors, gltf.material_factors(0));
+
+        let block = uploaded_block(&gltf, 0);
+        assert_eq!(factors.base_color, block.base_color_factor);
+        assert_eq!(
+            (0.25, 0.75, 0.5, 0.125),
+            (
+                block.metallic_factor,
+                block.roughness_factor,
+                block.normal_scale,
+                block.occlusion_strength
+            )
+        );
+        assert_eq!(Vec4::new(1.0, 2.0, 3.0, 1.0), block.emissive_factor);
+        // The other material's block is left as it was.
+        let blue_block = uploaded_block(&gltf, 1);
+        assert_eq!(Vec4::new(0.0, 0.0, 1.0, 1.0), blue_block.base_color_factor);
+        assert_eq!(1.0, blue_block.metallic_factor);
+    }
+
+    #[test]
+    fn cloned_materials_get_their_own_block() {
+        fake_gl::install();
+        let mut gltf = load_materials();
+        let copy = gltf.clone_material(0, "Red (copy)");
+        assert_eq!("Red (copy)", gltf.materials()[copy].name);
+        assert_eq!(gltf.material_factors(0), gltf.material_factors(copy));
+
+        let binding = ubo(&gltf, 0, UNIFORM_BLOCK_MATERIAL);
+        let copy_binding = ubo(&gltf, copy, UNIFORM_BLOCK_MATERIAL);
+        assert_eq!((binding.0, binding.1), (copy_binding.0, copy_binding.1));
+        assert_ne!(binding.2, copy_binding.2);
+        assert_eq!(mem::size_of::<UniformBlockMaterial>(), copy_binding.3);
+        let (block, copy_block) = (uploaded_block(&gltf, 0), uploaded_block(&gltf, copy));
+        assert_eq!(bytemuck::bytes_of(&block), bytemuck::bytes_of(&copy_block));
+        // The blocks shared by all materials are shared by the copy too.
+        assert_eq!(
+            ubo(&gltf, 0, UNIFORM_BLOCK_LIGHTS),
+            ubo(&gltf, copy, UNIFORM_BLOCK_LIGHTS),
+        );
+
+        // Changing the copy leaves the original as it was.
+        let mut factors = gltf.material_factors(copy);
+        factors.base_color = Vec4::ONE;
+        gltf.set_material_factors(copy, &factors);
+        let (block, copy_block) = (uploaded_block(&gltf, 0), uploaded_block(&gltf, copy));
+        assert_eq!(Vec4::new(1.0, 0.0, 0.0, 1.0), block.base_color_factor);
+        assert_eq!(Vec4::ONE, copy_block.base_color_factor);
+    }
+
+    #[test]
+    fn replaced_textures_are_bound_for_draws_queued_afterwards() {
+        fake_gl::install();
+        let mut gltf = load_materials();
+        let block_before = uploaded_block(&gltf, 0);
+        let (default_texture, _) = gltf.material_texture(0, MaterialTexture::Emissive);
+        let texture = Texture::new();
+        let sampler = Sampler::try_new().unwrap();
+        let texture_id = texture.id();
+        gltf.set_material_texture(0, MaterialTexture::Emissive, texture, sampler.clone());
+        assert!(fake_gl::is_live("texture", texture_id));
+
+        let (bound_texture, bound_sampler) = gltf.material_texture(0, MaterialTexture::Emissive);
+        assert_eq!((texture_id, sampler), (bound_texture.id(), bound_sampler));
+        assert_ne!(default_texture, bound_texture);
+        // Only the texture changes, the block keeps its contents.
+        let block = uploaded_block(&gltf, 0);
+        assert_eq!(
+            bytemuck::bytes_of(&block_before),
+            bytemuck::bytes_of(&block)
+        );
+
+        let mut draw_calls = DrawCalls::new();
+        let model_transform = Mat4::from_translation(Vec3::new(0.0, 0.0, -5.0));
+        gltf.draw(&mut draw_calls, model_transform, InstanceData::default());
+        fake_gl::take_calls();
+        draw_calls.draw(
+            Mat4::IDENTITY,
+            Mat4::perspective_rh_gl(1.0, 1.0, 100.0, 0.1),
+            gltf::ATTR_LOC_MODEL_TRANSFORM_COLUMNS,
+            gltf::ATTR_LOC_MORPH_WEIGHTS,
+            gltf::ATTR_LOC_INSTANCE_DATA,
+        );
+        let calls = fake_gl::take_calls();
+        let emissive_unit = gl::TEXTURE0 as i64 + gltf::TEX_UNIT_EMISSIVE as i64;
+        let unit_index = (calls.iter())
+            .position(|call| call.name == "ActiveTexture" && call.args[0] == emissive_unit)
+            .unwrap();
+        let bound = (calls[unit_index..].iter())
+            .find(|call| call.name == "BindTexture")
+            .unwrap();
+        assert_eq!(texture_id as i64, bound.args[1]);
+        assert_eq!(1, args_of(&calls, "DrawElementsInstanced").len());
+    }
+}
@@ -1,28 +1,50 @@
-use crate::renderer::draw_calls::{DrawCall, DrawCalls, Uniforms};
-use crate::renderer::gl;
-use glam::Mat4;
+use crate::renderer::bounds::Aabb;
+use crate::renderer::draw_calls::{
+    DrawCall, DrawCalls, DrawSource, Instance, InstanceData, Uniforms,
+};
+use crate::renderer::uniform_buffer::{UniformBlock, UniformBufferAllocator};
+use crate::renderer::{self, gl, Projection};
+use glam::{Mat4, Vec3};
+use std::sync::atomic::AtomicU64;
 
 mod animation;
+mod error;
+mod json;
 mod loader;
+mod material;
 mod program;
+mod skin;
 
 pub use animation::*;
-pub use loader::load_gltf;
+pub use error::GltfError;
+pub use loader::{load_glb, load_gltf};
+pub use material::*;
 pub use program::*;
+pub use skin::*;
+
+/// Used to give each loaded glTF an unique id, see [Gltf::id].
+static NEXT_GLTF_ID: AtomicU64 = AtomicU64::new(0);
 
 pub struct Gltf {
+    /// Identifies this glTF for remembering its instances' levels of detail
+    /// between frames.
+    id: u64,
+    /// The index of the scene which is drawn. The loader makes sure there's
+    /// at least one.
     pub scene: usize,
     pub animations: Vec<Animation>,
+    /// The KHR_lights_punctual lights of the glTF, in the same order as in the
+    /// lights uniform block.
+    pub lights: Vec<Light>,
+    pub cameras: Vec<Camera>,
     scenes: Vec<Scene>,
     nodes: Vec<Node>,
+    skins: Vec<Skin>,
     meshes: Vec<Mesh>,
     materials: Vec<Material>,
     primitives: Vec<Primitive>,
-
-    gl_vaos: Vec<gl::types::GLuint>,
-    gl_buffers: Vec<gl::types::GLuint>,
-    gl_textures: Vec<gl::types::GLuint>,
-    gl_samplers: Vec<gl::types::GLuint>,
+    /// Holds the material and lights uniform blocks.
+    uniform_buffer: UniformBufferAllocator,
 }
 
 pub struct Scene {
@@ -33,27 +55,114 @@ pub struct Node {
     pub name: String,
     pub transform: Mat4,
     pub original_transform: Mat4,
+    /// The default morph target weights of the node's mesh.
+    pub morph_weights: [f32; MAX_MORPH_TARGETS],
     mesh_index: Option<usize>,
+    skin_index: Option<usize>,
+    camera_index: Option<usize>,
     child_node_indices: Vec<usize>,
+    /// The nodes which replace this one (and its children) at lower levels of
+    /// detail, from the most detailed to the least detailed.
+    lod_node_indices: Vec<usize>,
+    /// The smallest fraction of the screen's height this node's mesh should
+    /// cover to be drawn at each level of detail, see
+    /// [crate::renderer::lod::LodSelector::select]. If there's a threshold for
+    /// the level after the last one, smaller instances are not drawn at all.
+    lod_screen_coverages: Vec<f32>,
 }
 
 pub struct Mesh {
     primitive_indices: Vec<usize>,
+    /// The union of the primitives' bounds, if they're all known.
+    bounds: Option<Aabb>,
 }
 
 pub struct Primitive {
     pub draw_call: DrawCall,
+    /// The bounding box of the primitive in model space, if the glTF
+    /// specifies it.
+    pub bounds: Option<Aabb>,
     material_index: usize,
 }
 
+pub struct Light {
+    pub kind: LightKind,
+    /// The position of the light in the glTF's world space.
+    pub position: Vec3,
+    /// The direction the light is pointing towards in the glTF's world space.
+    pub direction: Vec3,
+    /// The distance where the light's intensity reaches zero, or None if the
+    /// intensity just falls off with the inverse square law.
+    pub range: Option<f32>,
+    pub outer_cone_angle: f32,
+    /// Whether the light should be rendered into a shadow map. Can be turned
+    /// off per light in the glTF by setting `"castShadows": false` in the
+    /// light's extras.
+    pub casts_shadows: bool,
+}
+
+/// A camera defined in the glTF. The aspect ratio of the viewport is used
+/// for rendering, even if the glTF specifies one.
+pub struct Camera {
+    pub name: String,
+    pub projection: Projection,
+    pub near: f32,
+    /// The far plane, or [INFINITE_CAMERA_FAR] if the glTF leaves it out.
+    pub far: f32,
+}
+
+/// Used as the far plane distance for glTF cameras with an infinite
+/// projection, as the renderer requires a finite far plane.
+pub const INFINITE_CAMERA_FAR: f32 = 1000.0;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LightKind {
+    Directional,
+    Point,
+    Spot,
+}
+
 pub struct Material {
     pub name: String,
+    pub alpha_mode: AlphaMode,
+    pub double_sided: bool,
     pub uniforms: Uniforms,
+    block: UniformBlock<UniformBlockMaterial>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AlphaMode {
+    Opaque,
+    /// Fragments with an alpha value below the cutoff are discarded, the rest
+    /// are drawn as opaque.
+    Mask {
+        cutoff: f32,
+    },
+    /// Drawn after the opaque primitives, blended with what's behind them.
+    Blend,
 }
 
 impl Gltf {
-    pub fn draw(&self, draw_calls: &mut DrawCalls, model_transform: Mat4) {
-        self._draw(draw_calls, model_transform, |i| self.nodes[i].transform)
+    /// Returns an id which is unique to this glTF within the process, for
+    /// telling which glTF a [crate::renderer::PickResult] refers to.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn draw(
+        &self,
+        draw_calls: &mut DrawCalls,
+        model_transform: Mat4,
+        instance_data: InstanceData,
+    ) {
+        self._draw(
+            draw_calls,
+            model_transform,
+            instance_data,
+            &[],
+            |i| self.nodes[i].transform,
+            |i| self.nodes[i].morph_weights,
+        )
     }
 
     pub fn draw_animated(
@@ -61,37 +170,124 @@ impl Gltf {
         draw_calls: &mut DrawCalls,
         model_transform: Mat4,
         node_transforms: &[NodeTransform],
+        instance_data: InstanceData,
     ) {
-        self._draw(draw_calls, model_transform, |i| {
-            node_transforms[i].transform
-        })
+        self._draw(
+            draw_calls,
+            model_transform,
+            instance_data,
+            &[],
+            |i| node_transforms[i].transform,
+            |i| node_transforms[i].morph_weights,
+        )
+    }
+
+    /// Like [Gltf::draw] or [Gltf::draw_animated] (if `node_transforms` are
+    /// given), but with the materials replaced according to
+    /// `material_overrides`, which contains (original, replacement) material
+    /// index pairs. The replacements are usually made with
+    /// [Gltf::clone_material].
+    pub fn draw_with_materials(
+        &self,
+        draw_calls: &mut DrawCalls,
+        model_transform: Mat4,
+        node_transforms: Option<&[NodeTransform]>,
+        instance_data: InstanceData,
+        material_overrides: &[(usize, usize)],
+    ) {
+        let get_transform = |i: usize| match node_transforms {
+            Some(node_transforms) => node_transforms[i].transform,
+            None => self.nodes[i].transform,
+        };
+        let get_morph_weights = |i: usize| match node_transforms {
+            Some(node_transforms) => node_transforms[i].morph_weights,
+            None => self.nodes[i].morph_weights,
+        };
+        self._draw(
+            draw_calls,
+            model_transform,
+            instance_data,
+            material_overrides,
+            get_transform,
+            get_morph_weights,
+        )
     }
 
     #[inline]
-    fn _draw<F: Fn(usize) -> Mat4>(
+    fn _draw<F, W>(
         &self,
         draw_calls: &mut DrawCalls,
         model_transform: Mat4,
+        instance_data: InstanceData,
+        material_overrides: &[(usize, usize)],
         get_transform: F,
-    ) {
+        get_morph_weights: W,
+    ) where
+        F: Fn(usize) -> Mat4,
+        W: Fn(usize) -> [f32; MAX_MORPH_TARGETS],
+    {
         let scene = &self.scenes[self.scene];
         let mut node_queue = scene
             .node_indices
             .iter()
             .map(|&i| (model_transform, i))
             .collect::<Vec<_>>();
+        // Only calculated if there are skinned meshes to draw.
+        let mut global_transforms = None;
         while let Some((parent_transform, node_index)) = node_queue.pop() {
+            let Some(node_index) =
+                self.select_lod(draw_calls, parent_transform, node_index, &get_transform)
+            else {
+                continue;
+            };
             let transform = parent_transform * get_transform(node_index);
             if let Some(mesh_index) = self.nodes[node_index].mesh_index {
-                for &primitive_index in &self.meshes[mesh_index].primitive_indices {
+                let skin = self.nodes[node_index].skin_index.map(|skin_index| {
+                    let global_transforms = global_transforms
+                        .get_or_insert_with(|| self.get_global_transforms(&get_transform));
+                    self.skins[skin_index].get_joint_matrices(global_transforms)
+                });
+                // glTF spec section 3.7.3.3: the transform of the skinned
+                // mesh's node is ignored, only the joints' transforms apply.
+                let transform = if skin.is_some() {
+                    model_transform
+                } else {
+                    transform
+                };
+                let instance = Instance {
+                    transform,
+                    morph_weights: get_morph_weights(node_index),
+                    data: instance_data,
+                };
+                let primitive_indices = &self.meshes[mesh_index].primitive_indices;
+                for (i, &primitive_index) in primitive_indices.iter().enumerate() {
                     let primitive = &self.primitives[primitive_index];
-                    let uniforms = &self.materials[primitive.material_index].uniforms;
+                    let material_index = (material_overrides.iter())
+                        .find(|(original, _)| *original == primitive.material_index)
+                        .map_or(primitive.material_index, |&(_, replacement)| replacement);
+                    let uniforms = &self.materials[material_index].uniforms;
                     let mut draw_call = primitive.draw_call.clone();
                     // glTF spec section 3.7.4:
                     draw_call.front_face = (transform.determinant() > 0.0)
                         .then_some(gl::CCW)
                         .unwrap_or(gl::CW);
-                    draw_calls.add(uniforms, &draw_call, transform);
+                    let source = DrawSource {
+                        gltf_id: self.id,
+                        node_index,
+                        primitive_index: i,
+                    };
+                    if let Some(joint_matrices) = &skin {
+                        draw_calls.add_skinned(
+                            uniforms,
+                            &draw_call,
+                            instance,
+                            joint_matrices,
+                            source,
+                        );
+                    } else {
+                        let bounds = primitive.bounds.as_ref();
+                        draw_calls.add(uniforms, &draw_call, instance, bounds, source);
+                    }
                 }
             }
             for &child_index in &self.nodes[node_index].child_node_indices {
@@ -101,23 +297,107 @@ impl Gltf {
     }
 }
 
-impl Drop for Gltf {
-    fn drop(&mut self) {
-        gl::call!(gl::DeleteVertexArrays(
-            self.gl_vaos.len() as i32,
-            self.gl_vaos.as_ptr(),
-        ));
-        gl::call!(gl::DeleteBuffers(
-            self.gl_buffers.len() as i32,
-            self.gl_buffers.as_ptr(),
-        ));
-        gl::call!(gl::DeleteTextures(
-            self.gl_textures.len() as i32,
-            self.gl_textures.as_ptr(),
-        ));
-        gl::call!(gl::DeleteSamplers(
-            self.gl_samplers.len() as i32,
-            self.gl_samplers.as_ptr(),
-        ));
+impl Gltf {
+    /// Returns the node to draw in place of the given node, based on its
+    /// levels of detail, or None if the node is too small to be drawn at all.
+    /// Nodes without a mesh with known bounds are always drawn at full
+    /// detail.
+    fn select_lod<F: Fn(usize) -> Mat4>(
+        &self,
+        draw_calls: &mut DrawCalls,
+        parent_transform: Mat4,
+        node_index: usize,
+        get_transform: &F,
+    ) -> Option<usize> {
+        let node = &self.nodes[node_index];
+        if node.lod_node_indices.is_empty() {
+            return Some(node_index);
+        }
+        let Some(bounds) = node.mesh_index.and_then(|i| self.meshes[i].bounds) else {
+            return Some(node_index);
+        };
+        let transform = parent_transform * get_transform(node_index);
+        let level = draw_calls.select_lod(
+            (self.id, node_index),
+            &bounds.transformed(transform),
+            &node.lod_screen_coverages,
+        );
+        match level {
+            0 => Some(node_index),
+            level => node.lod_node_indices.get(level - 1).copied(),
+        }
+    }
+
+    /// Returns the camera attached to the node with the given name, or the
+    /// first node using a camera with the given name. The camera is
+    /// positioned with the nodes' original transforms, see
+    /// [Gltf::get_animated_camera] for animated ones.
+    pub fn get_camera(&self, name: &str, model_transform: Mat4) -> Option<renderer::Camera> {
+        self._get_camera(name, model_transform, |i| self.nodes[i].transform)
+    }
+
+    /// Returns the camera like [Gltf::get_camera], positioned with the
+    /// animated node transforms, e.g. from [Animation::animate_transforms].
+    pub fn get_animated_camera(
+        &self,
+        name: &str,
+        model_transform: Mat4,
+        node_transforms: &[NodeTransform],
+    ) -> Option<renderer::Camera> {
+        self._get_camera(name, model_transform, |i| node_transforms[i].transform)
+    }
+
+    fn _get_camera<F: Fn(usize) -> Mat4>(
+        &self,
+        name: &str,
+        model_transform: Mat4,
+        get_transform: F,
+    ) -> Option<renderer::Camera> {
+        let (node_index, camera) = (self.nodes.iter().enumerate()).find_map(|(i, node)| {
+            let camera = &self.cameras[node.camera_index?];
+            (node.name == name || camera.name == name).then_some((i, camera))
+        })?;
+        let transform = model_transform * self.get_global_transforms(get_transform)[node_index];
+        // glTF spec section 3.10.1: cameras look towards their local -Z, like
+        // the renderer's cameras, and the scale of the node is ignored.
+        let (_, orientation, position) = transform.to_scale_rotation_translation();
+        Some(renderer::Camera {
+            position,
+            orientation,
+            projection: camera.projection,
+            near: camera.near,
+            far: camera.far,
+        })
+    }
+
+    /// Returns the transforms of each node relative to the root of the scene,
+    /// when the local transforms of the nodes are the ones returned by
+    /// `get_transform`. Nodes which are not in the scene get an identity
+    /// transform.
+    fn get_global_transforms<F: Fn(usize) -> Mat4>(&self, get_transform: F) -> Vec<Mat4> {
+        self.scenes[self.scene].get_global_transforms(&self.nodes, get_transform)
+    }
+}
+
+impl Scene {
+    fn get_global_transforms<F: Fn(usize) -> Mat4>(
+        &self,
+        nodes: &[Node],
+        get_transform: F,
+    ) -> Vec<Mat4> {
+        let mut global_transforms = vec![Mat4::IDENTITY; nodes.len()];
+        let mut node_queue = self
+            .node_indices
+            .iter()
+            .map(|&i| (Mat4::IDENTITY, i))
+            .collect::<Vec<_>>();
+        while let Some((parent_transform, node_index)) = node_queue.pop() {
+            let transform = parent_transform * get_transform(node_index);
+            global_transforms[node_index] = transform;
+            for &child_index in &nodes[node_index].child_node_indices {
+                node_queue.push((transform, child_index));
+            }
+        }
+        global_transforms
     }
 }
@@ -1,6 +1,7 @@
 use crate::renderer::gl;
+use crate::renderer::handles::Program;
 use bytemuck::{Pod, Zeroable};
-use glam::Vec4;
+use glam::{Mat4, Vec4};
 
 pub const ATTR_LOC_POSITION: gl::types::GLuint = 0;
 pub const ATTR_LOC_NORMAL: gl::types::GLuint = 1;
@@ -9,17 +10,39 @@ pub const ATTR_LOC_TEXCOORD_0: gl::types::GLuint = 3;
 pub const ATTR_LOC_TEXCOORD_1: gl::types::GLuint = 4;
 pub const ATTR_LOC_COLOR_0: gl::types::GLuint = 5;
 pub const ATTR_LOC_MODEL_TRANSFORM_COLUMNS: [gl::types::GLuint; 4] = [6, 7, 8, 9];
+pub const ATTR_LOC_JOINTS_0: gl::types::GLuint = 10;
+pub const ATTR_LOC_WEIGHTS_0: gl::types::GLuint = 11;
+pub const ATTR_LOC_MORPH_WEIGHTS: [gl::types::GLuint; 2] = [12, 13];
+/// The instance data's tint, and its custom floats and object id.
+pub const ATTR_LOC_INSTANCE_DATA: [gl::types::GLuint; 2] = [14, 15];
 
 pub const TEX_UNIT_BASE_COLOR: u32 = 0;
 pub const TEX_UNIT_METALLIC_ROUGHNESS: u32 = 1;
 pub const TEX_UNIT_NORMAL: u32 = 2;
 pub const TEX_UNIT_OCCLUSION: u32 = 3;
 pub const TEX_UNIT_EMISSIVE: u32 = 4;
+pub const TEX_UNIT_MORPH_TARGETS: u32 = 5;
+pub const TEX_UNIT_SHADOW_MAPS: u32 = 6;
+pub const TEX_UNIT_ENVIRONMENT: u32 = 7;
 
 pub const UNIFORM_BLOCK_MATERIAL: u32 = 0;
 pub const UNIFORM_BLOCK_LIGHTS: u32 = 1;
+pub const UNIFORM_BLOCK_SKIN: u32 = 2;
+pub const UNIFORM_BLOCK_SHADOWS: u32 = 3;
+pub const UNIFORM_BLOCK_ENVIRONMENT: u32 = 4;
 
 pub const MAX_LIGHTS: usize = 32;
+/// The maximum amount of joints in a skin. Limited by the minimum
+/// GL_MAX_UNIFORM_BLOCK_SIZE of 16 KiB.
+pub const MAX_JOINTS: usize = 128;
+/// The maximum amount of morph targets per mesh. Limited by the weights being
+/// passed in as two vec4 instance attributes.
+pub const MAX_MORPH_TARGETS: usize = 8;
+/// The maximum amount of shadow maps, shared between all shadow casting
+/// lights. Directional lights use one per cascade, spot lights use one.
+pub const MAX_SHADOW_MAPS: usize = 8;
+/// The maximum amount of cascades for directional light shadows.
+pub const MAX_SHADOW_CASCADES: usize = 4;
 
 #[derive(Clone, Copy, Zeroable, Pod)]
 #[repr(C)]
@@ -30,6 +53,10 @@ pub struct UniformBlockMaterial {
     pub normal_scale: f32,
     pub occlusion_strength: f32,
     pub emissive_factor: Vec4,
+    pub alpha_cutoff: f32,
+    /// 0.0: opaque, 1.0: mask, 2.0: blend
+    pub alpha_mode: f32,
+    pub _padding: [f32; 2],
 }
 
 #[derive(Clone, Copy, Zeroable, Pod)]
@@ -38,14 +65,50 @@ pub struct UniformBlockLights {
     /// w: 0.0 as the null terminator, 1.0: directional, 2.0: point, 3.0: spot,
     /// xyz: rgb
     pub color_and_kind: [Vec4; MAX_LIGHTS],
-    /// x: intensity, y: angle scale, z: angle offset
+    /// x: intensity, y: angle scale, z: angle offset, w: range, 0.0 if infinite
     pub intensity_params: [Vec4; MAX_LIGHTS],
     pub position: [Vec4; MAX_LIGHTS],
     pub direction: [Vec4; MAX_LIGHTS],
 }
 
+#[derive(Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct UniformBlockSkin {
+    /// The amount of joints, or 0 if the mesh is not skinned.
+    pub joint_count: u32,
+    pub _padding: [u32; 3],
+    pub joint_matrices: [Mat4; MAX_JOINTS],
+}
+
+#[derive(Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct UniformBlockShadows {
+    /// x: the layer of the light's first shadow map, or -1.0 if the light
+    /// doesn't cast shadows, y: amount of shadow maps (cascades), z: depth
+    /// bias
+    pub light_shadow_params: [Vec4; MAX_LIGHTS],
+    /// The view-space depths where each cascade ends.
+    pub cascade_depths: [f32; MAX_SHADOW_CASCADES],
+    /// xyz: the camera's forward vector in view space, for calculating the
+    /// depth of fragments, w: the radius of the PCF kernel in texels
+    pub view_forward_and_filter_radius: Vec4,
+    /// Transforms view space positions into the shadow maps' clip space.
+    pub shadow_from_view: [Mat4; MAX_SHADOW_MAPS],
+}
+
+#[derive(Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct UniformBlockEnvironment {
+    /// The 9 spherical harmonics coefficients (in xyz) of the environment's
+    /// diffuse irradiance, already convolved with the cosine lobe.
+    pub irradiance_sh: [Vec4; 9],
+    /// x: the last mip level of the environment map, which is prefiltered for
+    /// a roughness of 1, y: intensity multiplier
+    pub params: Vec4,
+}
+
 pub struct ShaderProgram {
-    pub program: gl::types::GLuint,
+    pub program: Program,
     pub proj_from_view_location: gl::types::GLint,
     pub view_from_world_location: gl::types::GLint,
 }
@@ -53,10 +116,27 @@ pub struct ShaderProgram {
 /// Compiles and returns the shader program which should be used to render the
 /// glTF models.
 pub fn create_program() -> ShaderProgram {
+    link_program("glTF", include_str!("gltf_fragment.glsl"))
+}
+
+/// Compiles and returns the depth-only shader program used to render the glTF
+/// models into shadow maps.
+pub fn create_shadow_program() -> ShaderProgram {
+    link_program("glTF shadows", include_str!("shadow_fragment.glsl"))
+}
+
+/// Compiles and returns the shader program used to render the glTF models'
+/// pick indices for [crate::renderer::picking::Picker].
+pub fn create_picking_program() -> ShaderProgram {
+    link_program("glTF picking", include_str!("picking_fragment.glsl"))
+}
+
+fn link_program(name: &str, fragment_shader_source: &str) -> ShaderProgram {
     let vertex_shader = gl::create_shader(gl::VERTEX_SHADER, include_str!("gltf_vertex.glsl"));
-    let fragment_shader =
-        gl::create_shader(gl::FRAGMENT_SHADER, include_str!("gltf_fragment.glsl"));
+    let fragment_shader = gl::create_shader(gl::FRAGMENT_SHADER, fragment_shader_source);
     let program = gl::create_program(&[vertex_shader, fragment_shader]);
+    let handle = Program::from_raw(program);
+    gl::label(gl::PROGRAM_KHR, program, name);
     gl::call!(gl::DeleteShader(vertex_shader));
     gl::call!(gl::DeleteShader(fragment_shader));
     gl::call!(gl::UseProgram(program));
@@ -78,6 +158,15 @@ pub fn create_program() -> ShaderProgram {
     if let Some(location) = gl::get_uniform_location(program, "emissive_tex") {
         gl::call!(gl::Uniform1i(location, TEX_UNIT_EMISSIVE as i32));
     }
+    if let Some(location) = gl::get_uniform_location(program, "morph_targets_tex") {
+        gl::call!(gl::Uniform1i(location, TEX_UNIT_MORPH_TARGETS as i32));
+    }
+    if let Some(location) = gl::get_uniform_location(program, "shadow_maps_tex") {
+        gl::call!(gl::Uniform1i(location, TEX_UNIT_SHADOW_MAPS as i32));
+    }
+    if let Some(location) = gl::get_uniform_location(program, "environment_tex") {
+        gl::call!(gl::Uniform1i(location, TEX_UNIT_ENVIRONMENT as i32));
+    }
     if let Some(loc) = gl::get_uniform_block_index(program, "Material") {
         let binding = UNIFORM_BLOCK_MATERIAL;
         gl::call!(gl::UniformBlockBinding(program, loc, binding));
@@ -86,9 +175,25 @@ pub fn create_program() -> ShaderProgram {
         let binding = UNIFORM_BLOCK_LIGHTS;
         gl::call!(gl::UniformBlockBinding(program, loc, binding));
     }
+    if let Some(loc) = gl::get_uniform_block_index(program, "Skin") {
+        let binding = UNIFORM_BLOCK_SKIN;
+        gl::call!(gl::UniformBlockBinding(program, loc, binding));
+    }
+    if let Some(loc) = gl::get_uniform_block_index(program, "Shadows") {
+        let binding = UNIFORM_BLOCK_SHADOWS;
+        gl::call!(gl::UniformBlockBinding(program, loc, binding));
+    }
+    if let Some(loc) = gl::get_uniform_block_index(program, "Environment") {
+        let binding = UNIFORM_BLOCK_ENVIRONMENT;
+        gl::call!(gl::UniformBlockBinding(program, loc, binding));
+    }
+    // Unskinned meshes don't have a JOINTS_0 array, and WebGL requires the
+    // default value of an integer attribute to be an integer as well. This is
+    // context state, not program state, so it only needs to be set once.
+    gl::call!(gl::VertexAttribI4ui(ATTR_LOC_JOINTS_0, 0, 0, 0, 0));
 
     ShaderProgram {
-        program,
+        program: handle,
         proj_from_view_location,
         view_from_world_location,
     }
@@ -0,0 +1,27 @@
+#version 300 es
+precision highp float;
+
+in vec2 tex_coords;
+flat in vec4 instance_tint;
+
+uniform sampler2D base_color_tex;
+layout(std140) uniform Material {
+  vec4 base_color_factor;
+  // x: metallic factor, y: roughness factor, z: normal scale, w: occlusion
+  // strength
+  vec4 material_params;
+  vec4 emissive_factor;
+  // x: alpha cutoff, y: 0.0 opaque, 1.0 mask, 2.0 blend
+  vec4 alpha_params;
+};
+
+// Only depth is written, but masked materials' cutouts shouldn't cast shadows.
+void main() {
+  if (int(alpha_params.y) == 1) {
+    float alpha = texture(base_color_tex, tex_coords).a * base_color_factor.a *
+                  instance_tint.a;
+    if (alpha < alpha_params.x) {
+      discard;
+    }
+  }
+}
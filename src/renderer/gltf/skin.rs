@@ -0,0 +1,35 @@
+use crate::renderer::gltf::{Gltf, NodeTransform};
+use glam::Mat4;
+
+pub struct Skin {
+    /// The indices of the nodes used as the joints of this skin.
+    pub joints: Vec<usize>,
+    /// One matrix per joint, transforming the mesh into the joint's local
+    /// space.
+    pub inverse_bind_matrices: Vec<Mat4>,
+}
+
+impl Skin {
+    /// Returns the joint matrices of this skin, given the transforms of every
+    /// node in the glTF relative to the scene root.
+    pub fn get_joint_matrices(&self, global_transforms: &[Mat4]) -> Vec<Mat4> {
+        self.joints
+            .iter()
+            .zip(&self.inverse_bind_matrices)
+            .map(|(&joint, inverse_bind_matrix)| global_transforms[joint] * *inverse_bind_matrix)
+            .collect()
+    }
+}
+
+impl Gltf {
+    /// Returns the joint matrices of the skin, with the nodes posed according
+    /// to `node_transforms`, e.g. after [crate::renderer::gltf::Animation::animate_transforms].
+    pub fn get_joint_matrices(
+        &self,
+        skin_index: usize,
+        node_transforms: &[NodeTransform],
+    ) -> Vec<Mat4> {
+        let global_transforms = self.get_global_transforms(|i| node_transforms[i].transform);
+        self.skins[skin_index].get_joint_matrices(&global_transforms)
+    }
+}
@@ -0,0 +1,181 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use crate::renderer::gl;
+
+/// An OpenGL object, deleted when dropped.
+struct Object {
+    id: gl::types::GLuint,
+    delete: fn(gl::types::GLuint),
+}
+
+impl Drop for Object {
+    fn drop(&mut self) {
+        (self.delete)(self.id);
+    }
+}
+
+/// Defines a reference counted handle type for a kind of GL object. Handles
+/// compare and hash by the object's id, which is unique among live objects
+/// of the kind.
+macro_rules! handle {
+    ($(#[$attr:meta])* $name:ident($inner:ty), $delete:expr) => {
+        $(#[$attr])*
+        #[derive(Clone)]
+        pub struct $name(Rc<$inner>);
+
+        impl $name {
+            /// Takes ownership of an existing object, which is deleted when
+            /// the last handle to it is dropped.
+            pub fn from_raw(id: gl::types::GLuint) -> $name {
+                $name(Rc::new(<$inner>::from(Object { id, delete: $delete })))
+            }
+
+            pub fn id(&self) -> gl::types::GLuint {
+                AsRef::<Object>::as_ref(&*self.0).id
+            }
+        }
+
+        impl PartialEq for $name {
+            fn eq(&self, other: &Self) -> bool {
+                self.id() == other.id()
+            }
+        }
+
+        impl Eq for $name {}
+
+        impl Hash for $name {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                self.id().hash(state);
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}({})", stringify!($name), self.id())
+            }
+        }
+    };
+}
+
+/// Defines the listed constructors of a handle type whose objects are created
+/// with `$gen`: `new`, which checks for errors with [gl::call], and `try_new`,
+/// which returns them like [gl::try_call], for callers which report errors
+/// instead of panicking, like the glTF loader.
+macro_rules! constructors {
+    ($name:ident, $gen:ident, $($constructor:ident),+) => {
+        impl $name {
+            $(constructors!(@ $constructor $name $gen);)+
+        }
+    };
+    (@ new $name:ident $gen:ident) => {
+        pub fn new() -> $name {
+            let mut id = 0;
+            gl::call!(gl::$gen(1, &mut id));
+            $name::from_raw(id)
+        }
+    };
+    (@ try_new $name:ident $gen:ident) => {
+        pub fn try_new() -> Result<$name, gl::GlError> {
+            let mut id = 0;
+            gl::try_call!(gl::$gen(1, &mut id))?;
+            Ok($name::from_raw(id))
+        }
+    };
+}
+
+impl AsRef<Object> for Object {
+    fn as_ref(&self) -> &Object {
+        self
+    }
+}
+
+handle!(
+    /// A buffer object, shared between its users, e.g. the vertex arrays
+    /// reading from it and the draw calls using it as an index buffer.
+    Buffer(Object),
+    |id| gl::call!(gl::DeleteBuffers(1, &id))
+);
+
+handle!(
+    /// A texture object, shared between the materials and draw calls using
+    /// it.
+    Texture(Object),
+    |id| gl::call!(gl::DeleteTextures(1, &id))
+);
+
+handle!(
+    /// A sampler object, shared between the materials using it.
+    Sampler(Object),
+    |id| gl::call!(gl::DeleteSamplers(1, &id))
+);
+
+handle!(
+    /// A linked shader program.
+    Program(Object),
+    |id| gl::call!(gl::DeleteProgram(id))
+);
+
+handle!(
+    /// A framebuffer object, owned by the render target using it. The
+    /// textures and renderbuffers attached to it are kept alive separately.
+    Framebuffer(Object),
+    |id| gl::call!(gl::DeleteFramebuffers(1, &id))
+);
+
+handle!(
+    /// A renderbuffer object, e.g. a multisampled color or depth attachment.
+    Renderbuffer(Object),
+    |id| gl::call!(gl::DeleteRenderbuffers(1, &id))
+);
+
+handle!(
+    /// A vertex array object, which also keeps the buffers attached to it
+    /// alive, see [VertexArray::attach].
+    VertexArray(VertexArrayObject),
+    |id| gl::call!(gl::DeleteVertexArrays(1, &id))
+);
+
+constructors!(Buffer, GenBuffers, new, try_new);
+constructors!(Texture, GenTextures, new, try_new);
+constructors!(Sampler, GenSamplers, try_new);
+constructors!(Framebuffer, GenFramebuffers, new);
+constructors!(Renderbuffer, GenRenderbuffers, new);
+constructors!(VertexArray, GenVertexArrays, new, try_new);
+
+/// The vertex array object, and the buffers its attributes and index buffer
+/// binding refer to. The buffers are declared after the object, so they're
+/// deleted after it: deleting a buffer which is attached to the bound vertex
+/// array would detach it.
+struct VertexArrayObject {
+    object: Object,
+    buffers: RefCell<Vec<Buffer>>,
+}
+
+impl From<Object> for VertexArrayObject {
+    fn from(object: Object) -> Self {
+        VertexArrayObject {
+            object,
+            buffers: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl AsRef<Object> for VertexArrayObject {
+    fn as_ref(&self) -> &Object {
+        &self.object
+    }
+}
+
+impl VertexArray {
+    /// Keeps the buffer alive for as long as the vertex array is, for
+    /// buffers bound to its attributes or as its index buffer.
+    pub fn attach(&self, buffer: &Buffer) {
+        let mut buffers = self.0.buffers.borrow_mut();
+        if !buffers.contains(buffer) {
+            buffers.push(buffer.clone());
+        }
+    }
+}
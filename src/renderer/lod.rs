@@ -0,0 +1,200 @@
+use crate::renderer::bounds::Aabb;
+use glam::Mat4;
+use std::collections::HashMap;
+use std::mem;
+
+/// How much the screen coverage needs to cross a LOD threshold before the
+/// level changes, relative to the threshold. Avoids flickering between two
+/// levels when an instance's size is close to a threshold.
+const LOD_HYSTERESIS: f32 = 0.1;
+
+/// Identifies an instance of a LOD group across frames: the group (e.g. the
+/// glTF and the node), and how many times the same group was drawn before it
+/// during the frame.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct LodKey {
+    group: (u64, usize),
+    occurrence: u32,
+}
+
+/// Picks the levels of detail for instances based on how much of the screen
+/// they cover, remembering the previous frame's picks for hysteresis.
+///
+/// Since instances don't have persistent identities, they're recognized by
+/// the order they're drawn in, so for the hysteresis to work, the same
+/// instances of a group should be drawn in the same order every frame.
+pub struct LodSelector {
+    clip_from_world: Mat4,
+    /// Multiplies the screen coverages, so values below 1.0 make lower detail
+    /// levels be picked sooner.
+    pub bias: f32,
+    previous_levels: HashMap<LodKey, usize>,
+    levels: HashMap<LodKey, usize>,
+    occurrences: HashMap<(u64, usize), u32>,
+}
+
+impl LodSelector {
+    pub fn new() -> LodSelector {
+        LodSelector {
+            clip_from_world: Mat4::IDENTITY,
+            bias: 1.0,
+            previous_levels: HashMap::new(),
+            levels: HashMap::new(),
+            occurrences: HashMap::new(),
+        }
+    }
+
+    /// Sets the camera the screen coverages are calculated for.
+    pub fn set_view(&mut self, view_from_world: Mat4, proj_from_view: Mat4) {
+        self.clip_from_world = proj_from_view * view_from_world;
+    }
+
+    /// Starts a new frame: the levels picked since the last call are used
+    /// for hysteresis in the next frame.
+    pub fn next_frame(&mut self) {
+        self.previous_levels = mem::take(&mut self.levels);
+        self.occurrences.clear();
+    }
+
+    /// Returns the level of detail to draw, where 0 is the most detailed
+    /// level, and `min_screen_coverages[i]` is the smallest screen coverage
+    /// (the fraction of the viewport's height) level `i` is used at. Smaller
+    /// instances use level `min_screen_coverages.len()`.
+    ///
+    /// The group should uniquely identify the LOD chain, e.g. with the glTF's
+    /// and the node's index.
+    pub fn select(
+        &mut self,
+        group: (u64, usize),
+        world_bounds: &Aabb,
+        min_screen_coverages: &[f32],
+    ) -> usize {
+        let occurrence = self.occurrences.entry(group).or_insert(0);
+        let key = LodKey {
+            group,
+            occurrence: *occurrence,
+        };
+        *occurrence += 1;
+
+        let coverage = self.get_screen_coverage(world_bounds) * self.bias;
+        let pick = |coverage: f32| {
+            (min_screen_coverages.iter())
+                .position(|&min_coverage| coverage >= min_coverage)
+                .unwrap_or(min_screen_coverages.len())
+        };
+        let level = match self.previous_levels.get(&key) {
+            Some(&previous) => {
+                let finer = pick(coverage / (1.0 + LOD_HYSTERESIS));
+                let coarser = pick(coverage * (1.0 + LOD_HYSTERESIS));
+                if finer < previous {
+                    finer
+                } else if coarser > previous {
+                    coarser
+                } else {
+                    previous
+                }
+            }
+            None => pick(coverage),
+        };
+        self.levels.insert(key, level);
+        level
+    }
+
+    /// Returns the height of the bounding sphere of the box on the screen,
+    /// relative to the height of the screen.
+    fn get_screen_coverage(&self, world_bounds: &Aabb) -> f32 {
+        let radius = world_bounds.half_extents().length();
+        let clip_center = self.clip_from_world * world_bounds.center().extend(1.0);
+        // The distance from the camera, or 1 for orthographic projections.
+        let w = clip_center.w.max(f32::EPSILON);
+        let y_scale = self.clip_from_world.row(1).truncate().length();
+        radius * y_scale / w
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LodSelector;
+    use crate::renderer::bounds::Aabb;
+    use glam::{Mat4, Vec3};
+    use std::f32::consts::FRAC_PI_2;
+
+    const GROUP: (u64, usize) = (1, 0);
+    const SCREEN_COVERAGES: [f32; 2] = [0.5, 0.25];
+
+    /// Returns a selector looking down -Z with a 90 degree vertical field of
+    /// view, so that a bounding sphere of radius 1 at distance `d` covers
+    /// `1 / d` of the screen's height.
+    fn create_selector() -> LodSelector {
+        let mut selector = LodSelector::new();
+        let proj_from_view = Mat4::perspective_rh_gl(FRAC_PI_2, 1.0, 100.0, 0.1);
+        selector.set_view(Mat4::IDENTITY, proj_from_view);
+        selector
+    }
+
+    /// Returns a box with a bounding sphere of radius 1, which covers
+    /// `coverage` of the screen of [create_selector].
+    fn bounds_covering(coverage: f32) -> Aabb {
+        let center = Vec3::new(0.0, 0.0, -1.0 / coverage);
+        let half_extents = Vec3::splat(1.0 / 3.0f32.sqrt());
+        Aabb {
+            min: center - half_extents,
+            max: center + half_extents,
+        }
+    }
+
+    #[test]
+    fn levels_are_picked_by_screen_coverage() {
+        for (coverage, expected_level) in [(0.8, 0), (0.51, 0), (0.49, 1), (0.26, 1), (0.24, 2)] {
+            let mut selector = create_selector();
+            let level = selector.select(GROUP, &bounds_covering(coverage), &SCREEN_COVERAGES);
+            assert_eq!(expected_level, level, "at coverage {coverage}");
+        }
+
+        // The bias scales the coverage before comparing it to the thresholds.
+        let mut selector = create_selector();
+        selector.bias = 0.5;
+        let level = selector.select(GROUP, &bounds_covering(0.8), &SCREEN_COVERAGES);
+        assert_eq!(1, level);
+    }
+
+    #[test]
+    fn levels_only_change_past_the_hysteresis() {
+        let mut selector = create_selector();
+        let mut select_next_frame = |coverage: f32| {
+            let level = selector.select(GROUP, &bounds_covering(coverage), &SCREEN_COVERAGES);
+            selector.next_frame();
+            level
+        };
+        assert_eq!(0, select_next_frame(0.6));
+        // Slightly below the threshold keeps the previous level, until the
+        // coverage is more than 10% below it.
+        assert_eq!(0, select_next_frame(0.48));
+        assert_eq!(1, select_next_frame(0.44));
+        // The same applies when growing back above the threshold.
+        assert_eq!(1, select_next_frame(0.52));
+        assert_eq!(0, select_next_frame(0.56));
+        assert_eq!(0, select_next_frame(0.48));
+        // Big jumps skip over the levels in between.
+        assert_eq!(2, select_next_frame(0.1));
+        assert_eq!(0, select_next_frame(0.6));
+    }
+
+    #[test]
+    fn instances_of_a_group_keep_their_own_levels() {
+        let mut selector = create_selector();
+        let near = bounds_covering(0.6);
+        let far = bounds_covering(0.44);
+        assert_eq!(0, selector.select(GROUP, &near, &SCREEN_COVERAGES));
+        assert_eq!(1, selector.select(GROUP, &far, &SCREEN_COVERAGES));
+        selector.next_frame();
+
+        // Both instances move to 0.48: the first one is still within the
+        // hysteresis of its level, and so is the second one.
+        let middle = bounds_covering(0.48);
+        assert_eq!(0, selector.select(GROUP, &middle, &SCREEN_COVERAGES));
+        assert_eq!(1, selector.select(GROUP, &middle, &SCREEN_COVERAGES));
+        // Other groups don't have a previous level to stick to.
+        assert_eq!(1, selector.select((2, 0), &middle, &SCREEN_COVERAGES));
+    }
+}
@@ -1,13 +1,36 @@
 use std::f32::consts::TAU;
 
-use glam::{Mat4, Quat, Vec3, Vec4};
+use anyhow::Context;
+use glam::{Mat4, Quat, Vec2, Vec3};
+use gltf::MaterialTexture;
 
+mod bounds;
 mod bumpalloc_buffer;
+mod camera;
 mod draw_calls;
+mod environment;
+#[cfg(test)]
+mod fake_gl;
 pub mod gl;
 pub mod gltf;
+mod handles;
+mod lod;
+mod picking;
+mod post_process;
+mod shadows;
+mod streaming_buffer;
+mod uniform_buffer;
 
-pub use draw_calls::DrawCalls;
+pub use camera::{Camera, Projection, Ray};
+pub use draw_calls::{DrawCalls, DrawStats, InstanceData};
+pub use environment::Environment;
+use environment::Skybox;
+pub use picking::PickResult;
+use picking::Picker;
+use post_process::PostProcess;
+pub use post_process::PostProcessSettings;
+pub use shadows::ShadowQuality;
+use shadows::Shadows;
 
 /// The "up" vector in world-space (which is in glTF's coordinate system, for
 /// now).
@@ -19,15 +42,39 @@ pub const RIGHT: Vec3 = Vec3::new(-1.0, 0.0, 0.0);
 /// for now).
 pub const FORWARD: Vec3 = Vec3::new(0.0, 0.0, 1.0);
 
+/// The [InstanceData::object_id] of the static test model.
+pub const TEST_MODEL_OBJECT_ID: u32 = 1;
+/// The [InstanceData::object_id] of the animated test model.
+pub const ANIM_TEST_MODEL_OBJECT_ID: u32 = 2;
+/// How brightly highlighted objects glow, relative to their base color.
+const HIGHLIGHT_EMISSIVE_STRENGTH: f32 = 0.5;
+/// The exposure of the test scene. Its light was exported from Blender, which
+/// converts watts into candela at 683 lumens per watt, so this exposes the
+/// scene like the light's original wattage. The sky and the emissive
+/// materials are relative to the display's white instead, so they're scaled
+/// up by the inverse, see [scale_emissive_factors].
+const TEST_SCENE_EXPOSURE: f32 = 1.0 / 683.0;
+
 pub struct Renderer {
     test_model: gltf::Gltf,
     anim_test_model: gltf::Gltf,
+    /// The (original, highlighted) material index pairs of `test_model` and
+    /// `anim_test_model`, see [create_highlight_materials].
+    test_model_highlights: Vec<(usize, usize)>,
+    anim_test_model_highlights: Vec<(usize, usize)>,
+    highlighted_object: Option<u32>,
     gltf_shader: gltf::ShaderProgram,
     draw_calls: DrawCalls,
+    shadows: Shadows,
+    environment: Environment,
+    skybox: Skybox,
+    skybox_enabled: bool,
+    post_process: PostProcess,
+    picker: Picker,
 }
 
 impl Renderer {
-    pub fn new() -> Renderer {
+    pub fn new() -> anyhow::Result<Renderer> {
         macro_rules! boom_box_path {
             ($lit:literal) => {
                 concat!("../../resources/models/testing-static/", $lit)
@@ -39,7 +86,8 @@ impl Renderer {
             };
         }
         let gltf_shader = gltf::create_program();
-        let test_model = gltf::load_gltf(
+        let mut test_model = gltf::load_gltf(
+            "BoomBoxWithAxes.gltf",
             include_str!(boom_box_path!("BoomBoxWithAxes.gltf")),
             &[
                 boom_box_resource!("BoomBoxWithAxes.bin"),
@@ -49,75 +97,215 @@ impl Renderer {
                 boom_box_resource!("BoomBoxWithAxes_normal.png"),
                 boom_box_resource!("BoomBoxWithAxes_roughnessMetallic.png"),
             ],
-        );
-        let anim_test_model = gltf::load_gltf(
-            include_str!(boom_box_path!("InterpolationTest.gltf")),
-            &[
-                boom_box_resource!("interpolation.bin"),
-                boom_box_resource!("l.jpg"),
-            ],
-        );
+        )
+        .context("failed to load BoomBoxWithAxes.gltf")?;
+        let mut anim_test_model = gltf::load_glb(
+            "InterpolationTest.glb",
+            include_bytes!(boom_box_path!("InterpolationTest.glb")),
+        )
+        .context("failed to load InterpolationTest.glb")?;
+        scale_emissive_factors(&mut test_model, 1.0 / TEST_SCENE_EXPOSURE);
+        scale_emissive_factors(&mut anim_test_model, 1.0 / TEST_SCENE_EXPOSURE);
+        let test_model_highlights = create_highlight_materials(&mut test_model);
+        let anim_test_model_highlights = create_highlight_materials(&mut anim_test_model);
         let draw_calls = DrawCalls::new();
-        Renderer {
+        let shadows = Shadows::new(ShadowQuality::Medium);
+        let mut environment =
+            Environment::from_image(include_bytes!("../../resources/environments/sky.hdr"))
+                .context("failed to load sky.hdr")?;
+        environment.set_intensity(1.0 / TEST_SCENE_EXPOSURE);
+        let skybox = Skybox::new();
+        let post_process = PostProcess::new(PostProcessSettings {
+            exposure: TEST_SCENE_EXPOSURE,
+            ..Default::default()
+        });
+        Ok(Renderer {
             test_model,
             anim_test_model,
+            test_model_highlights,
+            anim_test_model_highlights,
+            highlighted_object: None,
             gltf_shader,
             draw_calls,
-        }
+            shadows,
+            environment,
+            skybox,
+            skybox_enabled: true,
+            post_process,
+            picker: Picker::new(),
+        })
+    }
+
+    /// Sets the [InstanceData::object_id] of the object to draw highlighted,
+    /// e.g. one selected with [Renderer::pick].
+    pub fn set_highlighted_object(&mut self, object_id: Option<u32>) {
+        self.highlighted_object = object_id;
+    }
+
+    pub fn lod_bias(&self) -> f32 {
+        self.draw_calls.lod_bias()
+    }
+
+    /// Sets the multiplier for the models' screen coverages when picking
+    /// their levels of detail. Values below 1.0 switch to less detailed
+    /// levels sooner, which can help stay within the frame budget on slower
+    /// devices.
+    pub fn set_lod_bias(&mut self, bias: f32) {
+        self.draw_calls.set_lod_bias(bias);
+    }
+
+    /// Returns what's drawn at `screen_position` (in pixels from the top left
+    /// corner of a screen of `screen_size`) as seen from `camera`, or None if
+    /// nothing is. Picks from the instances of the last [Renderer::render],
+    /// so `camera` should be the camera that frame was rendered with.
+    pub fn pick(
+        &mut self,
+        camera: &Camera,
+        screen_position: Vec2,
+        screen_size: Vec2,
+    ) -> Option<PickResult> {
+        self.picker.pick(
+            &mut self.draw_calls,
+            camera.view_from_world(),
+            camera.proj_from_view(screen_size.x / screen_size.y),
+            screen_position,
+            screen_size,
+        )
+    }
+
+    /// Returns the glTF camera with the given name (see
+    /// [gltf::Gltf::get_camera]) from the rendered models, positioned like
+    /// [Renderer::render] would position it at `time`.
+    pub fn gltf_camera(&self, name: &str, time: f32) -> Option<Camera> {
+        self.test_model
+            .get_camera(name, Mat4::IDENTITY)
+            .or_else(|| {
+                let transforms = animated_node_transforms(&self.anim_test_model, time);
+                self.anim_test_model.get_animated_camera(
+                    name,
+                    anim_test_model_transform(),
+                    &transforms,
+                )
+            })
+    }
+
+    /// Returns the draw call and state change counters of the last frame's
+    /// main pass.
+    pub fn draw_stats(&self) -> DrawStats {
+        self.draw_calls.stats()
+    }
+
+    pub fn post_process_settings(&self) -> PostProcessSettings {
+        self.post_process.settings
     }
 
-    pub fn render(&mut self, aspect_ratio: f32, time: f32) {
+    /// Sets the exposure, bloom, and tonemapping used to turn the rendered
+    /// HDR image into the final one.
+    pub fn set_post_process_settings(&mut self, settings: PostProcessSettings) {
+        self.post_process.settings = settings;
+    }
+
+    /// Replaces the environment used for ambient lighting and the skybox.
+    pub fn set_environment(&mut self, environment: Environment) {
+        self.environment = environment;
+    }
+
+    pub fn environment_mut(&mut self) -> &mut Environment {
+        &mut self.environment
+    }
+
+    pub fn skybox_enabled(&self) -> bool {
+        self.skybox_enabled
+    }
+
+    /// Sets whether the environment is drawn in the background. When
+    /// disabled, the background is cleared to black, but the environment
+    /// still lights the scene.
+    pub fn set_skybox_enabled(&mut self, enabled: bool) {
+        self.skybox_enabled = enabled;
+    }
+
+    pub fn shadow_quality(&self) -> ShadowQuality {
+        self.shadows.quality()
+    }
+
+    pub fn set_shadow_quality(&mut self, quality: ShadowQuality) {
+        self.shadows.set_quality(quality);
+    }
+
+    pub fn render(&mut self, camera: &Camera, aspect_ratio: f32, time: f32) {
+        let view_from_world = camera.view_from_world();
+        let view_matrix = view_from_world.to_cols_array();
+        let proj_from_view = camera.proj_from_view(aspect_ratio);
+        let proj_matrix = proj_from_view.to_cols_array();
+
         self.draw_calls.clear();
-        self.test_model.draw(&mut self.draw_calls, Mat4::IDENTITY);
-        let mut transforms = self.anim_test_model.get_node_transforms();
-        for anim in &self.anim_test_model.animations {
-            let time = anim.start + (time % anim.length);
-            anim.animate_transforms(&mut transforms, time);
+        self.draw_calls
+            .set_lod_view(view_from_world, proj_from_view);
+        let instance_data = InstanceData {
+            object_id: TEST_MODEL_OBJECT_ID,
+            ..Default::default()
+        };
+        if self.highlighted_object == Some(TEST_MODEL_OBJECT_ID) {
+            self.test_model.draw_with_materials(
+                &mut self.draw_calls,
+                Mat4::IDENTITY,
+                None,
+                instance_data,
+                &self.test_model_highlights,
+            );
+        } else {
+            self.test_model
+                .draw(&mut self.draw_calls, Mat4::IDENTITY, instance_data);
+        }
+        let transforms = animated_node_transforms(&self.anim_test_model, time);
+        let model_transform = anim_test_model_transform();
+        let instance_data = InstanceData {
+            object_id: ANIM_TEST_MODEL_OBJECT_ID,
+            ..Default::default()
+        };
+        if self.highlighted_object == Some(ANIM_TEST_MODEL_OBJECT_ID) {
+            self.anim_test_model.draw_with_materials(
+                &mut self.draw_calls,
+                model_transform,
+                Some(&transforms),
+                instance_data,
+                &self.anim_test_model_highlights,
+            );
+        } else {
+            self.anim_test_model.draw_animated(
+                &mut self.draw_calls,
+                model_transform,
+                &transforms,
+                instance_data,
+            );
         }
-        self.anim_test_model.draw_animated(
+
+        self.shadows.render(
             &mut self.draw_calls,
-            Mat4::from_scale_rotation_translation(
-                Vec3::splat(0.25),
-                Quat::from_rotation_y(TAU * 5.0 / 8.0),
-                Vec3::new(3.0, -0.5, 4.5),
-            ),
-            &transforms,
+            &self.test_model.lights,
+            view_from_world,
+            proj_from_view,
         );
 
-        fn aces_filmic(x: Vec3) -> Vec3 {
-            let a = Vec3::splat(2.51);
-            let b = Vec3::splat(0.03);
-            let c = Vec3::splat(2.43);
-            let d = Vec3::splat(0.59);
-            let e = Vec3::splat(0.14);
-            (x * (a * x + b) / (x * (c * x + d) + e)).clamp(Vec3::ZERO, Vec3::ONE)
-        }
-        fn srgb(linear: Vec3) -> Vec3 {
-            linear.powf(1.0 / 2.2)
-        }
-        let ambient = srgb(aces_filmic(Vec3::splat(0.1)));
-        gl::call!(gl::ClearColor(ambient.x, ambient.y, ambient.z, 1.0));
+        let mut viewport = [0; 4];
+        gl::call!(gl::GetIntegerv(gl::VIEWPORT, viewport.as_mut_ptr()));
+        self.post_process.begin(viewport[2], viewport[3]);
+
+        gl::call!(gl::ClearColor(0.0, 0.0, 0.0, 1.0));
         gl::call!(gl::ClearDepthf(0.0));
         gl::call!(gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT));
-        gl::call!(gl::Enable(gl::CULL_FACE));
         gl::call!(gl::Enable(gl::DEPTH_TEST));
         gl::call!(gl::DepthFunc(gl::GREATER));
 
-        let view_matrix = Mat4::IDENTITY.to_cols_array();
-        // OpenGL clip space: right-handed, +X right, +Y up, +Z backward (out of screen).
-        // GLTF:              right-handed, +X left, +Y up, +Z forward (into the screen).
-        let to_opengl_basis = Mat4::from_cols(
-            (RIGHT, 0.0).into(),    // +X is right in OpenGL clip space
-            (UP, 0.0).into(),       // +Y is up in OpenGL clip space
-            (-FORWARD, 0.0).into(), // +Z is backward in OpenGL clip space
-            Vec4::new(0.0, 0.0, 0.0, 1.0),
-        );
-        let proj_matrix = (Mat4::perspective_rh_gl(74f32.to_radians(), aspect_ratio, 100.0, 0.3)
-            * to_opengl_basis)
-            .to_cols_array();
+        self.environment.bind();
+        if self.skybox_enabled {
+            self.skybox.draw(view_from_world, proj_from_view);
+        }
 
         // Draw glTFs:
-        gl::call!(gl::UseProgram(self.gltf_shader.program));
+        self.shadows.bind();
+        gl::call!(gl::UseProgram(self.gltf_shader.program.id()));
         gl::call!(gl::UniformMatrix4fv(
             self.gltf_shader.proj_from_view_location,
             1,
@@ -130,6 +318,62 @@ impl Renderer {
             gl::FALSE,
             view_matrix.as_ptr(),
         ));
-        self.draw_calls.draw(gltf::ATTR_LOC_MODEL_TRANSFORM_COLUMNS);
+        self.draw_calls.draw(
+            view_from_world,
+            proj_from_view,
+            gltf::ATTR_LOC_MODEL_TRANSFORM_COLUMNS,
+            gltf::ATTR_LOC_MORPH_WEIGHTS,
+            gltf::ATTR_LOC_INSTANCE_DATA,
+        );
+
+        self.post_process.finish();
     }
 }
+
+/// Returns the node transforms of the glTF with all of its animations
+/// applied, looping each animation over its length.
+fn animated_node_transforms(gltf: &gltf::Gltf, time: f32) -> Vec<gltf::NodeTransform<'_>> {
+    let mut transforms = gltf.get_node_transforms();
+    for anim in &gltf.animations {
+        let time = anim.start + (time % anim.length);
+        anim.animate_transforms(&mut transforms, time);
+    }
+    transforms
+}
+
+fn anim_test_model_transform() -> Mat4 {
+    Mat4::from_scale_rotation_translation(
+        Vec3::splat(0.25),
+        Quat::from_rotation_y(TAU * 5.0 / 8.0),
+        Vec3::new(3.0, -0.5, 4.5),
+    )
+}
+
+/// Multiplies the emissive factors of the glTF's materials, e.g. to keep
+/// them as bright after lowering the exposure.
+fn scale_emissive_factors(gltf: &mut gltf::Gltf, scale: f32) {
+    for material in 0..gltf.materials().len() {
+        let mut factors = gltf.material_factors(material);
+        factors.emissive *= scale;
+        gltf.set_material_factors(material, &factors);
+    }
+}
+
+/// Adds a highlighted copy of each of the glTF's materials, which glows in
+/// the material's base color, and returns (original, highlighted) material
+/// index pairs for [gltf::Gltf::draw_with_materials].
+fn create_highlight_materials(gltf: &mut gltf::Gltf) -> Vec<(usize, usize)> {
+    (0..gltf.materials().len())
+        .map(|material| {
+            let name = format!("{} (highlighted)", gltf.materials()[material].name);
+            let highlighted = gltf.clone_material(material, &name);
+            let (texture, sampler) = gltf.material_texture(material, MaterialTexture::BaseColor);
+            gltf.set_material_texture(highlighted, MaterialTexture::Emissive, texture, sampler);
+            let mut factors = gltf.material_factors(material);
+            factors.emissive =
+                factors.base_color.truncate() * HIGHLIGHT_EMISSIVE_STRENGTH / TEST_SCENE_EXPOSURE;
+            gltf.set_material_factors(highlighted, &factors);
+            (material, highlighted)
+        })
+        .collect()
+}
@@ -0,0 +1,258 @@
+use crate::renderer::draw_calls::{DrawCalls, DrawSource, InstanceData};
+use crate::renderer::gl;
+use crate::renderer::gltf;
+use crate::renderer::handles::{Framebuffer, Renderbuffer};
+use glam::{Mat4, Vec2, Vec3, Vec4};
+
+/// What's under a pixel, as returned by [Picker::pick].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PickResult {
+    /// The glTF, node and primitive which was picked.
+    pub source: DrawSource,
+    /// The instance data the instance was drawn with, e.g. for its
+    /// [InstanceData::object_id].
+    pub instance_data: InstanceData,
+    /// The window space depth of the picked surface, with the reversed depth
+    /// range, so 1.0 is the near plane and 0.0 the far plane.
+    pub depth: f32,
+    /// The world space position of the picked surface.
+    pub world_position: Vec3,
+}
+
+/// Renders the instances of [DrawCalls] into a one pixel RG32UI target with
+/// their pick indices and depths, to find out what's under a pixel.
+pub struct Picker {
+    program: gltf::ShaderProgram,
+    pick_base_location: gl::types::GLint,
+    framebuffer: Framebuffer,
+    /// The RG32UI color and the depth renderbuffers of `framebuffer`, only
+    /// held to keep them alive.
+    _renderbuffers: [Renderbuffer; 2],
+}
+
+impl Picker {
+    pub fn new() -> Picker {
+        let program = gltf::create_picking_program();
+        let pick_base_location =
+            gl::get_uniform_location(program.program.id(), "pick_base").unwrap();
+        let renderbuffers = [Renderbuffer::new(), Renderbuffer::new()];
+        let framebuffer = Framebuffer::new();
+        let mut previous_framebuffer = 0;
+        gl::call!(gl::GetIntegerv(
+            gl::FRAMEBUFFER_BINDING,
+            &mut previous_framebuffer
+        ));
+        gl::call!(gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer.id()));
+        let [color, depth] = &renderbuffers;
+        for (renderbuffer, format, attachment) in [
+            (color, gl::RG32UI, gl::COLOR_ATTACHMENT0),
+            (depth, gl::DEPTH_COMPONENT32F, gl::DEPTH_ATTACHMENT),
+        ] {
+            gl::call!(gl::BindRenderbuffer(gl::RENDERBUFFER, renderbuffer.id()));
+            gl::call!(gl::RenderbufferStorage(gl::RENDERBUFFER, format, 1, 1));
+            gl::call!(gl::FramebufferRenderbuffer(
+                gl::FRAMEBUFFER,
+                attachment,
+                gl::RENDERBUFFER,
+                renderbuffer.id(),
+            ));
+        }
+        let status = gl::call!(gl::CheckFramebufferStatus(gl::FRAMEBUFFER));
+        assert_eq!(
+            gl::FRAMEBUFFER_COMPLETE,
+            status,
+            "incomplete picking framebuffer"
+        );
+        gl::call!(gl::BindFramebuffer(
+            gl::FRAMEBUFFER,
+            previous_framebuffer as u32
+        ));
+        Picker {
+            program,
+            pick_base_location,
+            framebuffer,
+            _renderbuffers: renderbuffers,
+        }
+    }
+
+    /// Returns what's drawn at `screen_position` (in pixels from the top left
+    /// corner of a screen of `screen_size`), or None if nothing is.
+    ///
+    /// Only the pixel being picked is rendered, by narrowing the projection
+    /// down to it. The instances in `draw_calls` are used as they are, so this
+    /// should be called after a frame has been rendered and before the next
+    /// one clears them, with the same camera. Reading the result back waits
+    /// for the GPU to finish rendering.
+    pub fn pick(
+        &mut self,
+        draw_calls: &mut DrawCalls,
+        view_from_world: Mat4,
+        proj_from_view: Mat4,
+        screen_position: Vec2,
+        screen_size: Vec2,
+    ) -> Option<PickResult> {
+        let ndc = get_pixel_center_ndc(screen_position, screen_size);
+        let pixel_from_view = get_pixel_from_clip(ndc, screen_size) * proj_from_view;
+
+        let mut previous_viewport = [0; 4];
+        gl::call!(gl::GetIntegerv(
+            gl::VIEWPORT,
+            previous_viewport.as_mut_ptr()
+        ));
+        let mut previous_framebuffer = 0;
+        gl::call!(gl::GetIntegerv(
+            gl::FRAMEBUFFER_BINDING,
+            &mut previous_framebuffer
+        ));
+
+        gl::call!(gl::BindFramebuffer(gl::FRAMEBUFFER, self.framebuffer.id()));
+        gl::call!(gl::Viewport(0, 0, 1, 1));
+        gl::call!(gl::DepthMask(gl::TRUE));
+        gl::call!(gl::ClearBufferuiv(gl::COLOR, 0, [0u32; 4].as_ptr()));
+        gl::call!(gl::ClearBufferfv(gl::DEPTH, 0, &0.0));
+        gl::call!(gl::Enable(gl::DEPTH_TEST));
+        gl::call!(gl::DepthFunc(gl::GREATER));
+        gl::call!(gl::UseProgram(self.program.program.id()));
+        gl::call!(gl::UniformMatrix4fv(
+            self.program.proj_from_view_location,
+            1,
+            gl::FALSE,
+            pixel_from_view.to_cols_array().as_ptr(),
+        ));
+        gl::call!(gl::UniformMatrix4fv(
+            self.program.view_from_world_location,
+            1,
+            gl::FALSE,
+            view_from_world.to_cols_array().as_ptr(),
+        ));
+        draw_calls.draw_picking(
+            pixel_from_view * view_from_world,
+            gltf::ATTR_LOC_MODEL_TRANSFORM_COLUMNS,
+            gltf::ATTR_LOC_MORPH_WEIGHTS,
+            gltf::ATTR_LOC_INSTANCE_DATA,
+            self.pick_base_location,
+        );
+        // RGBA_INTEGER and UNSIGNED_INT is always supported for reading
+        // unsigned integer color buffers.
+        let mut pixel = [0u32; 4];
+        gl::call!(gl::ReadPixels(
+            0,
+            0,
+            1,
+            1,
+            gl::RGBA_INTEGER,
+            gl::UNSIGNED_INT,
+            pixel.as_mut_ptr() as *mut _,
+        ));
+
+        gl::call!(gl::BindFramebuffer(
+            gl::FRAMEBUFFER,
+            previous_framebuffer as u32
+        ));
+        let [x, y, width, height] = previous_viewport;
+        gl::call!(gl::Viewport(x, y, width, height));
+
+        let [pick_index, depth_bits, ..] = pixel;
+        let (source, instance_data) = draw_calls.get_pick_target(pick_index)?;
+        let depth = f32::from_bits(depth_bits);
+        let clip_from_world = proj_from_view * view_from_world;
+        Some(PickResult {
+            source,
+            instance_data,
+            depth,
+            world_position: get_world_position(clip_from_world, ndc, depth),
+        })
+    }
+}
+
+/// Returns the normalized device coordinates of the center of the pixel at
+/// `screen_position`.
+fn get_pixel_center_ndc(screen_position: Vec2, screen_size: Vec2) -> Vec2 {
+    let pixel_center = screen_position.floor() + Vec2::splat(0.5);
+    Vec2::new(
+        pixel_center.x / screen_size.x * 2.0 - 1.0,
+        1.0 - pixel_center.y / screen_size.y * 2.0,
+    )
+}
+
+/// Returns a matrix which scales the pixel centered at `ndc` up to cover the
+/// whole clip space, so that only it is rendered into a one pixel viewport.
+fn get_pixel_from_clip(ndc: Vec2, screen_size: Vec2) -> Mat4 {
+    Mat4::from_cols(
+        Vec4::new(screen_size.x, 0.0, 0.0, 0.0),
+        Vec4::new(0.0, screen_size.y, 0.0, 0.0),
+        Vec4::Z,
+        Vec4::new(-ndc.x * screen_size.x, -ndc.y * screen_size.y, 0.0, 1.0),
+    )
+}
+
+/// Returns the world space position at `ndc` with the window space `depth`,
+/// as stored by the picking shader.
+fn get_world_position(clip_from_world: Mat4, ndc: Vec2, depth: f32) -> Vec3 {
+    let world_from_clip = clip_from_world.inverse();
+    world_from_clip.project_point3(ndc.extend(depth * 2.0 - 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_pixel_center_ndc, get_pixel_from_clip, get_world_position};
+    use crate::renderer::Camera;
+    use glam::{Vec2, Vec3, Vec3Swizzles, Vec4Swizzles};
+
+    const SCREEN_SIZE: Vec2 = Vec2::new(800.0, 600.0);
+
+    fn create_camera() -> Camera {
+        Camera::default()
+            .at(Vec3::new(1.0, 2.0, -3.0))
+            .looking_at(Vec3::new(0.0, 0.5, 4.0), Vec3::Y)
+    }
+
+    #[test]
+    fn only_the_picked_pixel_is_inside_the_narrowed_clip_space() {
+        let camera = create_camera();
+        let clip_from_world =
+            camera.proj_from_view(SCREEN_SIZE.x / SCREEN_SIZE.y) * camera.view_from_world();
+        let point = Vec3::new(0.5, 1.0, 2.0);
+        let screen_position = camera.world_to_screen(point, SCREEN_SIZE).unwrap();
+        let ndc = get_pixel_center_ndc(screen_position, SCREEN_SIZE);
+        let pixel_from_clip = get_pixel_from_clip(ndc, SCREEN_SIZE);
+
+        // The point's offset from the pixel's center, scaled from pixels to
+        // the [-1, 1] of the one pixel viewport (with Y flipped).
+        let pixel_center = screen_position.floor() + Vec2::splat(0.5);
+        let offset = (screen_position - pixel_center) * Vec2::new(2.0, -2.0);
+        let clip = clip_from_world * point.extend(1.0);
+        let pixel_clip = pixel_from_clip * clip;
+        assert!((pixel_clip.xy() / pixel_clip.w).abs_diff_eq(offset, 1e-3));
+        // Depth is left as it is.
+        assert_eq!(clip.zw(), pixel_clip.zw());
+
+        // Points in the neighboring pixels are outside of it.
+        for neighbor_offset in [Vec2::X, Vec2::NEG_X, Vec2::Y, Vec2::NEG_Y] {
+            let neighbor = pixel_center + neighbor_offset;
+            let neighbor_ndc = get_pixel_center_ndc(neighbor, SCREEN_SIZE).extend(0.5);
+            let neighbor_clip = pixel_from_clip * neighbor_ndc.extend(1.0);
+            assert!(neighbor_clip.xy().abs().max_element() > 1.0);
+        }
+    }
+
+    #[test]
+    fn world_positions_are_reconstructed_from_reversed_depth() {
+        let camera = create_camera();
+        let clip_from_world =
+            camera.proj_from_view(SCREEN_SIZE.x / SCREEN_SIZE.y) * camera.view_from_world();
+        let near_point = Vec3::new(0.5, 1.0, 2.0);
+        let far_point = Vec3::new(-3.0, -1.0, 30.0);
+        let mut depths = Vec::new();
+        for point in [near_point, far_point] {
+            let ndc = clip_from_world.project_point3(point);
+            let depth = ndc.z * 0.5 + 0.5;
+            assert!((0.0..=1.0).contains(&depth));
+            let world_position = get_world_position(clip_from_world, ndc.xy(), depth);
+            assert!(world_position.abs_diff_eq(point, 1e-3), "{world_position}");
+            depths.push(depth);
+        }
+        // Closer surfaces have the greater depth.
+        assert!(depths[0] > depths[1]);
+    }
+}
@@ -0,0 +1,391 @@
+use crate::renderer::gl;
+use crate::renderer::handles::{Framebuffer, Program, Renderbuffer, Texture, VertexArray};
+
+/// The maximum amount of times the image is halved for the bloom effect.
+const MAX_BLOOM_LEVELS: usize = 6;
+/// The smallest width or height of a bloom level.
+const MIN_BLOOM_LEVEL_SIZE: i32 = 8;
+/// How gradually the bloom fades in below the threshold, relative to the
+/// threshold.
+const BLOOM_SOFT_KNEE: f32 = 0.5;
+/// The maximum amount of samples per pixel for the multisampled render target.
+const MAX_SAMPLES: i32 = 4;
+
+const TEX_UNIT_SOURCE: u32 = 0;
+const TEX_UNIT_BLOOM: u32 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Tonemapper {
+    /// The colors are just clamped to the displayable range.
+    None,
+    Reinhard,
+    /// Krzysztof Narkowicz's fit of the ACES filmic curve.
+    Aces,
+    AgX,
+}
+
+impl Tonemapper {
+    /// The next tonemapper, wrapping around after the last one, for cycling
+    /// through them.
+    pub fn next(self) -> Tonemapper {
+        match self {
+            Tonemapper::None => Tonemapper::Reinhard,
+            Tonemapper::Reinhard => Tonemapper::Aces,
+            Tonemapper::Aces => Tonemapper::AgX,
+            Tonemapper::AgX => Tonemapper::None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PostProcessSettings {
+    /// A multiplier for the rendered radiance, applied before bloom and
+    /// tonemapping. Lights are in the photometric units of
+    /// KHR_lights_punctual, so scenes lit with real-world intensities need
+    /// exposures well below 1.0.
+    pub exposure: f32,
+    /// How much of the blurred bright areas is added on top of the image. 0.0
+    /// disables bloom.
+    pub bloom_intensity: f32,
+    /// The (exposed) brightness above which pixels start to bloom.
+    pub bloom_threshold: f32,
+    pub tonemapper: Tonemapper,
+}
+
+impl Default for PostProcessSettings {
+    fn default() -> Self {
+        PostProcessSettings {
+            exposure: 1.0,
+            bloom_intensity: 0.05,
+            bloom_threshold: 1.0,
+            tonemapper: Tonemapper::Aces,
+        }
+    }
+}
+
+/// A post-processing pass, drawn as a fullscreen triangle.
+struct FullscreenProgram {
+    program: Program,
+    params_location: Option<gl::types::GLint>,
+}
+
+impl FullscreenProgram {
+    fn new(fragment_shader_source: &str) -> FullscreenProgram {
+        let vertex_shader =
+            gl::create_shader(gl::VERTEX_SHADER, include_str!("fullscreen_vertex.glsl"));
+        let fragment_shader = gl::create_shader(gl::FRAGMENT_SHADER, fragment_shader_source);
+        let program = gl::create_program(&[vertex_shader, fragment_shader]);
+        let handle = Program::from_raw(program);
+        gl::call!(gl::DeleteShader(vertex_shader));
+        gl::call!(gl::DeleteShader(fragment_shader));
+        gl::call!(gl::UseProgram(program));
+        for (name, unit) in [
+            ("source_tex", TEX_UNIT_SOURCE),
+            ("hdr_tex", TEX_UNIT_SOURCE),
+            ("bloom_tex", TEX_UNIT_BLOOM),
+        ] {
+            if let Some(location) = gl::get_uniform_location(program, name) {
+                gl::call!(gl::Uniform1i(location, unit as i32));
+            }
+        }
+        let params_location = gl::get_uniform_location(program, "params");
+        FullscreenProgram {
+            program: handle,
+            params_location,
+        }
+    }
+}
+
+/// A texture with a framebuffer for rendering into it.
+struct RenderTexture {
+    texture: Texture,
+    framebuffer: Framebuffer,
+    width: i32,
+    height: i32,
+}
+
+impl RenderTexture {
+    fn new(format: gl::types::GLenum, width: i32, height: i32) -> RenderTexture {
+        let texture = Texture::new();
+        gl::call!(gl::BindTexture(gl::TEXTURE_2D, texture.id()));
+        gl::call!(gl::TexStorage2D(gl::TEXTURE_2D, 1, format, width, height));
+        let target = gl::TEXTURE_2D;
+        let linear = gl::LINEAR as i32;
+        gl::call!(gl::TexParameteri(target, gl::TEXTURE_MIN_FILTER, linear));
+        gl::call!(gl::TexParameteri(target, gl::TEXTURE_MAG_FILTER, linear));
+        let clamp = gl::CLAMP_TO_EDGE as i32;
+        gl::call!(gl::TexParameteri(target, gl::TEXTURE_WRAP_S, clamp));
+        gl::call!(gl::TexParameteri(target, gl::TEXTURE_WRAP_T, clamp));
+        let framebuffer = Framebuffer::new();
+        gl::call!(gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer.id()));
+        gl::call!(gl::FramebufferTexture2D(
+            gl::FRAMEBUFFER,
+            gl::COLOR_ATTACHMENT0,
+            gl::TEXTURE_2D,
+            texture.id(),
+            0,
+        ));
+        RenderTexture {
+            texture,
+            framebuffer,
+            width,
+            height,
+        }
+    }
+
+    fn bind_framebuffer(&self) {
+        gl::call!(gl::BindFramebuffer(gl::FRAMEBUFFER, self.framebuffer.id()));
+        gl::call!(gl::Viewport(0, 0, self.width, self.height));
+    }
+}
+
+/// The multisampled render target the scene is rendered into, and the
+/// textures and framebuffers used to post-process it into the default
+/// framebuffer.
+struct RenderTargets {
+    width: i32,
+    height: i32,
+    msaa_framebuffer: Framebuffer,
+    /// The color and depth renderbuffers of `msaa_framebuffer`, only held to
+    /// keep them alive.
+    _msaa_renderbuffers: [Renderbuffer; 2],
+    /// The multisampled color buffer is resolved here for post-processing.
+    resolved: RenderTexture,
+    /// Each level is half the size of the previous one, the first is half of
+    /// the rendering resolution.
+    bloom_levels: Vec<RenderTexture>,
+}
+
+impl RenderTargets {
+    fn new(format: gl::types::GLenum, samples: i32, width: i32, height: i32) -> RenderTargets {
+        let msaa_renderbuffers = [Renderbuffer::new(), Renderbuffer::new()];
+        let msaa_framebuffer = Framebuffer::new();
+        gl::call!(gl::BindFramebuffer(gl::FRAMEBUFFER, msaa_framebuffer.id()));
+        let [color, depth] = &msaa_renderbuffers;
+        for (renderbuffer, format, attachment) in [
+            (color, format, gl::COLOR_ATTACHMENT0),
+            (depth, gl::DEPTH_COMPONENT32F, gl::DEPTH_ATTACHMENT),
+        ] {
+            gl::call!(gl::BindRenderbuffer(gl::RENDERBUFFER, renderbuffer.id()));
+            gl::call!(gl::RenderbufferStorageMultisample(
+                gl::RENDERBUFFER,
+                samples,
+                format,
+                width,
+                height,
+            ));
+            gl::call!(gl::FramebufferRenderbuffer(
+                gl::FRAMEBUFFER,
+                attachment,
+                gl::RENDERBUFFER,
+                renderbuffer.id(),
+            ));
+        }
+        let status = gl::call!(gl::CheckFramebufferStatus(gl::FRAMEBUFFER));
+        assert_eq!(
+            gl::FRAMEBUFFER_COMPLETE,
+            status,
+            "incomplete HDR framebuffer"
+        );
+
+        let resolved = RenderTexture::new(format, width, height);
+        let mut bloom_levels = Vec::with_capacity(MAX_BLOOM_LEVELS);
+        let (mut level_width, mut level_height) = (width / 2, height / 2);
+        while bloom_levels.len() < MAX_BLOOM_LEVELS
+            && level_width.min(level_height) >= MIN_BLOOM_LEVEL_SIZE
+        {
+            bloom_levels.push(RenderTexture::new(format, level_width, level_height));
+            level_width /= 2;
+            level_height /= 2;
+        }
+
+        RenderTargets {
+            width,
+            height,
+            msaa_framebuffer,
+            _msaa_renderbuffers: msaa_renderbuffers,
+            resolved,
+            bloom_levels,
+        }
+    }
+}
+
+/// Renders the scene into a floating point framebuffer (if supported), and
+/// then applies exposure, bloom, tonemapping, and the sRGB encoding while
+/// drawing it into the default framebuffer.
+pub struct PostProcess {
+    pub settings: PostProcessSettings,
+    format: gl::types::GLenum,
+    samples: i32,
+    targets: Option<RenderTargets>,
+    downsample_program: FullscreenProgram,
+    upsample_program: FullscreenProgram,
+    composite_program: FullscreenProgram,
+    /// An empty vertex array for drawing the fullscreen triangles, which are
+    /// generated from gl_VertexID.
+    vao: VertexArray,
+}
+
+impl PostProcess {
+    pub fn new(settings: PostProcessSettings) -> PostProcess {
+        // Float color buffers are not renderable in plain GLES 3.0 (or WebGL
+        // 2), without them HDR values are clamped and bloom only picks up
+        // the brightest pixels.
+        let format = if gl::has_extension("GL_EXT_color_buffer_float")
+            || gl::has_extension("GL_EXT_color_buffer_half_float")
+        {
+            gl::RGBA16F
+        } else {
+            gl::RGBA8
+        };
+        let mut samples = MAX_SAMPLES;
+        for format in [format, gl::DEPTH_COMPONENT32F] {
+            let mut max_samples = 0;
+            gl::call!(gl::GetInternalformativ(
+                gl::RENDERBUFFER,
+                format,
+                gl::SAMPLES,
+                1,
+                &mut max_samples,
+            ));
+            samples = samples.min(max_samples);
+        }
+
+        PostProcess {
+            settings,
+            format,
+            samples,
+            targets: None,
+            downsample_program: FullscreenProgram::new(include_str!(
+                "bloom_downsample_fragment.glsl"
+            )),
+            upsample_program: FullscreenProgram::new(include_str!("bloom_upsample_fragment.glsl")),
+            composite_program: FullscreenProgram::new(include_str!("post_process_fragment.glsl")),
+            vao: VertexArray::new(),
+        }
+    }
+
+    /// Binds the HDR framebuffer for rendering the scene, resizing it to
+    /// `width` and `height` if needed. Rendering should be followed by a call
+    /// to [PostProcess::finish].
+    pub fn begin(&mut self, width: i32, height: i32) {
+        let (width, height) = (width.max(1), height.max(1));
+        let targets = match self.targets.take() {
+            Some(targets) if targets.width == width && targets.height == height => targets,
+            _ => RenderTargets::new(self.format, self.samples, width, height),
+        };
+        gl::call!(gl::BindFramebuffer(
+            gl::FRAMEBUFFER,
+            targets.msaa_framebuffer.id()
+        ));
+        gl::call!(gl::Viewport(0, 0, width, height));
+        self.targets = Some(targets);
+    }
+
+    /// Resolves the HDR framebuffer and draws it into the default framebuffer
+    /// with all the post-processing effects applied.
+    pub fn finish(&self) {
+        let Some(targets) = &self.targets else {
+            return;
+        };
+        let PostProcessSettings {
+            exposure,
+            bloom_intensity,
+            bloom_threshold,
+            tonemapper,
+        } = self.settings;
+        let (width, height) = (targets.width, targets.height);
+
+        gl::call!(gl::BindFramebuffer(
+            gl::READ_FRAMEBUFFER,
+            targets.msaa_framebuffer.id()
+        ));
+        gl::call!(gl::BindFramebuffer(
+            gl::DRAW_FRAMEBUFFER,
+            targets.resolved.framebuffer.id()
+        ));
+        gl::call!(gl::BlitFramebuffer(
+            0,
+            0,
+            width,
+            height,
+            0,
+            0,
+            width,
+            height,
+            gl::COLOR_BUFFER_BIT,
+            gl::NEAREST,
+        ));
+
+        gl::call!(gl::Disable(gl::DEPTH_TEST));
+        gl::call!(gl::Disable(gl::CULL_FACE));
+        gl::call!(gl::Disable(gl::BLEND));
+        gl::call!(gl::BindVertexArray(self.vao.id()));
+        for unit in [TEX_UNIT_SOURCE, TEX_UNIT_BLOOM] {
+            gl::call!(gl::BindSampler(unit, 0));
+        }
+
+        let bloom = bloom_intensity > 0.0 && !targets.bloom_levels.is_empty();
+        if bloom {
+            gl::call!(gl::UseProgram(self.downsample_program.program.id()));
+            let mut source = &targets.resolved;
+            for (i, level) in targets.bloom_levels.iter().enumerate() {
+                let first = if i == 0 { 1.0 } else { 0.0 };
+                if let Some(location) = self.downsample_program.params_location {
+                    let (threshold, knee) = (bloom_threshold, BLOOM_SOFT_KNEE);
+                    gl::call!(gl::Uniform4f(location, threshold, knee, first, exposure));
+                }
+                level.bind_framebuffer();
+                bind_texture(TEX_UNIT_SOURCE, &source.texture);
+                gl::call!(gl::DrawArrays(gl::TRIANGLES, 0, 3));
+                source = level;
+            }
+
+            gl::call!(gl::UseProgram(self.upsample_program.program.id()));
+            gl::call!(gl::Enable(gl::BLEND));
+            gl::call!(gl::BlendFunc(gl::ONE, gl::ONE));
+            for levels in targets.bloom_levels.windows(2).rev() {
+                let [target, source] = levels else {
+                    unreachable!()
+                };
+                target.bind_framebuffer();
+                bind_texture(TEX_UNIT_SOURCE, &source.texture);
+                gl::call!(gl::DrawArrays(gl::TRIANGLES, 0, 3));
+            }
+            gl::call!(gl::Disable(gl::BLEND));
+        }
+
+        gl::call!(gl::BindFramebuffer(gl::FRAMEBUFFER, 0));
+        gl::call!(gl::Viewport(0, 0, width, height));
+        gl::call!(gl::UseProgram(self.composite_program.program.id()));
+        if let Some(location) = self.composite_program.params_location {
+            let bloom_intensity = if bloom { bloom_intensity } else { 0.0 };
+            let tonemapper = match tonemapper {
+                Tonemapper::None => 0.0,
+                Tonemapper::Reinhard => 1.0,
+                Tonemapper::Aces => 2.0,
+                Tonemapper::AgX => 3.0,
+            };
+            gl::call!(gl::Uniform4f(
+                location,
+                exposure,
+                bloom_intensity,
+                tonemapper,
+                0.0
+            ));
+        }
+        bind_texture(TEX_UNIT_SOURCE, &targets.resolved.texture);
+        if let Some(first_level) = targets.bloom_levels.first() {
+            bind_texture(TEX_UNIT_BLOOM, &first_level.texture);
+        }
+        gl::call!(gl::DrawArrays(gl::TRIANGLES, 0, 3));
+
+        gl::call!(gl::Enable(gl::DEPTH_TEST));
+        gl::call!(gl::BindTexture(gl::TEXTURE_2D, 0));
+    }
+}
+
+fn bind_texture(unit: u32, texture: &Texture) {
+    gl::call!(gl::ActiveTexture(gl::TEXTURE0 + unit));
+    gl::call!(gl::BindTexture(gl::TEXTURE_2D, texture.id()));
+}
@@ -0,0 +1,82 @@
+#version 300 es
+precision highp float;
+
+#define TONEMAPPER_NONE 0
+#define TONEMAPPER_REINHARD 1
+#define TONEMAPPER_ACES 2
+#define TONEMAPPER_AGX 3
+
+out vec4 FRAG_COLOR;
+
+in vec2 tex_coords;
+
+uniform sampler2D hdr_tex;
+uniform sampler2D bloom_tex;
+// x: exposure, y: bloom intensity, z: tonemapper
+uniform vec4 params;
+
+vec3 aces_filmic(vec3 x) {
+  float a = 2.51;
+  float b = 0.03;
+  float c = 2.43;
+  float d = 0.59;
+  float e = 0.14;
+  return clamp(x * (a * x + b) / (x * (c * x + d) + e), vec3(0), vec3(1));
+}
+
+vec3 reinhard(vec3 x) { return x / (1.0 + x); }
+
+// The AgX approximation from Benjamin Wrensch's "Minimal AgX Implementation",
+// with the sigmoid fitted as a polynomial.
+vec3 agx_contrast_approx(vec3 x) {
+  vec3 x2 = x * x;
+  vec3 x4 = x2 * x2;
+  return 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x +
+         0.4298 * x2 + 0.1191 * x - 0.00232;
+}
+
+vec3 agx(vec3 x) {
+  const mat3 agx_mat = mat3(0.842479062253094, 0.0423282422610123,
+                            0.0423756549057051, 0.0784335999999992,
+                            0.878468636469772, 0.0784336, 0.0792237451477643,
+                            0.0791661274605434, 0.879142973793104);
+  const mat3 agx_mat_inv = mat3(1.19687900512017, -0.0528968517574562,
+                                -0.0529716355144438, -0.0980208811401368,
+                                1.15190312990417, -0.0980434501171241,
+                                -0.0990297440797205, -0.0989611768448433,
+                                1.15107367264116);
+  const float min_ev = -12.47393;
+  const float max_ev = 4.026069;
+  x = agx_mat * x;
+  x = clamp(log2(max(x, vec3(1e-10))), min_ev, max_ev);
+  x = (x - min_ev) / (max_ev - min_ev);
+  x = agx_contrast_approx(x);
+  x = agx_mat_inv * x;
+  // The inverse of the sigmoid's display encoding, back to linear.
+  return pow(max(x, vec3(0.0)), vec3(2.2));
+}
+
+vec3 linear_to_srgb(vec3 linear) {
+  vec3 low = linear * 12.92;
+  vec3 high = 1.055 * pow(linear, vec3(1.0 / 2.4)) - 0.055;
+  return mix(high, low, vec3(lessThanEqual(linear, vec3(0.0031308))));
+}
+
+void main() {
+  vec3 color = texture(hdr_tex, tex_coords).rgb * params.x;
+  if (params.y > 0.0) {
+    color += texture(bloom_tex, tex_coords).rgb * params.y;
+  }
+  int tonemapper = int(params.z);
+  if (tonemapper == TONEMAPPER_REINHARD) {
+    color = reinhard(color);
+  } else if (tonemapper == TONEMAPPER_ACES) {
+    color = aces_filmic(color);
+  } else if (tonemapper == TONEMAPPER_AGX) {
+    color = agx(color);
+  }
+  // The framebuffer is not SRGB (thanks to lacking WebGL support), so the
+  // encoding is done here.
+  FRAG_COLOR =
+      vec4(linear_to_srgb(clamp(color, vec3(0.0), vec3(1.0))), 1.0);
+}
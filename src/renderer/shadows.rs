@@ -0,0 +1,461 @@
+use crate::renderer::draw_calls::DrawCalls;
+use crate::renderer::gl;
+use crate::renderer::gltf::{
+    self, Light, LightKind, UniformBlockShadows, MAX_LIGHTS, MAX_SHADOW_CASCADES, MAX_SHADOW_MAPS,
+};
+use crate::renderer::handles::{Buffer, Framebuffer, Texture};
+use bytemuck::Zeroable;
+use glam::{Mat4, Vec3, Vec4};
+use std::ffi::c_void;
+use std::{mem, ptr};
+
+/// The distance from the camera up to which directional lights cast shadows.
+const DIRECTIONAL_SHADOW_DISTANCE: f32 = 30.0;
+/// How far behind the visible area (from the light's point of view) shadow
+/// casters are still rendered into directional lights' shadow maps.
+const DIRECTIONAL_SHADOW_CASTER_MARGIN: f32 = 50.0;
+/// How much the cascade splits lean towards a logarithmic distribution, as
+/// opposed to a linear one.
+const CASCADE_SPLIT_LAMBDA: f32 = 0.75;
+const DIRECTIONAL_DEPTH_BIAS: f32 = 0.0005;
+const SPOT_NEAR: f32 = 0.05;
+/// The far plane of spot lights which don't have a range.
+const SPOT_DEFAULT_FAR: f32 = 100.0;
+const SPOT_DEPTH_BIAS: f32 = 0.00005;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ShadowQuality {
+    Off,
+    Low,
+    Medium,
+    High,
+}
+
+impl ShadowQuality {
+    /// The next quality, wrapping around from High to Off, for cycling
+    /// through them.
+    pub fn next(self) -> ShadowQuality {
+        match self {
+            ShadowQuality::Off => ShadowQuality::Low,
+            ShadowQuality::Low => ShadowQuality::Medium,
+            ShadowQuality::Medium => ShadowQuality::High,
+            ShadowQuality::High => ShadowQuality::Off,
+        }
+    }
+
+    /// The width and height of each shadow map.
+    fn resolution(self) -> i32 {
+        match self {
+            ShadowQuality::Off => 1,
+            ShadowQuality::Low => 512,
+            ShadowQuality::Medium => 1024,
+            ShadowQuality::High => 2048,
+        }
+    }
+
+    /// The amount of cascades used for directional lights.
+    fn cascades(self) -> usize {
+        match self {
+            ShadowQuality::Off => 0,
+            ShadowQuality::Low => 2,
+            ShadowQuality::Medium => 3,
+            ShadowQuality::High => MAX_SHADOW_CASCADES,
+        }
+    }
+
+    /// The radius of the PCF kernel in texels. With 0, only one (bilinearly
+    /// filtered) sample is taken.
+    fn filter_radius(self) -> f32 {
+        match self {
+            ShadowQuality::Off | ShadowQuality::Low => 0.0,
+            ShadowQuality::Medium => 1.0,
+            ShadowQuality::High => 2.0,
+        }
+    }
+}
+
+/// Renders the shadow maps of shadow casting directional and spot lights, and
+/// holds the shadow maps and the uniform block used to sample them in the glTF
+/// fragment shader.
+pub struct Shadows {
+    quality: ShadowQuality,
+    program: gltf::ShaderProgram,
+    /// A TEXTURE_2D_ARRAY with a depth layer per shadow map.
+    texture: Texture,
+    /// A framebuffer for rendering into each layer of `texture`.
+    framebuffers: Vec<Framebuffer>,
+    uniform_buffer: Buffer,
+}
+
+impl Shadows {
+    pub fn new(quality: ShadowQuality) -> Shadows {
+        let program = gltf::create_shadow_program();
+        let uniform_buffer = Buffer::new();
+        gl::call!(gl::BindBuffer(gl::UNIFORM_BUFFER, uniform_buffer.id()));
+        gl::call!(gl::BufferData(
+            gl::UNIFORM_BUFFER,
+            mem::size_of::<UniformBlockShadows>() as isize,
+            ptr::null(),
+            gl::DYNAMIC_DRAW,
+        ));
+        let (texture, framebuffers) = create_shadow_maps(quality);
+        Shadows {
+            quality,
+            program,
+            texture,
+            framebuffers,
+            uniform_buffer,
+        }
+    }
+
+    pub fn quality(&self) -> ShadowQuality {
+        self.quality
+    }
+
+    /// Sets the quality of the shadows, recreating the shadow maps if the
+    /// resolution changes.
+    pub fn set_quality(&mut self, quality: ShadowQuality) {
+        if quality == self.quality {
+            return;
+        }
+        let recreate = quality.resolution() != self.quality.resolution();
+        self.quality = quality;
+        if recreate {
+            (self.texture, self.framebuffers) = create_shadow_maps(quality);
+        }
+    }
+
+    /// Renders the shadow maps of `lights` (as in [gltf::Gltf::lights]) using
+    /// the opaque instances in `draw_calls`, with the directional light
+    /// cascades fit to the view frustum of the camera. Should be called before
+    /// the main pass is set up, as this changes the viewport, framebuffer and
+    /// depth test state, and only restores the viewport and framebuffer.
+    ///
+    /// The shadows are assigned to the lights by their index, so that the
+    /// glTF fragment shader can find them based on the light's index in the
+    /// lights uniform block. Point lights don't cast shadows.
+    pub fn render(
+        &mut self,
+        draw_calls: &mut DrawCalls,
+        lights: &[Light],
+        view_from_world: Mat4,
+        proj_from_view: Mat4,
+    ) {
+        let mut shadows_block = UniformBlockShadows::zeroed();
+        for params in &mut shadows_block.light_shadow_params {
+            *params = Vec4::new(-1.0, 0.0, 0.0, 0.0);
+        }
+
+        let world_from_view = view_from_world.inverse();
+        let frustum = Frustum::new(world_from_view, proj_from_view * view_from_world);
+        let cascades = self.quality.cascades();
+        let shadow_far = frustum
+            .far_depth
+            .min(frustum.near_depth + DIRECTIONAL_SHADOW_DISTANCE);
+        let mut cascade_depths = [frustum.near_depth; MAX_SHADOW_CASCADES + 1];
+        for (i, depth) in cascade_depths.iter_mut().enumerate().skip(1).take(cascades) {
+            let (near, far) = (frustum.near_depth, shadow_far);
+            let split = i as f32 / cascades as f32;
+            let log = near * (far / near).powf(split);
+            let linear = near + (far - near) * split;
+            *depth = CASCADE_SPLIT_LAMBDA * log + (1.0 - CASCADE_SPLIT_LAMBDA) * linear;
+        }
+        for (i, depth) in shadows_block.cascade_depths.iter_mut().enumerate() {
+            *depth = cascade_depths[(i + 1).min(cascades)];
+        }
+        let view_forward = view_from_world.transform_vector3(frustum.forward);
+        shadows_block.view_forward_and_filter_radius =
+            Vec4::from((view_forward, self.quality.filter_radius()));
+
+        let resolution = self.quality.resolution();
+        let mut shadow_maps = Vec::with_capacity(MAX_SHADOW_MAPS);
+        if self.quality != ShadowQuality::Off {
+            for (i, light) in lights.iter().enumerate().take(MAX_LIGHTS) {
+                if !light.casts_shadows {
+                    continue;
+                }
+                match light.kind {
+                    LightKind::Directional => {
+                        if shadow_maps.len() + cascades > MAX_SHADOW_MAPS {
+                            continue;
+                        }
+                        shadows_block.light_shadow_params[i] = Vec4::new(
+                            shadow_maps.len() as f32,
+                            cascades as f32,
+                            DIRECTIONAL_DEPTH_BIAS,
+                            0.0,
+                        );
+                        for cascade in 0..cascades {
+                            let corners = frustum.get_slice_corners(
+                                cascade_depths[cascade],
+                                cascade_depths[cascade + 1],
+                            );
+                            shadow_maps.push(get_directional_shadow_clip_from_world(
+                                light.direction,
+                                &corners,
+                                resolution,
+                            ));
+                        }
+                    }
+                    LightKind::Spot => {
+                        if shadow_maps.len() >= MAX_SHADOW_MAPS {
+                            continue;
+                        }
+                        shadows_block.light_shadow_params[i] =
+                            Vec4::new(shadow_maps.len() as f32, 1.0, SPOT_DEPTH_BIAS, 0.0);
+                        let fov = (light.outer_cone_angle * 2.0).min(3.0);
+                        let far = light.range.unwrap_or(SPOT_DEFAULT_FAR);
+                        let light_up = get_up_vector(light.direction);
+                        shadow_maps.push(
+                            Mat4::perspective_rh_gl(fov, 1.0, SPOT_NEAR, far)
+                                * Mat4::look_to_rh(light.position, light.direction, light_up),
+                        );
+                    }
+                    // Point lights would need cube maps, which aren't
+                    // implemented.
+                    LightKind::Point => {}
+                }
+            }
+        }
+        for (shadow_from_view, clip_from_world) in
+            (shadows_block.shadow_from_view.iter_mut()).zip(&shadow_maps)
+        {
+            *shadow_from_view = *clip_from_world * world_from_view;
+        }
+
+        let shadows_data = [shadows_block];
+        let shadows_data: &[u8] = bytemuck::cast_slice(&shadows_data);
+        gl::call!(gl::BindBuffer(gl::UNIFORM_BUFFER, self.uniform_buffer.id()));
+        gl::call!(gl::BufferSubData(
+            gl::UNIFORM_BUFFER,
+            0,
+            shadows_data.len() as isize,
+            shadows_data.as_ptr() as *const c_void,
+        ));
+
+        if shadow_maps.is_empty() {
+            return;
+        }
+        let mut previous_viewport = [0; 4];
+        gl::call!(gl::GetIntegerv(
+            gl::VIEWPORT,
+            previous_viewport.as_mut_ptr()
+        ));
+        let mut previous_framebuffer = 0;
+        gl::call!(gl::GetIntegerv(
+            gl::FRAMEBUFFER_BINDING,
+            &mut previous_framebuffer
+        ));
+
+        gl::call!(gl::UseProgram(self.program.program.id()));
+        gl::call!(gl::UniformMatrix4fv(
+            self.program.view_from_world_location,
+            1,
+            gl::FALSE,
+            Mat4::IDENTITY.to_cols_array().as_ptr(),
+        ));
+        gl::call!(gl::Viewport(0, 0, resolution, resolution));
+        gl::call!(gl::Enable(gl::DEPTH_TEST));
+        gl::call!(gl::DepthFunc(gl::LESS));
+        gl::call!(gl::ClearDepthf(1.0));
+        gl::call!(gl::Enable(gl::POLYGON_OFFSET_FILL));
+        gl::call!(gl::PolygonOffset(1.5, 4.0));
+        for (framebuffer, clip_from_world) in self.framebuffers.iter().zip(&shadow_maps) {
+            gl::call!(gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer.id()));
+            gl::call!(gl::Clear(gl::DEPTH_BUFFER_BIT));
+            gl::call!(gl::UniformMatrix4fv(
+                self.program.proj_from_view_location,
+                1,
+                gl::FALSE,
+                clip_from_world.to_cols_array().as_ptr(),
+            ));
+            draw_calls.draw_opaque(
+                *clip_from_world,
+                gltf::ATTR_LOC_MODEL_TRANSFORM_COLUMNS,
+                gltf::ATTR_LOC_MORPH_WEIGHTS,
+                gltf::ATTR_LOC_INSTANCE_DATA,
+            );
+        }
+        gl::call!(gl::Disable(gl::POLYGON_OFFSET_FILL));
+        gl::call!(gl::BindFramebuffer(
+            gl::FRAMEBUFFER,
+            previous_framebuffer as u32
+        ));
+        let [x, y, width, height] = previous_viewport;
+        gl::call!(gl::Viewport(x, y, width, height));
+    }
+
+    /// Binds the shadow maps and the shadows uniform block for the glTF
+    /// shader.
+    pub fn bind(&self) {
+        gl::call!(gl::ActiveTexture(gl::TEXTURE0 + gltf::TEX_UNIT_SHADOW_MAPS));
+        gl::call!(gl::BindTexture(gl::TEXTURE_2D_ARRAY, self.texture.id()));
+        gl::call!(gl::BindSampler(gltf::TEX_UNIT_SHADOW_MAPS, 0));
+        gl::call!(gl::BindBufferBase(
+            gl::UNIFORM_BUFFER,
+            gltf::UNIFORM_BLOCK_SHADOWS,
+            self.uniform_buffer.id(),
+        ));
+    }
+}
+
+/// Creates the shadow map texture array for the quality, and a framebuffer
+/// for each of its layers.
+fn create_shadow_maps(quality: ShadowQuality) -> (Texture, Vec<Framebuffer>) {
+    let resolution = quality.resolution();
+    let layers = if quality == ShadowQuality::Off {
+        1
+    } else {
+        MAX_SHADOW_MAPS
+    };
+    let target = gl::TEXTURE_2D_ARRAY;
+    let texture = Texture::new();
+    gl::call!(gl::BindTexture(target, texture.id()));
+    gl::call!(gl::TexImage3D(
+        target,
+        0,
+        gl::DEPTH_COMPONENT24 as i32,
+        resolution,
+        resolution,
+        layers as i32,
+        0,
+        gl::DEPTH_COMPONENT,
+        gl::UNSIGNED_INT,
+        ptr::null(),
+    ));
+    // Linear filtering with depth comparison gives a bilinearly filtered
+    // shadow test per sample, on top of which the PCF kernel is applied.
+    let linear = gl::LINEAR as i32;
+    let clamp = gl::CLAMP_TO_EDGE as i32;
+    let compare_mode = gl::COMPARE_REF_TO_TEXTURE as i32;
+    gl::call!(gl::TexParameteri(target, gl::TEXTURE_MIN_FILTER, linear));
+    gl::call!(gl::TexParameteri(target, gl::TEXTURE_MAG_FILTER, linear));
+    gl::call!(gl::TexParameteri(target, gl::TEXTURE_WRAP_S, clamp));
+    gl::call!(gl::TexParameteri(target, gl::TEXTURE_WRAP_T, clamp));
+    gl::call!(gl::TexParameteri(
+        target,
+        gl::TEXTURE_COMPARE_MODE,
+        compare_mode
+    ));
+    gl::call!(gl::TexParameteri(
+        target,
+        gl::TEXTURE_COMPARE_FUNC,
+        gl::LEQUAL as i32
+    ));
+
+    let mut previous_framebuffer = 0;
+    gl::call!(gl::GetIntegerv(
+        gl::FRAMEBUFFER_BINDING,
+        &mut previous_framebuffer
+    ));
+    let framebuffers = (0..layers).map(|_| Framebuffer::new()).collect::<Vec<_>>();
+    for (layer, framebuffer) in framebuffers.iter().enumerate() {
+        gl::call!(gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer.id()));
+        gl::call!(gl::FramebufferTextureLayer(
+            gl::FRAMEBUFFER,
+            gl::DEPTH_ATTACHMENT,
+            texture.id(),
+            0,
+            layer as i32,
+        ));
+        let draw_buffers = [gl::NONE];
+        gl::call!(gl::DrawBuffers(1, draw_buffers.as_ptr()));
+        gl::call!(gl::ReadBuffer(gl::NONE));
+    }
+    gl::call!(gl::BindFramebuffer(
+        gl::FRAMEBUFFER,
+        previous_framebuffer as u32
+    ));
+    (texture, framebuffers)
+}
+
+/// The corners of the camera's view frustum in world space.
+struct Frustum {
+    camera_position: Vec3,
+    forward: Vec3,
+    near_corners: [Vec3; 4],
+    far_corners: [Vec3; 4],
+    near_depth: f32,
+    far_depth: f32,
+}
+
+impl Frustum {
+    fn new(world_from_view: Mat4, clip_from_world: Mat4) -> Frustum {
+        let world_from_clip = clip_from_world.inverse();
+        let camera_position = world_from_view.w_axis.truncate();
+        let get_corners = |z: f32| {
+            [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)]
+                .map(|(x, y)| world_from_clip.project_point3(Vec3::new(x, y, z)))
+        };
+        let center = |corners: &[Vec3; 4]| corners.iter().sum::<Vec3>() / 4.0;
+        let mut near_corners = get_corners(-1.0);
+        let mut far_corners = get_corners(1.0);
+        // With a reversed depth range, the near plane is at z = 1.
+        if center(&near_corners).distance_squared(camera_position)
+            > center(&far_corners).distance_squared(camera_position)
+        {
+            mem::swap(&mut near_corners, &mut far_corners);
+        }
+        let forward = (center(&far_corners) - camera_position).normalize();
+        let near_depth = (center(&near_corners) - camera_position).dot(forward);
+        let far_depth = (center(&far_corners) - camera_position).dot(forward);
+        Frustum {
+            camera_position,
+            forward,
+            near_corners,
+            far_corners,
+            near_depth,
+            far_depth,
+        }
+    }
+
+    /// Returns the corners of the part of the frustum between the given
+    /// depths.
+    fn get_slice_corners(&self, near_depth: f32, far_depth: f32) -> [Vec3; 8] {
+        let get_corner = |i: usize, depth: f32| {
+            let t = (depth - self.near_depth) / (self.far_depth - self.near_depth);
+            self.near_corners[i].lerp(self.far_corners[i], t)
+        };
+        let mut corners = [self.camera_position; 8];
+        for i in 0..4 {
+            corners[i] = get_corner(i, near_depth);
+            corners[i + 4] = get_corner(i, far_depth);
+        }
+        corners
+    }
+}
+
+/// Returns an orthographic projection covering the bounding sphere of the
+/// frustum slice. The projection is snapped to texel increments, so that the
+/// shadow edges don't shimmer as the camera moves.
+fn get_directional_shadow_clip_from_world(
+    direction: Vec3,
+    corners: &[Vec3; 8],
+    resolution: i32,
+) -> Mat4 {
+    let center = corners.iter().sum::<Vec3>() / 8.0;
+    let radius = corners
+        .iter()
+        .map(|corner| corner.distance(center))
+        .fold(0.0, f32::max);
+    // Rounded to make the size of the projection stable as the camera rotates.
+    let radius = (radius * 16.0).ceil() / 16.0;
+    let eye = center - direction * (radius + DIRECTIONAL_SHADOW_CASTER_MARGIN);
+    let view = Mat4::look_to_rh(eye, direction, get_up_vector(direction));
+    let far = radius * 2.0 + DIRECTIONAL_SHADOW_CASTER_MARGIN;
+    let proj = Mat4::orthographic_rh_gl(-radius, radius, -radius, radius, 0.0, far);
+    let clip_from_world = proj * view;
+
+    let half_resolution = resolution as f32 / 2.0;
+    let origin = clip_from_world.project_point3(Vec3::ZERO) * half_resolution;
+    let offset = (origin.round() - origin) / half_resolution;
+    Mat4::from_translation(Vec3::new(offset.x, offset.y, 0.0)) * clip_from_world
+}
+
+fn get_up_vector(direction: Vec3) -> Vec3 {
+    if direction.y.abs() > 0.99 {
+        Vec3::X
+    } else {
+        Vec3::Y
+    }
+}
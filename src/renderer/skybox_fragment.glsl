@@ -0,0 +1,26 @@
+#version 300 es
+precision highp float;
+
+#define PI 3.14159265
+
+out vec4 FRAG_COLOR;
+
+in vec3 world_dir;
+
+uniform sampler2D environment_tex;
+layout(std140) uniform Environment {
+  vec4 irradiance_sh[9];
+  // x: the last mip level, prefiltered for roughness 1.0, y: intensity
+  vec4 environment_params;
+};
+
+void main() {
+  vec3 dir = normalize(world_dir);
+  // The same equirectangular mapping as in gltf_fragment.glsl.
+  vec2 uv = vec2(atan(dir.z, dir.x) / (2.0 * PI) + 0.5,
+                 acos(clamp(dir.y, -1.0, 1.0)) / PI);
+  // The first mip level is sampled explicitly, as the derivatives of uv are
+  // discontinuous at the seam.
+  vec3 radiance = textureLod(environment_tex, uv, 0.0).rgb;
+  FRAG_COLOR = vec4(radiance * environment_params.y, 1.0);
+}
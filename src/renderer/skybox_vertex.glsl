@@ -0,0 +1,16 @@
+#version 300 es
+
+out vec3 world_dir;
+
+uniform mat4 world_from_clip;
+
+void main() {
+  // A triangle covering the whole screen, with the corners at (-1, -1),
+  // (3, -1), and (-1, 3).
+  vec2 clip_pos = vec2(float((gl_VertexID & 1) << 2) - 1.0,
+                       float((gl_VertexID & 2) << 1) - 1.0);
+  vec4 far_pos = world_from_clip * vec4(clip_pos, 1.0, 1.0);
+  vec4 near_pos = world_from_clip * vec4(clip_pos, -1.0, 1.0);
+  world_dir = far_pos.xyz / far_pos.w - near_pos.xyz / near_pos.w;
+  gl_Position = vec4(clip_pos, 0.0, 1.0);
+}
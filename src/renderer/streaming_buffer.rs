@@ -0,0 +1,312 @@
+use std::ffi::c_void;
+use std::ptr;
+
+use crate::renderer::gl;
+use crate::renderer::handles::Buffer;
+
+/// How many frames' worth of data can be in use by the GPU at once.
+const FRAMES_IN_FLIGHT: usize = 3;
+/// How long to wait for the GPU to finish with a region before reusing it
+/// anyway, in nanoseconds. WebGL doesn't allow waiting at all.
+#[cfg(not(target_family = "wasm"))]
+const FENCE_TIMEOUT: u64 = 100_000_000;
+#[cfg(target_family = "wasm")]
+const FENCE_TIMEOUT: u64 = 0;
+const INITIAL_SIZE: usize = 64 * 1024;
+
+/// The buffers written to during one frame. Usually there's just one, but if
+/// it runs out of space mid-frame, a bigger one is added, and the region is
+/// consolidated into a single buffer once the GPU is done with it.
+struct Region {
+    buffers: Vec<(Buffer, usize)>,
+    /// The index of the buffer in `buffers` currently being written to.
+    current: usize,
+    offset: usize,
+    /// Signaled when the GPU has finished the commands of the frame that last
+    /// used this region.
+    fence: gl::types::GLsync,
+    /// False if the GPU might still be reading the region, in which case it
+    /// needs to be written with synchronization.
+    idle: bool,
+}
+
+/// A streaming allocator for data which is written once per frame, like
+/// instance transforms and joint matrices.
+///
+/// Allocations go into one of [FRAMES_IN_FLIGHT] regions, one per frame, and
+/// a region is only reused after a fence confirms the GPU is done with the
+/// frame which used it. This way, writes never touch data the GPU may still
+/// be reading, and don't need to wait for it.
+pub struct StreamingBuffer {
+    target: gl::types::GLenum,
+    regions: [Region; FRAMES_IN_FLIGHT],
+    current_region: usize,
+}
+
+impl StreamingBuffer {
+    pub fn new(target: gl::types::GLenum) -> StreamingBuffer {
+        let regions = [(); FRAMES_IN_FLIGHT].map(|_| Region {
+            buffers: vec![create_buffer(target, INITIAL_SIZE)],
+            current: 0,
+            offset: 0,
+            fence: ptr::null(),
+            idle: true,
+        });
+        StreamingBuffer {
+            target,
+            regions,
+            current_region: 0,
+        }
+    }
+
+    /// Writes the bytes into the current frame's region, and returns the
+    /// buffer object and offset into it, where the bytes were written.
+    pub fn allocate_buffer(&mut self, bytes: &[u8]) -> (Buffer, usize) {
+        self.allocate_buffer_aligned(bytes, 1)
+    }
+
+    /// Like [Self::allocate_buffer], but the returned offset is a multiple of
+    /// `alignment`, e.g. `UNIFORM_BUFFER_OFFSET_ALIGNMENT` for uniform blocks.
+    pub fn allocate_buffer_aligned(&mut self, bytes: &[u8], alignment: usize) -> (Buffer, usize) {
+        let region = &mut self.regions[self.current_region];
+        let mut offset = region.offset.next_multiple_of(alignment.max(1));
+        let (_, size) = region.buffers[region.current];
+        if offset + bytes.len() > size {
+            // Running out of space mid-frame: the earlier allocations are
+            // already in use by draw calls, so continue in another buffer.
+            region.current += 1;
+            if region.current == region.buffers.len() {
+                let size = (size * 2).max(bytes.len());
+                region.buffers.push(create_buffer(self.target, size));
+            }
+            offset = 0;
+        }
+        let (_, size) = region.buffers[region.current];
+        if offset + bytes.len() > size {
+            // A leftover buffer from an earlier overflow which is too small.
+            region.buffers[region.current] = create_buffer(self.target, bytes.len());
+        }
+        let (buffer, _) = &region.buffers[region.current];
+        write_buffer(self.target, buffer, offset, bytes, region.idle);
+        region.offset = offset + bytes.len();
+        (buffer.clone(), offset)
+    }
+
+    /// Marks the end of the frame's allocations: a fence is placed after the
+    /// commands issued so far, and the next region is taken into use once the
+    /// GPU is done with it.
+    pub fn next_frame(&mut self) {
+        let region = &mut self.regions[self.current_region];
+        if !region.fence.is_null() {
+            gl::call!(gl::DeleteSync(region.fence));
+        }
+        region.fence = gl::call!(gl::FenceSync(gl::SYNC_GPU_COMMANDS_COMPLETE, 0));
+
+        self.current_region = (self.current_region + 1) % FRAMES_IN_FLIGHT;
+        let region = &mut self.regions[self.current_region];
+        region.idle = true;
+        if !region.fence.is_null() {
+            let status = gl::call!(gl::ClientWaitSync(
+                region.fence,
+                gl::SYNC_FLUSH_COMMANDS_BIT,
+                FENCE_TIMEOUT
+            ));
+            region.idle = matches!(status, gl::ALREADY_SIGNALED | gl::CONDITION_SATISFIED);
+        }
+        if region.idle && region.buffers.len() > 1 {
+            // Replace the overflow buffers with one big enough for all of the
+            // last frame's data.
+            let total_size = region.buffers.iter().map(|(_, size)| size).sum();
+            region.buffers.clear();
+            region.buffers.push(create_buffer(self.target, total_size));
+        }
+        region.current = 0;
+        region.offset = 0;
+    }
+}
+
+impl Drop for StreamingBuffer {
+    fn drop(&mut self) {
+        for region in &self.regions {
+            if !region.fence.is_null() {
+                gl::call!(gl::DeleteSync(region.fence));
+            }
+        }
+    }
+}
+
+fn create_buffer(target: gl::types::GLenum, size: usize) -> (Buffer, usize) {
+    let buffer = Buffer::new();
+    gl::call!(gl::BindBuffer(target, buffer.id()));
+    gl::call!(gl::BufferData(
+        target,
+        size as isize,
+        ptr::null(),
+        gl::STREAM_DRAW
+    ));
+    (buffer, size)
+}
+
+/// Writes the bytes into the buffer. If the range is known to be `idle`, i.e.
+/// the GPU isn't using it, the write skips the driver's synchronization where
+/// possible.
+fn write_buffer(
+    target: gl::types::GLenum,
+    buffer: &Buffer,
+    offset: usize,
+    bytes: &[u8],
+    idle: bool,
+) {
+    if bytes.is_empty() {
+        return;
+    }
+    gl::call!(gl::BindBuffer(target, buffer.id()));
+    // WebGL doesn't support mapping buffers, and the browser synchronizes
+    // BufferSubData itself, so there's nothing to skip.
+    if cfg!(target_family = "wasm") {
+        gl::call!(gl::BufferSubData(
+            target,
+            offset as isize,
+            bytes.len() as isize,
+            bytes.as_ptr() as *const c_void,
+        ));
+        return;
+    }
+    let mut access = gl::MAP_WRITE_BIT | gl::MAP_INVALIDATE_RANGE_BIT;
+    if idle {
+        access |= gl::MAP_UNSYNCHRONIZED_BIT;
+    }
+    let mapped = gl::call!(gl::MapBufferRange(
+        target,
+        offset as isize,
+        bytes.len() as isize,
+        access
+    ));
+    assert!(!mapped.is_null(), "mapping a streaming buffer failed");
+    // Safety: the mapped range is bytes.len() bytes long, and a new mapping
+    // can't overlap with the slice.
+    unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), mapped as *mut u8, bytes.len()) };
+    gl::call!(gl::UnmapBuffer(target));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StreamingBuffer, FRAMES_IN_FLIGHT, INITIAL_SIZE};
+    use crate::renderer::fake_gl::{self, args_of};
+    use crate::renderer::gl;
+
+    const SYNCHRONIZED: i64 = (gl::MAP_WRITE_BIT | gl::MAP_INVALIDATE_RANGE_BIT) as i64;
+    const UNSYNCHRONIZED: i64 = SYNCHRONIZED | gl::MAP_UNSYNCHRONIZED_BIT as i64;
+
+    #[test]
+    fn each_frame_writes_into_its_own_region() {
+        fake_gl::install();
+        let mut buffer = StreamingBuffer::new(gl::ARRAY_BUFFER);
+        let mut frame_buffers = Vec::new();
+        for frame in 0..FRAMES_IN_FLIGHT as u8 {
+            let (first, first_offset) = buffer.allocate_buffer(&[frame; 3]);
+            let (second, second_offset) = buffer.allocate_buffer_aligned(&[frame + 10; 2], 4);
+            assert_eq!(first, second);
+            assert_eq!((0, 4), (first_offset, second_offset));
+            let contents = fake_gl::buffer_contents(first.id());
+            assert_eq!(
+                &[frame, frame, frame, 0, frame + 10, frame + 10],
+                &contents[..6]
+            );
+            assert!(!frame_buffers.contains(&first));
+            frame_buffers.push(first);
+            buffer.next_frame();
+        }
+
+        // The first region comes around again, once its fence has signaled.
+        fake_gl::take_calls();
+        let (reused, offset) = buffer.allocate_buffer(&[0xFF; 4]);
+        assert_eq!((&frame_buffers[0], 0), (&reused, offset));
+        buffer.next_frame();
+        let calls = fake_gl::take_calls();
+        let fence_sync = [gl::SYNC_GPU_COMMANDS_COMPLETE as i64, 0];
+        assert_eq!(vec![&fence_sync], args_of(&calls, "FenceSync"));
+        // Each region's previous fence is deleted when it's replaced.
+        assert_eq!(vec![&[1]], args_of(&calls, "DeleteSync"));
+        // The second region's fence from the first round.
+        let flush = gl::SYNC_FLUSH_COMMANDS_BIT as i64;
+        assert_eq!(vec![&[2, flush]], args_of(&calls, "ClientWaitSync"));
+    }
+
+    #[test]
+    fn regions_are_mapped_unsynchronized_only_after_their_fence_signals() {
+        fake_gl::install();
+        let mut buffer = StreamingBuffer::new(gl::ARRAY_BUFFER);
+        let target = gl::ARRAY_BUFFER as i64;
+        fake_gl::take_calls();
+        buffer.allocate_buffer(&[1; 4]);
+        let calls = fake_gl::take_calls();
+        // The regions haven't been used by the GPU yet.
+        let expected_map = [target, 0, 4, UNSYNCHRONIZED];
+        assert_eq!(vec![&expected_map], args_of(&calls, "MapBufferRange"));
+
+        fake_gl::set_client_wait_status(gl::TIMEOUT_EXPIRED);
+        for _ in 0..FRAMES_IN_FLIGHT {
+            buffer.next_frame();
+        }
+        fake_gl::take_calls();
+        buffer.allocate_buffer(&[2; 4]);
+        let calls = fake_gl::take_calls();
+        let expected_map = [target, 0, 4, SYNCHRONIZED];
+        assert_eq!(vec![&expected_map], args_of(&calls, "MapBufferRange"));
+
+        fake_gl::set_client_wait_status(gl::CONDITION_SATISFIED);
+        for _ in 0..FRAMES_IN_FLIGHT {
+            buffer.next_frame();
+        }
+        fake_gl::take_calls();
+        buffer.allocate_buffer(&[3; 4]);
+        let calls = fake_gl::take_calls();
+        let expected_map = [target, 0, 4, UNSYNCHRONIZED];
+        assert_eq!(vec![&expected_map], args_of(&calls, "MapBufferRange"));
+    }
+
+    #[test]
+    fn overflowing_regions_are_consolidated_once_idle() {
+        fake_gl::install();
+        let mut buffer = StreamingBuffer::new(gl::UNIFORM_BUFFER);
+        let target = gl::UNIFORM_BUFFER as i64;
+        let usage = gl::STREAM_DRAW as i64;
+        fake_gl::take_calls();
+        let (first, _) = buffer.allocate_buffer(&vec![1; INITIAL_SIZE - 8]);
+        let (overflow, offset) = buffer.allocate_buffer(&[2; 16]);
+        assert_ne!(first, overflow);
+        assert_eq!(0, offset);
+        let calls = fake_gl::take_calls();
+        let expected_buffer_data = [target, 2 * INITIAL_SIZE as i64, usage];
+        assert_eq!(vec![&expected_buffer_data], args_of(&calls, "BufferData"));
+        assert_eq!(&[2; 16], &fake_gl::buffer_contents(overflow.id())[..16]);
+
+        // The region isn't consolidated while the GPU might be using it.
+        fake_gl::set_client_wait_status(gl::TIMEOUT_EXPIRED);
+        for _ in 0..FRAMES_IN_FLIGHT {
+            buffer.next_frame();
+        }
+        let calls = fake_gl::take_calls();
+        assert!(args_of(&calls, "BufferData").is_empty());
+        assert_eq!((first.clone(), 0), buffer.allocate_buffer(&[3; 4]));
+
+        fake_gl::set_client_wait_status(gl::ALREADY_SIGNALED);
+        for _ in 0..FRAMES_IN_FLIGHT {
+            buffer.next_frame();
+        }
+        let calls = fake_gl::take_calls();
+        let expected_buffer_data = [target, 3 * INITIAL_SIZE as i64, usage];
+        assert_eq!(vec![&expected_buffer_data], args_of(&calls, "BufferData"));
+        let (consolidated, offset) = buffer.allocate_buffer(&vec![4; INITIAL_SIZE + 8]);
+        assert_ne!(first, consolidated);
+        assert_ne!(overflow, consolidated);
+        assert_eq!(0, offset);
+
+        // The replaced buffers are deleted once nothing else uses them.
+        let (first_id, overflow_id) = (first.id(), overflow.id());
+        drop((first, overflow));
+        assert!(!fake_gl::is_live("buffer", first_id));
+        assert!(!fake_gl::is_live("buffer", overflow_id));
+    }
+}
@@ -0,0 +1,129 @@
+use std::ffi::c_void;
+use std::marker::PhantomData;
+use std::{mem, ptr};
+
+use bytemuck::Pod;
+
+use crate::renderer::gl;
+use crate::renderer::handles::Buffer;
+
+/// A `T` allocated from a [UniformBufferAllocator].
+pub struct UniformBlock<T> {
+    buffer: Buffer,
+    offset: usize,
+    _block: PhantomData<T>,
+}
+
+impl<T> Clone for UniformBlock<T> {
+    fn clone(&self) -> Self {
+        UniformBlock {
+            buffer: self.buffer.clone(),
+            offset: self.offset,
+            _block: PhantomData,
+        }
+    }
+}
+
+impl<T> UniformBlock<T> {
+    /// Returns the uniform block as `(binding, buffer, offset, size)`, as in
+    /// [crate::renderer::draw_calls::Uniforms::ubos].
+    pub fn binding(&self, binding: u32) -> (u32, Buffer, usize, usize) {
+        (
+            binding,
+            self.buffer.clone(),
+            self.offset,
+            mem::size_of::<T>(),
+        )
+    }
+}
+
+/// Packs uniform blocks into a single uniform buffer, with each block's offset
+/// aligned to `UNIFORM_BUFFER_OFFSET_ALIGNMENT` as required by
+/// glBindBufferRange.
+pub struct UniformBufferAllocator {
+    buffer: Buffer,
+    alignment: usize,
+    /// The contents of the buffer, for re-uploading them when it grows.
+    data: Vec<u8>,
+    /// The size of the buffer object, which may be more than `data.len()`.
+    capacity: usize,
+}
+
+impl UniformBufferAllocator {
+    pub fn new() -> UniformBufferAllocator {
+        let mut alignment = 0;
+        gl::call!(gl::GetIntegerv(
+            gl::UNIFORM_BUFFER_OFFSET_ALIGNMENT,
+            &mut alignment,
+        ));
+        UniformBufferAllocator {
+            buffer: Buffer::new(),
+            alignment: alignment.max(1) as usize,
+            data: Vec::new(),
+            capacity: 0,
+        }
+    }
+
+    /// Uploads the block into the buffer, growing it if needed.
+    pub fn allocate<T: Pod>(&mut self, block: &T) -> UniformBlock<T> {
+        let offset = self.data.len().next_multiple_of(self.alignment);
+        self.data.resize(offset, 0);
+        self.data.extend_from_slice(bytemuck::bytes_of(block));
+        gl::call!(gl::BindBuffer(gl::UNIFORM_BUFFER, self.buffer.id()));
+        if self.data.len() > self.capacity {
+            self.capacity = (self.capacity * 2).max(self.data.len());
+            gl::call!(gl::BufferData(
+                gl::UNIFORM_BUFFER,
+                self.capacity as isize,
+                ptr::null(),
+                gl::DYNAMIC_DRAW,
+            ));
+            gl::call!(gl::BufferSubData(
+                gl::UNIFORM_BUFFER,
+                0,
+                self.data.len() as isize,
+                self.data.as_ptr() as *const c_void,
+            ));
+        } else {
+            self.write(offset, mem::size_of::<T>());
+        }
+        UniformBlock {
+            buffer: self.buffer.clone(),
+            offset,
+            _block: PhantomData,
+        }
+    }
+
+    /// Replaces the contents of a block allocated from this allocator in
+    /// place. Only GL draw commands that have already been submitted keep
+    /// the old values: draws still queued in a [DrawCalls] that hasn't been
+    /// drawn yet will see the new ones.
+    ///
+    /// [DrawCalls]: crate::renderer::DrawCalls
+    pub fn update<T: Pod>(&mut self, block: &UniformBlock<T>, value: &T) {
+        assert_eq!(self.buffer, block.buffer, "block is from another allocator");
+        let bytes = bytemuck::bytes_of(value);
+        self.data[block.offset..block.offset + bytes.len()].copy_from_slice(bytes);
+        gl::call!(gl::BindBuffer(gl::UNIFORM_BUFFER, self.buffer.id()));
+        self.write(block.offset, bytes.len());
+    }
+
+    /// Returns the current contents of the block.
+    pub fn get<T: Pod>(&self, block: &UniformBlock<T>) -> T {
+        let bytes = &self.data[block.offset..block.offset + mem::size_of::<T>()];
+        bytemuck::pod_read_unaligned(bytes)
+    }
+
+    pub fn buffer(&self) -> &Buffer {
+        &self.buffer
+    }
+
+    fn write(&self, offset: usize, length: usize) {
+        gl::call!(gl::BufferSubData(
+            gl::UNIFORM_BUFFER,
+            offset as isize,
+            length as isize,
+            self.data[offset..].as_ptr() as *const c_void,
+        ));
+    }
+}
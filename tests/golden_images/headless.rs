@@ -0,0 +1,111 @@
+// An OpenGL ES 3.0 context without a window, for rendering frames in tests.
+// The default framebuffer is an EGL pbuffer, so the renderer's code runs
+// unchanged, and the frame can be read back with [read_pixels] instead of
+// being presented. Uses Mesa's surfaceless platform where available, which
+// falls back to software rasterisation (llvmpipe) on machines without a GPU.
+// libEGL is loaded at runtime, so the tests which don't use this can run on
+// machines without it.
+
+use anyhow::{anyhow, Context};
+use khronos_egl as egl;
+
+use ld53_base_code::renderer::gl;
+
+/// EGL_PLATFORM_SURFACELESS_MESA, from EGL_MESA_platform_surfaceless.
+const PLATFORM_SURFACELESS_MESA: egl::Enum = 0x31DD;
+
+pub struct HeadlessContext {
+    egl: egl::DynamicInstance<egl::EGL1_5>,
+    display: egl::Display,
+    surface: egl::Surface,
+    context: egl::Context,
+}
+
+impl HeadlessContext {
+    /// Creates a context with a `width` by `height` default framebuffer,
+    /// makes it current on this thread, and loads the GL functions from it.
+    pub fn new(width: i32, height: i32) -> anyhow::Result<HeadlessContext> {
+        let egl = unsafe { egl::DynamicInstance::<egl::EGL1_5>::load_required() }
+            .context("failed to load EGL 1.5")?;
+        let display = unsafe {
+            egl.get_platform_display(
+                PLATFORM_SURFACELESS_MESA,
+                egl::DEFAULT_DISPLAY,
+                &[egl::ATTRIB_NONE],
+            )
+        }
+        .ok()
+        .or_else(|| unsafe { egl.get_display(egl::DEFAULT_DISPLAY) })
+        .context("no EGL display available")?;
+        egl.initialize(display)
+            .context("EGL initialization failed")?;
+        egl.bind_api(egl::OPENGL_ES_API)
+            .context("OpenGL ES is not supported by EGL")?;
+
+        let config_attributes = [
+            egl::SURFACE_TYPE,
+            egl::PBUFFER_BIT,
+            egl::RENDERABLE_TYPE,
+            egl::OPENGL_ES3_BIT,
+            egl::RED_SIZE,
+            8,
+            egl::GREEN_SIZE,
+            8,
+            egl::BLUE_SIZE,
+            8,
+            egl::ALPHA_SIZE,
+            8,
+            egl::NONE,
+        ];
+        let config = egl
+            .choose_first_config(display, &config_attributes)
+            .context("EGL config selection failed")?
+            .ok_or_else(|| anyhow!("no EGL config for an RGBA8 OpenGL ES 3 pbuffer"))?;
+        let surface = egl
+            .create_pbuffer_surface(
+                display,
+                config,
+                &[egl::WIDTH, width, egl::HEIGHT, height, egl::NONE],
+            )
+            .context("EGL pbuffer creation failed")?;
+        let context = egl
+            .create_context(
+                display,
+                config,
+                None,
+                &[
+                    egl::CONTEXT_MAJOR_VERSION,
+                    3,
+                    egl::CONTEXT_MINOR_VERSION,
+                    0,
+                    egl::NONE,
+                ],
+            )
+            .context("OpenGL ES 3.0 context creation failed")?;
+        egl.make_current(display, Some(surface), Some(surface), Some(context))
+            .context("making the EGL context current failed")?;
+
+        gl::load_with(|s| match egl.get_proc_address(s) {
+            Some(f) => f as *const core::ffi::c_void,
+            None => std::ptr::null(),
+        });
+        gl::call!(gl::Viewport(0, 0, width, height));
+
+        Ok(HeadlessContext {
+            egl,
+            display,
+            surface,
+            context,
+        })
+    }
+}
+
+impl Drop for HeadlessContext {
+    fn drop(&mut self) {
+        let egl = &self.egl;
+        let _ = egl.make_current(self.display, None, None, None);
+        let _ = egl.destroy_context(self.display, self.context);
+        let _ = egl.destroy_surface(self.display, self.surface);
+        let _ = egl.terminate(self.display);
+    }
+}
@@ -0,0 +1,144 @@
+// Renders the bundled scenes with the system's OpenGL ES driver, and compares
+// the frames to the reference images in resources/golden-images.
+//
+// This is a test binary of its own, without the test harness, so that loading
+// the driver's GL functions doesn't interfere with the unit tests, which load
+// the fake ones from src/renderer/fake_gl.rs into the same global function
+// pointers. Like an ignored test, it only runs when asked to, with:
+//
+//     cargo test --test golden_images -- --ignored
+//
+// The reference images are rendered with Mesa's software rasteriser, which
+// needs neither a GPU nor a display, so on machines with a GPU, set
+// LIBGL_ALWAYS_SOFTWARE=1 to compare against the same rasteriser. After
+// intentional changes to the look, replace the references by running the test
+// with UPDATE_GOLDEN_IMAGES=1, and review the new images.
+
+use std::path::PathBuf;
+
+use image::RgbaImage;
+
+#[cfg(not(target_family = "wasm"))]
+mod headless;
+
+#[cfg(not(target_family = "wasm"))]
+fn main() {
+    use glam::Vec3;
+    use headless::HeadlessContext;
+    use ld53_base_code::renderer::{gl, Camera, Renderer, UP};
+
+    let run = std::env::args().any(|arg| arg == "--ignored" || arg == "--include-ignored");
+    if !run {
+        println!("golden_images: ignored, needs an EGL driver");
+        return;
+    }
+
+    let _context = HeadlessContext::new(WIDTH as i32, HEIGHT as i32).unwrap();
+    let mut renderer = Renderer::new().unwrap();
+    let scenes = [
+        (
+            "boom_box",
+            Camera::default()
+                .at(Vec3::new(0.0, 0.5, 0.0))
+                .looking_at(Vec3::new(0.0, -0.7, 3.4), UP),
+            0.0,
+        ),
+        (
+            "interpolation_test",
+            Camera::default()
+                .at(Vec3::new(1.5, 0.6, 2.0))
+                .looking_at(Vec3::new(3.0, 0.5, 4.5), UP),
+            1.5,
+        ),
+    ];
+
+    let mut failures = Vec::new();
+    for (name, camera, time) in scenes {
+        renderer.render(&camera, WIDTH as f32 / HEIGHT as f32, time);
+        let image = gl::read_default_framebuffer(WIDTH, HEIGHT);
+        match compare_to_golden_image(name, &image) {
+            Ok(()) => println!("golden_images: {name} ... ok"),
+            Err(failure) => failures.push(failure),
+        }
+    }
+    if !failures.is_empty() {
+        eprintln!("{}", failures.join("\n"));
+        std::process::exit(1);
+    }
+}
+
+#[cfg(target_family = "wasm")]
+fn main() {}
+
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 180;
+/// The largest difference in any channel for a pixel to still count as
+/// matching the reference.
+const CHANNEL_TOLERANCE: u8 = 8;
+/// The share of pixels which may differ from the reference by more than
+/// [CHANNEL_TOLERANCE], to allow for drivers rasterising edges slightly
+/// differently.
+const MISMATCHED_PIXELS_TOLERANCE: f32 = 0.005;
+
+fn golden_image_path(name: &str) -> PathBuf {
+    let directory = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/golden-images");
+    PathBuf::from(directory).join(format!("{name}.png"))
+}
+
+/// Compares the image to the reference image `name`, returning a
+/// description of the differences if it doesn't match. Mismatching images
+/// are saved into the temp directory for inspection, along with an image
+/// highlighting the differing pixels. With UPDATE_GOLDEN_IMAGES set, the
+/// reference image is replaced instead.
+fn compare_to_golden_image(name: &str, image: &RgbaImage) -> Result<(), String> {
+    let path = golden_image_path(name);
+    if std::env::var_os("UPDATE_GOLDEN_IMAGES").is_some() {
+        image.save(&path).unwrap();
+        return Ok(());
+    }
+    let golden = match image::open(&path) {
+        Ok(golden) => golden.to_rgba8(),
+        Err(err) => return Err(format!("{name}: failed to open {path:?}: {err}")),
+    };
+    if golden.dimensions() != image.dimensions() {
+        return Err(format!(
+            "{name}: the size {:?} doesn't match the reference's {:?}",
+            image.dimensions(),
+            golden.dimensions(),
+        ));
+    }
+
+    let mut diff = RgbaImage::new(image.width(), image.height());
+    let mut mismatched_pixels = 0;
+    for ((pixel, golden_pixel), diff_pixel) in
+        image.pixels().zip(golden.pixels()).zip(diff.pixels_mut())
+    {
+        let difference = (pixel.0.iter().zip(golden_pixel.0))
+            .map(|(a, b)| a.abs_diff(b))
+            .max()
+            .unwrap();
+        if difference > CHANNEL_TOLERANCE {
+            mismatched_pixels += 1;
+            *diff_pixel = image::Rgba([0xFF, 0, 0xFF, 0xFF]);
+        } else {
+            let [r, g, b, _] = golden_pixel.0;
+            let luma = ((r as u32 + g as u32 + b as u32) / 6) as u8;
+            *diff_pixel = image::Rgba([luma, luma, luma, 0xFF]);
+        }
+    }
+    let mismatched_share = mismatched_pixels as f32 / (WIDTH * HEIGHT) as f32;
+    if mismatched_share <= MISMATCHED_PIXELS_TOLERANCE {
+        return Ok(());
+    }
+
+    let output_dir = std::env::temp_dir().join("ld53-golden-images");
+    std::fs::create_dir_all(&output_dir).unwrap();
+    let actual_path = output_dir.join(format!("{name}.png"));
+    let diff_path = output_dir.join(format!("{name}.diff.png"));
+    image.save(&actual_path).unwrap();
+    diff.save(&diff_path).unwrap();
+    Err(format!(
+        "{name}: {:.2}% of the pixels differ from the reference, see {actual_path:?} and {diff_path:?}",
+        mismatched_share * 100.0,
+    ))
+}